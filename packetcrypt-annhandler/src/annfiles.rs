@@ -0,0 +1,509 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Files of announcements for block miners to download. Stored anns are collected into
+//! a file for each parent block height, which is written out once it has max_anns or
+//! is max_ms old, and only the newest files_to_keep files are kept.
+//!
+//! Files can also be pruned by age and by how much space they take, see Retention. A
+//! pruned file leaves the index at once but is only deleted RETIRE_MS later, so that
+//! block miners which have just seen it in the index can still download it.
+//!
+//! Block miners which would rather have fewer, bigger files can send x-pc-file-anns
+//! when getting the index, and then the files are listed in groups named
+//! anns_<first>-<last>.bin which are served as one.
+//!
+//! Files are numbered in order and the numbers carry on across restarts, so a block
+//! miner which comes back after losing its connection can send x-pc-since with the
+//! newest file it has and get only the files after it.
+//!
+//! If hash_index is enabled, the location of every ann in the files is kept by its
+//! hash so that explorers can get one ann without downloading whole files.
+//!
+//! For handlers with no disk of their own, the files can be kept in memory instead,
+//! then files_to_keep is the length of a ring of files and when it's full the oldest is
+//! dropped. The number of anns dropped this way is counted.
+//!
+//! Every file can also be copied into a mirror which is never pruned, see mirror.rs.
+use crate::mirror::{self, Mirror};
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use crossbeam_channel::{Receiver, Sender};
+use log::{debug, info, warn};
+use packetcrypt_util::protocol::AnnIndex;
+use packetcrypt_util::{hash, util};
+use parking_lot::Mutex;
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
+use std::io::SeekFrom;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+pub const DEFAULT_MAX_ANNS: usize = 1024;
+pub const DEFAULT_MAX_MS: u64 = 2000;
+
+// Largest number of files which can be served as one
+const MAX_GROUP: usize = 64;
+
+const FILE_REGEX: &str = r"^anns_([0-9]+)\.bin$";
+const GROUP_REGEX: &str = r"^anns_([0-9]+)(-([0-9]+))?\.bin$";
+
+// How long a pruned file can still be downloaded
+const RETIRE_MS: u64 = 30_000;
+// How often files are checked for age and retired files are deleted
+const PRUNE_MS: u64 = 1000;
+
+/// Which files to keep, a file is pruned when any of these says so
+#[derive(Clone, Copy, Default)]
+pub struct Retention {
+    // Newest number of files
+    pub files: usize,
+    // Files older than this, 0 for no limit
+    pub max_age_ms: u64,
+    // Oldest files while all of them take more than this, 0 for no limit
+    pub max_bytes: usize,
+}
+
+/// Files pruned for each reason, since the handler started
+#[derive(Default)]
+pub struct Pruned {
+    pub count: AtomicUsize,
+    pub age: AtomicUsize,
+    pub disk: AtomicUsize,
+}
+
+struct Pending {
+    parent_block_height: i32,
+    anns: BytesMut,
+    count: usize,
+    opened_ms: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct AnnLocation {
+    pub file: usize,
+    // Byte offset of the ann in the file
+    pub offset: usize,
+    pub content_type: u32,
+    pub content_length: u32,
+}
+
+#[derive(Default)]
+struct HashIndex {
+    by_hash: HashMap<[u8; 32], AnnLocation>,
+    // So that the entries can be removed when the file is deleted
+    by_file: HashMap<usize, Vec<[u8; 32]>>,
+}
+
+struct AnnFilesM {
+    pending: HashMap<i32, Pending>,
+    // Numbers of the files on disk, oldest first
+    files: VecDeque<usize>,
+    // When each file was written and its size
+    file_info: HashMap<usize, (u64, usize)>,
+    // Size of the files in the index
+    bytes: usize,
+    // Files which were pruned from the index and when, to be deleted after RETIRE_MS
+    retiring: VecDeque<(usize, u64)>,
+    hash_index: Option<HashIndex>,
+    // Content of the files, if they're kept in memory
+    in_memory: HashMap<usize, Bytes>,
+}
+
+pub struct AnnFilesS {
+    m: Mutex<AnnFilesM>,
+    // Empty if the files are kept in memory
+    dir: String,
+    max_anns: usize,
+    max_ms: u64,
+    retention: Retention,
+    pruned: Pruned,
+    // Files are written in order by one thread so that the index never has gaps
+    write_send: Sender<Pending>,
+    group_regex: Regex,
+    // Anns in files which were dropped from memory, since the last dropped_anns()
+    dropped_anns: AtomicUsize,
+    mirror: Option<Mirror>,
+}
+pub type AnnFiles = Arc<AnnFilesS>;
+
+fn file_name(dir: &str, num: usize) -> String {
+    format!("{}/anns_{}.bin", dir, num)
+}
+
+/// If dir is empty then the files are kept in memory.
+pub fn new(
+    dir: &str,
+    max_anns: usize,
+    max_ms: u64,
+    retention: Retention,
+    hash_index: bool,
+    mirror: Option<Mirror>,
+) -> Result<AnnFiles> {
+    // Files from the last run are not in the index, but the numbers carry on so that
+    // block miners don't mistake new files for ones they already have
+    let mut next_num = 0;
+    if dir.is_empty() {
+        // Nothing is left from the last run, but it won't have made a file every ms
+        next_num = util::now_ms() as usize;
+        info!(
+            "Keeping up to {} ann files ({}MB) in memory",
+            retention.files,
+            retention.files * max_anns / 1024
+        );
+    } else {
+        std::fs::create_dir_all(dir)?;
+        let file_regex = Regex::new(FILE_REGEX)?;
+        for e in std::fs::read_dir(dir)? {
+            let name = e?.file_name().to_string_lossy().into_owned();
+            if let Some(c) = file_regex.captures(&name) {
+                next_num = next_num.max(c[1].parse::<usize>()? + 1);
+                std::fs::remove_file(format!("{}/{}", dir, name))?;
+            }
+        }
+        info!("Writing ann files to {} starting from {}", dir, next_num);
+    }
+    let (write_send, write_recv) = crossbeam_channel::unbounded();
+    let af = Arc::new(AnnFilesS {
+        m: Mutex::new(AnnFilesM {
+            pending: HashMap::new(),
+            files: VecDeque::new(),
+            file_info: HashMap::new(),
+            bytes: 0,
+            retiring: VecDeque::new(),
+            hash_index: if hash_index {
+                Some(HashIndex::default())
+            } else {
+                None
+            },
+            in_memory: HashMap::new(),
+        }),
+        dir: dir.to_owned(),
+        max_anns,
+        max_ms,
+        retention,
+        pruned: Pruned::default(),
+        write_send,
+        group_regex: Regex::new(GROUP_REGEX)?,
+        dropped_anns: AtomicUsize::new(0),
+        mirror,
+    });
+    let af1 = Arc::clone(&af);
+    std::thread::spawn(move || writer_loop(&af1, write_recv, next_num));
+    let af1 = Arc::clone(&af);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(std::time::Duration::from_millis(PRUNE_MS));
+        prune(&af1);
+    });
+    Ok(af)
+}
+
+fn writer_loop(af: &AnnFiles, write_recv: Receiver<Pending>, mut next_num: usize) {
+    for p in write_recv.iter() {
+        let num = next_num;
+        next_num += 1;
+        let (count, anns) = (p.count, p.anns.freeze());
+        if af.dir.is_empty() {
+            af.m.lock().in_memory.insert(num, anns.clone());
+        } else if let Err(e) = std::fs::write(file_name(&af.dir, num), &anns) {
+            warn!("Unable to write ann file {}: {}", num, e);
+            continue;
+        }
+        debug!("Wrote ann file {} with {} anns", num, count);
+        if let Some(mi) = &af.mirror {
+            let path = if af.dir.is_empty() {
+                None
+            } else {
+                Some(file_name(&af.dir, num))
+            };
+            mirror::add(mi, num, p.parent_block_height, anns.clone(), path);
+        }
+        // Hash outside of the lock, it's only wasted if the index is disabled
+        let locs = if af.m.lock().hash_index.is_some() {
+            anns.chunks(1024)
+                .enumerate()
+                .map(|(i, ann)| {
+                    let loc = AnnLocation {
+                        file: num,
+                        offset: i * 1024,
+                        content_type: packetcrypt_sys::content_type(ann),
+                        content_length: packetcrypt_sys::content_length(ann),
+                    };
+                    (hash::compress32(ann), loc)
+                })
+                .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        };
+        {
+            let mut m = af.m.lock();
+            m.files.push_back(num);
+            m.file_info.insert(num, (util::now_ms(), anns.len()));
+            m.bytes += anns.len();
+            if let Some(hi) = &mut m.hash_index {
+                hi.by_file
+                    .insert(num, locs.iter().map(|(h, _)| *h).collect());
+                hi.by_hash.extend(locs);
+            }
+        }
+        prune(af);
+    }
+}
+
+// Take files out of the index which the retention says to, and delete the ones which
+// were taken out RETIRE_MS ago
+fn prune(af: &AnnFiles) {
+    let now = util::now_ms();
+    let ret = &af.retention;
+    let old = {
+        let mut m = af.m.lock();
+        while let Some(&num) = m.files.front() {
+            let (written_ms, len) = m.file_info.get(&num).copied().unwrap_or((now, 0));
+            let reason = if m.files.len() > ret.files {
+                &af.pruned.count
+            } else if ret.max_age_ms > 0 && now.saturating_sub(written_ms) > ret.max_age_ms {
+                &af.pruned.age
+            } else if ret.max_bytes > 0 && m.bytes > ret.max_bytes {
+                &af.pruned.disk
+            } else {
+                break;
+            };
+            reason.fetch_add(1, Ordering::Relaxed);
+            m.files.pop_front();
+            m.bytes -= len;
+            m.retiring.push_back((num, now));
+        }
+        let mut old = Vec::new();
+        while let Some(&(num, pruned_ms)) = m.retiring.front() {
+            if now.saturating_sub(pruned_ms) < RETIRE_MS {
+                break;
+            }
+            m.retiring.pop_front();
+            m.file_info.remove(&num);
+            if let Some(hi) = &mut m.hash_index {
+                for h in hi.by_file.remove(&num).unwrap_or_default() {
+                    hi.by_hash.remove(&h);
+                }
+            }
+            if let Some(b) = m.in_memory.remove(&num) {
+                af.dropped_anns.fetch_add(b.len() / 1024, Ordering::Relaxed);
+            }
+            old.push(num);
+        }
+        old
+    };
+    if af.dir.is_empty() {
+        return;
+    }
+    for num in old {
+        if let Err(e) = std::fs::remove_file(file_name(&af.dir, num)) {
+            warn!("Unable to delete ann file {}: {}", num, e);
+        }
+    }
+}
+
+fn write(af: &AnnFiles, p: Pending) {
+    if p.count > 0 && af.write_send.send(p).is_err() {
+        warn!("Ann file writer is gone");
+    }
+}
+
+pub fn push(af: &AnnFiles, parent_block_height: i32, anns: &[&[u8]]) {
+    let mut m = af.m.lock();
+    for ann in anns {
+        let p = m
+            .pending
+            .entry(parent_block_height)
+            .or_insert_with(|| Pending {
+                parent_block_height,
+                anns: BytesMut::with_capacity(af.max_anns * 1024),
+                count: 0,
+                opened_ms: util::now_ms(),
+            });
+        p.anns.extend_from_slice(ann);
+        p.count += 1;
+        if p.count >= af.max_anns {
+            if let Some(p) = m.pending.remove(&parent_block_height) {
+                write(af, p);
+            }
+        }
+    }
+}
+
+/// Write out the files which are max_ms old, this should be called often
+pub fn tick(af: &AnnFiles) {
+    let now = util::now_ms();
+    let mut m = af.m.lock();
+    let old = m
+        .pending
+        .iter()
+        .filter(|(_, p)| now - p.opened_ms >= af.max_ms)
+        .map(|(h, _)| *h)
+        .collect::<Vec<_>>();
+    for h in old {
+        if let Some(p) = m.pending.remove(&h) {
+            write(af, p);
+        }
+    }
+}
+
+/// The index of files, grouped so each one has about want_anns if that is more than
+/// one file holds, and only those with files newer than since if it is set
+pub fn index(af: &AnnFiles, want_anns: usize, since: Option<i64>) -> AnnIndex {
+    let m = af.m.lock();
+    let since = since.unwrap_or(-1);
+    let group = ((want_anns + af.max_anns - 1) / af.max_anns)
+        .max(1)
+        .min(MAX_GROUP);
+    let files = if group == 1 {
+        m.files
+            .iter()
+            .filter(|n| **n as i64 > since)
+            .map(|n| format!("anns_{}.bin", n))
+            .collect()
+    } else {
+        // Only groups which are complete, so a name always means the same files
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < m.files.len() {
+            let first = m.files[i] - m.files[i] % group;
+            let last = first + group - 1;
+            let members = m.files.iter().skip(i).take_while(|n| **n <= last).count();
+            if m.files[i] == first && members == group && last as i64 > since {
+                out.push(format!("anns_{}-{}.bin", first, last));
+            }
+            i += members;
+        }
+        out
+    };
+    AnnIndex {
+        highest_ann_file: m.files.back().map_or(-1, |n| *n as i64),
+        lowest_ann_file: m.files.front().map(|n| *n as i64),
+        files,
+    }
+}
+
+/// The content of a file or group of files, None if the name is not valid or any of
+/// the files are gone
+pub async fn read(af: &AnnFiles, name: &str) -> Option<Vec<u8>> {
+    let (first, last) = {
+        let c = af.group_regex.captures(name)?;
+        let first = c[1].parse::<usize>().ok()?;
+        let last = match c.get(3) {
+            Some(l) => l.as_str().parse::<usize>().ok()?,
+            None => first,
+        };
+        (first, last)
+    };
+    if last < first || last - first >= MAX_GROUP {
+        return None;
+    }
+    let mut out = Vec::new();
+    for num in first..=last {
+        if af.dir.is_empty() {
+            let b = af.m.lock().in_memory.get(&num)?.clone();
+            out.extend_from_slice(&b[..]);
+        } else {
+            out.extend(tokio::fs::read(file_name(&af.dir, num)).await.ok()?);
+        }
+    }
+    Some(out)
+}
+
+pub fn mirror(af: &AnnFiles) -> Option<&Mirror> {
+    af.mirror.as_ref()
+}
+
+/// Number of anns which were dropped from memory since the last call
+pub fn dropped_anns(af: &AnnFiles) -> usize {
+    af.dropped_anns.swap(0, Ordering::Relaxed)
+}
+
+/// Files in the index and how many bytes they take
+pub fn usage(af: &AnnFiles) -> (usize, usize) {
+    let m = af.m.lock();
+    (m.files.len(), m.bytes)
+}
+
+pub fn pruned(af: &AnnFiles) -> &Pruned {
+    &af.pruned
+}
+
+pub fn in_memory(af: &AnnFiles) -> bool {
+    af.dir.is_empty()
+}
+
+/// Where the ann with this hash is, None if it's not in any file or there's no index
+pub fn find(af: &AnnFiles, hash: &[u8; 32]) -> Option<AnnLocation> {
+    af.m.lock().hash_index.as_ref()?.by_hash.get(hash).copied()
+}
+
+/// The 1024 bytes of one ann, None if the file is gone
+pub async fn read_ann(af: &AnnFiles, loc: &AnnLocation) -> Option<Vec<u8>> {
+    if af.dir.is_empty() {
+        let b = af.m.lock().in_memory.get(&loc.file)?.clone();
+        return b.get(loc.offset..(loc.offset + 1024)).map(|a| a.to_vec());
+    }
+    let mut f = tokio::fs::File::open(file_name(&af.dir, loc.file))
+        .await
+        .ok()?;
+    f.seek(SeekFrom::Start(loc.offset as u64)).await.ok()?;
+    let mut out = vec![0u8; 1024];
+    f.read_exact(&mut out).await.ok()?;
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn index_groups() {
+        let dir = std::env::temp_dir().join(format!("annfiles_test_{}", std::process::id()));
+        let ret = super::Retention {
+            files: 100,
+            ..Default::default()
+        };
+        let af = super::new(dir.to_str().unwrap(), 2, 1000, ret, false, None).unwrap();
+        af.m.lock().files.extend(vec![3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(super::index(&af, 0, None).files.len(), 8);
+        assert_eq!(super::index(&af, 8, None).highest_ann_file, 10);
+        // 4 files of 2 anns per group, 3 is the end of an incomplete group
+        assert_eq!(
+            super::index(&af, 8, None).files,
+            vec!["anns_4-7.bin".to_owned()]
+        );
+        // Only what is newer than the miner has
+        assert_eq!(
+            super::index(&af, 0, Some(8)).files,
+            vec!["anns_9.bin".to_owned(), "anns_10.bin".to_owned()]
+        );
+        assert_eq!(super::index(&af, 8, Some(6)).files.len(), 1);
+        assert!(super::index(&af, 8, Some(7)).files.is_empty());
+        assert_eq!(super::index(&af, 0, Some(8)).lowest_ann_file, Some(3));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn prune_by_disk() {
+        let dir = std::env::temp_dir().join(format!("annfiles_prune_{}", std::process::id()));
+        let ret = super::Retention {
+            files: 100,
+            max_bytes: 2500,
+            ..Default::default()
+        };
+        let af = super::new(dir.to_str().unwrap(), 2, 1000, ret, false, None).unwrap();
+        {
+            let mut m = af.m.lock();
+            for num in 1..=3 {
+                m.files.push_back(num);
+                m.file_info.insert(num, (super::util::now_ms(), 1000));
+            }
+            m.bytes = 3000;
+        }
+        super::prune(&af);
+        assert_eq!(super::index(&af, 0, None).lowest_ann_file, Some(2));
+        assert_eq!(super::pruned(&af).disk.load(super::Ordering::Relaxed), 1);
+        assert_eq!(super::usage(&af), (2, 2000));
+        // Still there for miners which just saw it in the index
+        assert!(af.m.lock().file_info.contains_key(&1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,39 +1,55 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use crate::annfiles::{self, AnnFiles};
+use crate::bans::{self, Bans};
+use crate::mirror;
+use crate::pubsub::{self, PubSub};
+use crate::routes::{self, Routes};
 use anyhow::{bail, Result};
 use crossbeam_channel::{
-    Receiver as ReceiverCB, RecvTimeoutError, Sender as SenderCB, TryRecvError,
+    Receiver as ReceiverCB, Select, Sender as SenderCB, TryRecvError, TrySendError,
 };
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use packetcrypt_pool::accounting::{self, Accounting};
 use packetcrypt_pool::paymakerclient::{self, PaymakerClient};
 use packetcrypt_pool::poolcfg::AnnHandlerCfg;
 use packetcrypt_sys::{check_ann, PacketCryptAnn, ValidateCtx};
 use packetcrypt_util::poolclient::{self, PoolClient, PoolUpdate};
-use packetcrypt_util::protocol::{AnnPostReply, AnnsEvent, BlockInfo, MasterConf};
-use packetcrypt_util::{hash, util};
+use packetcrypt_util::protocol::{
+    AnnPostReply, AnnsEvent, BlockInfo, MasterConf, ANN_BAD_POW, ANN_DUP, ANN_OK, ANN_REJECTED,
+    ANN_STALE_PARENT, ANN_UNFIT_WORK,
+};
+use packetcrypt_util::stats::{self, Counter, Metric, Stats};
+use packetcrypt_util::{challenge, hash, util};
 use parking_lot::Mutex as MutexB; // blocking
 use regex::Regex;
 use std::cmp::max;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::Infallible;
 use std::convert::TryInto;
-use std::net::SocketAddr;
-use std::sync::atomic::{self, AtomicUsize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{self, AtomicI32, AtomicUsize};
 use std::sync::Arc;
 use tokio::sync::oneshot;
-use warp::Filter;
+use warp::http::HeaderMap;
+use warp::{Filter, Reply};
 
 const NUM_BLOCKS_TRACKING: usize = 6;
 const POOL_UPDATE_QUEUE_LEN: usize = 20;
 const RECV_WAIT_MS: u64 = 10;
 
-fn mk_dedups(w: &mut Worker) -> HashMap<u64, usize> {
-    let mut out = HashMap::new();
-    for (i, ann_opt) in (0..).zip(w.anns.iter()) {
-        let h = hash::compress32(&ann_opt.as_ref().unwrap().bytes[..]);
-        out.insert(u64::from_le_bytes(h[..8].try_into().unwrap()), i);
-    }
-    out
-}
+// Bounded queues between the intake stages, if a later stage backs up then the
+// workers stop taking new submissions and the http handler starts shedding load.
+const STAGE_QUEUE_LEN: usize = 64;
+
+// Most addresses which /work returns at once
+const MAX_WORK_PAGE: usize = 1000;
+
+// Delay which miners are asked to wait before uploading again when the input queue
+// is full, they are asked to wait less when it is over half full.
+const MAX_BACKOFF_MS: u64 = 5_000;
+
+// Verified batches used to tell which uploads to shed, see should_shed()
+const SHED_HISTORY: usize = 256;
 
 #[derive(Debug)]
 struct Output {
@@ -41,15 +57,37 @@ struct Output {
     dedup_tbl: HashSet<u64>,
 }
 
+#[derive(Default)]
+struct StageCounters {
+    parsed: Counter,
+    verified: Counter,
+    stored: Counter,
+}
+
 pub struct Global {
     outputs: [MutexB<Output>; NUM_BLOCKS_TRACKING],
 
     cfg: AnnHandlerCfg,
 
-    // Http posts
+    // Http posts, this is the head of the pipeline where load is shed
     submit_send: SenderCB<AnnPost>,
     submit_recv: ReceiverCB<AnnPost>,
 
+    // Parsed and deduplicated, waiting for verification
+    verify_send: SenderCB<Batch>,
+    verify_recv: ReceiverCB<Batch>,
+
+    // The same for anns for blocks before newest_height, if verify_newest_first
+    verify_old_send: SenderCB<Batch>,
+    verify_old_recv: ReceiverCB<Batch>,
+    newest_height: AtomicI32,
+
+    // Verified and classified, waiting to be stored
+    store_send: SenderCB<Batch>,
+    store_recv: ReceiverCB<Batch>,
+
+    stage_counters: StageCounters,
+
     // Work updates
     pc: PoolClient,
     pc_update_send: SenderCB<PoolUpdate>,
@@ -57,22 +95,78 @@ pub struct Global {
 
     pmc: PaymakerClient,
 
+    // Durable record of everything sent to the paymaker
+    acct: Accounting,
+
     sockaddr: std::net::SocketAddr,
 
     skip_check_chance: u8,
 
     sprayer: packetcrypt_sprayer::Sprayer,
 
-    overloads: AtomicUsize,
-    timeouts: AtomicUsize,
+    // Sources which keep sending invalid anns
+    bans: Bans,
+
+    // If upload_challenge_bits is set, the proof of work which uploaders must show
+    challenge: Option<challenge::Issuer>,
+
+    // If this handler is one shard of several
+    shards: Option<Shards>,
+
+    // Files for block miners to download, if files_to_keep is non-zero
+    ann_files: Option<AnnFiles>,
+
+    // Anns of some content types are also sent elsewhere
+    routes: Option<Routes>,
+
+    // Subscribers to the anns as they are accepted, if ann_pub_bind is set
+    pubsub: Option<PubSub>,
+
+    // Client certificates which have been seen, if client_cert_header is set
+    cert_identities: MutexB<HashSet<String>>,
+
+    // Targets of recently verified batches, to tell which uploads are worth the least
+    recent_targets: MutexB<VecDeque<u32>>,
+
+    overloads: Counter,
+    // Overloads which were uploads turned away for having less work than most
+    sheds: Counter,
+    timeouts: Counter,
+    // Uploads turned away for not answering the challenge
+    challenged: Counter,
     last_log_time: AtomicUsize,
 }
 
+struct Shards {
+    urls: Vec<String>,
+    num: usize,
+    passwd: Option<String>,
+    client: reqwest::Client,
+}
+
+// The top 16 bits of the dedup hash decide the shard, the low bits are already
+// used for splitting between handlers.
+fn shard_of(dedup: u64, shard_count: usize) -> usize {
+    (((dedup >> 48) as usize) * shard_count) >> 16
+}
+
+// The range of 16 bit prefixes which shard_of() gives to this shard, inclusive
+fn shard_range(shard: usize, shard_count: usize) -> (usize, usize) {
+    let start = |i: usize| ((i << 16) + shard_count - 1) / shard_count;
+    (start(shard), start(shard + 1) - 1)
+}
+
+fn is_our_shard(g: &Global, dedup: u64) -> bool {
+    match &g.shards {
+        Some(s) => shard_of(dedup, s.urls.len()) == s.num,
+        None => true,
+    }
+}
+
 struct Worker {
     global: Arc<Global>,
     random: u8,
     payto_regex: Regex,
-    anns: Vec<Option<PacketCryptAnn>>,
     vctx: ValidateCtx,
 }
 
@@ -90,13 +184,22 @@ const SUPPORT_V1: bool = true;
 //
 // If v1 is not supported then this rule is removed.
 //
+fn is_our_ann(num: u64, conf: &Config) -> bool {
+    if let Some((lo, hi, total)) = conf.handler_weights {
+        let slot = num % total;
+        slot >= lo && slot < hi
+    } else {
+        (num as usize % conf.handler_count) == conf.handler_num
+    }
+}
+
 fn hash_num_ok(pnr: &AnnPostMeta, ann: &PacketCryptAnn, dedup: u64, conf: &Config) -> bool {
     if SUPPORT_V1 {
         if pnr.sver < 2 {
             if !util::is_zero(ann.content_hash()) {
                 debug!("non-zero content hash, failing the ann");
                 false
-            } else if (dedup as usize % conf.handler_count) == conf.handler_num {
+            } else if is_our_ann(dedup, conf) {
                 true
             } else {
                 debug!(
@@ -109,120 +212,17 @@ fn hash_num_ok(pnr: &AnnPostMeta, ann: &PacketCryptAnn, dedup: u64, conf: &Confi
             debug!("zero content hash sver 2, failing the ann");
             false
         } else {
-            (ann.hard_nonce() as usize % conf.handler_count) == conf.handler_num
+            is_our_ann(ann.hard_nonce() as u64, conf)
         }
     } else {
-        (ann.hard_nonce() as usize % conf.handler_count) == conf.handler_num
+        is_our_ann(ann.hard_nonce() as u64, conf)
     }
 }
 
-fn validate_anns(
-    w: &mut Worker,
-    res: &mut AnnsEvent,
-    pnr: &AnnPostMeta,
-    conf: &Config,
-    dedups: &HashMap<u64, usize>,
-) -> Result<()> {
-    res.target = 0;
-    for (ann_opt, (dedup_hash, _dedup_index)) in w.anns.iter().zip(dedups.iter()) {
-        let ann = if let Some(x) = ann_opt {
-            x
-        } else {
-            bail!("empty ann entry");
-        };
-        let unsigned = util::is_zero(ann.signing_key());
-        if unsigned {
-        } else if let Some(sk) = conf.signing_key {
-            if sk != ann.signing_key() {
-                bail!("wrong signing key");
-            }
-        } else {
-            bail!("unexpected signed ann");
-        }
-        if conf.parent_block_height != ann.parent_block_height() {
-            bail!(
-                "wrong parent block height, want {} got {}",
-                conf.parent_block_height,
-                ann.parent_block_height()
-            );
-        } else if conf.min_work < ann.work_bits() {
-            bail!("not enough work");
-        } else if *dedup_hash == 0 || *dedup_hash == u64::MAX {
-            bail!("zero or fff hash");
-        } else if !hash_num_ok(pnr, ann, *dedup_hash, conf) {
-            bail!("submit elsewhere");
-        } else if conf.ann_version != ann.version() {
-            bail!("unsupported ann version");
-        } else if (*dedup_hash as u8 ^ w.random) < w.global.skip_check_chance {
-            // fallthrough
-        } else {
-            let mut pbh = conf.parent_block_hash;
-            pbh.reverse();
-            if let Err(x) = check_ann(ann, &pbh, &mut w.vctx) {
-                bail!("check_ann() -> {}", x);
-            }
-        }
-        res.unsigned += unsigned as u32;
-        // higher number represents less work
-        res.target = max(res.target, ann.work_bits());
-    }
-    res.target = if res.target == 0 {
-        conf.min_work
-    } else {
-        res.target
-    };
-    Ok(())
-}
-
 fn get_output(g: &Arc<Global>, parent_block_height: i32) -> &MutexB<Output> {
     &g.outputs[(parent_block_height as usize) % NUM_BLOCKS_TRACKING]
 }
 
-fn process_batch(
-    w: &mut Worker,
-    res: &mut AnnsEvent,
-    pnr: &AnnPostMeta,
-    conf: &Config,
-) -> Result<()> {
-    let dedups = mk_dedups(w);
-    validate_anns(w, res, pnr, conf, &dedups)?;
-    res.dup = (dedups.len() - w.anns.len()) as u32;
-
-    let g = w.global.clone();
-    let output_mtx = get_output(&g, conf.parent_block_height);
-    let mut dedup_set: HashSet<u64> = dedups.keys().cloned().collect();
-    {
-        let mut output = output_mtx.lock();
-        //if let Some(out) = output.
-        if output.config.parent_block_height != conf.parent_block_height {
-            // we were too late
-            bail!("block number out of range");
-        }
-        let v: HashSet<u64> = output.dedup_tbl.intersection(&dedup_set).cloned().collect();
-        for dup in v {
-            res.dup += 1;
-            dedup_set.remove(&dup);
-        }
-        output.dedup_tbl.extend(&dedup_set);
-    }
-    res.accepted += dedup_set.len() as u32;
-
-    // done in 2 stages because borrow checker
-    let good_anns = dedup_set
-        .iter()
-        .filter_map(|h| dedups.get(h))
-        .filter_map(|i| w.anns[*i].take())
-        .collect::<Vec<_>>();
-    w.global.sprayer.push_anns(
-        &good_anns
-            .iter()
-            .map(|ann| &ann.bytes[..])
-            .collect::<Vec<_>>()[..],
-    );
-
-    Ok(())
-}
-
 fn process_update(w: &mut Worker, conf: &MasterConf, bi: BlockInfo) {
     let g = w.global.clone();
     // note: this conf.current_height is the next height to be made, so we subtract 1
@@ -261,11 +261,19 @@ fn process_update(w: &mut Worker, conf: &MasterConf, bi: BlockInfo) {
         return;
     };
     output.config.handler_count = conf.submit_ann_urls.len();
+    let handler_num = output.config.handler_num;
+    output.config.handler_weights = conf.ann_handler_weights().map(|w| {
+        let lo: u64 = w[..handler_num].iter().map(|x| *x as u64).sum();
+        let total: u64 = w.iter().map(|x| *x as u64).sum();
+        (lo, lo + w[handler_num] as u64, total)
+    });
     output.config.ann_version = *conf.ann_versions.get(0).unwrap_or(&1);
     output.config.signing_key = bi.sig_key;
     output.config.parent_block_hash = bi.header.hash;
     output.config.min_work = conf.ann_target.unwrap();
     output.config.parent_block_height = bi.header.height;
+    g.newest_height
+        .fetch_max(bi.header.height, atomic::Ordering::Relaxed);
 }
 
 #[derive(Debug, Default, Clone, Copy)]
@@ -279,6 +287,10 @@ struct Config {
     // Number of ann handlers
     handler_count: usize,
 
+    // If the pool weights the handlers, the range of slots which belong to this one
+    // and the total number of slots
+    handler_weights: Option<(u64, u64, u64)>,
+
     // Refuse any ann signed with a different key, consider
     // anns unsigned if they don't bear any signature at all
     signing_key: Option<[u8; 32]>,
@@ -293,6 +305,7 @@ struct Config {
     parent_block_height: i32,
 }
 
+#[derive(Clone)]
 struct AnnPostMeta {
     sver: u32,
     next_block_height: i32,
@@ -305,7 +318,31 @@ struct AnnPost {
     bytes: bytes::Bytes,
     reply: Option<oneshot::Sender<AnnPostReply>>,
 }
-fn process_submit1(w: &mut Worker, sub: AnnPost) -> Result<AnnPostReply> {
+// A submission making its way through the pipeline
+struct Batch {
+    meta: AnnPostMeta,
+    config: Config,
+    res: AnnsEvent,
+    // Each ann along with its dedup hash and its position in the upload
+    anns: Vec<(u64, PacketCryptAnn, usize)>,
+    // What became of each ann, by position in the upload
+    results: Vec<u8>,
+    reply: Option<oneshot::Sender<AnnPostReply>>,
+}
+
+#[derive(Clone, Copy)]
+enum Stage {
+    Verify,
+    Store,
+}
+
+fn dedup_hash(ann: &PacketCryptAnn) -> u64 {
+    let h = hash::compress32(&ann.bytes[..]);
+    u64::from_le_bytes(h[..8].try_into().unwrap())
+}
+
+// parse and dedup
+fn parse(w: &mut Worker, sub: AnnPost) -> Result<Batch> {
     let (meta, mut bytes) = (sub.meta, sub.bytes);
     let config = {
         get_output(&w.global, meta.next_block_height - 1)
@@ -337,76 +374,413 @@ fn process_submit1(w: &mut Worker, sub: AnnPost) -> Result<AnnPostReply> {
     if bytes.len() % 1024 != 0 {
         bail!("size not an even multiple of 1024");
     }
-    w.anns.clear();
-    for i in (0..bytes.len()).step_by(1024) {
-        w.anns.push(Some(PacketCryptAnn {
-            bytes: bytes.slice(i..(i + 1024)),
-        }));
+    if let Some(max) = w.global.cfg.max_upload_anns {
+        if bytes.len() / 1024 > max {
+            bail!("too many anns, at most {} in one upload", max);
+        }
     }
     let mut res = AnnsEvent::default();
     res.anns_type = String::from("anns");
     res.pay_to = meta.pay_to.clone();
     res.event_id = hex::encode(&hash::compress32(&bytes)[..16]);
     res.time = util::now_ms();
-    process_batch(w, &mut res, &meta, &config)?;
-    Ok(AnnPostReply {
-        error: vec![],
-        warn: vec![],
-        result: Some(res),
+
+    let mut seen = HashSet::new();
+    let mut anns = Vec::with_capacity(bytes.len() / 1024);
+    let mut results = vec![ANN_OK; bytes.len() / 1024];
+    for i in (0..bytes.len()).step_by(1024) {
+        let ann = PacketCryptAnn {
+            bytes: bytes.slice(i..(i + 1024)),
+        };
+        let dedup = dedup_hash(&ann);
+        if seen.insert(dedup) {
+            anns.push((dedup, ann, i / 1024));
+        } else {
+            results[i / 1024] = ANN_DUP;
+            res.dup += 1;
+        }
+    }
+    // Don't waste time verifying anns which we already have,
+    // this is checked again in store() because of concurrent batches.
+    {
+        let output = get_output(&w.global, config.parent_block_height).lock();
+        anns.retain(|(dedup, _, n)| {
+            let dup = output.dedup_tbl.contains(dedup);
+            if dup {
+                results[*n] = ANN_DUP;
+            }
+            res.dup += dup as u32;
+            !dup
+        });
+    }
+    Ok(Batch {
+        meta,
+        config,
+        res,
+        anns,
+        results,
+        reply: None,
     })
 }
 
-fn process_submit0(w: &mut Worker, mut sub: AnnPost) {
-    let remote_addr: Option<SocketAddr> = sub.meta.remote_addr.take();
-    match sub
-        .reply
-        .take()
-        .unwrap()
-        .send(match process_submit1(w, sub) {
-            Ok(resp) => resp,
-            Err(e) => {
-                debug!("Error processing req from [{:?}] [{:?}]", &remote_addr, e);
-                AnnPostReply {
-                    error: vec![e.to_string()],
-                    warn: vec![],
-                    result: None,
+// Anns which are stale or unfit are dropped one by one so that the miner can learn
+// which, anything else rejects the whole batch. One ann which fails validation must
+// reject them all because with skip_check_chance most anns are never checked, so that
+// is all which stops a sender from slipping forged anns in among good ones.
+fn verify(w: &mut Worker, b: &mut Batch) -> Result<()> {
+    let conf = &b.config;
+    for (dedup_hash, ann, n) in &b.anns {
+        let unsigned = util::is_zero(ann.signing_key());
+        if unsigned {
+        } else if let Some(sk) = conf.signing_key {
+            if sk != ann.signing_key() {
+                bail!("wrong signing key");
+            }
+        } else {
+            bail!("unexpected signed ann");
+        }
+        b.results[*n] = if conf.parent_block_height != ann.parent_block_height() {
+            ANN_STALE_PARENT
+        } else if conf.min_work < ann.work_bits() {
+            ANN_UNFIT_WORK
+        } else if *dedup_hash == 0 || *dedup_hash == u64::MAX {
+            bail!("zero or fff hash");
+        } else if !hash_num_ok(&b.meta, ann, *dedup_hash, conf) {
+            bail!("submit elsewhere");
+        } else if !is_our_shard(&w.global, *dedup_hash) {
+            bail!("wrong shard");
+        } else if conf.ann_version != ann.version() {
+            bail!("unsupported ann version");
+        } else if (*dedup_hash as u8 ^ w.random) < w.global.skip_check_chance {
+            ANN_OK
+        } else {
+            let mut pbh = conf.parent_block_hash;
+            pbh.reverse();
+            if let Err(x) = check_ann(ann, &pbh, &mut w.vctx) {
+                debug!("check_ann() -> {}", x);
+                b.results[*n] = ANN_BAD_POW;
+                bail!("invalid ann {}", n);
+            }
+            ANN_OK
+        };
+    }
+    let count = b.anns.len();
+    let results = &b.results;
+    b.anns.retain(|(_, _, n)| results[*n] == ANN_OK);
+    b.res.inval += (count - b.anns.len()) as u32;
+    Ok(())
+}
+
+fn classify(b: &mut Batch) {
+    b.res.target = 0;
+    for (_, ann, _) in &b.anns {
+        b.res.unsigned += util::is_zero(ann.signing_key()) as u32;
+        // higher number represents less work
+        b.res.target = max(b.res.target, ann.work_bits());
+    }
+    if b.res.target == 0 {
+        b.res.target = b.config.min_work;
+    }
+}
+
+fn store(w: &mut Worker, b: &mut Batch) -> Result<()> {
+    let g = w.global.clone();
+    let output_mtx = get_output(&g, b.config.parent_block_height);
+    {
+        let mut output = output_mtx.lock();
+        if output.config.parent_block_height != b.config.parent_block_height {
+            // we were too late
+            bail!("block number out of range");
+        }
+        let res = &mut b.res;
+        let results = &mut b.results;
+        b.anns.retain(|(dedup, _, n)| {
+            let new = output.dedup_tbl.insert(*dedup);
+            if !new {
+                results[*n] = ANN_DUP;
+            }
+            res.dup += !new as u32;
+            new
+        });
+    }
+    b.res.accepted += b.anns.len() as u32;
+    let anns = b
+        .anns
+        .iter()
+        .map(|(_, ann, _)| &ann.bytes[..])
+        .collect::<Vec<_>>();
+    let anns = match &w.global.routes {
+        Some(r) => routes::forward(r, anns),
+        None => anns,
+    };
+    w.global.sprayer.push_anns(&anns[..]);
+    if let Some(ps) = &w.global.pubsub {
+        pubsub::publish(ps, &anns[..]);
+    }
+    if let Some(af) = &w.global.ann_files {
+        annfiles::push(af, b.config.parent_block_height, &anns[..]);
+    }
+    Ok(())
+}
+
+fn send_reply(
+    w: &Worker,
+    reply: oneshot::Sender<AnnPostReply>,
+    remote_addr: &Option<SocketAddr>,
+    res: Result<AnnsEvent>,
+    ann_results: Vec<u8>,
+) {
+    let r = match res {
+        Ok(res) => AnnPostReply {
+            error: vec![],
+            warn: vec![],
+            result: Some(res),
+            ann_results,
+        },
+        Err(e) => {
+            debug!("Error processing req from [{:?}] [{:?}]", remote_addr, e);
+            AnnPostReply {
+                error: vec![e.to_string()],
+                warn: vec![],
+                result: None,
+                ann_results,
+            }
+        }
+    };
+    if reply.send(r).is_err() {
+        w.global.timeouts.add(1);
+    }
+}
+
+fn finish(w: &Worker, b: Batch, res: Result<()>) {
+    let Batch {
+        meta,
+        res: ev,
+        mut results,
+        reply,
+        ..
+    } = b;
+    if res.is_err() {
+        for r in results.iter_mut().filter(|r| **r == ANN_OK) {
+            *r = ANN_REJECTED;
+        }
+    }
+    send_reply(
+        w,
+        reply.unwrap(),
+        &meta.remote_addr,
+        res.map(|()| ev),
+        results,
+    );
+}
+
+fn is_old(g: &Global, b: &Batch) -> bool {
+    g.cfg.verify_newest_first.unwrap_or(true)
+        && b.config.parent_block_height < g.newest_height.load(atomic::Ordering::Relaxed)
+}
+
+// Queue the batch for the next stage, if that queue is full then do the work now
+// rather than blocking, so the backlog is felt at the head of the pipeline.
+fn forward(w: &mut Worker, b: Batch, stage: Stage) {
+    let g = Arc::clone(&w.global);
+    let send = match stage {
+        Stage::Verify if is_old(&g, &b) => &g.verify_old_send,
+        Stage::Verify => &g.verify_send,
+        Stage::Store => &g.store_send,
+    };
+    match send.try_send(b) {
+        Ok(()) => (),
+        Err(TrySendError::Full(b)) | Err(TrySendError::Disconnected(b)) => run_stage(w, b, stage),
+    }
+}
+
+fn run_stage(w: &mut Worker, mut b: Batch, stage: Stage) {
+    let sc = &w.global.stage_counters;
+    match stage {
+        Stage::Verify => {
+            sc.verified.add(1);
+            match verify(w, &mut b) {
+                Ok(()) => {
+                    classify(&mut b);
+                    record_target(&w.global, b.res.target);
+                    forward(w, b, Stage::Store);
+                }
+                Err(e) => {
+                    if let Some(addr) = b.meta.remote_addr {
+                        w.global.bans.record(addr.ip(), false);
+                    }
+                    finish(w, b, Err(e))
                 }
             }
-        }) {
-        Ok(_) => (),
-        Err(_) => {
-            w.global.timeouts.fetch_add(1, atomic::Ordering::Relaxed);
-            //info!("Error sending reply [{:?}]", e);
+        }
+        Stage::Store => {
+            sc.stored.add(1);
+            let res = store(w, &mut b);
+            if let Some(addr) = b.meta.remote_addr {
+                w.global.bans.record(addr.ip(), true);
+            }
+            finish(w, b, res);
         }
     }
 }
 
-fn worker_loop(g: Arc<Global>, thread_num: usize) {
-    let pc_update_recv = g.pc_update_recv.clone();
-    let submit_recv = g.submit_recv.clone();
-    let mut w: Worker = Worker {
+fn process_submit(w: &mut Worker, mut sub: AnnPost) {
+    w.global.stage_counters.parsed.add(1);
+    let reply = sub.reply.take().unwrap();
+    let remote_addr = sub.meta.remote_addr;
+    match parse(w, sub) {
+        Ok(mut b) => {
+            b.reply = Some(reply);
+            forward(w, b, Stage::Verify);
+        }
+        Err(e) => send_reply(w, reply, &remote_addr, Err(e), Vec::new()),
+    }
+}
+
+fn log_stats(g: &Global) {
+    let llt = g.last_log_time.load(atomic::Ordering::Relaxed);
+    let now = util::now_ms() / 1000;
+    if (now as usize) - llt <= 5 {
+        return;
+    }
+    let overloads = g.overloads.take();
+    let sheds = g.sheds.take();
+    let timeouts = g.timeouts.take();
+    let sc = &g.stage_counters;
+    info!(
+        "overloads: {} (shed {}) timeout: {} q: {} / {}+{} / {} done: {} / {} / {}",
+        overloads,
+        sheds,
+        timeouts,
+        g.submit_recv.len(),
+        g.verify_recv.len(),
+        g.verify_old_recv.len(),
+        g.store_recv.len(),
+        sc.parsed.take(),
+        sc.verified.take(),
+        sc.stored.take(),
+    );
+    if let Some(af) = &g.ann_files {
+        let (files, bytes) = annfiles::usage(af);
+        let p = annfiles::pruned(af);
+        info!(
+            "ann files: {} ({}MB) pruned by count: {} age: {} disk: {}",
+            files,
+            bytes >> 20,
+            p.count.load(atomic::Ordering::Relaxed),
+            p.age.load(atomic::Ordering::Relaxed),
+            p.disk.load(atomic::Ordering::Relaxed),
+        );
+        if annfiles::in_memory(af) {
+            info!(
+                "ann files dropped from memory: {} anns",
+                annfiles::dropped_anns(af)
+            );
+        }
+        if let Some(mi) = annfiles::mirror(af) {
+            let dropped = mirror::dropped(mi);
+            if dropped > 0 {
+                warn!(
+                    "{} ann files were not mirrored, the mirror is behind",
+                    dropped
+                );
+            }
+        }
+    }
+    if let Some(ps) = &g.pubsub {
+        let (subs, dropped) = pubsub::stats(ps);
+        info!("ann subscribers: {} missed: {} anns", subs, dropped);
+    }
+    if let Some(r) = &g.routes {
+        for (route, sent, dropped) in routes::stats(r) {
+            info!("route {} sent: {} dropped: {}", route, sent, dropped);
+        }
+    }
+    g.last_log_time
+        .store(now as usize, atomic::Ordering::Relaxed);
+}
+
+impl Stats for Global {
+    fn component(&self) -> &'static str {
+        "annhandler"
+    }
+    fn metrics(&self) -> Vec<Metric> {
+        let sc = &self.stage_counters;
+        let batches = |name: &str, c: &Counter| {
+            Metric::counter("batches_total", "Batches through each stage", c.get())
+                .label("stage", name)
+        };
+        let queue = |name: &str, len: usize| {
+            Metric::gauge("queue", "Batches waiting for each stage", len as f64)
+                .label("stage", name)
+        };
+        let mut out = vec![
+            Metric::counter(
+                "overloads_total",
+                "Uploads turned away because the handler was busy",
+                self.overloads.get(),
+            ),
+            Metric::counter(
+                "sheds_total",
+                "Overloads which were uploads with less work than most",
+                self.sheds.get(),
+            ),
+            Metric::counter(
+                "timeouts_total",
+                "Uploads which went away before they were answered",
+                self.timeouts.get(),
+            ),
+            Metric::counter(
+                "challenged_total",
+                "Uploads turned away for not answering the challenge",
+                self.challenged.get(),
+            ),
+            batches("parse", &sc.parsed),
+            batches("verify", &sc.verified),
+            batches("store", &sc.stored),
+            queue("parse", self.submit_recv.len()),
+            queue("verify", self.verify_recv.len()),
+            queue("verify_old", self.verify_old_recv.len()),
+            queue("store", self.store_recv.len()),
+        ];
+        if let Some(af) = &self.ann_files {
+            let (files, bytes) = annfiles::usage(af);
+            out.push(Metric::gauge(
+                "ann_files",
+                "Ann files kept for the block miners",
+                files as f64,
+            ));
+            out.push(Metric::gauge(
+                "ann_file_bytes",
+                "Size of the ann files",
+                bytes as f64,
+            ));
+        }
+        out
+    }
+}
+
+fn new_worker(g: Arc<Global>) -> Worker {
+    Worker {
         global: g,
         random: util::rand_u32() as u8,
         payto_regex: Regex::new(r"^[a-zA-Z0-9]+$").unwrap(),
-        anns: Vec::new(),
         vctx: ValidateCtx::default(),
-    };
+    }
+}
+
+fn worker_loop(g: Arc<Global>, thread_num: usize) {
+    let pc_update_recv = g.pc_update_recv.clone();
+    let submit_recv = g.submit_recv.clone();
+    let verify_recv = g.verify_recv.clone();
+    let verify_old_recv = g.verify_old_recv.clone();
+    let store_recv = g.store_recv.clone();
+    let mut w = new_worker(g);
     loop {
         if thread_num == 0 {
-            let llt = w.global.last_log_time.load(atomic::Ordering::Relaxed);
-            let now = util::now_ms() / 1000;
-            if (now as usize) - llt > 5 {
-                let overloads = w.global.overloads.swap(0, atomic::Ordering::Relaxed);
-                let timeouts = w.global.timeouts.swap(0, atomic::Ordering::Relaxed);
-                info!(
-                    "overloads: {} timeout: {} q: {}",
-                    overloads,
-                    timeouts,
-                    w.global.submit_recv.len()
-                );
-                w.global
-                    .last_log_time
-                    .store(now as usize, atomic::Ordering::Relaxed);
+            log_stats(&w.global);
+            if let Some(af) = &w.global.ann_files {
+                annfiles::tick(af);
             }
             loop {
                 match pc_update_recv.try_recv() {
@@ -425,16 +799,58 @@ fn worker_loop(g: Arc<Global>, thread_num: usize) {
                 }
             }
         }
-        match submit_recv.recv_timeout(core::time::Duration::from_millis(RECV_WAIT_MS)) {
-            Ok(sub) => {
-                process_submit0(&mut w, sub);
+        // Always drain the later stages first so that batches finish
+        if let Ok(b) = store_recv.try_recv() {
+            run_stage(&mut w, b, Stage::Store);
+            continue;
+        }
+        if let Ok(b) = verify_recv.try_recv() {
+            run_stage(&mut w, b, Stage::Verify);
+            continue;
+        }
+        // Only take new submissions if there is room for them downstream, they go
+        // ahead of old anns because they may be for the newest block
+        let room = !w.global.verify_send.is_full() && !w.global.verify_old_send.is_full();
+        if room {
+            if let Ok(sub) = submit_recv.try_recv() {
+                process_submit(&mut w, sub);
                 continue;
             }
-            Err(RecvTimeoutError::Timeout) => (),
-            Err(RecvTimeoutError::Disconnected) => {
-                error!("submit_recv disconnected");
-            }
         }
+        if let Ok(b) = verify_old_recv.try_recv() {
+            run_stage(&mut w, b, Stage::Verify);
+            continue;
+        }
+        let mut sel = Select::new();
+        sel.recv(&store_recv);
+        sel.recv(&verify_recv);
+        sel.recv(&verify_old_recv);
+        if room {
+            sel.recv(&submit_recv);
+        }
+        // We don't care which one, the next loop will pick in order of priority
+        let _ = sel.ready_timeout(core::time::Duration::from_millis(RECV_WAIT_MS));
+    }
+}
+
+// For verify_workers, these take the place of the verify stage in worker_loop()
+fn verify_loop(g: Arc<Global>) {
+    let verify_recv = g.verify_recv.clone();
+    let verify_old_recv = g.verify_old_recv.clone();
+    let mut w = new_worker(g);
+    loop {
+        if let Ok(b) = verify_recv.try_recv() {
+            run_stage(&mut w, b, Stage::Verify);
+            continue;
+        }
+        if let Ok(b) = verify_old_recv.try_recv() {
+            run_stage(&mut w, b, Stage::Verify);
+            continue;
+        }
+        let mut sel = Select::new();
+        sel.recv(&verify_recv);
+        sel.recv(&verify_old_recv);
+        let _ = sel.ready_timeout(core::time::Duration::from_millis(RECV_WAIT_MS));
     }
 }
 
@@ -443,6 +859,8 @@ pub type AnnHandler = Arc<Global>;
 pub async fn new(
     pc: &PoolClient,
     pmc: &PaymakerClient,
+    acct: &Accounting,
+    anns_dir: &str,
     mut cfg: AnnHandlerCfg,
 ) -> Result<AnnHandler> {
     if cfg.skip_check_chance > 1.0 || cfg.skip_check_chance < 0.0 {
@@ -463,6 +881,65 @@ pub async fn new(
         .try_into()
         .unwrap();
 
+    let shards = match (cfg.shard_urls.take(), cfg.shard_num) {
+        (None, _) => None,
+        (Some(urls), Some(num)) if num < urls.len() => Some(Shards {
+            urls,
+            num,
+            passwd: cfg.shard_passwd.clone(),
+            client: util::client_builder().build()?,
+        }),
+        (Some(urls), num) => bail!(
+            "shard_num must be set and less than the {} shard_urls, got {:?}",
+            urls.len(),
+            num
+        ),
+    };
+
+    let ann_files = if cfg.files_to_keep > 0 {
+        Some(annfiles::new(
+            if cfg.ann_files_in_memory.unwrap_or(false) {
+                ""
+            } else {
+                anns_dir
+            },
+            cfg.ann_file_max_anns
+                .unwrap_or(annfiles::DEFAULT_MAX_ANNS)
+                .max(1),
+            cfg.ann_file_max_ms.unwrap_or(annfiles::DEFAULT_MAX_MS),
+            annfiles::Retention {
+                files: cfg.files_to_keep,
+                max_age_ms: cfg.ann_file_max_age_seconds.unwrap_or(0) * 1000,
+                max_bytes: cfg.ann_files_max_mb.unwrap_or(0) * 1024 * 1024,
+            },
+            cfg.ann_hash_index.unwrap_or(false),
+            cfg.ann_mirror_dir.as_deref().map(mirror::new).transpose()?,
+        )?)
+    } else {
+        None
+    };
+
+    let routes = match &cfg.content_routes {
+        Some(cr) if !cr.is_empty() => Some(routes::new(cr)?),
+        _ => None,
+    };
+
+    let challenge = match (cfg.upload_challenge_bits, &cfg.challenge_secret) {
+        (None, _) => None,
+        (Some(bits), _) if bits > challenge::MAX_BITS => {
+            bail!(
+                "upload_challenge_bits can be at most {}",
+                challenge::MAX_BITS
+            );
+        }
+        (Some(bits), Some(secret)) => Some(challenge::Issuer::new(bits, secret)),
+        (Some(_), None) => bail!("upload_challenge_bits needs challenge_secret"),
+    };
+    let pubsub = match &cfg.ann_pub_bind {
+        Some(bind) => Some(pubsub::new(bind, cfg.ann_pub_bodies.unwrap_or(false))?),
+        None => None,
+    };
+
     let bind_pub: SocketAddr = cfg.bind_pub.parse()?;
     let sprayer = packetcrypt_sprayer::Sprayer::new(&packetcrypt_sprayer::Config {
         passwd: cfg.block_miner_passwd.clone(),
@@ -470,92 +947,795 @@ pub async fn new(
         workers: cfg.spray_workers as usize,
         subscribe_to: cfg.subscribe_to.clone(),
         log_peer_stats: true,
-        mss: if let Some(mss) = cfg.mss { mss } else { 1472 },
+        mss: cfg.mss.unwrap_or(0),
         spray_at: cfg.spray_at.take().unwrap_or_else(Vec::new),
         mcast: "".to_owned(),
-    })?;
+        relay_dir: String::new(),
+        tcp_fallback: cfg.spray_tcp.unwrap_or(false),
+        proxy: String::new(),
+        psk: cfg.spray_psk.clone().unwrap_or_default(),
+    })
+    .await?;
 
     let (submit_send, submit_recv) = crossbeam_channel::bounded(cfg.input_queue_len);
+    let verify_queue_len = cfg.verify_queue_len.unwrap_or(STAGE_QUEUE_LEN).max(1);
+    let (verify_send, verify_recv) = crossbeam_channel::bounded(verify_queue_len);
+    let (verify_old_send, verify_old_recv) = crossbeam_channel::bounded(verify_queue_len);
+    let (store_send, store_recv) = crossbeam_channel::bounded(STAGE_QUEUE_LEN);
     let (pc_update_send, pc_update_recv) = crossbeam_channel::bounded(POOL_UPDATE_QUEUE_LEN);
     let global = Arc::new(Global {
         outputs: *outputs,
         submit_send,
         submit_recv,
+        verify_send,
+        verify_recv,
+        verify_old_send,
+        verify_old_recv,
+        newest_height: AtomicI32::new(0),
+        store_send,
+        store_recv,
+        stage_counters: StageCounters::default(),
         pc: pc.clone(),
         pc_update_recv,
         pc_update_send,
         pmc: pmc.clone(),
+        acct: acct.clone(),
         sockaddr: bind_pub,
         skip_check_chance: 255 * cfg.skip_check_chance as u8,
+        bans: Bans::new(cfg.ban_seconds.unwrap_or(bans::DEFAULT_BAN_SECONDS)),
+        challenge,
         cfg,
         sprayer,
-        overloads: AtomicUsize::new(0),
-        timeouts: AtomicUsize::new(0),
+        shards,
+        ann_files,
+        routes,
+        pubsub,
+        cert_identities: MutexB::new(HashSet::new()),
+        recent_targets: MutexB::new(VecDeque::with_capacity(SHED_HISTORY)),
+        overloads: Counter::default(),
+        sheds: Counter::default(),
+        timeouts: Counter::default(),
+        challenged: Counter::default(),
         last_log_time: AtomicUsize::new(0),
     });
 
     Ok(global)
 }
 
+// Anns for one shard along with their positions in the upload
+type ShardPart = (bytes::Bytes, Vec<usize>);
+
+// Split a submission into the anns which are ours and the anns for each other shard
+fn split_shards(ah: &AnnHandler, bytes: bytes::Bytes) -> (ShardPart, Vec<(usize, ShardPart)>) {
+    let s = match &ah.shards {
+        Some(s) if bytes.len() % 1024 == 0 => s,
+        // If it's not a multiple of 1024 then parse() will reject it
+        _ => return ((bytes, Vec::new()), Vec::new()),
+    };
+    let mut out = vec![(bytes::BytesMut::new(), Vec::new()); s.urls.len()];
+    for (n, ann) in bytes.chunks(1024).enumerate() {
+        let h = hash::compress32(ann);
+        let dedup = u64::from_le_bytes(h[..8].try_into().unwrap());
+        let part = &mut out[shard_of(dedup, s.urls.len())];
+        part.0.extend_from_slice(ann);
+        part.1.push(n);
+    }
+    let (ours, our_pos) = std::mem::take(&mut out[s.num]);
+    let others = out
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (b, _))| !b.is_empty())
+        .map(|(i, (b, pos))| (i, (b.freeze(), pos)))
+        .collect();
+    ((ours.freeze(), our_pos), others)
+}
+
+// Put the ann_results of one shard back where those anns were in the upload
+fn place_results(out: &mut [u8], pos: &[usize], results: &[u8]) {
+    for (i, p) in pos.iter().enumerate() {
+        out[*p] = results.get(i).copied().unwrap_or(ANN_REJECTED);
+    }
+}
+
+async fn forward_to_shard(
+    ah: &AnnHandler,
+    shard: usize,
+    bytes: bytes::Bytes,
+    meta: &AnnPostMeta,
+    identity: &Option<String>,
+    challenge: &Option<String>,
+) -> Result<AnnPostReply> {
+    let s = ah.shards.as_ref().unwrap();
+    let mut req = s
+        .client
+        .post(&s.urls[shard])
+        .header("x-pc-sver", meta.sver)
+        .header("x-pc-worknum", meta.next_block_height)
+        .header("x-pc-payto", &meta.pay_to);
+    if let (Some(passwd), Some(addr)) = (&s.passwd, meta.remote_addr) {
+        req = req
+            .header("x-pc-shard-passwd", passwd)
+            .header("x-pc-forwarded-for", addr.to_string());
+    }
+    // The other shards expect the same header as the TLS proxy sends us
+    if let (Some(h), Some(id)) = (&ah.cfg.client_cert_header, identity) {
+        req = req.header(h.as_str(), id.as_str());
+    }
+    if let Some(c) = challenge {
+        req = req.header("x-pc-challenge", c.as_str());
+    }
+    let req = util::with_token(req, &ah.cfg.upload_token);
+    let res = req.body(bytes).send().await?;
+    Ok(serde_json::from_slice(&res.bytes().await?)?)
+}
+
+// Add the counts from another shard's reply into ours
+fn merge_reply(reply: &mut AnnPostReply, shard: usize, pos: &[usize], other: Result<AnnPostReply>) {
+    let other = match other {
+        Ok(o) => o,
+        Err(e) => {
+            reply.warn.push(format!("shard {}: {}", shard, e));
+            place_results(&mut reply.ann_results, pos, &[]);
+            return;
+        }
+    };
+    place_results(&mut reply.ann_results, pos, &other.ann_results);
+    for e in other.error.iter().chain(other.warn.iter()) {
+        reply.warn.push(format!("shard {}: {}", shard, e));
+    }
+    if let Some(o) = other.result {
+        let r = reply.result.get_or_insert_with(|| AnnsEvent {
+            anns_type: o.anns_type.clone(),
+            pay_to: o.pay_to.clone(),
+            event_id: o.event_id.clone(),
+            time: o.time,
+            target: o.target,
+            ..Default::default()
+        });
+        r.accepted += o.accepted;
+        r.dup += o.dup;
+        r.inval += o.inval;
+        r.bad_hash += o.bad_hash;
+        r.runt += o.runt;
+        r.internal_err += o.internal_err;
+        r.unsigned += o.unsigned;
+        r.total_len += o.total_len;
+        r.target = max(r.target, o.target);
+    }
+}
+
+// Submissions forwarded by another shard carry the address of the miner
+fn forwarded_addr(
+    ah: &AnnHandler,
+    remote_addr: Option<SocketAddr>,
+    forwarded_for: Option<String>,
+    shard_passwd: Option<String>,
+) -> Option<SocketAddr> {
+    let s = match &ah.shards {
+        Some(s) => s,
+        None => return remote_addr,
+    };
+    match (&s.passwd, forwarded_for, shard_passwd) {
+        (Some(p), Some(fwd), Some(sp)) if util::secret_eq(p, &sp) => {
+            fwd.parse().ok().or(remote_addr)
+        }
+        _ => remote_addr,
+    }
+}
+
+// With client_cert_header set, rigs are known by the client certificate which the TLS
+// proxy in front of the handler verified. Err if there is no certificate.
+fn client_identity(
+    ah: &AnnHandler,
+    headers: &HeaderMap,
+    remote_addr: Option<SocketAddr>,
+) -> Result<Option<String>, ()> {
+    let name = match &ah.cfg.client_cert_header {
+        Some(n) => n,
+        None => return Ok(None),
+    };
+    let id = match headers.get(name.as_str()).and_then(|v| v.to_str().ok()) {
+        Some(id) if !id.is_empty() => id.to_owned(),
+        _ => {
+            debug!("No client certificate from {:?}", remote_addr);
+            return Err(());
+        }
+    };
+    if ah.cert_identities.lock().insert(id.clone()) {
+        info!("New client certificate [{}] from {:?}", id, remote_addr);
+    } else {
+        debug!("Client certificate [{}] from {:?}", id, remote_addr);
+    }
+    Ok(Some(id))
+}
+
+// How long miners should wait before their next upload
+fn backoff_ms(ah: &AnnHandler) -> u64 {
+    let (depth, cap) = (ah.submit_recv.len(), ah.cfg.input_queue_len);
+    if cap == 0 || depth * 2 <= cap {
+        0
+    } else {
+        MAX_BACKOFF_MS * (depth * 2 - cap).min(cap) as u64 / cap as u64
+    }
+}
+
+fn record_target(g: &Global, target: u32) {
+    let mut recent = g.recent_targets.lock();
+    if recent.len() >= SHED_HISTORY {
+        recent.pop_front();
+    }
+    recent.push_back(target);
+}
+
+// What an upload says its target is before it is verified, the easiest of its anns
+fn claimed_target(bytes: &[u8]) -> u32 {
+    bytes
+        .chunks_exact(1024)
+        .map(packetcrypt_sys::work_bits)
+        .max()
+        .unwrap_or(0)
+}
+
+// Once the queue is more than half full the least valuable uploads are turned away
+// first, rather than whichever happen to arrive when it is full. The share which is
+// turned away goes from none at half full to all at full, and an upload is turned away
+// if its claimed target is easier than that share of the recently verified batches.
+fn should_shed(ah: &AnnHandler, target: u32) -> bool {
+    let (depth, cap) = (ah.submit_recv.len(), ah.cfg.input_queue_len);
+    if cap == 0 || depth * 2 <= cap {
+        return false;
+    }
+    let mut recent = ah.recent_targets.lock().iter().copied().collect::<Vec<_>>();
+    if recent.is_empty() {
+        return false;
+    }
+    recent.sort_unstable();
+    let shed = (depth * 2 - cap).min(cap) as f64 / cap as f64;
+    let keep = ((1.0 - shed) * recent.len() as f64) as usize;
+    // Higher is less work, a batch at the cutoff is kept so that when every upload
+    // has the same target none are shed until the queue is full
+    recent.get(keep).map_or(true, |cutoff| target > *cutoff)
+}
+
+// Every reply says how full the queue is, so that miners can slow down before
+// their uploads start failing
+fn with_queue_headers(ah: &AnnHandler, reply: impl Reply) -> warp::reply::Response {
+    let reply = warp::reply::with_header(
+        reply,
+        "x-pc-queue",
+        format!("{}/{}", ah.submit_recv.len(), ah.cfg.input_queue_len),
+    );
+    // So that miners can make their uploads fit, 0 is no limit
+    let reply = warp::reply::with_header(
+        reply,
+        "x-pc-max-anns",
+        ah.cfg.max_upload_anns.unwrap_or(0).to_string(),
+    );
+    warp::reply::with_header(reply, "x-pc-backoff-ms", backoff_ms(ah).to_string()).into_response()
+}
+
+// An upload which did not answer the challenge, see challenge_gate()
+#[derive(Debug)]
+struct Challenged(IpAddr);
+impl warp::reject::Reject for Challenged {}
+
+// The challenge is checked from the headers, before the body is read, so that a flooder
+// who hasn't done the work doesn't get to send it. Uploads forwarded by other shards are
+// checked again with the miner's answer, which they pass on, so knowing the shard
+// password is no way around it. Uploads which are going to be turned away for a missing
+// token or a ban are let through to get that answer instead.
+fn check_challenge(
+    ah: &AnnHandler,
+    remote_addr: Option<SocketAddr>,
+    forwarded_for: Option<String>,
+    shard_passwd: Option<String>,
+    headers: &HeaderMap,
+) -> Result<(), warp::Rejection> {
+    let ch = if let Some(ch) = &ah.challenge {
+        ch
+    } else {
+        return Ok(());
+    };
+    let addr = match forwarded_addr(ah, remote_addr, forwarded_for, shard_passwd) {
+        Some(addr) => addr,
+        None => return Ok(()),
+    };
+    if !upload_token_ok(ah, headers) || ah.bans.is_banned(&addr.ip()) {
+        return Ok(());
+    }
+    let answer = headers
+        .get("x-pc-challenge")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if ch.check(&addr.ip(), answer, util::now_ms() / 1000) {
+        return Ok(());
+    }
+    ah.challenged.add(1);
+    Err(warp::reject::custom(Challenged(addr.ip())))
+}
+
+fn challenge_gate(ah: AnnHandler) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::filters::addr::remote()
+        .and(warp::header::optional::<String>("x-pc-forwarded-for"))
+        .and(warp::header::optional::<String>("x-pc-shard-passwd"))
+        .and(warp::header::headers_cloned())
+        .and_then(
+            move |remote_addr, forwarded_for, shard_passwd, headers: HeaderMap| {
+                let res = check_challenge(&ah, remote_addr, forwarded_for, shard_passwd, &headers);
+                async move { res }
+            },
+        )
+        .untuple_one()
+}
+
+// 428 with what to solve before uploading again, see challenge.rs
+async fn handle_challenged(
+    ah: AnnHandler,
+    r: warp::Rejection,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let (ch, ip) = match (&ah.challenge, r.find::<Challenged>()) {
+        (Some(ch), Some(Challenged(ip))) => (ch, ip),
+        _ => return Err(r),
+    };
+    let reply = warp::reply::with_status(
+        warp::reply::json(&AnnPostReply {
+            error: vec!["challenge required".into()],
+            warn: vec![],
+            result: None,
+            ann_results: Vec::new(),
+        }),
+        warp::http::StatusCode::PRECONDITION_REQUIRED,
+    );
+    let mut res = with_queue_headers(&ah, reply);
+    let token = ch.issue(ip, util::now_ms() / 1000);
+    let h = res.headers_mut();
+    h.insert("x-pc-challenge", token.parse().unwrap());
+    h.insert("x-pc-challenge-bits", ch.bits.into());
+    Ok(res)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_submit(
     ah: AnnHandler,
     remote_addr: Option<SocketAddr>,
     bytes: bytes::Bytes,
-    //content_length: usize,
     sver: u32,
     next_block_height: i32,
     pay_to: String,
+    forwarded_for: Option<String>,
+    shard_passwd: Option<String>,
+    headers: HeaderMap,
 ) -> Result<impl warp::Reply, Infallible> {
-    let (reply, getreply) = oneshot::channel();
-    match ah.submit_send.try_send(AnnPost {
-        meta: AnnPostMeta {
-            sver,
-            next_block_height,
-            pay_to,
-            remote_addr,
-        },
+    let reply = submit(
+        Arc::clone(&ah),
+        remote_addr,
         bytes,
-        reply: Some(reply),
-    }) {
-        Ok(_) => {
-            let reply = getreply.await.unwrap();
-            let ok = reply.error.is_empty();
-            if let Some(res) = &reply.result {
-                if let Err(e) = paymakerclient::handle_paylog(&ah.pmc, &res).await {
-                    error!("Unable to send paylog {}", e);
-                }
-            }
-            Ok(warp::reply::with_status(
-                warp::reply::json(&reply),
-                if ok {
-                    warp::http::StatusCode::OK
-                } else {
-                    warp::http::StatusCode::BAD_REQUEST
-                },
-            ))
+        sver,
+        next_block_height,
+        pay_to,
+        forwarded_for,
+        shard_passwd,
+        headers,
+    )
+    .await?;
+    Ok(with_queue_headers(&ah, reply))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn submit(
+    ah: AnnHandler,
+    remote_addr: Option<SocketAddr>,
+    bytes: bytes::Bytes,
+    //content_length: usize,
+    sver: u32,
+    next_block_height: i32,
+    pay_to: String,
+    forwarded_for: Option<String>,
+    shard_passwd: Option<String>,
+    headers: HeaderMap,
+) -> Result<warp::reply::WithStatus<warp::reply::Json>, Infallible> {
+    if !upload_token_ok(&ah, &headers) {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&AnnPostReply {
+                error: vec!["missing or wrong pool token".into()],
+                warn: vec![],
+                result: None,
+                ann_results: Vec::new(),
+            }),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    }
+    let remote_addr = forwarded_addr(&ah, remote_addr, forwarded_for, shard_passwd);
+    let identity = match client_identity(&ah, &headers, remote_addr) {
+        Ok(id) => id,
+        Err(()) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&AnnPostReply {
+                    error: vec!["client certificate required".into()],
+                    warn: vec![],
+                    result: None,
+                    ann_results: Vec::new(),
+                }),
+                warp::http::StatusCode::FORBIDDEN,
+            ));
         }
-        Err(e) => {
+    };
+    if let Some(addr) = remote_addr {
+        if ah.bans.is_banned(&addr.ip()) {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&AnnPostReply {
+                    error: vec!["banned".into()],
+                    warn: vec![],
+                    result: None,
+                    ann_results: Vec::new(),
+                }),
+                warp::http::StatusCode::FORBIDDEN,
+            ));
+        }
+    }
+    let challenge = headers
+        .get("x-pc-challenge")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let meta = AnnPostMeta {
+        sver,
+        next_block_height,
+        pay_to,
+        remote_addr,
+    };
+    let count = bytes.len() / 1024;
+    // Before it is split, each shard's part could be under the limit
+    if let Some(max) = ah.cfg.max_upload_anns {
+        if count > max {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&AnnPostReply {
+                    error: vec![format!("too many anns, at most {} in one upload", max)],
+                    warn: vec![],
+                    result: None,
+                    ann_results: Vec::new(),
+                }),
+                warp::http::StatusCode::PAYLOAD_TOO_LARGE,
+            ));
+        }
+    }
+    let ((bytes, our_pos), others) = split_shards(&ah, bytes);
+    // When every ann belongs to the other shards there is nothing to parse or account
+    // for here
+    let getreply = if bytes.is_empty() && !others.is_empty() {
+        None
+    } else {
+        let (reply, getreply) = oneshot::channel();
+        let shed = should_shed(&ah, claimed_target(&bytes));
+        let post = AnnPost {
+            meta: meta.clone(),
+            bytes,
+            reply: Some(reply),
+        };
+        let sent = if shed {
+            ah.sheds.add(1);
+            Err(TrySendError::Full(post))
+        } else {
+            ah.submit_send.try_send(post)
+        };
+        if let Err(e) = sent {
+            // Nothing has been forwarded yet, so the miner can send it all again
             let err: String = (if e.is_full() {
-                ah.overloads.fetch_add(1, atomic::Ordering::Relaxed);
+                ah.overloads.add(1);
                 "overloaded"
             } else {
                 error!("channel disconnected");
                 "disconnected"
             })
             .into();
-            Ok(warp::reply::with_status(
+            return Ok(warp::reply::with_status(
                 warp::reply::json(&AnnPostReply {
                     error: vec![err],
                     warn: vec![],
                     result: None,
+                    ann_results: Vec::new(),
                 }),
                 warp::http::StatusCode::SERVICE_UNAVAILABLE,
-            ))
+            ));
+        }
+        Some(getreply)
+    };
+    let forwards = others
+        .into_iter()
+        .map(|(shard, (b, pos))| {
+            let ah = Arc::clone(&ah);
+            let identity = identity.clone();
+            let challenge = challenge.clone();
+            let meta = meta.clone();
+            (
+                shard,
+                pos,
+                tokio::spawn(async move {
+                    forward_to_shard(&ah, shard, b, &meta, &identity, &challenge).await
+                }),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut reply = if let Some(getreply) = getreply {
+        let mut reply = getreply.await.unwrap();
+        // Only what this shard accepted goes to the paymaker from here
+        if let Some(res) = &reply.result {
+            if let Err(e) = accounting::write(&ah.acct, &res).await {
+                error!("Unable to write accounting log {}", e);
+            }
+            if let Err(e) = paymakerclient::handle_paylog(&ah.pmc, &res).await {
+                error!("Unable to send paylog {}", e);
+            }
+        }
+        if !forwards.is_empty() {
+            let ours = std::mem::take(&mut reply.ann_results);
+            reply.ann_results = vec![ANN_REJECTED; count];
+            place_results(&mut reply.ann_results, &our_pos, &ours);
+        }
+        reply
+    } else {
+        AnnPostReply {
+            error: vec![],
+            warn: vec![],
+            result: None,
+            ann_results: vec![ANN_REJECTED; count],
+        }
+    };
+    for (shard, pos, f) in forwards {
+        let res = f.await.map_err(anyhow::Error::from).and_then(|r| r);
+        merge_reply(&mut reply, shard, &pos, res);
+    }
+    let ok = reply.error.is_empty();
+    Ok(warp::reply::with_status(
+        warp::reply::json(&reply),
+        if ok {
+            warp::http::StatusCode::OK
+        } else {
+            warp::http::StatusCode::BAD_REQUEST
+        },
+    ))
+}
+
+fn admin_ok(ah: &AnnHandler, passwd: &Option<String>) -> bool {
+    match (&ah.cfg.admin_passwd, passwd) {
+        (Some(a), Some(b)) => util::secret_eq(a, b),
+        _ => false,
+    }
+}
+
+fn upload_token_ok(ah: &AnnHandler, headers: &HeaderMap) -> bool {
+    let want = match &ah.cfg.upload_token {
+        Some(t) => t,
+        None => return true,
+    };
+    headers
+        .get(warp::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map_or(false, |t| util::secret_eq(want, t))
+}
+
+fn block_miner_ok(ah: &AnnHandler, passwd: &Option<String>) -> bool {
+    let want = &ah.cfg.block_miner_passwd;
+    want.is_empty() || passwd.as_ref().map_or(false, |p| util::secret_eq(want, p))
+}
+
+fn forbidden() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": "forbidden" })),
+        warp::http::StatusCode::FORBIDDEN,
+    )
+}
+
+async fn handle_shards(ah: AnnHandler) -> Result<impl warp::Reply, Infallible> {
+    let s = if let Some(s) = &ah.shards {
+        s
+    } else {
+        return Ok(warp::reply::json(&serde_json::json!({ "shards": null })));
+    };
+    // Each shard owns the anns whose dedup hash has its top 16 bits in this range
+    let shards = s
+        .urls
+        .iter()
+        .enumerate()
+        .map(|(i, url)| {
+            let (from, to) = shard_range(i, s.urls.len());
+            serde_json::json!({ "url": url, "prefixFrom": from, "prefixTo": to })
+        })
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&serde_json::json!({
+        "shardNum": s.num,
+        "shards": shards,
+    })))
+}
+
+async fn handle_ann_index(
+    ah: AnnHandler,
+    file_anns: Option<usize>,
+    since: Option<i64>,
+) -> Result<impl warp::Reply, Infallible> {
+    Ok(if let Some(af) = &ah.ann_files {
+        warp::reply::with_status(
+            warp::reply::json(&annfiles::index(af, file_anns.unwrap_or(0), since)),
+            warp::http::StatusCode::OK,
+        )
+    } else {
+        warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": "no ann files" })),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+    })
+}
+
+async fn handle_ann_file(
+    name: String,
+    ah: AnnHandler,
+    passwd: Option<String>,
+    remote_addr: Option<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<impl warp::Reply, Infallible> {
+    let pass_ok =
+        block_miner_ok(&ah, &passwd) && client_identity(&ah, &headers, remote_addr).is_ok();
+    let content = match &ah.ann_files {
+        Some(af) if pass_ok => annfiles::read(af, &name).await,
+        _ => None,
+    };
+    Ok(match content {
+        Some(c) => warp::reply::with_status(c, warp::http::StatusCode::OK),
+        None if !pass_ok => warp::reply::with_status(Vec::new(), warp::http::StatusCode::FORBIDDEN),
+        None => warp::reply::with_status(Vec::new(), warp::http::StatusCode::NOT_FOUND),
+    })
+}
+
+fn find_ann(ah: &AnnHandler, hash: &str) -> Option<annfiles::AnnLocation> {
+    let hash: [u8; 32] = hex::decode(hash).ok()?.as_slice().try_into().ok()?;
+    annfiles::find(ah.ann_files.as_ref()?, &hash)
+}
+
+async fn handle_ann_find(
+    hash: String,
+    ah: AnnHandler,
+    passwd: Option<String>,
+) -> Result<impl warp::Reply, Infallible> {
+    if !block_miner_ok(&ah, &passwd) {
+        return Ok(forbidden());
+    }
+    Ok(if let Some(loc) = find_ann(&ah, &hash) {
+        warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "file": format!("anns_{}.bin", loc.file),
+                "offset": loc.offset,
+                "contentType": loc.content_type,
+                "contentLength": loc.content_length,
+            })),
+            warp::http::StatusCode::OK,
+        )
+    } else {
+        warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": "not found" })),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+    })
+}
+
+async fn handle_ann_get(
+    hash: String,
+    ah: AnnHandler,
+    passwd: Option<String>,
+) -> Result<impl warp::Reply, Infallible> {
+    if !block_miner_ok(&ah, &passwd) {
+        return Ok(warp::reply::with_status(
+            Vec::new(),
+            warp::http::StatusCode::FORBIDDEN,
+        ));
+    }
+    let content = match (&ah.ann_files, find_ann(&ah, &hash)) {
+        (Some(af), Some(loc)) => annfiles::read_ann(af, &loc).await,
+        _ => None,
+    };
+    Ok(match content {
+        Some(c) => warp::reply::with_status(c, warp::http::StatusCode::OK),
+        None => warp::reply::with_status(Vec::new(), warp::http::StatusCode::NOT_FOUND),
+    })
+}
+
+// Work credited to each address this round, so miners can check that their anns are
+// counted before they are paid. ?payto= for one address, ?offset= and ?limit= to page.
+async fn handle_work(
+    ah: AnnHandler,
+    q: HashMap<String, String>,
+) -> Result<impl warp::Reply, Infallible> {
+    let num = |k: &str, default: usize| q.get(k).and_then(|v| v.parse().ok()).unwrap_or(default);
+    let (offset, limit) = (num("offset", 0), num("limit", 100).min(MAX_WORK_PAGE));
+    let round_seconds = poolclient::conf(&ah.pc)
+        .await
+        .and_then(|c| c.payout_interval_seconds)
+        .unwrap_or(accounting::DEFAULT_ROUND_SECONDS);
+    let (start_ms, lines) = match accounting::round_work(&ah.acct, round_seconds).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Unable to read round work: {}", e);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "error": "internal error" })),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ));
         }
+    };
+    let total_work: f64 = lines.iter().map(|l| l.work).sum();
+    let count = lines.len();
+    let page = lines
+        .iter()
+        .filter(|l| q.get("payto").map_or(true, |p| p == &l.pay_to))
+        .skip(offset)
+        .take(limit)
+        .map(|l| {
+            serde_json::json!({
+                "payTo": l.pay_to,
+                "events": l.events,
+                "anns": l.anns,
+                "work": l.work,
+                "share": l.share,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+            "roundStartMs": start_ms,
+            "roundSeconds": round_seconds,
+            "addresses": count,
+            "totalWork": total_work,
+            "work": page,
+        })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn handle_list_bans(
+    ah: AnnHandler,
+    passwd: Option<String>,
+) -> Result<impl warp::Reply, Infallible> {
+    if !admin_ok(&ah, &passwd) {
+        return Ok(forbidden());
     }
+    let bans = ah
+        .bans
+        .list()
+        .iter()
+        .map(|b| {
+            serde_json::json!({
+                "addr": b.addr.to_string(),
+                "remaining_sec": b.remaining_sec,
+                "bans": b.bans,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(warp::reply::with_status(
+        warp::reply::json(&bans),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn handle_lift_ban(
+    addr: IpAddr,
+    ah: AnnHandler,
+    passwd: Option<String>,
+) -> Result<impl warp::Reply, Infallible> {
+    if !admin_ok(&ah, &passwd) {
+        return Ok(forbidden());
+    }
+    let lifted = ah.bans.lift(&addr);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "lifted": lifted })),
+        if lifted {
+            warp::http::StatusCode::OK
+        } else {
+            warp::http::StatusCode::NOT_FOUND
+        },
+    ))
 }
 
 pub async fn start(ah: &AnnHandler) {
+    stats::register(ah);
+    let challenged = ah.clone();
     let sub = warp::post()
         .and(warp::path("submit"))
         .and(warp::path::end())
@@ -563,12 +1743,105 @@ pub async fn start(ah: &AnnHandler) {
             ah.clone(),
         ))
         .and(warp::filters::addr::remote())
+        .and(challenge_gate(ah.clone()))
         .and(warp::body::bytes())
         //.and(warp::header::<usize>("content-length"))
         .and(warp::header::<u32>("x-pc-sver"))
         .and(warp::header::<i32>("x-pc-worknum"))
         .and(warp::header::<String>("x-pc-payto"))
-        .and_then(handle_submit);
+        .and(warp::header::optional::<String>("x-pc-forwarded-for"))
+        .and(warp::header::optional::<String>("x-pc-shard-passwd"))
+        .and(warp::header::headers_cloned())
+        .and_then(handle_submit)
+        .recover(move |r| handle_challenged(challenged.clone(), r));
+
+    // Ann files for block miners, the index is checked before the files
+    let ann_index = warp::get()
+        .and(warp::path("anns"))
+        .and(warp::path("index.json"))
+        .and(warp::path::end())
+        .and((|ah: AnnHandler| warp::any().map(move || ah.clone()))(
+            ah.clone(),
+        ))
+        .and(warp::header::optional::<usize>("x-pc-file-anns"))
+        .and(warp::header::optional::<i64>("x-pc-since"))
+        .and_then(handle_ann_index);
+    let ann_file = warp::get()
+        .and(warp::path("anns"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and((|ah: AnnHandler| warp::any().map(move || ah.clone()))(
+            ah.clone(),
+        ))
+        .and(warp::header::optional::<String>("x-pc-passwd"))
+        .and(warp::filters::addr::remote())
+        .and(warp::header::headers_cloned())
+        .and_then(handle_ann_file);
+    // Single anns by hash, if ann_hash_index is enabled
+    let ann_find = warp::get()
+        .and(warp::path("anns"))
+        .and(warp::path("find"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and((|ah: AnnHandler| warp::any().map(move || ah.clone()))(
+            ah.clone(),
+        ))
+        .and(warp::header::optional::<String>("x-pc-passwd"))
+        .and_then(handle_ann_find);
+    let ann_get = warp::get()
+        .and(warp::path("anns"))
+        .and(warp::path("ann"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and((|ah: AnnHandler| warp::any().map(move || ah.clone()))(
+            ah.clone(),
+        ))
+        .and(warp::header::optional::<String>("x-pc-passwd"))
+        .and_then(handle_ann_get);
+
+    let shards = warp::get()
+        .and(warp::path("shards"))
+        .and(warp::path::end())
+        .and((|ah: AnnHandler| warp::any().map(move || ah.clone()))(
+            ah.clone(),
+        ))
+        .and_then(handle_shards);
+    let work = warp::get()
+        .and(warp::path("work"))
+        .and(warp::path::end())
+        .and((|ah: AnnHandler| warp::any().map(move || ah.clone()))(
+            ah.clone(),
+        ))
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(handle_work);
+
+    // Moderation, requires admin_passwd to be set in the config
+    let list_bans = warp::get()
+        .and(warp::path("bans"))
+        .and(warp::path::end())
+        .and((|ah: AnnHandler| warp::any().map(move || ah.clone()))(
+            ah.clone(),
+        ))
+        .and(warp::header::optional::<String>("x-pc-passwd"))
+        .and_then(handle_list_bans);
+    let lift_ban = warp::delete()
+        .and(warp::path("bans"))
+        .and(warp::path::param::<IpAddr>())
+        .and(warp::path::end())
+        .and((|ah: AnnHandler| warp::any().map(move || ah.clone()))(
+            ah.clone(),
+        ))
+        .and(warp::header::optional::<String>("x-pc-passwd"))
+        .and_then(handle_lift_ban);
+    let routes = sub
+        .or(ann_index)
+        .or(ann_file)
+        .or(ann_find)
+        .or(ann_get)
+        .or(shards)
+        .or(work)
+        .or(list_bans)
+        .or(lift_ban);
 
     // Pipe new work updates through to a crossbeam channel
     util::tokio_bcast_to_crossbeam(
@@ -578,7 +1851,7 @@ pub async fn start(ah: &AnnHandler) {
     )
     .await;
 
-    packetcrypt_util::async_spawn!(ah, { warp::serve(sub).run(ah.sockaddr).await });
+    packetcrypt_util::async_spawn!(ah, { warp::serve(routes).run(ah.sockaddr).await });
 
     for i in 0..(ah.cfg.num_workers) {
         let g = ah.clone();
@@ -586,6 +1859,12 @@ pub async fn start(ah: &AnnHandler) {
             worker_loop(g, i);
         });
     }
+    for _ in 0..ah.cfg.verify_workers.unwrap_or(0) {
+        let g = ah.clone();
+        std::thread::spawn(move || {
+            verify_loop(g);
+        });
+    }
 
     ah.sprayer.start();
 }
@@ -632,6 +1911,34 @@ mod tests {
         83c622154dac17ad9141c4b4b2a733934af0b24ff24f81ec9a16058f2fee88d4"
     );
 
+    #[test]
+    fn shard_range() {
+        for n in 1..8 {
+            for i in 0..n {
+                let (from, to) = super::shard_range(i, n);
+                assert_eq!(super::shard_of((from as u64) << 48, n), i);
+                assert_eq!(super::shard_of((to as u64) << 48 | 0xffff, n), i);
+                if i + 1 < n {
+                    assert_eq!(super::shard_range(i + 1, n).0, to + 1);
+                }
+            }
+            assert_eq!(super::shard_range(n - 1, n).1, 0xffff);
+        }
+    }
+
+    #[test]
+    fn place_results() {
+        use packetcrypt_util::protocol::{ANN_DUP, ANN_OK, ANN_REJECTED};
+        let mut out = vec![ANN_REJECTED; 5];
+        super::place_results(&mut out, &[0, 3], &[ANN_OK, ANN_DUP]);
+        // a shard which replied with fewer results than anns
+        super::place_results(&mut out, &[1, 4], &[ANN_OK]);
+        assert_eq!(
+            out,
+            vec![ANN_OK, ANN_OK, ANN_REJECTED, ANN_DUP, ANN_REJECTED]
+        );
+    }
+
     #[test]
     fn hash() {
         let ann_hash = hash::compress32(&ANN);
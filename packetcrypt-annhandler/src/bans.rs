@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use log::{info, warn};
+use packetcrypt_util::util;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Invalid batches are counted over this window
+const WINDOW_SECONDS: u64 = 60;
+
+// A source is banned if it submits at least this many invalid batches in a window...
+const MIN_INVALID: u32 = 10;
+
+// ...and they are more than this fraction of everything it submitted.
+const INVALID_RATIO: f32 = 0.5;
+
+pub const DEFAULT_BAN_SECONDS: u64 = 600;
+
+// Most sources tracked at once, so that a spray of addresses can't use up the memory
+const MAX_SOURCES: usize = 100_000;
+
+#[derive(Default)]
+struct Source {
+    window_start_sec: u64,
+    valid: u32,
+    invalid: u32,
+    banned_until_sec: u64,
+    bans: u32,
+}
+
+pub struct BanInfo {
+    pub addr: IpAddr,
+    pub remaining_sec: u64,
+    pub bans: u32,
+}
+
+pub struct Bans {
+    ban_seconds: u64,
+    sources: Mutex<HashMap<IpAddr, Source>>,
+    pruned_sec: AtomicU64,
+}
+
+fn now_sec() -> u64 {
+    util::now_ms() / 1000
+}
+
+// Forget sources which are no longer banned and have been quiet
+fn prune(sources: &mut HashMap<IpAddr, Source>, now: u64) {
+    sources.retain(|_, s| s.banned_until_sec > now || s.window_start_sec + WINDOW_SECONDS > now);
+}
+
+impl Bans {
+    pub fn new(ban_seconds: u64) -> Self {
+        Bans {
+            ban_seconds,
+            sources: Mutex::new(HashMap::new()),
+            pruned_sec: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_banned(&self, addr: &IpAddr) -> bool {
+        match self.sources.lock().get(addr) {
+            Some(s) => s.banned_until_sec > now_sec(),
+            None => false,
+        }
+    }
+
+    /// Record the result of verifying a batch from addr
+    pub fn record(&self, addr: IpAddr, valid: bool) {
+        let now = now_sec();
+        let mut sources = self.sources.lock();
+        if self.pruned_sec.load(Ordering::Relaxed) + WINDOW_SECONDS < now {
+            self.pruned_sec.store(now, Ordering::Relaxed);
+            prune(&mut sources, now);
+        }
+        if !sources.contains_key(&addr) {
+            if valid {
+                // Don't track well behaved sources until they misbehave
+                return;
+            }
+            if sources.len() >= MAX_SOURCES {
+                prune(&mut sources, now);
+            }
+            if sources.len() >= MAX_SOURCES {
+                // Too many suspects to keep track of, keep only the bans
+                warn!(
+                    "Tracking {} sources, forgetting those which are not banned",
+                    sources.len()
+                );
+                sources.retain(|_, s| s.banned_until_sec > now);
+                if sources.len() >= MAX_SOURCES {
+                    return;
+                }
+            }
+        }
+        let s = sources.entry(addr).or_insert_with(Source::default);
+        if s.window_start_sec + WINDOW_SECONDS < now {
+            s.window_start_sec = now;
+            s.valid = 0;
+            s.invalid = 0;
+        }
+        if valid {
+            s.valid += 1;
+            return;
+        }
+        s.invalid += 1;
+        let ratio = s.invalid as f32 / (s.valid + s.invalid) as f32;
+        if s.invalid >= MIN_INVALID && ratio > INVALID_RATIO && s.banned_until_sec <= now {
+            s.banned_until_sec = now + self.ban_seconds;
+            s.bans += 1;
+            warn!(
+                "Banning [{}] for {} seconds, {} of {} batches invalid",
+                addr,
+                self.ban_seconds,
+                s.invalid,
+                s.valid + s.invalid
+            );
+        }
+    }
+
+    pub fn list(&self) -> Vec<BanInfo> {
+        let now = now_sec();
+        let mut sources = self.sources.lock();
+        prune(&mut sources, now);
+        sources
+            .iter()
+            .filter(|(_, s)| s.banned_until_sec > now)
+            .map(|(addr, s)| BanInfo {
+                addr: *addr,
+                remaining_sec: s.banned_until_sec - now,
+                bans: s.bans,
+            })
+            .collect()
+    }
+
+    /// Lift a ban, returns false if addr was not banned
+    pub fn lift(&self, addr: &IpAddr) -> bool {
+        if let Some(s) = self.sources.lock().remove(addr) {
+            info!("Lifted ban on [{}]", addr);
+            s.banned_until_sec > now_sec()
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bans, MAX_SOURCES, MIN_INVALID};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_ban() {
+        let bans = Bans::new(600);
+        let good: IpAddr = "10.0.0.1".parse().unwrap();
+        let bad: IpAddr = "10.0.0.2".parse().unwrap();
+        for _ in 0..100 {
+            bans.record(good, true);
+        }
+        for _ in 0..MIN_INVALID {
+            assert!(!bans.is_banned(&bad));
+            bans.record(bad, false);
+        }
+        assert!(bans.is_banned(&bad));
+        assert!(!bans.is_banned(&good));
+        assert_eq!(bans.list().len(), 1);
+        assert!(bans.lift(&bad));
+        assert!(!bans.is_banned(&bad));
+    }
+
+    #[test]
+    fn test_many_sources() {
+        let bans = Bans::new(600);
+        for i in 0..(MAX_SOURCES as u32 + 10) {
+            bans.record(IpAddr::V4(Ipv4Addr::from(i)), false);
+        }
+        assert!(bans.sources.lock().len() <= MAX_SOURCES);
+    }
+}
@@ -1 +1,6 @@
+mod annfiles;
 pub mod annhandler;
+mod bans;
+mod mirror;
+mod pubsub;
+mod routes;
@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Mirroring the ann files into a directory which any web server can serve, so that
+//! third parties can crawl the anns which were accepted, for research or to bootstrap a
+//! block miner of their own. Nothing is ever deleted from the mirror. The layout is:
+//!
+//! ```text
+//! index.json              {"buckets": [<bucket>, ...]}
+//! <bucket>/index.json     {"heights": [{"height": <h>, "files": <n>, "anns": <n>}, ...]}
+//! <bucket>/<h>/index.json {"height": <h>, "files": [{"name": "anns_<num>.bin",
+//!                             "anns": <n>, "blake2b": "<hex>"}, ...]}
+//! <bucket>/<h>/anns_<num>.bin
+//! ```
+//!
+//! Where h is the parent block height of the anns in the file and bucket is h / 1000,
+//! so that no directory gets too big. blake2b is the 32 byte blake2b of the whole file.
+//! Lists are in order, a file is written before it is in an index and indexes are
+//! replaced whole, so a crawler never sees one which is half written.
+use anyhow::Result;
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use log::{debug, info, warn};
+use packetcrypt_util::hash;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Files waiting to be mirrored, more than this and they are dropped rather than taking
+// up memory while the mirror's disk is slow
+const QUEUE_LEN: usize = 256;
+
+const BUCKET_HEIGHTS: i32 = 1000;
+
+struct ToMirror {
+    num: usize,
+    parent_block_height: i32,
+    anns: Bytes,
+    // The handler's copy, if it's on disk, so that it can be linked instead of copied
+    path: Option<String>,
+}
+
+pub struct MirrorS {
+    dir: String,
+    send: Sender<ToMirror>,
+    dropped: AtomicUsize,
+}
+pub type Mirror = Arc<MirrorS>;
+
+pub fn new(dir: &str) -> Result<Mirror> {
+    std::fs::create_dir_all(dir)?;
+    let (send, recv) = crossbeam_channel::bounded(QUEUE_LEN);
+    let mi = Arc::new(MirrorS {
+        dir: dir.to_owned(),
+        send,
+        dropped: AtomicUsize::new(0),
+    });
+    let mi1 = Arc::clone(&mi);
+    std::thread::spawn(move || mirror_loop(&mi1, recv));
+    info!("Mirroring ann files to {}", dir);
+    Ok(mi)
+}
+
+/// Mirror an ann file which was just written, path is where the handler wrote it
+pub fn add(mi: &Mirror, num: usize, parent_block_height: i32, anns: Bytes, path: Option<String>) {
+    let tm = ToMirror {
+        num,
+        parent_block_height,
+        anns,
+        path,
+    };
+    match mi.send.try_send(tm) {
+        Ok(()) => (),
+        Err(TrySendError::Full(_)) => {
+            mi.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(TrySendError::Disconnected(_)) => warn!("Ann file mirror is gone"),
+    }
+}
+
+/// Files which could not be mirrored because it was behind, since the last call
+pub fn dropped(mi: &Mirror) -> usize {
+    mi.dropped.swap(0, Ordering::Relaxed)
+}
+
+// An index, or a new one if there is none yet
+fn read_index(path: &str, empty: Value) -> Value {
+    match std::fs::read(path) {
+        Ok(b) => match serde_json::from_slice(&b) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Replacing mirror index {} which is not valid: {}", path, e);
+                empty
+            }
+        },
+        Err(_) => empty,
+    }
+}
+
+fn write_index(path: &str, v: &Value) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_json::to_vec(v)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn list<'a>(v: &'a mut Value, name: &str) -> &'a mut Vec<Value> {
+    if !v[name].is_array() {
+        v[name] = json!([]);
+    }
+    v[name].as_array_mut().unwrap()
+}
+
+fn mirror_file(mi: &Mirror, tm: &ToMirror) -> Result<()> {
+    let h = tm.parent_block_height;
+    let bucket = h / BUCKET_HEIGHTS;
+    let bucket_dir = format!("{}/{}", mi.dir, bucket);
+    let height_dir = format!("{}/{}", bucket_dir, h);
+    std::fs::create_dir_all(&height_dir)?;
+
+    let name = format!("anns_{}.bin", tm.num);
+    let dest = format!("{}/{}", height_dir, name);
+    let linked = match &tm.path {
+        Some(p) => std::fs::hard_link(p, &dest).is_ok(),
+        None => false,
+    };
+    if !linked {
+        std::fs::write(&dest, &tm.anns)?;
+    }
+    let anns = tm.anns.len() / 1024;
+
+    let height_index = format!("{}/index.json", height_dir);
+    let mut hi = read_index(&height_index, json!({ "height": h }));
+    let files = list(&mut hi, "files");
+    files.push(json!({
+        "name": name,
+        "anns": anns,
+        "blake2b": hex::encode(hash::compress32(&tm.anns)),
+    }));
+    let file_count = files.len();
+    write_index(&height_index, &hi)?;
+
+    let bucket_index = format!("{}/index.json", bucket_dir);
+    let mut bi = read_index(&bucket_index, json!({}));
+    let heights = list(&mut bi, "heights");
+    let pos = heights
+        .iter()
+        .position(|e| e["height"].as_i64() == Some(h as i64));
+    let total = pos.map_or(0, |p| heights[p]["anns"].as_u64().unwrap_or(0)) + anns as u64;
+    let entry = json!({ "height": h, "files": file_count, "anns": total });
+    match pos {
+        Some(p) => heights[p] = entry,
+        None => {
+            heights.push(entry);
+            heights.sort_by_key(|e| e["height"].as_i64());
+        }
+    }
+    write_index(&bucket_index, &bi)?;
+
+    let top_index = format!("{}/index.json", mi.dir);
+    let mut ti = read_index(&top_index, json!({}));
+    let buckets = list(&mut ti, "buckets");
+    if !buckets.iter().any(|b| b.as_i64() == Some(bucket as i64)) {
+        buckets.push(json!(bucket));
+        buckets.sort_by_key(|b| b.as_i64());
+        write_index(&top_index, &ti)?;
+    }
+    debug!("Mirrored ann file {} to {}", tm.num, dest);
+    Ok(())
+}
+
+fn mirror_loop(mi: &Mirror, recv: Receiver<ToMirror>) {
+    for tm in recv.iter() {
+        if let Err(e) = mirror_file(mi, &tm) {
+            warn!("Unable to mirror ann file {}: {}", tm.num, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    #[test]
+    fn layout() {
+        let dir = std::env::temp_dir().join(format!("mirror_test_{}", std::process::id()));
+        let dir = dir.to_str().unwrap().to_owned();
+        let (send, _recv) = crossbeam_channel::bounded(1);
+        let mi = std::sync::Arc::new(super::MirrorS {
+            dir: dir.clone(),
+            send,
+            dropped: Default::default(),
+        });
+        for (num, h) in &[(7, 1999), (8, 2001), (9, 1999)] {
+            let tm = super::ToMirror {
+                num: *num,
+                parent_block_height: *h,
+                anns: bytes::Bytes::from(vec![0u8; 2048]),
+                path: None,
+            };
+            super::mirror_file(&mi, &tm).unwrap();
+        }
+        let read = |p: &str| super::read_index(&format!("{}/{}", dir, p), json!({}));
+        assert_eq!(read("index.json")["buckets"], json!([1, 2]));
+        let b1 = read("1/index.json");
+        assert_eq!(
+            b1["heights"],
+            json!([{ "height": 1999, "files": 2, "anns": 4 }])
+        );
+        let h = read("1/1999/index.json");
+        assert_eq!(h["files"][1]["name"], "anns_9.bin");
+        assert!(std::path::Path::new(&format!("{}/2/2001/anns_8.bin", dir)).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Publishing every accepted ann as it is stored, so that analytics, routing daemons
+//! and other miners can follow the handler in real time rather than polling the ann
+//! files. The socket speaks the NNG / nanomsg pub0 protocol over TCP, so any NNG or
+//! nanomsg SUB socket can dial tcp://<ann_pub_bind> and subscribe, e.g. with pynng:
+//!
+//! ```text
+//! s = pynng.Sub0(dial="tcp://127.0.0.1:8089", topics=b"")
+//! ```
+//!
+//! Each message is one ann, the 88 byte header (version, nonces, work bits, parent
+//! block height, content type and length, content hash and signing key) or, with
+//! ann_pub_bodies, the whole 1024 bytes. Topics are prefixes of the message, as always
+//! with pub0, so b"\x01" subscribes to anns of version 1. A subscriber which can't keep
+//! up misses messages rather than slowing the handler down.
+use anyhow::{bail, Result};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use log::{debug, info, warn};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// The SP header, "\0SP\0" then the protocol number and 2 reserved bytes
+const PROTO_PUB: u16 = 0x20;
+const PROTO_SUB: u16 = 0x21;
+
+const HEADER_LEN: usize = 88;
+
+// Batches waiting to be sent to each subscriber
+const QUEUE_LEN: usize = 64;
+
+fn sp_header(proto: u16) -> [u8; 8] {
+    let p = proto.to_be_bytes();
+    [0, b'S', b'P', 0, p[0], p[1], 0, 0]
+}
+
+pub struct PubSubS {
+    bodies: bool,
+    subs: Mutex<Vec<Sender<Arc<Vec<u8>>>>>,
+    dropped: AtomicUsize,
+}
+pub type PubSub = Arc<PubSubS>;
+
+pub fn new(bind: &str, bodies: bool) -> Result<PubSub> {
+    let listener = TcpListener::bind(bind)?;
+    let ps = Arc::new(PubSubS {
+        bodies,
+        subs: Mutex::new(Vec::new()),
+        dropped: AtomicUsize::new(0),
+    });
+    let ps1 = Arc::clone(&ps);
+    std::thread::spawn(move || accept_loop(&ps1, listener));
+    info!("Publishing accepted anns on tcp://{}", bind);
+    Ok(ps)
+}
+
+// Every ann as a message, each one an 8 byte length and then the ann or its header
+fn frame(anns: &[&[u8]], bodies: bool) -> Vec<u8> {
+    let len = if bodies { 1024 } else { HEADER_LEN };
+    let mut out = Vec::with_capacity(anns.len() * (8 + len));
+    for ann in anns {
+        let msg = &ann[..len.min(ann.len())];
+        out.extend_from_slice(&(msg.len() as u64).to_be_bytes());
+        out.extend_from_slice(msg);
+    }
+    out
+}
+
+/// Publish anns which were just stored
+pub fn publish(ps: &PubSub, anns: &[&[u8]]) {
+    let mut subs = ps.subs.lock().unwrap();
+    if subs.is_empty() || anns.is_empty() {
+        return;
+    }
+    let msgs = Arc::new(frame(anns, ps.bodies));
+    subs.retain(|s| match s.try_send(Arc::clone(&msgs)) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            ps.dropped.fetch_add(anns.len(), Ordering::Relaxed);
+            true
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    });
+}
+
+/// Number of subscribers and the anns which they missed since the last call
+pub fn stats(ps: &PubSub) -> (usize, usize) {
+    let subs = ps.subs.lock().unwrap().len();
+    (subs, ps.dropped.swap(0, Ordering::Relaxed))
+}
+
+fn handshake(sock: &mut TcpStream) -> Result<()> {
+    sock.write_all(&sp_header(PROTO_PUB))?;
+    let mut theirs = [0u8; 8];
+    sock.read_exact(&mut theirs)?;
+    if theirs != sp_header(PROTO_SUB) {
+        bail!("not an SP sub socket: {}", hex::encode(theirs));
+    }
+    Ok(())
+}
+
+fn send_loop(mut sock: TcpStream, recv: Receiver<Arc<Vec<u8>>>) -> Result<()> {
+    for msgs in recv.iter() {
+        sock.write_all(&msgs[..])?;
+    }
+    Ok(())
+}
+
+fn accept_loop(ps: &PubSub, listener: TcpListener) {
+    for sock in listener.incoming() {
+        let mut sock = match sock {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Unable to accept ann subscriber: {}", e);
+                continue;
+            }
+        };
+        let ps = Arc::clone(ps);
+        std::thread::spawn(move || {
+            let peer = sock.peer_addr().ok();
+            if let Err(e) = handshake(&mut sock) {
+                debug!("Ann subscriber {:?} refused: {}", peer, e);
+                return;
+            }
+            let (send, recv) = crossbeam_channel::bounded(QUEUE_LEN);
+            ps.subs.lock().unwrap().push(send);
+            info!("Ann subscriber {:?} connected", peer);
+            // The subscriber never sends anything, so an error writing is how we know
+            // that it's gone and dropping recv removes it on the next publish()
+            if let Err(e) = send_loop(sock, recv) {
+                info!("Ann subscriber {:?} disconnected: {}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    #[test]
+    fn subscribe() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ps = std::sync::Arc::new(super::PubSubS {
+            bodies: false,
+            subs: Default::default(),
+            dropped: Default::default(),
+        });
+        let ps1 = std::sync::Arc::clone(&ps);
+        std::thread::spawn(move || super::accept_loop(&ps1, listener));
+
+        let mut sub = std::net::TcpStream::connect(addr).unwrap();
+        sub.write_all(&super::sp_header(super::PROTO_SUB)).unwrap();
+        let mut hdr = [0u8; 8];
+        sub.read_exact(&mut hdr).unwrap();
+        assert_eq!(hdr, super::sp_header(super::PROTO_PUB));
+        while super::stats(&ps).0 == 0 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        let ann = [7u8; 1024];
+        super::publish(&ps, &[&ann[..], &ann[..]]);
+        let mut buf = vec![0u8; 2 * (8 + super::HEADER_LEN)];
+        sub.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..8], (super::HEADER_LEN as u64).to_be_bytes());
+        assert_eq!(buf[8..8 + super::HEADER_LEN], ann[..super::HEADER_LEN]);
+    }
+}
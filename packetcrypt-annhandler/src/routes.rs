@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Passing on anns of chosen content types to something other than the block miners,
+//! e.g. cjdns route announcements to a daemon on the same machine. Each accepted ann
+//! of a routed content type is sent whole, as one 1024 byte datagram, to a unix socket
+//! or a UDP address. Sending never waits, if the receiver can't keep up then the ann is
+//! counted as dropped. Routed anns are also put in the ann files and sprayed unless
+//! the route says not to keep them.
+use anyhow::{bail, Context, Result};
+use log::info;
+use packetcrypt_pool::poolcfg::ContentRoute;
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+enum Dest {
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram, String),
+    Udp(UdpSocket, SocketAddr),
+}
+
+struct Route {
+    dest: Dest,
+    keep: bool,
+    sent: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+pub struct Routes {
+    by_type: HashMap<u32, Vec<Route>>,
+}
+
+fn dest(cr: &ContentRoute) -> Result<Dest> {
+    match (&cr.unix_socket, &cr.udp) {
+        (Some(_), Some(_)) => bail!("only one of unix_socket and udp can be set"),
+        #[cfg(unix)]
+        (Some(path), None) => {
+            let s = std::os::unix::net::UnixDatagram::unbound()?;
+            s.set_nonblocking(true)?;
+            Ok(Dest::Unix(s, path.clone()))
+        }
+        #[cfg(not(unix))]
+        (Some(_), None) => bail!("unix_socket is not supported on this system"),
+        (None, Some(addr)) => {
+            let to: SocketAddr = addr
+                .parse()
+                .with_context(|| format!("Invalid udp address [{}]", addr))?;
+            let bind = if to.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            let s = UdpSocket::bind(bind)?;
+            s.set_nonblocking(true)?;
+            Ok(Dest::Udp(s, to))
+        }
+        (None, None) => bail!("one of unix_socket or udp must be set"),
+    }
+}
+
+pub fn new(cfg: &[ContentRoute]) -> Result<Routes> {
+    let mut by_type: HashMap<u32, Vec<Route>> = HashMap::new();
+    for cr in cfg {
+        let dest = dest(cr).with_context(|| {
+            format!("Invalid content route for content type {}", cr.content_type)
+        })?;
+        info!(
+            "Routing anns of content type {} to {}",
+            cr.content_type,
+            describe(&dest)
+        );
+        by_type.entry(cr.content_type).or_default().push(Route {
+            dest,
+            keep: cr.keep.unwrap_or(true),
+            sent: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        });
+    }
+    Ok(Routes { by_type })
+}
+
+fn describe(d: &Dest) -> String {
+    match d {
+        #[cfg(unix)]
+        Dest::Unix(_, path) => format!("unix socket [{}]", path),
+        Dest::Udp(_, addr) => format!("udp [{}]", addr),
+    }
+}
+
+fn send(d: &Dest, ann: &[u8]) -> bool {
+    match d {
+        #[cfg(unix)]
+        Dest::Unix(s, path) => s.send_to(ann, path).is_ok(),
+        Dest::Udp(s, addr) => s.send_to(ann, addr).is_ok(),
+    }
+}
+
+/// Send the anns which have a route, returns those which are to be kept
+pub fn forward<'a>(r: &Routes, anns: Vec<&'a [u8]>) -> Vec<&'a [u8]> {
+    anns.into_iter()
+        .filter(|ann| {
+            let routes = match r.by_type.get(&packetcrypt_sys::content_type(ann)) {
+                Some(routes) => routes,
+                None => return true,
+            };
+            let mut keep = false;
+            for route in routes {
+                let counter = if send(&route.dest, ann) {
+                    &route.sent
+                } else {
+                    &route.dropped
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                keep |= route.keep;
+            }
+            keep
+        })
+        .collect()
+}
+
+/// For each route, a description and the anns sent and dropped since the last call
+pub fn stats(r: &Routes) -> Vec<(String, usize, usize)> {
+    let mut out = Vec::new();
+    for (ct, routes) in &r.by_type {
+        for route in routes {
+            out.push((
+                format!("{} -> {}", ct, describe(&route.dest)),
+                route.sent.swap(0, Ordering::Relaxed),
+                route.dropped.swap(0, Ordering::Relaxed),
+            ));
+        }
+    }
+    out
+}
@@ -5,18 +5,32 @@ use core::time::Duration;
 use log::{debug, info, trace, warn};
 use packetcrypt_sys::PacketCryptAnn;
 use packetcrypt_util::poolclient::{self, PoolClient, PoolUpdate};
-use packetcrypt_util::protocol::{AnnPostReply, BlockInfo};
-use packetcrypt_util::util;
+use packetcrypt_util::protocol::{self, AnnPostReply, BlockInfo};
+use packetcrypt_util::stats::{self, Counter, Metric, Stats};
+use packetcrypt_util::{challenge, history, tasks, telemetry, util};
 use std::cmp::max;
-use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
 use std::sync::Arc;
 use std::sync::Mutex;
 use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver};
 
 const RECENT_WORK_BUF: usize = 8;
 const MAX_ANN_BATCH_SIZE: usize = 1024;
+// Uploads are never made smaller than this, however small the handler's limit is
+const MIN_ANN_BATCH_SIZE: usize = 16;
 const MAX_MS_BETWEEN_POSTS: u64 = 10_000;
+// How long after a block change the batch for the old block is kept open
+const OLD_BATCH_LINGER_MS: u64 = 2_000;
+// The name of each protocol::ANN_ reject code and what to look at if it is common
+const REJECT_REASONS: [(&str, &str); 6] = [
+    ("ok", ""),
+    ("dup", "the same anns are being uploaded more than once"),
+    ("bad pow", "invalid anns, try `packetcrypt self-test`"),
+    ("stale", "anns for an old block, is the pool slow?"),
+    ("unfit", "anns have less work than the pool requires"),
+    ("batch", "uploads rejected, see the handler errors"),
+];
 
 struct AnnBatch {
     parent_block_height: i32,
@@ -26,9 +40,19 @@ struct AnnBatch {
 
 struct Handler {
     tip: Mutex<AnnBatch>,
+    // Batch for the block before the tip, which is held open for a moment after the
+    // block changes so that anns still coming out of the miner are not thrown away
+    old_tip: Mutex<AnnBatch>,
     url: Arc<String>,
     recv_upload: tokio::sync::Mutex<Receiver<AnnBatch>>,
     send_upload: Sender<AnnBatch>,
+    // Don't upload before this time, because the handler asked us to back off
+    backoff_until_ms: AtomicU64,
+    // Most anns in one upload, less than MAX_ANN_BATCH_SIZE if the handler or a proxy in
+    // front of it can't take that many
+    max_batch: AtomicUsize,
+    // Answer to the handler's challenge, if it asked for one
+    challenge: Mutex<Option<String>>,
 }
 
 const STATS_SECONDS_TO_KEEP: usize = 10;
@@ -44,16 +68,22 @@ struct PoolMut {
     currently_mining: i32,
     recent_work: [Option<BlockInfo>; RECENT_WORK_BUF],
     handlers: Vec<Arc<Handler>>,
+    // Weights of the handlers from the pool, empty if they all get the same
+    handler_weights: Vec<u32>,
 }
 struct Pool {
     primary: bool,
     pcli: PoolClient,
     m: Mutex<PoolMut>,
     inflight_anns: AtomicUsize,
-    lost_anns: AtomicUsize,
-    accepted_anns: AtomicUsize,
-    rejected_anns: AtomicUsize,
-    overload_anns: AtomicUsize,
+    lost_anns: Counter,
+    accepted_anns: Counter,
+    rejected_anns: Counter,
+    overload_anns: Counter,
+    // Anns rejected by the handlers, by protocol::ANN_ code
+    reject_reasons: [AtomicUsize; REJECT_REASONS.len()],
+    // Only reported if --telemetry is set
+    telemetry: telemetry::Counters,
 }
 
 struct AnnMineM {
@@ -68,6 +98,8 @@ pub struct AnnMineS {
     pools: Vec<Arc<Pool>>,
     cfg: AnnMineCfg,
     upload_num: AtomicUsize,
+    // When uploads began to fail with none being accepted since, 0 if they are not
+    failing_since_ms: AtomicU64,
 }
 pub type AnnMine = Arc<AnnMineS>;
 
@@ -79,12 +111,34 @@ pub struct AnnMineCfg {
     pub pay_to: String,
     pub upload_timeout: usize,
     pub mine_old_anns: i32,
+    pub pool_token: Option<String>,
+    // Percent of the time to mine, 100 to mine all the time
+    pub cpu_duty: u32,
+    // Percent of the time to mine while every upload is failing, 0 to pause
+    pub failing_duty: u32,
+    // Percent of the mining time to spend on anns for the block before
+    pub prev_percent: u32,
+    // Keep the miner's identity and hourly history here
+    pub state_dir: Option<String>,
 }
 
 const UPLOAD_CHANNEL_LEN: usize = 100;
 
+// Longest we will wait when a handler asks us to back off, so a broken handler can't
+// stop uploads for good
+const MAX_BACKOFF_MS: u64 = 10_000;
+
 const PREFETCH_HISTORY_DEPTH: i32 = 6;
 
+// How long uploads must fail, with none accepted by any handler, before mining slows
+const FAILING_AFTER_MS: u64 = 60_000;
+
+// When paused because uploads are failing, mine this long every PROBE_EVERY_MS so that
+// a batch is uploaded which tells us when a handler accepts again. It is long enough
+// for the batch to be sent even if it is not full.
+const PROBE_MS: u64 = MAX_MS_BETWEEN_POSTS + 5_000;
+const PROBE_EVERY_MS: u64 = 120_000;
+
 pub async fn new(cfg: AnnMineCfg) -> Result<AnnMine> {
     let pools = cfg
         .pools
@@ -97,13 +151,16 @@ pub async fn new(cfg: AnnMineCfg) -> Result<AnnMine> {
                     currently_mining: -1,
                     recent_work: [None; RECENT_WORK_BUF],
                     handlers: Vec::new(),
+                    handler_weights: Vec::new(),
                 }),
-                pcli: poolclient::new(x, PREFETCH_HISTORY_DEPTH, 5),
+                pcli: poolclient::new(x, PREFETCH_HISTORY_DEPTH, 5, cfg.pool_token.clone()),
                 inflight_anns: AtomicUsize::new(0),
-                lost_anns: AtomicUsize::new(0),
-                accepted_anns: AtomicUsize::new(0),
-                rejected_anns: AtomicUsize::new(0),
-                overload_anns: AtomicUsize::new(0),
+                lost_anns: Counter::default(),
+                accepted_anns: Counter::default(),
+                rejected_anns: Counter::default(),
+                overload_anns: Counter::default(),
+                reject_reasons: Default::default(),
+                telemetry: telemetry::Counters::default(),
             })
         })
         .collect::<Vec<_>>();
@@ -119,6 +176,7 @@ pub async fn new(cfg: AnnMineCfg) -> Result<AnnMine> {
         pools,
         cfg,
         upload_num: AtomicUsize::new(0),
+        failing_since_ms: AtomicU64::new(0),
     }))
 }
 
@@ -181,15 +239,29 @@ fn update_work_cycle(am: &AnnMine, p: &Arc<Pool>, update: PoolUpdate) -> Vec<Arc
                 parent_block_height: job.header.height,
                 anns: Vec::new(),
             }),
+            old_tip: Mutex::new(AnnBatch {
+                create_time: util::now_ms(),
+                parent_block_height: -1,
+                anns: Vec::new(),
+            }),
             url: Arc::new(url.clone()),
             send_upload,
+            backoff_until_ms: AtomicU64::new(0),
+            max_batch: AtomicUsize::new(MAX_ANN_BATCH_SIZE),
+            challenge: Mutex::new(None),
         });
         for _ in 0..am.cfg.uploaders {
             let p1 = Arc::clone(p);
             let h1 = Arc::clone(&h);
-            packetcrypt_util::async_spawn!(am, {
-                uploader_loop(&am, p1, h1).await;
-            });
+            let am = Arc::clone(am);
+            tasks::spawn(
+                format!("ann uploader {}", h.url),
+                tasks::Restart::OnPanic,
+                move || {
+                    let (am, p1, h1) = (Arc::clone(&am), Arc::clone(&p1), Arc::clone(&h1));
+                    async move { uploader_loop(&am, p1, h1).await }
+                },
+            );
         }
         pm.handlers.push(h);
     }
@@ -212,6 +284,11 @@ fn update_work_cycle(am: &AnnMine, p: &Arc<Pool>, update: PoolUpdate) -> Vec<Arc
         assert!(pm.handlers.is_empty());
         pm.handlers = new_handlers;
     }
+    let weights = update.conf.ann_handler_weights().map(|w| w.to_vec()).unwrap_or_default();
+    if weights != pm.handler_weights {
+        info!("Handler weights {:?}", weights);
+        pm.handler_weights = weights;
+    }
 
     if !p.primary {
         // got an update from a secondary pool
@@ -231,20 +308,30 @@ fn update_work_cycle(am: &AnnMine, p: &Arc<Pool>, update: PoolUpdate) -> Vec<Arc
         job.header.height,
         mine_old
     );
-    // Reverse the parent block hash because hashes in bitcoin are always expressed backward
-    let mut rev_hash = job.header.hash;
-    rev_hash.reverse();
-    if let Err(e) = annminer::start(
-        &am.miner,
-        rev_hash,
-        job.header.height,
-        ann_target,
-        job.sig_key,
-    ) {
+    let prev = if am.cfg.prev_percent > 0 {
+        pm.recent_work[(job.header.height - 1) as usize % RECENT_WORK_BUF]
+            .filter(|bi| bi.header.height == job.header.height - 1)
+            .map(|bi| miner_job(&bi, ann_target))
+    } else {
+        None
+    };
+    if let Err(e) = annminer::start(&am.miner, miner_job(&job, ann_target), prev) {
         warn!("Error starting annminer {}", e);
     }
     out
 }
+
+fn miner_job(bi: &BlockInfo, target: u32) -> annminer::Job {
+    // Reverse the parent block hash because hashes in bitcoin are always expressed backward
+    let mut rev_hash = bi.header.hash;
+    rev_hash.reverse();
+    annminer::Job {
+        parent_block_hash: rev_hash,
+        parent_block_height: bi.header.height,
+        target,
+        signing_key: bi.sig_key,
+    }
+}
 async fn update_work_loop(am: &AnnMine, p: Arc<Pool>) {
     let mut chan = poolclient::update_chan(&p.pcli).await;
     loop {
@@ -279,7 +366,7 @@ fn submit_anns(
         Ok(_) => (),
         Err(tokio::sync::mpsc::error::TrySendError::Full(tip)) => {
             debug!("Failed to submit {} anns to {}", tip.anns.len(), h.url);
-            p.lost_anns.fetch_add(tip.anns.len(), Ordering::Relaxed);
+            p.lost_anns.add(tip.anns.len());
         }
         Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => {
             warn!("Failed to submit anns to {}, channel closed", h.url);
@@ -296,15 +383,38 @@ fn submit_to_pool(p: &Pool, ann_struct: &AnnResult, now: u64) {
             // no handlers for this pool yet
             return;
         }
-        Arc::clone(&pm.handlers[(ann_struct.dedup_hash as u64 % hcount) as usize])
+        let i = if pm.handler_weights.is_empty() {
+            (ann_struct.dedup_hash as u64 % hcount) as usize
+        } else {
+            protocol::weighted_ann_handler(ann_struct.dedup_hash as u64, &pm.handler_weights)
+        };
+        Arc::clone(&pm.handlers[i])
     };
     let mut tip = handler.tip.lock().unwrap();
     match tip.parent_block_height.cmp(&parent_block_height) {
         std::cmp::Ordering::Greater => {
-            debug!(
-                "Miner produced an old announcement, want parent_block_height {} got {}",
-                tip.parent_block_height, parent_block_height
-            );
+            let mut old = handler.old_tip.lock().unwrap();
+            if old.anns.is_empty() && parent_block_height == tip.parent_block_height - 1 {
+                // Mining the block before, with --prev-percent
+                old.parent_block_height = parent_block_height;
+            }
+            if old.parent_block_height != parent_block_height {
+                debug!(
+                    "Miner produced an old announcement, want parent_block_height {} got {}",
+                    tip.parent_block_height, parent_block_height
+                );
+                return;
+            }
+            old.anns.push(ann_struct.ann.clone());
+            if old.anns.len() >= handler.max_batch.load(Ordering::Relaxed) {
+                submit_anns(
+                    p,
+                    &handler,
+                    &mut *old,
+                    &mut handler.send_upload.clone(),
+                    parent_block_height,
+                );
+            }
             return;
         }
         std::cmp::Ordering::Less => {
@@ -314,19 +424,44 @@ fn submit_to_pool(p: &Pool, ann_struct: &AnnResult, now: u64) {
                 tip.parent_block_height,
                 parent_block_height
             );
+            // The batch we were building becomes the old one, it gets sent once the
+            // anns which the miner was working on have come in.
+            let mut old = handler.old_tip.lock().unwrap();
+            if !old.anns.is_empty() {
+                let old_height = old.parent_block_height;
+                submit_anns(
+                    p,
+                    &handler,
+                    &mut *old,
+                    &mut handler.send_upload.clone(),
+                    old_height,
+                );
+            }
+            std::mem::swap(&mut *old, &mut *tip);
+            old.create_time = now;
+            tip.parent_block_height = parent_block_height;
+            tip.create_time = now;
+        }
+        std::cmp::Ordering::Equal => (),
+    }
+    {
+        let mut old = handler.old_tip.lock().unwrap();
+        if !old.anns.is_empty() && old.create_time + OLD_BATCH_LINGER_MS < now {
+            let old_height = old.parent_block_height;
             submit_anns(
                 p,
                 &handler,
-                &mut *tip,
+                &mut *old,
                 &mut handler.send_upload.clone(),
-                parent_block_height,
+                old_height,
             );
         }
-        std::cmp::Ordering::Equal => (),
     }
 
     tip.anns.push(ann_struct.ann.clone());
-    if tip.anns.len() >= MAX_ANN_BATCH_SIZE || tip.create_time + MAX_MS_BETWEEN_POSTS < now {
+    if tip.anns.len() >= handler.max_batch.load(Ordering::Relaxed)
+        || tip.create_time + MAX_MS_BETWEEN_POSTS < now
+    {
         submit_anns(
             p,
             &handler,
@@ -382,14 +517,67 @@ async fn handle_ann_loop(am: &AnnMine) {
     }
 }
 
+async fn post_anns(
+    am: &AnnMine,
+    client: &reqwest::Client,
+    h: &Handler,
+    anns: &[bytes::Bytes],
+    worknum: i32,
+) -> Result<reqwest::Response> {
+    let v: Vec<Result<bytes::Bytes>> = anns.iter().map(|a| Ok(a.clone())).collect();
+    let body = reqwest::Body::wrap_stream(tokio::stream::iter(v));
+    let req = util::request(client, reqwest::Method::POST, &h.url).await?;
+    let mut req = util::with_token(req, &am.cfg.pool_token)
+        .header("x-pc-payto", &am.cfg.pay_to)
+        .header("x-pc-sver", 1)
+        .header("x-pc-annver", 1)
+        .header("x-pc-worknum", worknum);
+    let answer = h.challenge.lock().unwrap().clone();
+    if let Some(a) = answer {
+        req = req.header("x-pc-challenge", a);
+    }
+    Ok(req.body(body).send().await?)
+}
+
+// The handler wants a proof of work before it takes uploads from this IP, see
+// challenge.rs. It can be up to 2^24 hashes so it's done on a blocking thread, not on
+// the one which the uploaders and pool client run on.
+async fn solve_challenge(
+    h: &Handler,
+    upload_n: usize,
+    headers: &reqwest::header::HeaderMap,
+) -> Result<()> {
+    let (token, bits) = {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let bits = header("x-pc-challenge-bits")
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(0);
+        (header("x-pc-challenge").unwrap_or("").to_owned(), bits)
+    };
+    let t0 = util::now_ms();
+    let answer = tokio::task::spawn_blocking(move || challenge::solve(&token, bits))
+        .await?
+        .map_err(|e| anyhow::anyhow!("[{}] handler [{}]: {}", upload_n, h.url, e))?;
+    debug!(
+        "[{}] solved [{}] bit challenge of handler [{}] in [{}]ms",
+        upload_n,
+        bits,
+        h.url,
+        util::now_ms().saturating_sub(t0)
+    );
+    *h.challenge.lock().unwrap() = Some(answer);
+    Ok(())
+}
+
 async fn upload_batch(
     am: &AnnMine,
     client: &reqwest::Client,
     mut batch: AnnBatch,
-    url: &str,
+    h: &Handler,
     upload_n: usize,
     p: &Arc<Pool>,
-) -> Result<()> {
+) -> Result<usize> {
+    let url = &h.url[..];
     debug!(
         "[{}] uploading [{}] anns to [{}]",
         upload_n,
@@ -397,26 +585,58 @@ async fn upload_batch(
         url
     );
     let count = batch.anns.len();
-    let v: Vec<Result<bytes::Bytes>> = batch
-        .anns
-        .drain(..)
-        .map(|a| Ok(a.bytes) as Result<bytes::Bytes>)
-        .collect();
-    let stream = tokio::stream::iter(v);
-    let body = reqwest::Body::wrap_stream(stream);
+    let anns = batch.anns.drain(..).map(|a| a.bytes).collect::<Vec<_>>();
     // The server wants to see "work num" which is the height of the next block
     // and the parent_block_height is the height of the most recent mined block.
     let worknum = batch.parent_block_height + 1;
-    let res = client
-        .post(url)
-        .header("x-pc-payto", &am.cfg.pay_to)
-        .header("x-pc-sver", 1)
-        .header("x-pc-annver", 1)
-        .header("x-pc-worknum", worknum)
-        .body(body)
-        .send()
-        .await?;
+    let mut res = post_anns(am, client, h, &anns, worknum).await?;
+    if res.status() == reqwest::StatusCode::PRECONDITION_REQUIRED {
+        solve_challenge(h, upload_n, res.headers()).await?;
+        res = post_anns(am, client, h, &anns, worknum).await?;
+    }
     let status = res.status();
+    if status == reqwest::StatusCode::PAYLOAD_TOO_LARGE {
+        let max = (count / 2).max(MIN_ANN_BATCH_SIZE);
+        h.max_batch.store(max, Ordering::Relaxed);
+        bail!(
+            "[{}] handler [{}] can't take [{}] anns in one upload, uploading at most [{}]",
+            upload_n,
+            url,
+            count,
+            max
+        );
+    }
+    let max_anns = res
+        .headers()
+        .get("x-pc-max-anns")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    if max_anns > 0 {
+        let max = max_anns.max(MIN_ANN_BATCH_SIZE).min(MAX_ANN_BATCH_SIZE);
+        if h.max_batch.swap(max, Ordering::Relaxed) != max {
+            info!(
+                "Handler [{}] takes at most [{}] anns in one upload",
+                url, max
+            );
+        }
+    }
+    let backoff = res
+        .headers()
+        .get("x-pc-backoff-ms")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    if backoff > 0 {
+        debug!(
+            "[{}] handler [{}] asked us to wait [{}]ms",
+            upload_n, url, backoff
+        );
+        h.backoff_until_ms.store(
+            util::now_ms() + backoff.min(MAX_BACKOFF_MS),
+            Ordering::Relaxed,
+        );
+    }
     let resbytes = res.bytes().await?;
     let reply = if let Ok(x) = serde_json::from_slice::<AnnPostReply>(&resbytes) {
         x
@@ -429,13 +649,18 @@ async fn upload_batch(
             String::from_utf8_lossy(&resbytes[..])
         );
     };
+    for code in &reply.ann_results {
+        if let Some(c) = p.reject_reasons.get(*code as usize) {
+            c.fetch_add((*code != protocol::ANN_OK) as usize, Ordering::Relaxed);
+        }
+    }
     let result = if let Some(x) = reply.result {
         x
     } else {
         if reply.error.iter().any(|x| x == "overloaded") {
             //am.overload_anns
-            p.overload_anns.fetch_add(count, Ordering::Relaxed);
-            return Ok(());
+            p.overload_anns.add(count);
+            return Ok(0);
         }
         bail!(
             "[{}] handler [{}] replied with no result [{}]",
@@ -466,14 +691,52 @@ async fn upload_batch(
             upload_n, url, reply.warn
         );
     }
-    //Ok(result.accepted as usize)
-    p.accepted_anns
+    p.accepted_anns.add(result.accepted as usize);
+    p.telemetry
+        .accepted
         .fetch_add(result.accepted as usize, Ordering::Relaxed);
     let rejected = count - (result.accepted as usize);
     if rejected > 0 {
-        p.rejected_anns.fetch_add(rejected, Ordering::Relaxed);
+        p.rejected_anns.add(rejected);
+        p.telemetry.rejected.fetch_add(rejected, Ordering::Relaxed);
     }
-    Ok(())
+    Ok(result.accepted as usize)
+}
+
+// Rejects since the last call by reason, with a hint about the most common one
+fn describe_rejects(reasons: &[AtomicUsize]) -> Option<String> {
+    let counts = reasons
+        .iter()
+        .map(|c| c.swap(0, Ordering::Relaxed))
+        .collect::<Vec<_>>();
+    let (top, _) = counts
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| **c > 0)
+        .max_by_key(|(_, c)| **c)?;
+    let parts = counts
+        .iter()
+        .zip(REJECT_REASONS.iter())
+        .filter(|(c, _)| **c > 0)
+        .map(|(c, (name, _))| format!("{} {}", c, name))
+        .collect::<Vec<_>>();
+    Some(format!("{} - {}", parts.join(", "), REJECT_REASONS[top].1))
+}
+
+// Encryptions per second of each mining thread, so a slow core shows up rather than
+// only a drop in the total. Threads under half of the median are marked with a *.
+fn describe_threads(anns: &[usize], diff: f64, secs: f64) -> String {
+    let mut sorted = anns.to_vec();
+    sorted.sort_unstable();
+    let median = sorted.get(sorted.len() / 2).copied().unwrap_or(0);
+    anns.iter()
+        .map(|a| {
+            let eps = diff * *a as f64 / secs;
+            let slow = if *a * 2 < median { "*" } else { "" };
+            format!("{}e/s{}", util::big_number(eps), slow)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
 async fn stats_loop(am: &AnnMine) {
@@ -501,13 +764,14 @@ async fn stats_loop(am: &AnnMine) {
             let mut accepted_rejected_over_anns = Vec::new();
             let mut rate = Vec::new();
             for p in &am.pools {
-                let lost = p.lost_anns.swap(0, Ordering::Relaxed);
+                p.telemetry.set_hashrate(estimated_eps);
+                let lost = p.lost_anns.take();
                 lost_anns.push(format!("{}", lost));
                 let inflight = p.inflight_anns.load(Ordering::Relaxed);
                 inflight_anns.push(format!("{}", inflight));
-                let accepted = p.accepted_anns.swap(0, Ordering::Relaxed);
-                let rejected = p.rejected_anns.swap(0, Ordering::Relaxed);
-                let over = p.overload_anns.swap(0, Ordering::Relaxed);
+                let accepted = p.accepted_anns.take();
+                let rejected = p.rejected_anns.take();
+                let over = p.overload_anns.take();
                 accepted_rejected_over_anns.push(format!("{}/{}/{}", accepted, rejected, over));
                 let total = lost + over + rejected + accepted;
                 rate.push(format!(
@@ -537,17 +801,35 @@ async fn stats_loop(am: &AnnMine) {
                     format!("[{}]", rate.join(", "))
                 );
             }
+            for p in &am.pools {
+                if let Some(r) = describe_rejects(&p.reject_reasons) {
+                    info!("rejected by {}: {}", p.pcli.url, r);
+                }
+            }
+            let thread_anns = annminer::thread_anns(&am.miner);
+            if kbps > 0.0 && time_of_last_msg > 0 {
+                let secs = (now - time_of_last_msg) as f64 / 1000.0;
+                info!("threads: {}", describe_threads(&thread_anns, diff, secs));
+            }
             time_of_last_msg = now;
         }
     }
 }
 
 async fn uploader_loop(am: &AnnMine, p: Arc<Pool>, h: Arc<Handler>) {
-    let client = reqwest::ClientBuilder::new()
+    let client = util::client_builder()
         .timeout(Duration::from_secs(am.cfg.upload_timeout as u64))
         .build()
         .unwrap();
     loop {
+        let wait = h
+            .backoff_until_ms
+            .load(Ordering::Relaxed)
+            .saturating_sub(util::now_ms());
+        if wait > 0 {
+            util::sleep_ms(wait).await;
+            continue;
+        }
         let mut batch : Option<AnnBatch> = None;
         match h.recv_upload.lock().await.try_recv() {
             Ok(x) => {
@@ -565,16 +847,28 @@ async fn uploader_loop(am: &AnnMine, p: Arc<Pool>, h: Arc<Handler>) {
                     .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                 let count = batch.anns.len();
                 p.inflight_anns.fetch_add(count, Ordering::Relaxed);
-                match upload_batch(am, &client, batch, &h.url, upload_n, &p).await {
-                    Ok(_) => (),
+                let accepted = match upload_batch(am, &client, batch, &h, upload_n, &p).await {
+                    Ok(accepted) => accepted,
                     Err(e) => {
                         warn!(
                             "[{}] Error uploading ann batch to {}: {}",
                             upload_n, h.url, e
                         );
-                        p.lost_anns.fetch_add(count, Ordering::Relaxed);
+                        p.lost_anns.add(count);
+                        p.telemetry.errors.fetch_add(1, Ordering::Relaxed);
+                        0
                     }
                 };
+                if accepted > 0 {
+                    am.failing_since_ms.store(0, Ordering::Relaxed);
+                } else {
+                    let _ = am.failing_since_ms.compare_exchange(
+                        0,
+                        util::now_ms(),
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
+                }
                 p.inflight_anns.fetch_sub(count, Ordering::Relaxed);
             }
             None => {
@@ -585,19 +879,163 @@ async fn uploader_loop(am: &AnnMine, p: Arc<Pool>, h: Arc<Handler>) {
     debug!("Uploader for {} shutting down", h.url);
 }
 
+// The duty to mine at when uploads have been failing for failing_ms
+fn throttled_duty(cfg: &AnnMineCfg, failing_ms: u64) -> u32 {
+    if failing_ms < FAILING_AFTER_MS {
+        cfg.cpu_duty
+    } else if cfg.failing_duty > 0 {
+        cfg.failing_duty.min(cfg.cpu_duty)
+    } else if failing_ms % PROBE_EVERY_MS < PROBE_MS {
+        cfg.cpu_duty
+    } else {
+        0
+    }
+}
+
+// Slow or pause mining while no handler accepts anns, rather than mining anns which
+// will only be dropped, and go back to cpu_duty as soon as one accepts
+async fn throttle_loop(am: &AnnMine) {
+    let mut failing = false;
+    loop {
+        util::sleep_ms(1_000).await;
+        let since = am.failing_since_ms.load(Ordering::Relaxed);
+        let failing_ms = if since > 0 {
+            util::now_ms().saturating_sub(since)
+        } else {
+            0
+        };
+        let now_failing = failing_ms >= FAILING_AFTER_MS;
+        if now_failing && !failing && am.cfg.failing_duty == 0 {
+            warn!(
+                "No anns accepted for {} seconds, pausing mining until a handler accepts",
+                failing_ms / 1000
+            );
+        } else if now_failing && !failing {
+            warn!(
+                "No anns accepted for {} seconds, mining slower until a handler accepts",
+                failing_ms / 1000
+            );
+        } else if failing && !now_failing {
+            info!("Anns are being accepted again, mining normally");
+        }
+        failing = now_failing;
+        annminer::set_duty(&am.miner, throttled_duty(&am.cfg, failing_ms));
+    }
+}
+
+impl Stats for AnnMineS {
+    fn component(&self) -> &'static str {
+        "annmine"
+    }
+    fn metrics(&self) -> Vec<Metric> {
+        let mut out = Vec::new();
+        if let Some(p) = self.pools.first() {
+            let eps = p.telemetry.hashrate();
+            out.push(Metric::gauge("hashrate", "Encryptions per second", eps));
+        }
+        for p in &self.pools {
+            let url = &p.pcli.url;
+            out.extend(
+                vec![
+                    Metric::counter(
+                        "anns_accepted_total",
+                        "Anns accepted by the handlers",
+                        p.accepted_anns.get(),
+                    ),
+                    Metric::counter(
+                        "anns_rejected_total",
+                        "Anns rejected by the handlers",
+                        p.rejected_anns.get(),
+                    ),
+                    Metric::counter(
+                        "anns_overload_total",
+                        "Anns turned away by busy handlers",
+                        p.overload_anns.get(),
+                    ),
+                    Metric::counter(
+                        "anns_lost_total",
+                        "Anns which could not be uploaded",
+                        p.lost_anns.get(),
+                    ),
+                    Metric::counter(
+                        "upload_errors_total",
+                        "Uploads which failed",
+                        p.telemetry.errors.load(Ordering::Relaxed) as u64,
+                    ),
+                    Metric::gauge(
+                        "anns_inflight",
+                        "Anns being uploaded",
+                        p.inflight_anns.load(Ordering::Relaxed) as f64,
+                    ),
+                ]
+                .into_iter()
+                .map(|m| m.label("pool", url)),
+            );
+        }
+        out
+    }
+}
+
 pub async fn start(am: &AnnMine) -> Result<()> {
-    packetcrypt_util::async_spawn!(am, {
+    stats::register(am);
+    annminer::set_duty(&am.miner, am.cfg.cpu_duty);
+    if am.cfg.prev_percent > 0 {
+        info!(
+            "Mining anns for the block before {}% of the time",
+            am.cfg.prev_percent
+        );
+        annminer::set_prev_percent(&am.miner, am.cfg.prev_percent);
+    }
+    let miner = Arc::clone(&am.miner);
+    std::thread::spawn(move || annminer::duty_cycle_loop(&miner));
+    if am.cfg.failing_duty < 100 {
+        packetcrypt_util::async_supervise!("annmine throttle", tasks::Restart::Always, am, {
+            throttle_loop(&am).await;
+        });
+    }
+    // These take their channels from AnnMineM so they cannot be restarted
+    packetcrypt_util::async_supervise!("handle anns", tasks::Restart::Never, am, {
         handle_ann_loop(&am).await;
     });
-    packetcrypt_util::async_spawn!(am, {
+    packetcrypt_util::async_supervise!("annmine stats", tasks::Restart::Never, am, {
         stats_loop(&am).await;
     });
     for p in &am.pools {
         poolclient::start(&p.pcli).await;
-        let p1 = Arc::clone(p);
-        packetcrypt_util::async_spawn!(am, {
-            update_work_loop(&am, p1).await;
-        });
+        let (am, p1) = (Arc::clone(am), Arc::clone(p));
+        tasks::spawn(
+            format!("update work {}", p.pcli.url),
+            tasks::Restart::Always,
+            move || {
+                let (am, p1) = (Arc::clone(&am), Arc::clone(&p1));
+                async move { update_work_loop(&am, p1).await }
+            },
+        );
+        if telemetry::enabled() {
+            let (am, p1) = (Arc::clone(am), Arc::clone(p));
+            tasks::spawn(
+                format!("telemetry {}", p.pcli.url),
+                tasks::Restart::Always,
+                move || {
+                    let (am, p1) = (Arc::clone(&am), Arc::clone(&p1));
+                    async move {
+                        telemetry::report_loop(&p1.pcli, "ann", &am.cfg.pay_to, &p1.telemetry)
+                            .await
+                    }
+                },
+            );
+        }
+        if let Some(dir) = &am.cfg.state_dir {
+            let (dir, p1) = (dir.clone(), Arc::clone(p));
+            tasks::spawn(
+                format!("history {}", p.pcli.url),
+                tasks::Restart::Always,
+                move || {
+                    let (dir, p1) = (dir.clone(), Arc::clone(&p1));
+                    async move { history::record_loop(&dir, "ann", &p1.pcli.url, &p1.telemetry).await }
+                },
+            );
+        }
     }
     Ok(())
 }
@@ -1,14 +1,17 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
 use anyhow::Result;
 use bytes::Buf;
-use log::warn;
+use log::{info, warn};
+use packetcrypt_sys::kernel::{self, AnnMinerKernel};
 use packetcrypt_sys::PacketCryptAnn;
 use packetcrypt_util::{hash, util};
+use std::cell::Cell;
 use std::os::raw::c_int;
 use std::os::raw::c_void;
-use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 use tokio::sync::mpsc::UnboundedReceiver;
 
 pub struct AnnResult {
@@ -18,15 +21,59 @@ pub struct AnnResult {
 
 pub struct CallbackCtx {
     send_ann: tokio::sync::mpsc::UnboundedSender<AnnResult>,
+    // Anns found by each mining thread, threads are numbered in the order in which
+    // they find their first ann because the kernel doesn't say which one it is
+    per_thread: Vec<AtomicUsize>,
+    next_thread: AtomicUsize,
+}
+
+thread_local! {
+    static THREAD_NUM: Cell<Option<usize>> = Cell::new(None);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Job {
+    pub parent_block_hash: [u8; 32],
+    pub parent_block_height: i32,
+    pub target: u32,
+    pub signing_key: Option<[u8; 32]>,
+}
+
+#[derive(Default)]
+struct Duty {
+    // Last job started, so that mining can resume after a pause
+    job: Option<Job>,
+    // The block before job's, which is mined for part of the time if there is a
+    // set_prev_percent()
+    prev: Option<Job>,
+    paused: bool,
+    on_prev: bool,
+}
+impl Duty {
+    fn running(&self) -> Option<Job> {
+        if self.paused {
+            None
+        } else if self.on_prev {
+            self.prev.or(self.job)
+        } else {
+            self.job
+        }
+    }
 }
 
 pub struct AnnMinerS {
-    _cbc: Box<CallbackCtx>,
+    cbc: Box<CallbackCtx>,
     miner: Mutex<AtomicPtr<packetcrypt_sys::AnnMiner_t>>,
+    kernel: AnnMinerKernel,
+    duty: Mutex<Duty>,
+    // Percent of the time to mine, read by duty_cycle_loop() at each cycle
+    duty_pct: AtomicU32,
+    // Percent of the mining time to spend on the previous block
+    prev_pct: AtomicU32,
 }
 impl Drop for AnnMinerS {
     fn drop(&mut self) {
-        unsafe { packetcrypt_sys::AnnMiner_free(*self.miner.lock().unwrap().get_mut()) }
+        unsafe { (self.kernel.free)(*self.miner.lock().unwrap().get_mut()) }
     }
 }
 pub type AnnMiner = Arc<AnnMinerS>;
@@ -36,8 +83,18 @@ pub unsafe extern "C" fn on_ann_found(vctx: *mut c_void, ann: *mut u8) {
         bytes: util::aligned_bytes(std::slice::from_raw_parts(ann, 1024), 4),
     };
     let dedup_hash = (&hash::compress32(&ann.bytes[..])[..]).get_u64_le();
-    let ctx = vctx as *const CallbackCtx;
-    if let Err(e) = (*ctx).send_ann.send(AnnResult { ann, dedup_hash }) {
+    let ctx = &*(vctx as *const CallbackCtx);
+    let num = THREAD_NUM.with(|n| {
+        n.get().unwrap_or_else(|| {
+            let num = ctx.next_thread.fetch_add(1, Ordering::Relaxed);
+            n.set(Some(num));
+            num
+        })
+    });
+    if let Some(c) = ctx.per_thread.get(num) {
+        c.fetch_add(1, Ordering::Relaxed);
+    }
+    if let Err(e) = ctx.send_ann.send(AnnResult { ann, dedup_hash }) {
         warn!("Unable to send announcement to channel because [{}]", e);
     }
 }
@@ -45,29 +102,59 @@ pub unsafe extern "C" fn on_ann_found(vctx: *mut c_void, ann: *mut u8) {
 pub fn new(miner_id: u32, workers: usize) -> (AnnMiner, UnboundedReceiver<AnnResult>) {
     packetcrypt_sys::init();
     let (send_ann, recv_ann) = tokio::sync::mpsc::unbounded_channel();
-    let mut cbc = Box::new(CallbackCtx { send_ann });
+    let mut cbc = Box::new(CallbackCtx {
+        send_ann,
+        per_thread: (0..workers).map(|_| AtomicUsize::new(0)).collect(),
+        next_thread: AtomicUsize::new(0),
+    });
     let ptr = (&mut *cbc as *mut CallbackCtx) as *mut c_void;
-    let miner = unsafe {
-        packetcrypt_sys::AnnMiner_create(miner_id, workers as c_int, ptr, Some(on_ann_found))
-    };
+    let kernel = kernel::ann_miner_kernel();
+    info!("Using {:?} announcement mining kernel", kernel.kernel);
+    let miner = unsafe { (kernel.create)(miner_id, workers as c_int, ptr, Some(on_ann_found)) };
     (
         Arc::new(AnnMinerS {
-            _cbc: cbc,
+            cbc,
             miner: Mutex::new(AtomicPtr::new(miner)),
+            kernel,
+            duty: Mutex::new(Duty::default()),
+            duty_pct: AtomicU32::new(100),
+            prev_pct: AtomicU32::new(0),
         }),
         recv_ann,
     )
 }
 
+/// Anns found by each mining thread since the last call
+pub fn thread_anns(miner: &AnnMiner) -> Vec<usize> {
+    miner
+        .cbc
+        .per_thread
+        .iter()
+        .map(|c| c.swap(0, Ordering::Relaxed))
+        .collect()
+}
+
 const ANN_VERSION: c_int = 1;
 
-pub fn start(
-    miner: &AnnMiner,
-    parent_block_hash: [u8; 32],
-    parent_block_height: i32,
-    target: u32,
-    signing_key: Option<[u8; 32]>,
-) -> Result<()> {
+/// Mine anns for job, and for prev (the block before) for set_prev_percent() of the
+/// time if it is given
+pub fn start(miner: &AnnMiner, job: Job, prev: Option<Job>) -> Result<()> {
+    let mut duty = miner.duty.lock().unwrap();
+    duty.job = Some(job);
+    duty.prev = prev;
+    if let Some(job) = duty.running() {
+        start_job(miner, &job);
+    }
+    Ok(())
+}
+
+fn start_job(miner: &AnnMiner, job: &Job) {
+    let Job {
+        parent_block_hash,
+        parent_block_height,
+        target,
+        signing_key,
+    } = *job;
     let mut req = packetcrypt_sys::AnnMiner_Request_t {
         contentLen: 0,
         contentType: 0,
@@ -81,8 +168,61 @@ pub fn start(
         workTarget: target,
     };
     let ptr = &mut req as *mut packetcrypt_sys::AnnMiner_Request_t;
-    unsafe {
-        packetcrypt_sys::AnnMiner_start(*miner.miner.lock().unwrap().get_mut(), ptr, ANN_VERSION)
-    };
-    Ok(())
+    unsafe { (miner.kernel.start)(*miner.miner.lock().unwrap().get_mut(), ptr, ANN_VERSION) };
+}
+
+// Length of one on/off cycle when mining with a duty cycle
+const DUTY_PERIOD_MS: u64 = 2_000;
+
+/// Mine only duty percent of the time from the next cycle of duty_cycle_loop(), 0 to
+/// stop mining until it is set again
+pub fn set_duty(miner: &AnnMiner, duty: u32) {
+    let old = miner.duty_pct.swap(duty.min(100), Ordering::Relaxed);
+    if old != duty {
+        info!("Mining {}% of the time", duty);
+    }
+}
+
+/// Spend prev percent of the mining time on anns for the block before the one which
+/// is being mined, from the next cycle of duty_cycle_loop()
+pub fn set_prev_percent(miner: &AnnMiner, prev: u32) {
+    miner.prev_pct.store(prev.min(100), Ordering::Relaxed);
+}
+
+fn set_state(miner: &AnnMiner, paused: bool, on_prev: bool) {
+    let mut d = miner.duty.lock().unwrap();
+    let was = d.running();
+    d.paused = paused;
+    d.on_prev = on_prev;
+    let now = d.running();
+    if was == now {
+        return;
+    }
+    match now {
+        Some(job) => start_job(miner, &job),
+        None => unsafe { (miner.kernel.stop)(*miner.miner.lock().unwrap().get_mut()) },
+    }
+}
+
+/// Mine only the set_duty() percent of the time by stopping and restarting the miner,
+/// so that it stays in the background without needing fewer threads, and switch to
+/// the previous block for set_prev_percent() of that. This never returns.
+pub fn duty_cycle_loop(miner: &AnnMiner) {
+    loop {
+        let duty = miner.duty_pct.load(Ordering::Relaxed) as u64;
+        let on_ms = DUTY_PERIOD_MS * duty / 100;
+        let prev_ms = on_ms * miner.prev_pct.load(Ordering::Relaxed) as u64 / 100;
+        if on_ms > prev_ms {
+            set_state(miner, false, false);
+            std::thread::sleep(Duration::from_millis(on_ms - prev_ms));
+        }
+        if prev_ms > 0 {
+            set_state(miner, false, true);
+            std::thread::sleep(Duration::from_millis(prev_ms));
+        }
+        if on_ms < DUTY_PERIOD_MS {
+            set_state(miner, true, false);
+            std::thread::sleep(Duration::from_millis(DUTY_PERIOD_MS - on_ms));
+        }
+    }
 }
@@ -0,0 +1,322 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Choosing which anns to keep with a small expression, e.g.
+//! `--ann-filter "work<=0x2000ffff && age<3"`. Anns which don't match are dropped on
+//! intake, before they are classified or given any memory.
+//!
+//! A comparison is a variable, one of `< <= > >= == !=` and a number in decimal or 0x
+//! hex. Comparisons can be combined with `&&`, `||`, `!` and parentheses, `&&` binds
+//! tighter than `||`. The variables are:
+//!
+//! * work: the ann's work target bits, smaller is more work
+//! * age: how many blocks older the ann's parent block is than the work being mined
+//! * height: the ann's parent block height
+//! * version: the ann's version
+//! * content_type: the ann's content type
+//! * signed: 1 if the ann is signed, otherwise 0
+use anyhow::{bail, Result};
+use packetcrypt_util::util;
+
+#[derive(Clone, Copy, Debug)]
+enum Var {
+    Work,
+    Age,
+    Height,
+    Version,
+    ContentType,
+    Signed,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Cmp(Var, Cmp, i64),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+pub struct Filter {
+    text: String,
+    expr: Expr,
+}
+
+#[derive(Debug, PartialEq)]
+enum Tok<'a> {
+    Ident(&'a str),
+    Num(i64),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+const OPS: [&str; 9] = ["&&", "||", "<=", ">=", "==", "!=", "<", ">", "!"];
+
+fn lex(s: &str) -> Result<Vec<Tok<'_>>> {
+    let b = s.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        let start = i;
+        match b[i] {
+            c if c.is_ascii_whitespace() => i += 1,
+            b'(' => {
+                out.push(Tok::Open);
+                i += 1;
+            }
+            b')' => {
+                out.push(Tok::Close);
+                i += 1;
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                while i < b.len() && (b[i].is_ascii_alphanumeric() || b[i] == b'_') {
+                    i += 1;
+                }
+                out.push(Tok::Ident(&s[start..i]));
+            }
+            c if c.is_ascii_digit() => {
+                while i < b.len() && b[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+                let t = &s[start..i];
+                let n = if let Some(hex) = t.strip_prefix("0x") {
+                    i64::from_str_radix(hex, 16)
+                } else {
+                    t.parse()
+                };
+                match n {
+                    Ok(n) => out.push(Tok::Num(n)),
+                    Err(_) => bail!("Invalid number [{}]", t),
+                }
+            }
+            _ => {
+                let op = if let Some(op) = OPS.iter().find(|op| s[i..].starts_with(*op)) {
+                    op
+                } else {
+                    bail!("Unexpected [{}]", &s[i..]);
+                };
+                out.push(Tok::Op(*op));
+                i += op.len();
+            }
+        }
+    }
+    Ok(out)
+}
+
+struct Parser<'a> {
+    toks: Vec<Tok<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<&Tok<'a>> {
+        self.pos += 1;
+        self.toks.get(self.pos - 1)
+    }
+
+    fn eat_op(&mut self, op: &'static str) -> bool {
+        if self.toks.get(self.pos) == Some(&Tok::Op(op)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut e = self.and()?;
+        while self.eat_op("||") {
+            e = Expr::Or(Box::new(e), Box::new(self.and()?));
+        }
+        Ok(e)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut e = self.not()?;
+        while self.eat_op("&&") {
+            e = Expr::And(Box::new(e), Box::new(self.not()?));
+        }
+        Ok(e)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.eat_op("!") {
+            Ok(Expr::Not(Box::new(self.not()?)))
+        } else {
+            self.atom()
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr> {
+        let var = match self.next() {
+            Some(Tok::Open) => {
+                let e = self.or()?;
+                if self.next() != Some(&Tok::Close) {
+                    bail!("Missing )");
+                }
+                return Ok(e);
+            }
+            Some(Tok::Ident(name)) => match *name {
+                "work" => Var::Work,
+                "age" => Var::Age,
+                "height" => Var::Height,
+                "version" => Var::Version,
+                "content_type" => Var::ContentType,
+                "signed" => Var::Signed,
+                _ => bail!("Unknown variable [{}]", name),
+            },
+            t => bail!("Expected a variable or ( but found {:?}", t),
+        };
+        let cmp = match self.next() {
+            Some(Tok::Op("<")) => Cmp::Lt,
+            Some(Tok::Op("<=")) => Cmp::Le,
+            Some(Tok::Op(">")) => Cmp::Gt,
+            Some(Tok::Op(">=")) => Cmp::Ge,
+            Some(Tok::Op("==")) => Cmp::Eq,
+            Some(Tok::Op("!=")) => Cmp::Ne,
+            t => bail!("Expected a comparison after {:?} but found {:?}", var, t),
+        };
+        match self.next() {
+            Some(Tok::Num(n)) => Ok(Expr::Cmp(var, cmp, *n)),
+            t => bail!("Expected a number after {:?} but found {:?}", cmp, t),
+        }
+    }
+}
+
+/// Parse a filter, the error says what is wrong with it
+pub fn parse(text: &str) -> Result<Filter> {
+    let res = lex(text).and_then(|toks| {
+        let mut p = Parser { toks, pos: 0 };
+        let expr = p.or()?;
+        if let Some(t) = p.toks.get(p.pos) {
+            bail!("Unexpected {:?}", t);
+        }
+        Ok(expr)
+    });
+    match res {
+        Ok(expr) => Ok(Filter {
+            text: text.to_owned(),
+            expr,
+        }),
+        Err(e) => bail!("Invalid ann filter [{}]: {}", text, e),
+    }
+}
+
+fn value(var: Var, ann: &[u8], work_height: Option<i32>) -> i64 {
+    let height = packetcrypt_sys::parent_block_height(ann);
+    match var {
+        Var::Work => packetcrypt_sys::work_bits(ann) as i64,
+        Var::Age => work_height.map_or(0, |h| (h - height) as i64),
+        Var::Height => height as i64,
+        Var::Version => packetcrypt_sys::version(ann) as i64,
+        Var::ContentType => packetcrypt_sys::content_type(ann) as i64,
+        Var::Signed => (!util::is_zero(packetcrypt_sys::signing_key(ann))) as i64,
+    }
+}
+
+fn eval(e: &Expr, ann: &[u8], work_height: Option<i32>) -> bool {
+    match e {
+        Expr::Cmp(var, cmp, n) => {
+            let v = value(*var, ann, work_height);
+            match cmp {
+                Cmp::Lt => v < *n,
+                Cmp::Le => v <= *n,
+                Cmp::Gt => v > *n,
+                Cmp::Ge => v >= *n,
+                Cmp::Eq => v == *n,
+                Cmp::Ne => v != *n,
+            }
+        }
+        Expr::Not(e) => !eval(e, ann, work_height),
+        Expr::And(a, b) => eval(a, ann, work_height) && eval(b, ann, work_height),
+        Expr::Or(a, b) => eval(a, ann, work_height) || eval(b, ann, work_height),
+    }
+}
+
+impl Filter {
+    /// Whether to keep an ann, work_height is the height of the work being mined, if
+    /// there is none then age is 0
+    pub fn matches(&self, ann: &[u8], work_height: Option<i32>) -> bool {
+        eval(&self.expr, ann, work_height)
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    fn ann(work: u32, height: i32, signed: bool) -> [u8; 1024] {
+        let mut a = [0u8; 1024];
+        a[8..12].copy_from_slice(&work.to_le_bytes());
+        a[12..16].copy_from_slice(&height.to_le_bytes());
+        if signed {
+            a[56] = 1;
+        }
+        a
+    }
+
+    fn m(filter: &str, a: &[u8; 1024]) -> bool {
+        parse(filter).unwrap().matches(&a[..], Some(100))
+    }
+
+    #[test]
+    fn test_numbers() {
+        let a = ann(0x2000ffff, 98, false);
+        assert!(m("work == 0x2000ffff", &a));
+        assert!(m("work == 536936447", &a));
+        assert!(m("work <= 0x2000ffff && age < 3", &a));
+        assert!(!m("age > 2", &a));
+        assert!(m("height >= 98", &a));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let a = ann(1, 100, true);
+        // && binds tighter, so this is signed || (height == 0 && age == 1)
+        assert!(m("signed == 1 || height == 0 && age == 1", &a));
+        assert!(!m("(signed == 1 || height == 0) && age == 1", &a));
+        assert!(!m("!signed == 1", &a));
+        assert!(m("!(signed == 0) && !!work == 1", &a));
+        assert!(m("! (signed == 0 || work > 1)", &a));
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |f: &str| parse(f).unwrap_err().to_string();
+        assert_eq!(
+            err("work < 0xzz"),
+            "Invalid ann filter [work < 0xzz]: Invalid number [0xzz]"
+        );
+        assert_eq!(
+            err("weight < 1"),
+            "Invalid ann filter [weight < 1]: Unknown variable [weight]"
+        );
+        assert_eq!(
+            err("(work < 1"),
+            "Invalid ann filter [(work < 1]: Missing )"
+        );
+        assert_eq!(
+            err("work < 1 age"),
+            "Invalid ann filter [work < 1 age]: Unexpected Ident(\"age\")"
+        );
+        assert_eq!(
+            err("work = 1"),
+            "Invalid ann filter [work = 1]: Unexpected [= 1]"
+        );
+        assert!(err("work <").contains("Expected a number after Lt but found None"));
+        assert!(err("").contains("Expected a variable or ( but found None"));
+    }
+}
@@ -0,0 +1,264 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! `packetcrypt ann-proxy` sits between the block miners on one site and the pool's ann
+//! handlers so that each ann file crosses the uplink once, however many miners there
+//! are. A block miner started with --ann-proxy gets the index and files of the handler
+//! at url from <proxy>/ah/<hex of url>/anns/... and only handlers which are in the
+//! pool's download_ann_urls are proxied. Indexes are passed through, files are kept in
+//! memory, oldest dropped first, and a file which is being downloaded is downloaded once
+//! for all of the miners asking for it. There is no access control, bind it to an
+//! address which only the miners can reach.
+use anyhow::{bail, Result};
+use log::{debug, info};
+use packetcrypt_util::poolclient::{self, PoolClient};
+use packetcrypt_util::tasks::{self, Restart};
+use packetcrypt_util::util;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+use warp::http::StatusCode;
+use warp::Filter;
+
+const STATS_MS: u64 = 60_000;
+
+pub struct Config {
+    pub bind: String,
+    pub pool_master: String,
+    pub pool_token: Option<String>,
+    // Sent to the handlers, the miners don't need it
+    pub handler_pass: Option<String>,
+    pub cache_bytes: usize,
+}
+
+// What a download came to, Err is passed on to the miners as 502 so they try again
+type Download = std::result::Result<Option<bytes::Bytes>, String>;
+
+struct AnnProxyM {
+    files: HashMap<String, bytes::Bytes>,
+    // Oldest first, for dropping them
+    order: VecDeque<String>,
+    bytes: usize,
+    // Downloads in progress, with the requests waiting for them
+    pending: HashMap<String, Vec<oneshot::Sender<Download>>>,
+}
+
+pub struct AnnProxyS {
+    cfg: Config,
+    pcli: PoolClient,
+    client: reqwest::Client,
+    m: Mutex<AnnProxyM>,
+    hits: AtomicUsize,
+    downloads: AtomicUsize,
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
+}
+pub type AnnProxy = Arc<AnnProxyS>;
+
+pub fn new(cfg: Config) -> Result<AnnProxy> {
+    let pcli = poolclient::new(&cfg.pool_master, 1, 10, cfg.pool_token.clone());
+    Ok(Arc::new(AnnProxyS {
+        cfg,
+        pcli,
+        client: util::client_builder().build()?,
+        m: Mutex::new(AnnProxyM {
+            files: HashMap::new(),
+            order: VecDeque::new(),
+            bytes: 0,
+            pending: HashMap::new(),
+        }),
+        hits: AtomicUsize::new(0),
+        downloads: AtomicUsize::new(0),
+        bytes_in: AtomicUsize::new(0),
+        bytes_out: AtomicUsize::new(0),
+    }))
+}
+
+fn keep(ap: &AnnProxy, m: &mut AnnProxyM, url: String, file: bytes::Bytes) {
+    m.bytes += file.len();
+    m.order.push_back(url.clone());
+    m.files.insert(url, file);
+    while m.bytes > ap.cfg.cache_bytes {
+        let old = match m.order.pop_front() {
+            Some(old) => old,
+            None => break,
+        };
+        if let Some(f) = m.files.remove(&old) {
+            m.bytes -= f.len();
+        }
+    }
+}
+
+async fn fetch(ap: &AnnProxy, url: &str) -> Result<Option<bytes::Bytes>> {
+    loop {
+        let mut req = util::request(&ap.client, reqwest::Method::GET, url).await?;
+        if let Some(p) = &ap.cfg.handler_pass {
+            req = req.header("x-pc-passwd", p);
+        }
+        let res = req.send().await?;
+        return match res.status() {
+            reqwest::StatusCode::OK => {
+                let bin = res.bytes().await?;
+                ap.bytes_in.fetch_add(bin.len(), Ordering::Relaxed);
+                Ok(Some(bin))
+            }
+            reqwest::StatusCode::MULTIPLE_CHOICES => continue,
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::METHOD_NOT_ALLOWED => Ok(None),
+            st => bail!("Status code was {:?}", st),
+        };
+    }
+}
+
+// Runs apart from the request which started it, so that the others waiting for the
+// file still get it if that miner goes away
+async fn download(ap: AnnProxy, url: String) {
+    ap.downloads.fetch_add(1, Ordering::Relaxed);
+    let res = fetch(&ap, &url).await.map_err(|e| {
+        debug!("Error downloading {}: {}", url, e);
+        e.to_string()
+    });
+    let waiting = {
+        let mut m = ap.m.lock().unwrap();
+        if let Ok(Some(f)) = &res {
+            keep(&ap, &mut m, url.clone(), f.clone());
+        }
+        m.pending.remove(&url).unwrap_or_default()
+    };
+    for w in waiting {
+        let _ = w.send(res.clone());
+    }
+}
+
+async fn get_file(ap: &AnnProxy, url: String) -> Download {
+    let rx = {
+        let mut m = ap.m.lock().unwrap();
+        if let Some(f) = m.files.get(&url) {
+            ap.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(f.clone()));
+        }
+        let (tx, rx) = oneshot::channel();
+        if let Some(waiting) = m.pending.get_mut(&url) {
+            ap.hits.fetch_add(1, Ordering::Relaxed);
+            waiting.push(tx);
+        } else {
+            m.pending.insert(url.clone(), vec![tx]);
+            tokio::spawn(download(Arc::clone(ap), url));
+        }
+        rx
+    };
+    rx.await
+        .unwrap_or_else(|_| Err("download abandoned".into()))
+}
+
+// The handler url from the path, if it is one of the pool's
+async fn handler_url(ap: &AnnProxy, hex_url: &str) -> Option<String> {
+    let url = String::from_utf8(hex::decode(hex_url).ok()?).ok()?;
+    let conf = poolclient::conf(&ap.pcli).await?;
+    if conf.download_ann_urls.contains(&url) {
+        Some(url)
+    } else {
+        None
+    }
+}
+
+async fn get_index(
+    ap: &AnnProxy,
+    url: &str,
+    file_anns: Option<String>,
+    since: Option<String>,
+) -> Result<(StatusCode, bytes::Bytes)> {
+    let mut req = util::request(&ap.client, reqwest::Method::GET, url).await?;
+    if let Some(fa) = file_anns {
+        req = req.header("x-pc-file-anns", fa);
+    }
+    if let Some(s) = since {
+        req = req.header("x-pc-since", s);
+    }
+    let res = req.send().await?;
+    let status = StatusCode::from_u16(res.status().as_u16())?;
+    Ok((status, res.bytes().await?))
+}
+
+fn reply(status: StatusCode, body: bytes::Bytes) -> warp::http::Response<bytes::Bytes> {
+    let mut r = warp::http::Response::new(body);
+    *r.status_mut() = status;
+    r
+}
+
+async fn handle_anns(
+    hex_url: String,
+    file: String,
+    file_anns: Option<String>,
+    since: Option<String>,
+    ap: AnnProxy,
+) -> Result<impl warp::Reply, Infallible> {
+    let base = match handler_url(&ap, &hex_url).await {
+        Some(base) => base,
+        None => return Ok(reply(StatusCode::FORBIDDEN, bytes::Bytes::new())),
+    };
+    let url = format!("{}/anns/{}", base, file);
+    if file == "index.json" {
+        return Ok(match get_index(&ap, &url, file_anns, since).await {
+            Ok((status, body)) => reply(status, body),
+            Err(e) => reply(StatusCode::BAD_GATEWAY, e.to_string().into()),
+        });
+    }
+    Ok(match get_file(&ap, url).await {
+        Ok(Some(f)) => {
+            ap.bytes_out.fetch_add(f.len(), Ordering::Relaxed);
+            reply(StatusCode::OK, f)
+        }
+        Ok(None) => reply(StatusCode::NOT_FOUND, bytes::Bytes::new()),
+        Err(e) => reply(StatusCode::BAD_GATEWAY, e.into()),
+    })
+}
+
+async fn stats_loop(ap: &AnnProxy) {
+    loop {
+        util::sleep_ms(STATS_MS).await;
+        let (files, bytes) = {
+            let m = ap.m.lock().unwrap();
+            (m.files.len(), m.bytes)
+        };
+        info!(
+            "ann-proxy: {} files {}MB cached, {} requests served from cache, {} downloads, {}MB in {}MB out",
+            files,
+            bytes >> 20,
+            ap.hits.swap(0, Ordering::Relaxed),
+            ap.downloads.swap(0, Ordering::Relaxed),
+            ap.bytes_in.swap(0, Ordering::Relaxed) >> 20,
+            ap.bytes_out.swap(0, Ordering::Relaxed) >> 20,
+        );
+    }
+}
+
+pub async fn start(ap: &AnnProxy) -> Result<()> {
+    let addr: SocketAddr = ap.cfg.bind.parse()?;
+    poolclient::start(&ap.pcli).await;
+    let ap1 = Arc::clone(ap);
+    tasks::spawn("ann-proxy stats", Restart::Always, move || {
+        let ap = Arc::clone(&ap1);
+        async move { stats_loop(&ap).await }
+    });
+    let with_ap = (|ap: AnnProxy| warp::any().map(move || Arc::clone(&ap)))(Arc::clone(ap));
+    let anns = warp::get()
+        .and(warp::path("ah"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("anns"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("x-pc-file-anns"))
+        .and(warp::header::optional::<String>("x-pc-since"))
+        .and(with_ap)
+        .and_then(handle_anns);
+    info!("Serving anns to block miners on http://{}/ah/", addr);
+    tokio::spawn(async move { warp::serve(anns).run(addr).await });
+    Ok(())
+}
+
+/// The url which a block miner using the proxy at proxy gets the anns of handler url from
+pub fn proxied_url(proxy: &str, url: &str) -> String {
+    format!("{}/ah/{}", proxy.trim_end_matches('/'), hex::encode(url))
+}
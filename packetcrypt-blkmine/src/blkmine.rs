@@ -1,23 +1,46 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use crate::annfilter;
+use crate::annproxy;
 use crate::blkminer::{BlkMiner, BlkResult, OnShare};
+use crate::bufpool::{self, AnnInfo, BufPool, FreeInfo};
+use crate::capture;
+use crate::checkpoint;
 use crate::downloader;
+use crate::pktd::{self, Pktd};
 use crate::prooftree::{self, ProofTree};
+use crate::replay;
+use crate::shared;
+use crate::standby::{self, Standby};
+use crate::template;
 use anyhow::{bail, Result};
 use bytes::BufMut;
+use futures::SinkExt;
 use log::{debug, info, trace, warn};
 use packetcrypt_sys::difficulty::{pc_degrade_announcement_target, pc_get_effective_target};
+use packetcrypt_util::alloc_audit::{self, Stage};
 use packetcrypt_util::poolclient::{self, PoolClient, PoolUpdate};
 use packetcrypt_util::protocol;
-use packetcrypt_util::{hash, util};
+use packetcrypt_util::stats::{self, Metric, Stats};
+use packetcrypt_util::tasks::{self, Restart};
+use packetcrypt_util::telemetry;
+use packetcrypt_util::{clock, hash, history, util};
 use rayon::prelude::*;
-use std::cmp::max;
-use std::sync::atomic::AtomicUsize;
+use serde::Serialize;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
+use warp::Filter;
 
 pub struct BlkArgs {
     pub payment_addr: String,
+    // If not empty, shares are paid to these addresses instead, in proportion to the
+    // weights, for machines which mine for several people
+    pub payment_split: Vec<(String, u32)>,
     pub threads: usize,
     pub downloader_count: usize,
     pub pool_master: String,
@@ -27,35 +50,111 @@ pub struct BlkArgs {
     pub uploaders: usize,
     pub handler_pass: String,
     pub spray_cfg: Option<packetcrypt_sprayer::Config>,
-}
-
-struct FreeInfo {
-    // Number of anns at this location
-    ann_count: u32,
-
-    // Location of the ann in the memory slab
-    mloc: u32,
-}
-
-#[derive(Clone, Default)]
-struct AnnInfo {
-    // Parent block height for this batch of anns
-    parent_block_height: i32,
-
-    // Work for this batch
-    ann_min_work: u32,
-
-    // Effective work for this batch, temporary and used when sorting active_infos
-    ann_effective_work: u32,
-
-    // Number of anns or ann slots at this memory location
-    ann_count: u32,
 
-    // Location of the ann in the memory slab
-    mloc: u32,
-
-    // Hashes of anns, empty if this represents a block of free space
-    hashes: Vec<[u8; 32]>,
+    // Do everything except submit shares, log what would have been submitted
+    pub dry_run: bool,
+
+    // If non-empty, serve the debug snapshot API on this address
+    pub debug_bind: String,
+
+    // Also submit blocks directly to this pktd node
+    pub pktd: Option<Pktd>,
+
+    // Access token for private pools
+    pub pool_token: Option<String>,
+
+    // Significant bits of the ann work target which are used to sort anns into
+    // classes, 23 or more means only anns with exactly the same target share a class
+    pub ann_class_bits: u32,
+
+    // Block reward in PKT for estimating what shares are worth, zero means read it from the coinbase
+    pub block_reward: f64,
+
+    // Percent of the block reward which the pool keeps
+    pub pool_fee: f64,
+
+    // If non-zero, submit only this many of the best shares found each second
+    pub max_shares_per_sec: usize,
+
+    // One intake shard per core, each owning part of the slab, so that threads
+    // loading anns do not contend on the same locks (--exec-model sharded)
+    pub sharded: bool,
+    // If sharded and non-zero, shards of this many MB rather than one per core, so that
+    // how often threads take a lock and how much they take from another shard when
+    // their own is full can be fitted to the size of the machine
+    pub shard_mb: usize,
+
+    // Mine block templates from an adapter instead of work from the pool, shares
+    // are written back to the adapter rather than posted to the pool
+    pub templates: Option<template::Source>,
+
+    // If non-empty, save shares which the pool rejects in this directory, along with
+    // what was being mined, deleting the oldest to keep it under capture_max_mb
+    pub capture_dir: String,
+    pub capture_max_mb: usize,
+
+    // Number of free ann slots which anns mined on old blocks may not take, so that
+    // there is always room for the fresh ones right after a block change
+    pub fresh_reserve: u32,
+
+    // Write all work and anns which are received to this file
+    pub record: Option<String>,
+    // Take work and anns from a recording instead of the pool, implies dry_run
+    pub replay: Option<String>,
+
+    // Preferred number of anns per downloaded file, zero means take what the handler
+    // makes. Large files are better over slow links, small ones get anns sooner.
+    pub ann_file_anns: usize,
+
+    // Keep a checkpoint of the anns in this file and load it on startup
+    pub checkpoint: Option<String>,
+    // Checkpoints of other runs or other machines to load on startup, never written
+    pub ann_archives: Vec<String>,
+
+    // Keep the anns in one lane of this file of shared memory, so that the next block
+    // miner to start on this machine gets them when this one stops, the file is made
+    // with shared_lanes lanes if it doesn't exist
+    pub shared_anns: Option<String>,
+    pub shared_lanes: u32,
+
+    // Drop anns which don't match this on intake
+    pub ann_filter: Option<annfilter::Filter>,
+
+    // Keep the miner's identity and hourly history here
+    pub state_dir: Option<String>,
+
+    // If non-zero, run share uploads and work updates on a thread with this realtime
+    // priority so that intake can't slow down the reaction to a new block
+    pub realtime_priority: i32,
+    // If non-zero, the most milliseconds that choosing anns and building the tree should
+    // take when a block comes in, over that and fewer anns are used next time
+    pub tree_budget_ms: u64,
+    // Lock the proof trees in RAM
+    pub mlock_trees: bool,
+    // Give building the proof tree and copying in anns their own threads, one on each
+    // of these cores, rather than sharing the global rayon pool
+    pub tree_cores: Vec<usize>,
+    pub intake_cores: Vec<usize>,
+
+    // Debug api of the block miner which this one is a hot standby for, shares are
+    // only submitted while it is down
+    pub standby_of: Option<String>,
+
+    // Speak HTTP/2 to the block handlers without negotiating it, so that all of the
+    // uploaders' shares are streams on one connection
+    pub share_http2: bool,
+
+    // Only mine work which is signed by this key, if unset then work is checked with
+    // the first key which the pool's config gives, if it gives one
+    pub work_key: Option<[u8; 32]>,
+
+    // Get anns through the `packetcrypt ann-proxy` at this url rather than from the
+    // handlers directly
+    pub ann_proxy: Option<String>,
+
+    // A second pool to mine for, each block goes to whichever of the two has work which
+    // the anns serve best, see pick_work()
+    pub alt_pool: Option<String>,
 }
 
 #[derive(Default, Clone)]
@@ -72,38 +171,65 @@ struct CurrentMining {
     shares: usize,
 }
 
+#[derive(Clone)]
 struct CurrentWork {
     work: protocol::Work,
     conf: protocol::MasterConf,
+    // 0 for the pool, 1 for the alt pool
+    pool: usize,
+}
+
+// The --alt-pool, with its own work and its own share uploaders so that one pool being
+// slow to take shares doesn't hold up the other
+struct AltPool {
+    pcli: PoolClient,
+    // Newest work of each of the pools, the main one first
+    work: [Mutex<Option<CurrentWork>>; 2],
+    share_send: Mutex<tokio::sync::mpsc::UnboundedSender<Share>>,
+    share_recv: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<Share>>,
+}
+
+// What was mined for each of the pools, only when there is an alt pool
+#[derive(Default)]
+struct PoolShares {
+    blocks: AtomicUsize,
+    accepted: AtomicUsize,
+    rejected: AtomicUsize,
+    earnings: Mutex<f64>,
 }
 
 pub struct BlkMineS {
     // Memory location where the actual announcements are stored
     block_miner: BlkMiner,
 
-    // Free space and discards from last mining lock
-    inactive_infos: Mutex<Vec<AnnInfo>>,
-
-    // Newly added, not yet selected for mining
-    new_infos: Mutex<Vec<AnnInfo>>,
-
-    // Currently in use mining (do not touch these anns)
-    active_infos: Mutex<Vec<AnnInfo>>,
+    // Every ann slot in the slab, along with what is in it
+    pool: BufPool,
 
     trees: [Mutex<ProofTree>; 2],
 
+    // If tree_cores and intake_cores are set
+    tree_pool: Option<rayon::ThreadPool>,
+    intake_pool: Option<rayon::ThreadPool>,
+
     current_mining: Mutex<Option<CurrentMining>>,
 
     downloaders: tokio::sync::Mutex<Vec<downloader::Downloader<BlkMine>>>,
 
     current_work: Mutex<Option<CurrentWork>>,
 
-    // Maximum number of anns which we allow to mine at a time
-    // This should be less than the size of the slab in order to allow
-    // new anns to be added while mining is ongoing
-    max_mining: u32,
+    // Latest conf from the pool, kept for when work comes from templates
+    pool_conf: Mutex<protocol::MasterConf>,
+
+    // Key which each pool's work must be signed with, --work-key or else the first
+    // key which the pool's config gave
+    work_keys: Mutex<[Option<[u8; 32]>; 2]>,
+
+    // Where to write shares for the template which is being mined
+    template_out: Mutex<Option<template::Output>>,
 
     pcli: PoolClient,
+    alt: Option<AltPool>,
+    pool_shares: [PoolShares; 2],
     ba: BlkArgs,
 
     spray: Option<packetcrypt_sprayer::Sprayer>,
@@ -112,6 +238,71 @@ pub struct BlkMineS {
     share_channel_recv: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<Share>>,
 
     share_num: AtomicUsize,
+
+    // Shares found this cycle, waiting for the best of them to be picked
+    share_candidates: Mutex<Vec<Share>>,
+
+    // Hashes of recent blocks by height, byte order as used by check_ann()
+    block_hashes: Mutex<HashMap<i32, [u8; 32]>>,
+
+    // Set once the --ann-archive files have been started loading
+    archives_loaded: AtomicBool,
+
+    // Estimated value in PKT of the shares accepted by the pool since time_started_ms
+    earnings: Mutex<f64>,
+    time_started_ms: u64,
+
+    // Only reported if --telemetry is set
+    telemetry: telemetry::Counters,
+
+    recorder: Option<replay::Recorder>,
+
+    standby: Option<Standby>,
+
+    // Shared by the uploaders so that the connection to the handler stays open
+    share_client: reqwest::Client,
+    // The handler last posted to and when, for keeping the connection warm
+    last_share: Mutex<(String, u64)>,
+    // Work hash of each share posted in the last SHARE_DEDUP_MS and when, pools count a
+    // share which comes twice as a duplicate
+    posted_shares: Mutex<HashMap<[u8; 32], u64>>,
+    dup_shares: AtomicUsize,
+
+    // Number of anns received by (version, content type, signed)
+    ann_kinds: Mutex<BTreeMap<(u8, u32, bool), u64>>,
+
+    // What the anns of each class (parent block height, work) earned in accepted shares
+    class_earnings: Mutex<BTreeMap<(i32, u32), ClassEarnings>>,
+
+    // Difficulty of the shares posted for each of the payment_split so far
+    payee_diff: Mutex<Vec<f64>>,
+
+    // Height of the newest work which is known of, a tree which is being built for older
+    // work is abandoned
+    newest_height: AtomicI32,
+
+    // Most anns to mine with so that a cycle fits in tree_budget_ms, see enforce_budget()
+    ann_limit: AtomicU32,
+
+    // The block which is being mined, sent to /events when the next one starts
+    block_event: Mutex<Option<BlockEvent>>,
+    block_events: tokio::sync::broadcast::Sender<String>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct BlockEvent {
+    pub height: i32,
+    pub anns: u32,
+    pub ann_min_work: u32,
+    pub tree_ms: u64,
+    pub mining_ms: u64,
+    pub shares_found: u64,
+    // Replies from the pool accepting shares which came in while mining this block
+    pub shares_accepted: u64,
+    pub hashes_per_second: f64,
+    pub effective_hashes_per_second: f64,
+    #[serde(skip)]
+    started_ms: u64,
 }
 
 #[derive(Clone)]
@@ -145,44 +336,15 @@ fn get_current_mining(bm: &BlkMine) -> Option<CurrentMining> {
     out
 }
 
-// Reclaims free space or poor quality AnnInfos which are not currently being mined
-// This might not return the number of free items you want, it can even return 0
-// if there is no space available.
-fn get_free(bm: &BlkMine, mut count: u32) -> Vec<FreeInfo> {
-    let mut inactive_l = bm.inactive_infos.lock().unwrap();
-    let mut out = Vec::new();
-    //debug!("Get {} free from {} inactives", count, inactive_l.len());
-    loop {
-        if count == 0 {
-            return out;
-        }
-        out.push(if let Some(mut ai) = inactive_l.pop() {
-            if ai.ann_count > count {
-                // Split the AnnInfo, taking the low mloc's and leaving the high ones
-                let fi = FreeInfo {
-                    ann_count: count,
-                    mloc: ai.mloc,
-                };
-                ai.mloc += count;
-                ai.ann_count -= count;
-                if ai.hashes.len() > count as usize {
-                    // remove the first n hashes so that the AnnInfo returned
-                    // is still valid
-                    ai.hashes.drain(0..(count as usize)).count();
-                }
-                inactive_l.push(ai);
-                count = 0;
-                fi
-            } else {
-                count -= ai.ann_count;
-                FreeInfo {
-                    ann_count: ai.ann_count,
-                    mloc: ai.mloc,
-                }
-            }
-        } else {
-            return out;
-        });
+// How many free slots anns with this parent block must leave for fresher ones,
+// anns on the block before the one we're mining (or newer) may take everything.
+fn reserve_for(bm: &BlkMine, parent_block_height: i32) -> u32 {
+    if bm.ba.fresh_reserve == 0 {
+        return 0;
+    }
+    match &*bm.current_work.lock().unwrap() {
+        Some(cw) if parent_block_height < cw.work.height - 1 => bm.ba.fresh_reserve,
+        _ => 0,
     }
 }
 
@@ -191,15 +353,29 @@ struct AnnStats {
     ann_min_work: u32,
     hash: [u8; 32],
 }
-fn get_ann_stats(b: &[u8]) -> AnnStats {
+fn get_ann_stats(b: &[u8], class_bits: u32) -> AnnStats {
     let hash = hash::compress32(b);
     AnnStats {
         parent_block_height: packetcrypt_sys::parent_block_height(b),
-        ann_min_work: packetcrypt_sys::work_bits(b),
+        ann_min_work: ann_class_work(packetcrypt_sys::work_bits(b), class_bits),
         hash,
     }
 }
 
+// Round the work target up to the given number of significant bits so that anns
+// with similar work end up in the same class. Rounding up means the class is mined
+// as if every ann had the least work of any of them, so the coinbase commitment is
+// still good for all of them.
+pub(crate) fn ann_class_work(work_bits: u32, class_bits: u32) -> u32 {
+    let mantissa = work_bits & 0x007fffff;
+    let sig_bits = 32 - mantissa.leading_zeros();
+    if sig_bits <= class_bits {
+        work_bits
+    } else {
+        work_bits | ((1 << (sig_bits - class_bits)) - 1)
+    }
+}
+
 trait GetAnn {
     fn get_ann(&self, num: usize) -> &[u8];
     fn ann_count(&self) -> usize;
@@ -213,7 +389,7 @@ impl GetAnn for bytes::Bytes {
     }
 }
 
-fn mk_ann_info(anns: &impl GetAnn, mut free: Vec<FreeInfo>) -> Vec<AnnInfo> {
+fn mk_ann_info(anns: &impl GetAnn, mut free: Vec<FreeInfo>, class_bits: u32) -> Vec<AnnInfo> {
     let mut out = Vec::with_capacity(anns.ann_count());
     let mut ann_i = 0;
     let mut maybe_fi = free.pop();
@@ -235,7 +411,7 @@ fn mk_ann_info(anns: &impl GetAnn, mut free: Vec<FreeInfo>) -> Vec<AnnInfo> {
             }
             return out;
         };
-        let stats = get_ann_stats(anns.get_ann(ann_i));
+        let stats = get_ann_stats(anns.get_ann(ann_i), class_bits);
         ann_i += 1;
         maybe_ai = {
             let mut next_ai = None;
@@ -284,13 +460,114 @@ impl<'a> GetAnn for AnnChunk<'a> {
     }
 }
 
+// Past this many kinds of ann, the ones with unusual content types are counted together
+// as content type 0xffffffff so that junk can't make the table grow forever
+const MAX_ANN_KINDS: usize = 256;
+
+// Which kinds of anns are out there, so that it's clear how far the network has moved
+// to a new version
+fn count_ann_kinds<'a>(bm: &BlkMine, anns: impl Iterator<Item = &'a [u8]>) {
+    let mut kinds = bm.ann_kinds.lock().unwrap();
+    for ann in anns {
+        let signed = !util::is_zero(packetcrypt_sys::signing_key(ann));
+        let mut k = (
+            packetcrypt_sys::version(ann),
+            packetcrypt_sys::content_type(ann),
+            signed,
+        );
+        if kinds.len() >= MAX_ANN_KINDS && !kinds.contains_key(&k) {
+            k.1 = u32::MAX;
+        }
+        *kinds.entry(k).or_insert(0) += 1;
+    }
+}
+
+// Checking an ann is slow so only this many from each batch are checked on intake
+const ANN_CHECK_SAMPLE: usize = 4;
+
+// Anns older than this are worthless, see pc_degrade_announcement_target()
+const BLOCK_HASH_CACHE_DEPTH: i32 = 256 + 3;
+
+fn on_update_blocks(bm: &BlkMine, update_blocks: &[protocol::BlockInfo]) {
+    let mut hashes = bm.block_hashes.lock().unwrap();
+    for bi in update_blocks {
+        let mut hash = bi.header.hash;
+        hash.reverse();
+        hashes.insert(bi.header.height, hash);
+    }
+    if let Some(&top) = hashes.keys().max() {
+        hashes.retain(|&height, _| height > top - BLOCK_HASH_CACHE_DEPTH);
+    }
+    if !hashes.is_empty() && !bm.archives_loaded.swap(true, Ordering::Relaxed) {
+        load_archives(bm);
+    }
+}
+
+// The anns in archives are checked against the block hashes, so they are loaded once
+// the pool has given some
+fn load_archives(bm: &BlkMine) {
+    if bm.ba.ann_archives.is_empty() {
+        return;
+    }
+    let bm = bm.clone();
+    std::thread::spawn(move || {
+        let hashes = bm.block_hashes.lock().unwrap().clone();
+        for path in &bm.ba.ann_archives {
+            let res = checkpoint::load_archive(
+                path,
+                &bm.pool,
+                &bm.block_miner,
+                bm.ba.ann_class_bits,
+                &hashes,
+            );
+            if let Err(e) = res {
+                warn!("{:?}", e);
+            }
+        }
+    });
+}
+
+// Make sure that anns were mined on the block they claim to be, otherwise they will
+// invalidate every share which they are part of. Anns whose parent block we don't
+// know about yet are let through.
+fn check_parent_hashes(bm: &BlkMine, anns: &impl GetAnn) -> Result<()> {
+    let count = anns.ann_count();
+    if count == 0 {
+        return Ok(());
+    }
+    let hashes = bm.block_hashes.lock().unwrap();
+    let mut vctx = None;
+    let step = max(1, count / ANN_CHECK_SAMPLE);
+    for i in (0..count).step_by(step).take(ANN_CHECK_SAMPLE) {
+        let ann = anns.get_ann(i);
+        let height = packetcrypt_sys::parent_block_height(ann);
+        let pbh = if let Some(pbh) = hashes.get(&height) {
+            pbh
+        } else {
+            continue;
+        };
+        let pca = packetcrypt_sys::PacketCryptAnn {
+            bytes: util::aligned_bytes(ann, 4),
+        };
+        let vctx = vctx.get_or_insert_with(packetcrypt_sys::ValidateCtx::default);
+        if let Err(e) = packetcrypt_sys::check_ann(&pca, pbh, vctx) {
+            bail!("ann with parent block height {} is invalid [{}]", height, e);
+        }
+    }
+    Ok(())
+}
+
 fn on_anns(bm: &BlkMine, ac: AnnChunk) {
     // Try to get unused space to place them
-    let free = get_free(bm, ac.indexes.len() as u32);
+    let reserve = reserve_for(bm, packetcrypt_sys::parent_block_height(ac.get_ann(0)));
+    let free = bm.pool.alloc(ac.indexes.len() as u32, reserve);
+    let taken = free.iter().map(|fi| fi.ann_count).sum();
 
     // generate ann infos from them
     let num_frees = free.len();
-    let mut info = mk_ann_info(&ac, free);
+    let mut info = alloc_audit::scope(Stage::Classify, || {
+        mk_ann_info(&ac, free, bm.ba.ann_class_bits)
+    });
 
     // place anns in the data buffer
     let mut ann_i = 0;
@@ -303,7 +580,7 @@ fn on_anns(bm: &BlkMine, ac: AnnChunk) {
 
     // place the ann infos, this is what will make it possible to use the data
     let num_infos = info.len();
-    bm.new_infos.lock().unwrap().append(&mut info);
+    alloc_audit::scope(Stage::Classify, || bm.pool.place(&mut info, taken));
 
     // Stats
     let count = ac.ann_count();
@@ -322,136 +599,221 @@ fn on_anns(bm: &BlkMine, ac: AnnChunk) {
     );
 }
 
+fn filter_height(bm: &BlkMine) -> Option<i32> {
+    bm.ba.ann_filter.as_ref()?;
+    bm.current_work
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|cw| cw.work.height)
+}
+
+// Drop the anns which don't match --ann-filter, None if there are none left
+fn filter_anns(bm: &BlkMine, anns: bytes::Bytes, url: &str) -> Option<bytes::Bytes> {
+    let f = if let Some(f) = &bm.ba.ann_filter {
+        f
+    } else {
+        return Some(anns);
+    };
+    let work_height = filter_height(bm);
+    if anns.chunks(1024).all(|a| f.matches(a, work_height)) {
+        return Some(anns);
+    }
+    let mut out = bytes::BytesMut::with_capacity(anns.len());
+    for a in anns.chunks(1024).filter(|a| f.matches(a, work_height)) {
+        out.extend_from_slice(a);
+    }
+    debug!(
+        "Filtered out {} of {} anns from {}",
+        (anns.len() - out.len()) / 1024,
+        anns.len() / 1024,
+        url
+    );
+    if out.is_empty() {
+        None
+    } else {
+        Some(out.freeze())
+    }
+}
+
+// Sprayed anns are copied straight from the sprayer's receive buffer into the block
+// miner's memory, they never touch the disk on the way
 impl packetcrypt_sprayer::OnAnns for BlkMine {
     fn on_anns(&self, anns: &[&[u8]]) {
-        struct Ai {
-            hw: HeightWork,
-            index: u32,
-        }
-        let mut v: Vec<Ai> = Vec::with_capacity(anns.len());
-        for (bytes, i) in anns.iter().zip(0..) {
-            v.push(Ai {
-                hw: HeightWork {
-                    block_height: packetcrypt_sys::parent_block_height(bytes),
-                    work: packetcrypt_sys::work_bits(bytes),
-                },
-                index: i,
-            });
-        }
-        v.sort_by(|a, b| {
-            if a.hw.block_height != b.hw.block_height {
-                b.hw.block_height.cmp(&a.hw.block_height)
-            } else if a.hw.work != b.hw.work {
-                a.hw.work.cmp(&b.hw.work)
-            } else {
-                std::cmp::Ordering::Equal
-            }
-        });
+        in_pool(&self.intake_pool, || self.sprayed_anns(anns))
+    }
+}
 
-        let mut indexes: Vec<u32> = Vec::with_capacity(anns.len());
-        let mut height_work: Option<HeightWork> = None;
-        for ai in v {
-            let hw = match &height_work {
-                None => {
-                    indexes.push(ai.index);
-                    height_work = Some(ai.hw);
-                    continue;
+impl BlkMine {
+    fn sprayed_anns(&self, anns: &[&[u8]]) {
+        alloc_audit::scope(Stage::Intake, || {
+            if let Some(r) = &self.recorder {
+                r.sprayed(anns);
+            }
+            count_ann_kinds(self, anns.iter().copied());
+            struct Ai {
+                hw: HeightWork,
+                index: u32,
+            }
+            let mut v: Vec<Ai> = Vec::with_capacity(anns.len());
+            let work_height = filter_height(self);
+            for (bytes, i) in anns.iter().zip(0..) {
+                if let Some(f) = &self.ba.ann_filter {
+                    if !f.matches(bytes, work_height) {
+                        continue;
+                    }
+                }
+                v.push(Ai {
+                    hw: HeightWork {
+                        block_height: packetcrypt_sys::parent_block_height(bytes),
+                        work: ann_class_work(
+                            packetcrypt_sys::work_bits(bytes),
+                            self.ba.ann_class_bits,
+                        ),
+                    },
+                    index: i,
+                });
+            }
+            v.sort_by(|a, b| {
+                if a.hw.block_height != b.hw.block_height {
+                    b.hw.block_height.cmp(&a.hw.block_height)
+                } else if a.hw.work != b.hw.work {
+                    a.hw.work.cmp(&b.hw.work)
+                } else {
+                    std::cmp::Ordering::Equal
                 }
-                Some(hw) => {
-                    if hw == &ai.hw {
+            });
+
+            let mut indexes: Vec<u32> = Vec::with_capacity(anns.len());
+            let mut height_work: Option<HeightWork> = None;
+            for ai in v {
+                let hw = match &height_work {
+                    None => {
                         indexes.push(ai.index);
+                        height_work = Some(ai.hw);
                         continue;
                     }
-                    hw
-                }
-            };
-            trace!(
-                "Batch of {} anns {} @ {}",
-                indexes.len(),
-                hw.block_height,
-                packetcrypt_sys::difficulty::tar_to_diff(hw.work)
-            );
-            on_anns(
-                self,
-                AnnChunk {
+                    Some(hw) => {
+                        if hw == &ai.hw {
+                            indexes.push(ai.index);
+                            continue;
+                        }
+                        hw
+                    }
+                };
+                trace!(
+                    "Batch of {} anns {} @ {}",
+                    indexes.len(),
+                    hw.block_height,
+                    packetcrypt_sys::difficulty::tar_to_diff(hw.work)
+                );
+                let ac = AnnChunk {
                     anns,
                     indexes: &indexes[..],
-                },
-            );
-            indexes.clear();
-            indexes.push(ai.index);
-            height_work = Some(ai.hw);
-        }
+                };
+                match check_parent_hashes(self, &ac) {
+                    Ok(()) => on_anns(self, ac),
+                    Err(e) => debug!("Discarding batch of {} anns because {}", indexes.len(), e),
+                }
+                indexes.clear();
+                indexes.push(ai.index);
+                height_work = Some(ai.hw);
+            }
+        })
     }
 }
 
 impl downloader::OnAnns for BlkMine {
     fn on_anns(&self, anns: bytes::Bytes, url: &str) {
-        // Get the number of anns
-        let count = if anns.len() % 1024 == 0 {
-            anns.len() / 1024
-        } else {
-            info!(
-                "Anns [{}] had unexpected length [{}] (not a multiple of 1024)",
-                url,
-                anns.len()
-            );
-            return;
-        } as u32;
+        in_pool(&self.intake_pool, || self.downloaded_anns(anns, url))
+    }
+}
 
-        let stats = get_ann_stats(&anns[0..1024]);
-        {
-            let cw_l = self.current_work.lock().unwrap();
-            match &*cw_l {
-                Some(cw) => {
-                    let age = max(0, cw.work.height - stats.parent_block_height) as u32;
-                    let ann_effective_work =
-                        pc_degrade_announcement_target(stats.ann_min_work, age);
-                    if age > 3 && ann_effective_work == 0xffffffff {
-                        debug!("Discarding {} because it is already out of date", url);
-                        return;
+impl BlkMine {
+    fn downloaded_anns(&self, anns: bytes::Bytes, url: &str) {
+        alloc_audit::scope(Stage::Intake, || {
+            if let Some(r) = &self.recorder {
+                r.downloaded(url, &anns);
+            }
+            if anns.len() % 1024 != 0 {
+                info!(
+                    "Anns [{}] had unexpected length [{}] (not a multiple of 1024)",
+                    url,
+                    anns.len()
+                );
+                return;
+            }
+            count_ann_kinds(self, anns.chunks(1024));
+            let anns = if let Some(anns) = filter_anns(self, anns, url) {
+                anns
+            } else {
+                return;
+            };
+            let count = (anns.len() / 1024) as u32;
+
+            let stats = get_ann_stats(&anns[0..1024], self.ba.ann_class_bits);
+            {
+                let cw_l = self.current_work.lock().unwrap();
+                match &*cw_l {
+                    Some(cw) => {
+                        let age = max(0, cw.work.height - stats.parent_block_height) as u32;
+                        let ann_effective_work =
+                            pc_degrade_announcement_target(stats.ann_min_work, age);
+                        if age > 3 && ann_effective_work == 0xffffffff {
+                            debug!("Discarding {} because it is already out of date", url);
+                            return;
+                        }
                     }
+                    None => (),
                 }
-                None => (),
             }
-        }
+            if let Err(e) = check_parent_hashes(self, &anns) {
+                info!("Discarding {} because {}", url, e);
+                return;
+            }
 
-        // Try to get unused space to place them
-        let free = get_free(self, count);
+            // Try to get unused space to place them
+            let free = self
+                .pool
+                .alloc(count, reserve_for(self, stats.parent_block_height));
+            let taken = free.iter().map(|fi| fi.ann_count).sum();
 
-        // generate ann infos from them
-        let num_frees = free.len();
-        let mut info = mk_ann_info(&anns, free);
+            // generate ann infos from them
+            let num_frees = free.len();
+            let mut info = alloc_audit::scope(Stage::Classify, || {
+                mk_ann_info(&anns, free, self.ba.ann_class_bits)
+            });
 
-        // place anns in the data buffer
-        let mut ann_index = 0;
-        let mut count_landed = 0;
-        for r in &info {
-            for i in 0..r.ann_count {
-                self.block_miner
-                    .put_ann(r.mloc + i, &anns[ann_index..(ann_index + 1024)]);
-                ann_index += 1024;
-                count_landed += 1;
+            // place anns in the data buffer
+            let mut ann_index = 0;
+            let mut count_landed = 0;
+            for r in &info {
+                for i in 0..r.ann_count {
+                    self.block_miner
+                        .put_ann(r.mloc + i, &anns[ann_index..(ann_index + 1024)]);
+                    ann_index += 1024;
+                    count_landed += 1;
+                }
             }
-        }
 
-        // place the ann infos, this is what will make it possible to use the data
-        let num_infos = info.len();
-        self.new_infos.lock().unwrap().append(&mut info);
+            // place the ann infos, this is what will make it possible to use the data
+            let num_infos = info.len();
+            alloc_audit::scope(Stage::Classify, || self.pool.place(&mut info, taken));
 
-        // Stats
-        if count_landed != count {
-            debug!(
-                "Out of slab space, could only store {} of {} anns from req {}",
-                count_landed, count, url
+            // Stats
+            if count_landed != count {
+                debug!(
+                    "Out of slab space, could only store {} of {} anns from req {}",
+                    count_landed, count, url
+                );
+            }
+            trace!(
+                "Loaded {} ANNS - {} frees, {} infos",
+                count_landed,
+                num_frees,
+                num_infos
             );
-        }
-        trace!(
-            "Loaded {} ANNS - {} frees, {} infos",
-            count_landed,
-            num_frees,
-            num_infos
-        );
+        })
     }
 }
 
@@ -463,78 +825,86 @@ fn reload_anns(
     next_work: &protocol::Work,
     active_l: &mut Vec<AnnInfo>,
 ) -> ReloadAnns {
-    // Collect all of the active infos, inactive infos and new infos
-    // compute effective work for everything
-    // place discards into inactive, accepted into active, leave new as empty
-
-    // Lets avoid unlocking inactive until we've re-added entries to it because
-    // otherwise a call to on_anns will have no free work
-    let mut inactive_l = bm.inactive_infos.lock().unwrap();
-    let mut new_l = bm.new_infos.lock().unwrap();
-
-    let mut v = Vec::with_capacity(inactive_l.len() + new_l.len() + active_l.len());
-    v.append(&mut inactive_l);
-    v.append(&mut new_l);
-    v.append(active_l);
-    for ai in &mut v {
-        if ai.hashes.is_empty() {
-            // This is the free space marker
-            ai.ann_effective_work = u32::MAX;
-        } else {
-            let age = max(0, next_work.height - ai.parent_block_height) as u32;
-            ai.ann_effective_work = pc_degrade_announcement_target(ai.ann_min_work, age);
-            trace!(
-                "computed effective work of ann {:#x} with age {} -> {:#x}",
-                ai.ann_min_work,
-                age,
-                ai.ann_effective_work
-            );
-        }
-    }
-    debug!("reload_anns() processing {} ann files", v.len());
-    // Sort by effective work, lowest numbers (most work) first
-    v.sort_by(|a, b| a.ann_effective_work.cmp(&b.ann_effective_work));
-
-    // Get the best subset
+    // Find the best effective work to mine at from the classes, then everything with
+    // at least that much work goes into active and the discards go back to inactive,
+    // leaving new empty
     let mut best_aew = 0xffffffff;
-    let mut best_tar = 0;
-    let mut best_i = 0;
-    let mut sum_count = 0;
-    for (i, elem) in (0..).zip(v.iter()) {
-        if elem.ann_effective_work == 0xffffffff {
-            break;
-        }
-        sum_count += elem.ann_count;
-        let tar = pc_get_effective_target(
-            next_work.share_target,
-            elem.ann_effective_work,
-            sum_count as u64,
-        );
-        trace!(
-            "reload_anns() try {}/{:#x}",
-            sum_count,
-            elem.ann_effective_work
+    let max_locked = min(bm.pool.max_locked(), bm.ann_limit.load(Ordering::Relaxed));
+    bm.pool.reload(active_l, |v, classes| {
+        let mut by_aew = classes
+            .iter()
+            .map(|c| {
+                let age = max(0, next_work.height - c.parent_block_height) as u32;
+                let aew = pc_degrade_announcement_target(c.ann_min_work, age);
+                trace!(
+                    "computed effective work of ann {:#x} with age {} -> {:#x}",
+                    c.ann_min_work,
+                    age,
+                    aew
+                );
+                (aew, c.ann_count)
+            })
+            .collect::<Vec<_>>();
+        debug!(
+            "reload_anns() processing {} ann files in {} classes",
+            v.len(),
+            classes.len()
         );
-        if tar > best_tar {
-            best_tar = tar;
-            best_i = i;
-            best_aew = elem.ann_effective_work;
-        }
-        if sum_count >= bm.max_mining {
-            break;
+        // Sort by effective work, lowest numbers (most work) first
+        by_aew.sort_unstable();
+
+        // Get the best subset
+        let mut best_tar = 0;
+        let mut sum_count = 0;
+        for (aew, count) in by_aew {
+            if aew == 0xffffffff {
+                break;
+            }
+            sum_count += count;
+            let tar = pc_get_effective_target(next_work.share_target, aew, sum_count as u64);
+            trace!("reload_anns() try {}/{:#x}", sum_count, aew);
+            if tar > best_tar {
+                best_tar = tar;
+                best_aew = aew;
+            }
+            if sum_count >= max_locked {
+                break;
+            }
         }
-    }
-    //debug!("Best target is {}, best_i {}", best_tar, best_i);
+        //debug!("Best target is {}, best_aew {}", best_tar, best_aew);
 
-    for (i, elem) in (0..).zip(v.drain(..)) {
-        if i >= best_i {
-            inactive_l.push(elem);
-        } else {
-            active_l.push(elem);
+        for ai in v.iter_mut() {
+            ai.ann_effective_work = if ai.hashes.is_empty() {
+                // This is the free space marker
+                u32::MAX
+            } else {
+                let age = max(0, next_work.height - ai.parent_block_height) as u32;
+                pc_degrade_announcement_target(ai.ann_min_work, age)
+            };
         }
-    }
-    // This is important because if we keep inactive sorted
-    inactive_l.sort_by(|b, a| a.parent_block_height.cmp(&b.parent_block_height));
+        if best_aew == 0xffffffff {
+            return 0;
+        }
+        // Move the chosen ones to the front, the ones with more work than best_aew first
+        // in case the last class doesn't all fit
+        let (mut n, mut total) = (0, 0);
+        for better in &[true, false] {
+            for i in n..v.len() {
+                let aew = v[i].ann_effective_work;
+                let take = if *better {
+                    aew < best_aew
+                } else {
+                    aew == best_aew
+                };
+                if take && total + v[i].ann_count <= max_locked {
+                    total += v[i].ann_count;
+                    v.swap(i, n);
+                    n += 1;
+                }
+            }
+        }
+        n
+    });
     //debug!("active_l.len() -> {}", active_l.len());
 
     ReloadAnns {
@@ -542,15 +912,15 @@ fn reload_anns(
     }
 }
 
-const COINBASE_COMMIT_LEN: usize = 50;
-const COINBASE_COMMIT_PATTERN: [u8; COINBASE_COMMIT_LEN] = hex_literal::hex!(
+pub(crate) const COINBASE_COMMIT_LEN: usize = 50;
+pub(crate) const COINBASE_COMMIT_PATTERN: [u8; COINBASE_COMMIT_LEN] = hex_literal::hex!(
     "
     6a3009f91102fcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfc
     fcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfcfc
 "
 );
 
-fn compute_block_header(next_work: &protocol::Work, commit: &[u8]) -> bytes::BytesMut {
+fn coinbase_with_commit(next_work: &protocol::Work, commit: &[u8]) -> bytes::BytesMut {
     let mut cnw = bytes::BytesMut::from(&next_work.coinbase_no_witness[..]);
     let pos = if let Some(pos) = cnw[..]
         .windows(COINBASE_COMMIT_LEN)
@@ -561,6 +931,11 @@ fn compute_block_header(next_work: &protocol::Work, commit: &[u8]) -> bytes::Byt
         panic!("Work did not contain commit pattern");
     };
     cnw[(pos + 2)..(pos + COINBASE_COMMIT_LEN)].copy_from_slice(&commit[..]);
+    cnw
+}
+
+fn compute_block_header(next_work: &protocol::Work, commit: &[u8]) -> bytes::BytesMut {
+    let cnw = coinbase_with_commit(next_work, commit);
     let txid = hash::compress_dsha256(&cnw[..]);
     let mut buf = [0; 64];
     buf[0..32].copy_from_slice(&txid[..]);
@@ -579,43 +954,113 @@ fn compute_block_header(next_work: &protocol::Work, commit: &[u8]) -> bytes::Byt
     bh
 }
 
+// With the alloc_audit feature, what the hot path allocated since the last work
+fn log_allocs(height: i32) {
+    let counts = alloc_audit::take()
+        .iter()
+        .map(|(st, c)| format!("{}: {} ({} bytes)", st.name(), c.allocs, c.bytes))
+        .collect::<Vec<_>>();
+    if !counts.is_empty() {
+        info!("Allocations before work {}: {}", height, counts.join(" "));
+    }
+}
+
+// Finish the event for the block which was being mined and send it to any subscribers
+fn send_block_event(bm: &BlkMine) {
+    let mut ev = if let Some(ev) = bm.block_event.lock().unwrap().take() {
+        ev
+    } else {
+        return;
+    };
+    let hashrate = bm.block_miner.hashes_per_second() as f64;
+    let hrm =
+        packetcrypt_sys::difficulty::pc_get_hashrate_multiplier(ev.ann_min_work, ev.anns as u64);
+    ev.mining_ms = util::now_ms().saturating_sub(ev.started_ms);
+    ev.hashes_per_second = hashrate;
+    ev.effective_hashes_per_second = hashrate * hrm as f64;
+    match serde_json::to_string(&ev) {
+        Ok(j) => {
+            // Err only means nobody is listening
+            let _ = bm.block_events.send(j);
+        }
+        Err(e) => warn!("Unable to serialize block event {}", e),
+    }
+}
+
 fn on_work(bm: &BlkMine, next_work: &protocol::Work) {
+    send_block_event(bm);
     bm.block_miner.stop();
-    let (index_table, real_target, current_mining) = {
+    // Shares of the old work are worthless once the pool has moved on, so don't wait
+    // for the end of the cycle to submit them
+    if bm.ba.max_shares_per_sec > 0 {
+        submit_best_shares(bm);
+    }
+    log_allocs(next_work.height);
+    let (index_table, real_target, current_mining, tree_ms) = {
         let (tree, tree_num) = get_tree(bm, false);
         let mut tree_l = tree.lock().unwrap();
-        let (reload, mut data) = {
-            let mut active_l = bm.active_infos.lock().unwrap();
-            let reload = reload_anns(bm, next_work, &mut active_l);
+        let cycle_started_ms = util::now_ms();
+        let (reload, mut data, tree_started_ms) = {
+            let mut active_l = bm.pool.lock_active();
+            let reload = alloc_audit::scope(Stage::Classify, || {
+                reload_anns(bm, next_work, &mut active_l)
+            });
             debug!("Inserting in tree");
+            let tree_started_ms = util::now_ms();
             tree_l.reset();
-            let data = active_l
-                .par_iter()
-                .map(|ai| {
-                    //debug!("active_l has {} hashes", ai.hashes.len());
-                    let mut out: Vec<prooftree::AnnData> = Vec::with_capacity(ai.hashes.len());
-                    for (h, i) in ai.hashes.iter().zip(0..) {
-                        let mloc = ai.mloc + i;
-                        assert!(mloc < bm.block_miner.max_anns);
-                        out.push(prooftree::AnnData {
-                            hash: *h,
-                            mloc,
-                            index: 0,
-                        });
-                    }
-                    out
-                })
-                .flatten()
-                .collect::<Vec<_>>();
+            let active = &active_l[..];
+            let data = in_pool(&bm.tree_pool, || {
+                active
+                    .par_iter()
+                    .map(|ai| {
+                        alloc_audit::scope(Stage::Tree, || {
+                            //debug!("active_l has {} hashes", ai.hashes.len());
+                            let mut out: Vec<prooftree::AnnData> =
+                                Vec::with_capacity(ai.hashes.len());
+                            for (h, i) in ai.hashes.iter().zip(0..) {
+                                let mloc = ai.mloc + i;
+                                assert!(mloc < bm.block_miner.max_anns);
+                                out.push(prooftree::AnnData {
+                                    hash: *h,
+                                    mloc,
+                                    index: 0,
+                                });
+                            }
+                            out
+                        })
+                    })
+                    .flatten()
+                    .collect::<Vec<_>>()
+            });
             if data.is_empty() {
                 bm.block_miner.stop();
                 debug!("Not mining, no anns ready");
                 return;
             }
-            (reload, data)
+            (reload, data, tree_started_ms)
         };
         debug!("Computing tree");
-        let index_table = tree_l.compute(&mut data).unwrap();
+        let cancel = || bm.newest_height.load(Ordering::Relaxed) > next_work.height;
+        let tree_mut: &mut ProofTree = &mut tree_l;
+        let index_table = match in_pool(&bm.tree_pool, || {
+            alloc_audit::scope(Stage::Tree, || tree_mut.compute(&mut data, cancel))
+        }) {
+            Ok(it) => it,
+            Err(prooftree::CANCELLED) => {
+                info!(
+                    "Abandoned the tree for {} after {}ms, there is newer work",
+                    next_work.height,
+                    util::now_ms() - tree_started_ms
+                );
+                return;
+            }
+            Err(e) => panic!("Unable to compute tree: {}", e),
+        };
+        let tree_ms = util::now_ms() - tree_started_ms;
+        let count = index_table.len() as u32;
+        enforce_budget(bm, count, util::now_ms() - cycle_started_ms);
+        let picked = pick_work(bm, next_work, reload.ann_min_work, count);
+        let next_work = picked.as_ref().map_or(next_work, |cw| &cw.work);
         debug!("Computing block header");
         let coinbase_commit = tree_l.get_commit(reload.ann_min_work).unwrap();
         let block_header = compute_block_header(next_work, &coinbase_commit[..]);
@@ -624,7 +1069,6 @@ fn on_work(bm: &BlkMine, next_work: &protocol::Work) {
             reload.ann_min_work,
             index_table.len() as u64,
         );
-        let count = index_table.len() as u32;
         (
             index_table,
             real_target,
@@ -638,6 +1082,7 @@ fn on_work(bm: &BlkMine, next_work: &protocol::Work) {
                 block_header,
                 shares: 0,
             },
+            tree_ms,
         )
     };
 
@@ -663,6 +1108,14 @@ fn on_work(bm: &BlkMine, next_work: &protocol::Work) {
         index_table.len(),
         packetcrypt_sys::difficulty::tar_to_diff(current_mining.ann_min_work),
     );
+    bm.block_event.lock().unwrap().replace(BlockEvent {
+        height: current_mining.mining_height,
+        anns: current_mining.count,
+        ann_min_work: current_mining.ann_min_work,
+        tree_ms,
+        started_ms: current_mining.time_started_ms,
+        ..Default::default()
+    });
     bm.current_mining.lock().unwrap().replace(current_mining);
 
     // Validate self-test
@@ -672,54 +1125,330 @@ fn on_work(bm: &BlkMine, next_work: &protocol::Work) {
     };
 }
 
-pub async fn new(ba: BlkArgs) -> Result<BlkMine> {
-    let pcli = poolclient::new(&ba.pool_master, 1, 1);
-    let block_miner = BlkMiner::new(ba.max_mem as u64, ba.threads as u32)?;
-    let max_anns = block_miner.max_anns;
-    let spray = if let Some(sc) = &ba.spray_cfg {
-        Some(packetcrypt_sprayer::Sprayer::new(sc)?)
-    } else {
-        None
-    };
-    let (send, recv) = tokio::sync::mpsc::unbounded_channel();
-    let bm = BlkMine(Arc::new(BlkMineS {
-        block_miner,
-        inactive_infos: Mutex::new(vec![AnnInfo {
-            parent_block_height: 0,
-            ann_min_work: 0,
-            ann_effective_work: 0,
-            ann_count: max_anns,
-            mloc: 0,
-            hashes: Vec::new(),
-        }]),
-        new_infos: Mutex::new(Vec::new()),
-        active_infos: Mutex::new(Vec::new()),
-        trees: [
-            Mutex::new(ProofTree::new(max_anns)),
-            Mutex::new(ProofTree::new(max_anns)),
-        ],
-        downloaders: tokio::sync::Mutex::new(Vec::new()),
-        current_mining: Mutex::new(None),
-        current_work: Mutex::new(None),
-        max_mining: ((1.0 - ba.min_free_space) * max_anns as f64) as u32,
-        pcli,
-        ba,
-        spray,
-        share_channel_recv: tokio::sync::Mutex::new(recv),
-        share_channel_send: Mutex::new(send),
-        share_num: AtomicUsize::new(0),
-    }));
-    bm.block_miner.set_handler(bm.clone());
-    Ok(bm)
-}
+// Less than this is not worth mining, however slow the machine is
+const MIN_LIMIT_ANNS: u32 = 1 << 14;
 
-async fn downloader_loop(bm: &BlkMine) {
-    let mut chan = poolclient::update_chan(&bm.pcli).await;
-    let mut urls: Vec<String> = Vec::new();
-    loop {
-        let upd = match chan.recv().await {
-            Ok(x) => x,
-            Err(e) => {
+// After a cycle which took cycle_ms to choose count anns and build their tree, limit the
+// anns of the next cycles so that they fit in --tree-budget-ms. The time is about in
+// proportion to the anns, the limit is raised again when there is time to spare.
+fn enforce_budget(bm: &BlkMine, count: u32, cycle_ms: u64) {
+    let budget = bm.ba.tree_budget_ms;
+    if budget == 0 || count == 0 {
+        return;
+    }
+    let limit = bm.ann_limit.load(Ordering::Relaxed);
+    if cycle_ms > budget {
+        // Aim a little under the budget so that it isn't missed again by a hair
+        let new = max(
+            MIN_LIMIT_ANNS,
+            (count as u64 * budget * 9 / 10 / cycle_ms) as u32,
+        );
+        if new < limit {
+            info!(
+                "Took {}ms to get to mining {} anns, over the budget of {}ms, using at most {} from now on",
+                cycle_ms, count, budget, new
+            );
+            bm.ann_limit.store(new, Ordering::Relaxed);
+        }
+    } else if limit != u32::MAX && cycle_ms < budget / 2 && count as u64 * 10 >= limit as u64 * 9 {
+        // Only when the limit is what held it back
+        let new = limit.saturating_add(limit / 10);
+        let new = if new >= bm.pool.max_locked() {
+            u32::MAX
+        } else {
+            new
+        };
+        info!(
+            "Took {}ms to get to mining {} anns, raising the limit to {}",
+            cycle_ms,
+            count,
+            if new == u32::MAX {
+                "all of them".to_owned()
+            } else {
+                new.to_string()
+            }
+        );
+        bm.ann_limit.store(new, Ordering::Relaxed);
+    }
+}
+
+// How well the anns serve work: what the pool pays for a block first, because a share is
+// worth the same per hash whatever the share target, then the effective share target so
+// that shares come as often as possible
+fn work_score(
+    ba: &BlkArgs,
+    work: &protocol::Work,
+    ann_min_work: u32,
+    ann_count: u32,
+) -> (f64, u32) {
+    (
+        block_payout(ba, work),
+        pc_get_effective_target(work.share_target, ann_min_work, ann_count as u64),
+    )
+}
+
+// With an alt pool, the newest work for height which the anns serve best, the main pool
+// if they are equal, it becomes the current work
+fn pick_work(
+    bm: &BlkMine,
+    work: &protocol::Work,
+    ann_min_work: u32,
+    ann_count: u32,
+) -> Option<CurrentWork> {
+    let alt = bm.alt.as_ref()?;
+    let score = |cw: &CurrentWork| work_score(&bm.ba, &cw.work, ann_min_work, ann_count);
+    // max_by() takes the last of equals
+    let best = alt
+        .work
+        .iter()
+        .rev()
+        .filter_map(|w| w.lock().unwrap().clone())
+        .filter(|cw| cw.work.height == work.height)
+        .max_by(|a, b| {
+            score(a)
+                .partial_cmp(&score(b))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })?;
+    let prev = bm.current_work.lock().unwrap().replace(best.clone());
+    if prev.map_or(true, |p| p.pool != best.pool) {
+        info!(
+            "Mining {} for {}",
+            best.work.height,
+            pool_client(bm, best.pool).url
+        );
+    }
+    bm.pool_shares[best.pool]
+        .blocks
+        .fetch_add(1, Ordering::Relaxed);
+    Some(best)
+}
+
+// Work for the height which is already being mined, from the other pool, only restarts
+// mining if the anns which are in use serve it better
+fn worth_switching(bm: &BlkMine, cw: &CurrentWork) -> bool {
+    let cur = match &*bm.current_work.lock().unwrap() {
+        Some(cur) if cur.pool != cw.pool && cur.work.height == cw.work.height => cur.work.clone(),
+        _ => return true,
+    };
+    match get_current_mining(bm) {
+        Some(cm) => {
+            work_score(&bm.ba, &cw.work, cm.ann_min_work, cm.count)
+                > work_score(&bm.ba, &cur, cm.ann_min_work, cm.count)
+        }
+        None => false,
+    }
+}
+
+fn pool_client(bm: &BlkMine, pool: usize) -> &PoolClient {
+    match &bm.alt {
+        Some(alt) if pool == 1 => &alt.pcli,
+        _ => &bm.pcli,
+    }
+}
+
+// A pool with one thread pinned to each of the cores, None if there are none
+fn core_pool(name: &'static str, cores: &[usize]) -> Result<Option<rayon::ThreadPool>> {
+    if cores.is_empty() {
+        return Ok(None);
+    }
+    let pinned = cores.to_vec();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(cores.len())
+        .thread_name(move |i| format!("{} {}", name, i))
+        .start_handler(move |i| {
+            if let Err(e) = util::set_affinity(&pinned[i..=i]) {
+                warn!("{}", e);
+            }
+        })
+        .build()?;
+    info!("Running {} on cores {:?}", name, cores);
+    Ok(Some(pool))
+}
+
+fn in_pool<T: Send>(pool: &Option<rayon::ThreadPool>, f: impl FnOnce() -> T + Send) -> T {
+    match pool {
+        Some(p) => p.install(f),
+        None => f(),
+    }
+}
+
+fn share_client(ba: &BlkArgs) -> Result<reqwest::Client> {
+    let mut b = util::client_builder()
+        .timeout(Duration::from_secs(ba.upload_timeout as u64))
+        .tcp_nodelay()
+        .pool_idle_timeout(None);
+    if ba.share_http2 {
+        b = b.http2_prior_knowledge();
+    }
+    Ok(b.build()?)
+}
+
+pub async fn new(ba: BlkArgs) -> Result<BlkMine> {
+    if ba.work_key.is_some() && !packetcrypt_sys::HAS_ED25519 {
+        bail!("--work-key needs a build with libsodium to check the signatures");
+    }
+    let share_client = share_client(&ba)?;
+    // Enough history to check the parent hash of anns already in flight when we start
+    let pcli = poolclient::new(&ba.pool_master, 8, 1, ba.pool_token.clone());
+    let block_miner = if let Some(path) = &ba.shared_anns {
+        // Besides its 1KB, each ann has a 32 byte hash in the file and 4 bytes of index
+        let lane_anns = (ba.max_mem / (1024 + 32 + 4)) as u32;
+        let lane = shared::open(path, ba.shared_lanes, lane_anns)?;
+        BlkMiner::new_shared(lane, ba.threads as u32)?
+    } else {
+        BlkMiner::new(ba.max_mem as u64, ba.threads as u32)?
+    };
+    let max_anns = block_miner.max_anns;
+    let spray = if let Some(sc) = &ba.spray_cfg {
+        Some(packetcrypt_sprayer::Sprayer::new(sc).await?)
+    } else {
+        None
+    };
+    let (send, recv) = tokio::sync::mpsc::unbounded_channel();
+    let shard_count = if !ba.sharded {
+        1
+    } else if ba.shard_mb > 0 {
+        // Anns are 1KB
+        max(1, max_anns / (ba.shard_mb as u32).saturating_mul(1024))
+    } else {
+        max(1, rayon::current_num_threads() as u32)
+    };
+    let pool = bufpool::new(
+        max_anns,
+        shard_count,
+        ((1.0 - ba.min_free_space) * max_anns as f64) as u32,
+    );
+    if let Some(f) = &ba.ann_filter {
+        info!("Keeping only anns which match [{}]", f.text());
+    }
+    if let Some(lane) = block_miner.lane() {
+        shared::recover(lane, &pool, ba.ann_class_bits);
+    }
+    if let Some(path) = &ba.checkpoint {
+        if let Err(e) = checkpoint::restore(path, &pool, &block_miner) {
+            warn!("{:?}", e);
+        }
+    }
+    let recorder = ba.record.as_deref().map(replay::record).transpose()?;
+    let ba_split_len = ba.payment_split.len();
+    let alt = ba.alt_pool.as_ref().map(|url| {
+        info!("Also mining for {}", url);
+        let (send, recv) = tokio::sync::mpsc::unbounded_channel();
+        AltPool {
+            pcli: poolclient::new(url, 8, 1, ba.pool_token.clone()),
+            work: [Mutex::new(None), Mutex::new(None)],
+            share_send: Mutex::new(send),
+            share_recv: tokio::sync::Mutex::new(recv),
+        }
+    });
+    let total: u32 = ba.payment_split.iter().map(|(_, w)| w).sum();
+    for (addr, weight) in &ba.payment_split {
+        info!(
+            "Paying {:.1}% of shares to {}",
+            *weight as f64 * 100.0 / total as f64,
+            addr
+        );
+    }
+    let bm = BlkMine(Arc::new(BlkMineS {
+        block_miner,
+        pool,
+        trees: [
+            Mutex::new(ProofTree::new(max_anns)),
+            Mutex::new(ProofTree::new(max_anns)),
+        ],
+        tree_pool: core_pool("tree", &ba.tree_cores)?,
+        intake_pool: core_pool("intake", &ba.intake_cores)?,
+        downloaders: tokio::sync::Mutex::new(Vec::new()),
+        current_mining: Mutex::new(None),
+        current_work: Mutex::new(None),
+        pool_conf: Mutex::new(protocol::MasterConf::default()),
+        work_keys: Mutex::new([ba.work_key, None]),
+        template_out: Mutex::new(None),
+        pcli,
+        alt,
+        pool_shares: Default::default(),
+        ba,
+        spray,
+        share_channel_recv: tokio::sync::Mutex::new(recv),
+        share_channel_send: Mutex::new(send),
+        share_num: AtomicUsize::new(0),
+        share_candidates: Mutex::new(Vec::new()),
+        block_hashes: Mutex::new(HashMap::new()),
+        archives_loaded: AtomicBool::new(false),
+        earnings: Mutex::new(0.0),
+        time_started_ms: util::now_ms(),
+        telemetry: telemetry::Counters::default(),
+        recorder,
+        standby: ba.standby_of.as_deref().map(Standby::new),
+        share_client,
+        last_share: Mutex::new((String::new(), 0)),
+        posted_shares: Mutex::new(HashMap::new()),
+        dup_shares: AtomicUsize::new(0),
+        ann_kinds: Mutex::new(BTreeMap::new()),
+        class_earnings: Mutex::new(BTreeMap::new()),
+        payee_diff: Mutex::new(vec![0.0; ba_split_len]),
+        newest_height: AtomicI32::new(0),
+        ann_limit: AtomicU32::new(u32::MAX),
+        block_event: Mutex::new(None),
+        block_events: tokio::sync::broadcast::channel(16).0,
+    }));
+    bm.block_miner.set_handler(bm.clone());
+    if bm.ba.mlock_trees {
+        for t in &bm.trees {
+            t.lock().unwrap().mlock()?;
+        }
+        info!("Locked proof trees in memory");
+    }
+    Ok(bm)
+}
+
+// A runtime whose only thread has realtime priority, for the tasks which have to
+// react quickly to a new block
+fn realtime_runtime(priority: i32) -> Result<tokio::runtime::Handle> {
+    let (send, recv) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let res = util::set_realtime_priority(priority).and_then(|_| {
+            Ok(tokio::runtime::Builder::new()
+                .basic_scheduler()
+                .enable_all()
+                .build()?)
+        });
+        let mut rt = match res {
+            Ok(rt) => rt,
+            Err(e) => {
+                let _ = send.send(Err(e));
+                return;
+            }
+        };
+        let _ = send.send(Ok(rt.handle().clone()));
+        rt.block_on(util::sleep_forever());
+    });
+    recv.recv()?
+}
+
+fn checkpoint_loop(bm: &BlkMine, path: &str) {
+    loop {
+        std::thread::sleep(Duration::from_secs(checkpoint::CHECKPOINT_EVERY_SECS));
+        let time_started_ms = util::now_ms();
+        match checkpoint::write(path, &bm.pool, &bm.block_miner) {
+            Ok(count) => info!(
+                "Checkpointed {} anns to {} in {}ms",
+                count,
+                path,
+                util::now_ms() - time_started_ms
+            ),
+            Err(e) => warn!("{:?}", e),
+        }
+    }
+}
+
+async fn downloader_loop(bm: &BlkMine) {
+    let mut chan = poolclient::update_chan(&bm.pcli).await;
+    let mut urls: Vec<String> = Vec::new();
+    loop {
+        let upd = match chan.recv().await {
+            Ok(x) => x,
+            Err(e) => {
                 info!("Error recv from pool client channel {}", e);
                 util::sleep_ms(5_000).await;
                 continue;
@@ -744,8 +1473,18 @@ async fn downloader_loop(bm: &BlkMine) {
                 None
             };
             for url in &upd.conf.download_ann_urls {
-                let dl =
-                    downloader::new(bm.ba.downloader_count, url.to_owned(), bm, pass.clone()).await;
+                let url = match &bm.ba.ann_proxy {
+                    Some(proxy) => annproxy::proxied_url(proxy, url),
+                    None => url.to_owned(),
+                };
+                let dl = downloader::new(
+                    bm.ba.downloader_count,
+                    url,
+                    bm,
+                    pass.clone(),
+                    bm.ba.ann_file_anns,
+                )
+                .await;
                 downloader::start(&dl).await.unwrap();
                 downloaders.push(dl);
             }
@@ -755,7 +1494,11 @@ async fn downloader_loop(bm: &BlkMine) {
     }
 }
 
-async fn update_work_cycle(bm: &BlkMine, chan: &mut tokio::sync::broadcast::Receiver<PoolUpdate>) {
+async fn update_work_cycle(
+    bm: &BlkMine,
+    pool: usize,
+    chan: &mut tokio::sync::broadcast::Receiver<PoolUpdate>,
+) {
     //debug!("Waiting for work");
     let update = if let Ok(x) = chan.recv().await {
         x
@@ -764,50 +1507,222 @@ async fn update_work_cycle(bm: &BlkMine, chan: &mut tokio::sync::broadcast::Rece
         util::sleep_ms(5_000).await;
         return;
     };
-    let work_url = format!("{}/work_{}.bin", bm.pcli.url, update.conf.current_height);
+    let pcli = pool_client(bm, pool);
+    if pool == 0 {
+        if let Some(r) = &bm.recorder {
+            r.blocks(&update.update_blocks);
+        }
+        on_update_blocks(bm, &update.update_blocks);
+    } else if bm.newest_height.load(Ordering::Relaxed) < update.conf.current_height {
+        // The alt pool has the block first
+        newer_work(bm, update.conf.current_height);
+    }
+    if bm.ba.templates.is_some() {
+        // Work comes from on_template(), only the conf is needed
+        *bm.pool_conf.lock().unwrap() = update.conf.clone();
+        if let Some(cw) = &mut *bm.current_work.lock().unwrap() {
+            cw.conf = update.conf;
+        }
+        return;
+    }
+    let work_url = format!("{}/work_{}.bin", pcli.url, update.conf.current_height);
     debug!("Getting work {}", work_url);
-    let mut work_bin = if let Ok(x) = util::get_url_bin(&work_url).await {
+    let mut work_bin = if let Ok(x) = util::get_url_bin(&work_url, &pcli.token).await {
         x
     } else {
         info!("Unable to download {}", work_url);
         util::sleep_ms(5_000).await;
         return;
     };
+    if let Err(e) = check_work_sig(bm, pool, &update.conf, &work_bin).await {
+        warn!("Not mining work {}: {}", work_url, e);
+        util::sleep_ms(5000).await;
+        return;
+    }
     let mut work = protocol::Work::default();
     if let Err(e) = protocol::work_decode(&mut work, &mut work_bin) {
         info!("Failed to deserialize work {} {:?}", work_url, e);
         util::sleep_ms(5000).await;
         return;
     };
+    // The signature is only over the work, so an old work file which was validly signed
+    // could be passed off as the current one
+    if work.height != update.conf.current_height {
+        warn!(
+            "Not mining work {}: it is for height {}",
+            work_url, work.height
+        );
+        util::sleep_ms(5000).await;
+        return;
+    }
     debug!("Got work {}", work_url);
-    bm.current_work.lock().unwrap().replace(CurrentWork {
+    if let (Some(r), 0) = (&bm.recorder, pool) {
+        r.work(&update.conf, &work);
+    }
+    let cw = CurrentWork {
         work: work.clone(),
         conf: update.conf.clone(),
-    });
+        pool,
+    };
+    if let Some(alt) = &bm.alt {
+        alt.work[pool].lock().unwrap().replace(cw.clone());
+        if !worth_switching(bm, &cw) {
+            debug!("Keeping the work being mined over {}", work_url);
+            return;
+        }
+    }
+    bm.current_work.lock().unwrap().replace(cw);
     on_work(bm, &work);
 }
 
-async fn update_work_loop(bm: &BlkMine) {
+// So that someone between the pool and the miner can't give it work which wastes its
+// hashpower or pays someone else
+async fn check_work_sig(
+    bm: &BlkMine,
+    pool: usize,
+    conf: &protocol::MasterConf,
+    work_bin: &[u8],
+) -> Result<()> {
+    // The config comes the same way as the work, so once there is a key, work which
+    // is unsigned or signed with another key is refused, whatever the config says
+    let key = {
+        let mut keys = bm.work_keys.lock().unwrap();
+        match (keys[pool], conf.work_signing_key) {
+            (Some(k), Some(ck)) if k != ck => bail!(
+                "the pool's work key changed from {} to {}, restart to accept the new key",
+                hex::encode(k),
+                hex::encode(ck)
+            ),
+            (Some(k), _) => k,
+            (None, Some(ck)) => {
+                info!("Pinning the pool's work key {}", hex::encode(ck));
+                keys[pool] = Some(ck);
+                ck
+            }
+            (None, None) => return Ok(()),
+        }
+    };
+    if !packetcrypt_sys::HAS_ED25519 {
+        bail!("the work is signed and this build has no libsodium to check it");
+    }
+    let pcli = pool_client(bm, pool);
+    let sig_url = format!("{}/work_{}.sig", pcli.url, conf.current_height);
+    let sig = match util::get_url_bin(&sig_url, &pcli.token).await {
+        Ok(sig) => sig,
+        Err(e) => bail!("unable to get signature {}: {}", sig_url, e),
+    };
+    if !packetcrypt_sys::ed25519_verify(&sig, work_bin, &key) {
+        bail!(
+            "signature {} is not valid for key {}",
+            sig_url,
+            hex::encode(key)
+        );
+    }
+    Ok(())
+}
+
+// So that a tree which is being built for older work is abandoned, not fetch_max
+// because the height can go back after a reorg
+fn newer_work(bm: &BlkMine, height: i32) {
+    bm.newest_height.store(height, Ordering::Relaxed);
+}
+
+async fn newest_height_loop(bm: &BlkMine) {
     let mut chan = poolclient::update_chan(&bm.pcli).await;
     loop {
-        update_work_cycle(bm, &mut chan).await;
+        match chan.recv().await {
+            Ok(upd) => newer_work(bm, upd.conf.current_height),
+            Err(tokio::sync::broadcast::RecvError::Lagged(_)) => (),
+            Err(tokio::sync::broadcast::RecvError::Closed) => return,
+        }
+    }
+}
+
+fn on_template(bm: &BlkMine, work: protocol::Work, out: template::Output) {
+    info!("Got block template for height {}", work.height);
+    newer_work(bm, work.height);
+    bm.template_out.lock().unwrap().replace(out);
+    let conf = bm.pool_conf.lock().unwrap().clone();
+    if let Some(r) = &bm.recorder {
+        r.work(&conf, &work);
+    }
+    bm.current_work.lock().unwrap().replace(CurrentWork {
+        work: work.clone(),
+        conf,
+        pool: 0,
+    });
+    on_work(bm, &work);
+}
+
+fn on_replay(bm: &BlkMine, ev: replay::Event) {
+    match ev {
+        replay::Event::Blocks(blocks) => on_update_blocks(bm, &blocks),
+        replay::Event::Work(conf, work) => {
+            info!("Replaying work for height {}", work.height);
+            bm.current_work.lock().unwrap().replace(CurrentWork {
+                work: work.clone(),
+                conf,
+                pool: 0,
+            });
+            on_work(bm, &work);
+        }
+        replay::Event::Downloaded(url, anns) => downloader::OnAnns::on_anns(bm, anns, &url),
+        replay::Event::Sprayed(anns) => {
+            let anns = anns.chunks(1024).collect::<Vec<_>>();
+            packetcrypt_sprayer::OnAnns::on_anns(bm, &anns);
+        }
+    }
+}
+
+async fn update_work_loop(bm: &BlkMine, pool: usize) {
+    let mut chan = poolclient::update_chan(pool_client(bm, pool)).await;
+    loop {
+        update_work_cycle(bm, pool, &mut chan).await;
     }
 }
 
 async fn stats_loop(bm: &BlkMine) {
     loop {
-        let unused = bm.inactive_infos.lock().unwrap().len();
-        let ready = bm.new_infos.lock().unwrap().len();
+        let counts = bm.pool.counts();
+        if counts.leaked() != 0 {
+            warn!(
+                "{} ann slots are unaccounted for {:?}",
+                counts.leaked(),
+                counts
+            );
+        }
+        let unused = counts.available();
         let mut downloaded: Vec<usize> = Vec::new();
         let mut downloading: Vec<usize> = Vec::new();
         let mut queued: Vec<usize> = Vec::new();
+        let (mut retrying, mut lost) = (0, 0);
         for dl in bm.downloaders.lock().await.iter() {
             let st = downloader::stats(dl, true).await;
             downloaded.push(st.downloaded);
             downloading.push(st.downloading);
             queued.push(st.queued);
+            retrying += st.retrying;
+            lost += st.lost;
+        }
+        debug!(
+            "Anns received by version/content type/signed: {}",
+            bm.ann_kinds
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(k, anns)| format!("v{}/{}/{}: {}", k.0, k.1, k.2, anns))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if lost > 0 {
+            warn!(
+                "Lost {} ann files which failed {} times, {} more to retry",
+                lost,
+                downloader::MAX_RETRIES + 1,
+                retrying
+            );
         }
-        let spr = util::pad_to(27, format!("spare: {} rdy: {} ", unused, ready));
+        let spr = util::pad_to(27, format!("spare: {} rdy: {} ", unused, counts.ready));
         let dlst = if let Some(spray) = &bm.spray {
             let st = spray.get_peer_stats();
             let v = st
@@ -824,10 +1739,12 @@ async fn stats_loop(bm: &BlkMine) {
         let start_mining = match get_current_mining(bm) {
             None => {
                 info!("Not mining{}", dlst);
+                bm.telemetry.set_hashrate(0.0);
                 true
             }
             Some(cm) => {
                 let hashrate = bm.block_miner.hashes_per_second() as f64;
+                bm.telemetry.set_hashrate(hashrate);
                 let hrm = packetcrypt_sys::difficulty::pc_get_hashrate_multiplier(
                     cm.ann_min_work,
                     cm.count as u64,
@@ -849,6 +1766,22 @@ async fn stats_loop(bm: &BlkMine) {
                 util::now_ms() - cm.time_started_ms > 45_000
             }
         };
+        if bm.alt.is_some() {
+            let pools = (0..2)
+                .map(|pool| {
+                    let ps = &bm.pool_shares[pool];
+                    format!(
+                        "{} blocks: {} shares: {}/{} ~{:.4} PKT",
+                        pool_client(bm, pool).url,
+                        ps.blocks.load(Ordering::Relaxed),
+                        ps.accepted.load(Ordering::Relaxed),
+                        ps.rejected.load(Ordering::Relaxed),
+                        *ps.earnings.lock().unwrap()
+                    )
+                })
+                .collect::<Vec<_>>();
+            info!("By pool, accepted/rejected: {}", pools.join(", "));
+        }
         if unused == 0 {
             info!("Out of buffer space, increasing --memorysizemb will improve efficiency");
         }
@@ -875,6 +1808,297 @@ async fn stats_loop(bm: &BlkMine) {
     }
 }
 
+// Size of the announcement header (everything before the merkle proof)
+const ANN_HEADER_SZ: usize = 88;
+
+#[derive(Serialize)]
+pub struct ClassEarningsSnapshot {
+    pub parent_block_height: i32,
+    pub ann_min_work: u32,
+    pub ann_min_diff: f64,
+    // Anns of this class which were in accepted shares
+    pub anns: u64,
+    // Estimated PKT earned by them
+    pub value: f64,
+}
+
+#[derive(Serialize)]
+pub struct AnnKindSnapshot {
+    pub version: u8,
+    pub content_type: u32,
+    pub signed: bool,
+    // Received since the miner started
+    pub anns: u64,
+}
+
+#[derive(Serialize)]
+pub struct AnnClassSnapshot {
+    // Which list this class is in: "active", "new" or "inactive"
+    pub list: &'static str,
+    pub parent_block_height: i32,
+    pub ann_min_work: u32,
+    pub ann_min_diff: f64,
+    // Work after degrading for age, only known if we have current work
+    pub ann_effective_work: Option<u32>,
+    // Number of AnnInfos (contiguous ranges in the slab) in this class
+    pub bufs: usize,
+    pub anns: u64,
+    // Memory locations of the AnnInfos, for use with the ann dump
+    pub mlocs: Vec<u32>,
+    // Proof tree which depends on these anns, if they are being mined
+    pub tree: Option<usize>,
+    pub mining: bool,
+}
+
+#[derive(Serialize)]
+pub struct BlkMineSnapshot {
+    pub mining_height: Option<i32>,
+    pub mining_count: u32,
+    pub work_height: Option<i32>,
+    pub free_slots: u64,
+    pub pool: bufpool::Counts,
+    pub classes: Vec<AnnClassSnapshot>,
+    // The pool's index of the classes which could be mined, as last published
+    pub class_index: Vec<bufpool::Class>,
+    // As advertised by the pool
+    pub pool_fees: protocol::PoolFees,
+    pub ann_kinds: Vec<AnnKindSnapshot>,
+    pub class_earnings: Vec<ClassEarningsSnapshot>,
+    // Pool's clock minus ours
+    pub clock_skew_ms: i64,
+}
+
+#[derive(Serialize)]
+pub struct AnnHeaderSnapshot {
+    pub mloc: u32,
+    pub hash: String,
+    pub header: String,
+}
+
+/// Summarize every class of anns (same parent block height and min work) in each list.
+pub fn snapshot(bm: &BlkMine) -> BlkMineSnapshot {
+    let cm = bm.current_mining.lock().unwrap().clone();
+    let work_height = bm
+        .current_work
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|cw| cw.work.height);
+    let mut classes: Vec<AnnClassSnapshot> = Vec::new();
+    let mut free_slots = 0;
+    // Lock one at a time because on_work() nests these locks
+    for (list, infos) in bm.pool.lists() {
+        let mining = list == "active" && cm.is_some();
+        let tree = if mining {
+            cm.as_ref().map(|cm| cm.using_tree & 1)
+        } else {
+            None
+        };
+        for ai in infos.lock().unwrap().iter() {
+            if ai.hashes.is_empty() {
+                free_slots += ai.ann_count as u64;
+                continue;
+            }
+            if let Some(c) = classes.iter_mut().find(|c| {
+                c.list == list
+                    && c.parent_block_height == ai.parent_block_height
+                    && c.ann_min_work == ai.ann_min_work
+            }) {
+                c.bufs += 1;
+                c.anns += ai.ann_count as u64;
+                c.mlocs.push(ai.mloc);
+                continue;
+            }
+            classes.push(AnnClassSnapshot {
+                list,
+                parent_block_height: ai.parent_block_height,
+                ann_min_work: ai.ann_min_work,
+                ann_min_diff: packetcrypt_sys::difficulty::tar_to_diff(ai.ann_min_work),
+                ann_effective_work: work_height.map(|h| {
+                    let age = max(0, h - ai.parent_block_height) as u32;
+                    pc_degrade_announcement_target(ai.ann_min_work, age)
+                }),
+                bufs: 1,
+                anns: ai.ann_count as u64,
+                mlocs: vec![ai.mloc],
+                tree,
+                mining,
+            });
+        }
+    }
+    classes.sort_by(|a, b| {
+        b.parent_block_height
+            .cmp(&a.parent_block_height)
+            .then_with(|| a.ann_min_work.cmp(&b.ann_min_work))
+    });
+    BlkMineSnapshot {
+        mining_height: cm.as_ref().map(|cm| cm.mining_height),
+        mining_count: cm.as_ref().map(|cm| cm.count).unwrap_or(0),
+        work_height,
+        free_slots,
+        pool: bm.pool.counts(),
+        classes,
+        class_index: bm.pool.classes().to_vec(),
+        pool_fees: bm.pool_conf.lock().unwrap().fees(),
+        ann_kinds: bm
+            .ann_kinds
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, anns)| AnnKindSnapshot {
+                version: k.0,
+                content_type: k.1,
+                signed: k.2,
+                anns: *anns,
+            })
+            .collect(),
+        class_earnings: bm
+            .class_earnings
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((height, work), e)| ClassEarningsSnapshot {
+                parent_block_height: *height,
+                ann_min_work: *work,
+                ann_min_diff: packetcrypt_sys::difficulty::tar_to_diff(*work),
+                anns: e.anns,
+                value: e.value,
+            })
+            .collect(),
+        clock_skew_ms: clock::skew_ms(),
+    }
+}
+
+/// Dump the headers of the anns in the AnnInfo which begins at mloc.
+pub fn dump_anns(bm: &BlkMine, mloc: u32) -> Option<Vec<AnnHeaderSnapshot>> {
+    let count = bm.pool.lists().iter().find_map(|(_, infos)| {
+        infos
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|ai| ai.mloc == mloc && !ai.hashes.is_empty())
+            .map(|ai| ai.ann_count)
+    })?;
+    let mut ann = [0u8; 1024];
+    Some(
+        (mloc..(mloc + count))
+            .map(|ml| {
+                bm.block_miner.get_ann(ml, &mut ann[..]);
+                AnnHeaderSnapshot {
+                    mloc: ml,
+                    hash: hex::encode(hash::compress32(&ann[..])),
+                    header: hex::encode(&ann[..ANN_HEADER_SZ]),
+                }
+            })
+            .collect(),
+    )
+}
+
+async fn handle_snapshot(bm: BlkMine) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(&snapshot(&bm)))
+}
+
+// For a standby, healthy means that this miner is mining
+fn handle_health(bm: BlkMine) -> warp::reply::WithStatus<warp::reply::Json> {
+    let mining = bm.current_mining.lock().unwrap().is_some();
+    let height = bm
+        .current_work
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|cw| cw.work.height);
+    let body = serde_json::json!({ "mining": mining, "height": height });
+    let status = if mining {
+        warp::http::StatusCode::OK
+    } else {
+        warp::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    warp::reply::with_status(warp::reply::json(&body), status)
+}
+
+async fn handle_dump_anns(mloc: u32, bm: BlkMine) -> Result<impl warp::Reply, Infallible> {
+    Ok(match dump_anns(&bm, mloc) {
+        Some(anns) => {
+            warp::reply::with_status(warp::reply::json(&anns), warp::http::StatusCode::OK)
+        }
+        None => warp::reply::with_status(
+            warp::reply::json(&format!("No anns at mloc {}", mloc)),
+            warp::http::StatusCode::NOT_FOUND,
+        ),
+    })
+}
+
+// Body is a filter like packetcrypt_blkmine::downloader=trace, empty to reset
+fn handle_put_log(body: bytes::Bytes) -> warp::reply::WithStatus<String> {
+    let filter = String::from_utf8_lossy(&body[..]);
+    match util::set_log_filter(filter.trim()) {
+        Ok(()) => warp::reply::with_status(util::log_filter(), warp::http::StatusCode::OK),
+        Err(e) => {
+            warp::reply::with_status(e.to_string(), warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// One JSON BlockEvent per message, each time mining moves to a new block
+fn handle_events(ws: warp::ws::Ws, bm: BlkMine) -> impl warp::Reply {
+    let mut events = bm.block_events.subscribe();
+    ws.on_upgrade(move |mut sock| async move {
+        loop {
+            let ev = match events.recv().await {
+                Ok(ev) => ev,
+                // A slow subscriber misses events rather than holding up the miner
+                Err(tokio::sync::broadcast::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::RecvError::Closed) => return,
+            };
+            if sock.send(warp::ws::Message::text(ev)).await.is_err() {
+                return;
+            }
+        }
+    })
+}
+
+fn start_debug_server(bm: &BlkMine) -> Result<()> {
+    let addr: SocketAddr = bm.ba.debug_bind.parse()?;
+    let with_bm = (|bm: BlkMine| warp::any().map(move || bm.clone()))(bm.clone());
+    let classes = warp::get()
+        .and(warp::path("classes"))
+        .and(warp::path::end())
+        .and(with_bm.clone())
+        .and_then(handle_snapshot);
+    let anns = warp::get()
+        .and(warp::path("anns"))
+        .and(warp::path::param::<u32>())
+        .and(warp::path::end())
+        .and(with_bm.clone())
+        .and_then(handle_dump_anns);
+    let events = warp::path("events")
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(with_bm.clone())
+        .map(handle_events);
+    let get_log = warp::get()
+        .and(warp::path("log"))
+        .and(warp::path::end())
+        .map(util::log_filter);
+    let health = warp::get()
+        .and(warp::path("health"))
+        .and(warp::path::end())
+        .and(with_bm)
+        .map(handle_health);
+    let put_log = warp::put()
+        .and(warp::path("log"))
+        .and(warp::path::end())
+        .and(warp::body::bytes())
+        .map(handle_put_log);
+    info!("Serving debug snapshots on http://{}/classes", addr);
+    tokio::spawn(async move {
+        let routes = classes.or(anns).or(health).or(events);
+        warp::serve(routes.or(get_log).or(put_log)).run(addr).await
+    });
+    Ok(())
+}
+
+const PC_TYPE_END: u64 = 0;
 const PC_TYPE_PROOF: u64 = 1;
 const PC_TYPE_VER: u64 = 4;
 const PC_VERSION: u64 = 2;
@@ -893,10 +2117,79 @@ fn share_id(block_header: &[u8], low_nonce: u32) -> u32 {
     }
 }
 
+// The protocol::BlkShare json which is posted to the block handler, kept in pieces
+// so that it can be hex encoded as it is being sent rather than all at once.
+#[derive(Clone)]
+struct ShareBody {
+    coinbase_commit: bytes::Bytes,
+    header_and_proof: Vec<bytes::Bytes>,
+}
+
+impl ShareBody {
+    fn prefix(&self) -> String {
+        format!(
+            "{{\"coinbase_commit\":\"{}\",\"header_and_proof\":\"",
+            hex::encode(&self.coinbase_commit)
+        )
+    }
+
+    const SUFFIX: &'static str = "\"}";
+
+    fn json_len(&self) -> usize {
+        let hap: usize = self.header_and_proof.iter().map(|p| p.len() * 2).sum();
+        self.prefix().len() + hap + Self::SUFFIX.len()
+    }
+
+    fn json(&self) -> String {
+        let mut out = String::with_capacity(self.json_len());
+        out.push_str(&self.prefix());
+        for p in &self.header_and_proof {
+            out.push_str(&hex::encode(p));
+        }
+        out.push_str(Self::SUFFIX);
+        out
+    }
+
+    // Each piece is encoded only when the http client is ready for it
+    fn into_json_chunks(self) -> impl Iterator<Item = bytes::Bytes> + Send + Sync + 'static {
+        std::iter::once(bytes::Bytes::from(self.prefix()))
+            .chain(
+                self.header_and_proof
+                    .into_iter()
+                    .map(|p| bytes::Bytes::from(hex::encode(&p))),
+            )
+            .chain(std::iter::once(bytes::Bytes::from_static(
+                Self::SUFFIX.as_bytes(),
+            )))
+    }
+}
+
 struct Share {
-    json: String,
+    body: ShareBody,
     handler_url: String,
     num: usize,
+    // Difficulty of the share target, i.e. what the share is worth to the pool
+    diff: f64,
+    // This share is also good enough to be a block
+    is_block: bool,
+    // If this share is a block, the full block for submitting to pktd
+    block: Option<bytes::Bytes>,
+    // Estimated value of the share in PKT
+    value: f64,
+    // Work hash, lower is better
+    hash: [u8; 32],
+    // What was being mined when the share was found, only if capturing rejected shares
+    state: Option<serde_json::Value>,
+    // Parent block height and class work of each of the anns
+    ann_classes: Vec<(i32, u32)>,
+    // Which pool's work it is, see CurrentWork
+    pool: usize,
+}
+
+#[derive(Default, Clone, Copy)]
+struct ClassEarnings {
+    anns: u64,
+    value: f64,
 }
 
 impl OnShare for BlkMine {
@@ -908,46 +2201,124 @@ impl OnShare for BlkMine {
             }
             Ok(s) => s,
         };
-        if let Err(e) = self.share_channel_send.lock().unwrap().send(s) {
-            warn!("Unable to send share to channel {}", e);
+        if let Some(ev) = &mut *self.block_event.lock().unwrap() {
+            ev.shares_found += 1;
+        }
+        // Blocks are never held back or dropped
+        if self.ba.max_shares_per_sec > 0 && !s.is_block {
+            self.share_candidates.lock().unwrap().push(s);
+        } else {
+            send_share(self, s);
         }
     }
 }
 
-fn make_share(bm: &BlkMine, share: BlkResult, dry_run: bool) -> Result<Share> {
+fn send_share(bm: &BlkMine, s: Share) {
+    let res = match &bm.alt {
+        Some(alt) if s.pool == 1 => alt.share_send.lock().unwrap().send(s),
+        _ => bm.share_channel_send.lock().unwrap().send(s),
+    };
+    if let Err(e) = res {
+        warn!("Unable to send share to channel {}", e);
+    }
+}
+
+const SHARE_CYCLE_MS: u64 = 1000;
+
+// Submit the best of the shares which were found, the work hash is little endian so
+// the last byte is the most significant.
+fn submit_best_shares(bm: &BlkMine) {
+    let mut shares = std::mem::take(&mut *bm.share_candidates.lock().unwrap());
+    shares.sort_by(|a, b| a.hash.iter().rev().cmp(b.hash.iter().rev()));
+    if shares.len() > bm.ba.max_shares_per_sec {
+        let dropped = shares.split_off(bm.ba.max_shares_per_sec);
+        info!(
+            "Found {} shares this cycle, submitting the best {} and dropping {} worth ~{:.6} PKT",
+            shares.len() + dropped.len(),
+            shares.len(),
+            dropped.len(),
+            dropped.iter().map(|s| s.value).sum::<f64>()
+        );
+    }
+    for s in shares {
+        send_share(bm, s);
+    }
+}
+
+// Every cycle, submit the best of the shares which were found in it
+async fn select_shares_loop(bm: &BlkMine) {
+    loop {
+        util::sleep_ms(SHARE_CYCLE_MS).await;
+        submit_best_shares(bm);
+    }
+}
+
+fn make_share(bm: &BlkMine, share: BlkResult, self_test: bool) -> Result<Share> {
     // Get the header and commit
-    let (mut header_and_proof, coinbase_commit, mining_height) = {
+    let (mut header, coinbase_commit, mining_height, ann_min_work, ann_count) = {
         let mut cm_l = bm.current_mining.lock().unwrap();
         let cm = match &mut *cm_l {
             Some(x) => x,
             None => bail!("no current_mining"),
         };
-        if !dry_run {
+        if !self_test {
             cm.shares += 1;
         }
         (
             cm.block_header.clone(),
             cm.coinbase_commit.clone().freeze(),
             cm.mining_height,
+            cm.ann_min_work,
+            cm.count,
         )
     };
 
     // Set the correct nonce in the header
-    header_and_proof.truncate(76);
-    header_and_proof.put_u32_le(share.high_nonce);
+    header.truncate(76);
+    header.put_u32_le(share.high_nonce);
 
-    let (share_target, handler_url) = if dry_run {
-        (0x207fffff, "dry_run".to_owned())
+    let (share_target, block_bits, handler_url, work, value, state, pool) = if self_test {
+        (0x207fffff, None, "self_test".to_owned(), None, 0.0, None, 0)
     } else {
-        let id = share_id(&header_and_proof[..], share.low_nonce) as usize;
+        let id = share_id(&header[..], share.low_nonce) as usize;
         let cw_l = bm.current_work.lock().unwrap();
         let cw = match &*cw_l {
             Some(x) => x,
             None => bail!("no current_work"),
         };
+        let urls = &cw.conf.submit_block_urls;
         (
             cw.work.share_target,
-            cw.conf.submit_block_urls[id % cw.conf.submit_block_urls.len()].clone(),
+            // The block target, even if there is no pktd to submit it to
+            if cw.work.height == mining_height {
+                Some(cw.work.header.work_bits)
+            } else {
+                None
+            },
+            // Template shares go back to the adapter, not to the pool
+            if bm.ba.templates.is_some() || urls.is_empty() {
+                String::new()
+            } else {
+                urls[id % urls.len()].clone()
+            },
+            // Only if it's the work we are actually mining
+            if bm.ba.pktd.is_some() && cw.work.height == mining_height {
+                Some(cw.work.clone())
+            } else {
+                None
+            },
+            share_value(&bm.ba, &cw.work, ann_min_work, ann_count),
+            if bm.ba.capture_dir.is_empty() {
+                None
+            } else {
+                Some(serde_json::json!({
+                    "miningHeight": mining_height,
+                    "annMinWork": ann_min_work,
+                    "annCount": ann_count,
+                    "work": template::encode(&cw.work),
+                }))
+            },
+            cw.pool,
         )
     };
 
@@ -974,18 +2345,27 @@ fn make_share(bm: &BlkMine, share: BlkResult, dry_run: bool) -> Result<Share> {
             ann
         })
         .collect::<Vec<_>>();
+    let ann_classes = anns
+        .iter()
+        .map(|a| {
+            let work = packetcrypt_sys::work_bits(a);
+            (
+                packetcrypt_sys::parent_block_height(a),
+                ann_class_work(work, bm.ba.ann_class_bits),
+            )
+        })
+        .collect();
 
     trace!("Got share / {} / {}", share.high_nonce, share.low_nonce);
-    trace!("{}", hex::encode(&header_and_proof));
-    trace!("{}", hex::encode(hash::compress32(&header_and_proof)));
+    trace!("{}", hex::encode(&header));
+    trace!("{}", hex::encode(hash::compress32(&header)));
     trace!("{}", hex::encode(&coinbase_commit));
     for (ann, i) in anns.iter().zip(0..) {
         trace!("{} - {}", share.ann_llocs[i], hex::encode(&ann[0..32]));
     }
 
-    // At this point header_and_proof is really just the block header
-    let share_n = match packetcrypt_sys::check_block_work(
-        &header_and_proof,
+    let (share_n, hash) = match packetcrypt_sys::check_block_work(
+        &header,
         share.low_nonce,
         share_target,
         &anns,
@@ -994,71 +2374,394 @@ fn make_share(bm: &BlkMine, share: BlkResult, dry_run: bool) -> Result<Share> {
         &pb,
     ) {
         Err(e) => {
-            if e.contains("INSUF_POW") && dry_run {
-                usize::MAX
+            if e.contains("INSUF_POW") && self_test {
+                (usize::MAX, [0_u8; 32])
             } else {
                 bail!("Unable to validate share [{}]", e);
             }
         }
         Ok(h) => {
-            if dry_run {
-                usize::MAX
+            if self_test {
+                (usize::MAX, h)
             } else {
                 let share_n = bm
                     .share_num
                     .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                if !dry_run {
-                    info!("[{}] Got share [{}]", share_n, hex::encode(h));
+                if !self_test {
+                    info!(
+                        "[{}] Got share [{}] worth ~{:.6} PKT",
+                        share_n,
+                        hex::encode(h),
+                        value
+                    );
                 }
-                share_n
+                (share_n, h)
             }
         }
     };
 
+    // Check whether this share is also good enough to be a block
+    let is_block = match block_bits {
+        Some(bits) if bits != share_target => packetcrypt_sys::check_block_work(
+            &header,
+            share.low_nonce,
+            bits,
+            &anns,
+            &coinbase_commit,
+            mining_height,
+            &pb,
+        )
+        .is_ok(),
+        Some(_) => true,
+        None => false,
+    };
+
     let proof_len = 4 + // low_nonce
             1024 * 4 + // anns
             pb.len(); // proof
 
-    // reserve proof space
-    header_and_proof.reserve(
+    // The proof is not copied into one buffer, the pieces are sent as they are
+    header.reserve(
         8 + // proof type + proof length
-            proof_len +
-            8, // version type + version length + version
+            4, // low_nonce
     );
-    protocol::put_varint(PC_TYPE_PROOF, &mut header_and_proof);
-    protocol::put_varint(proof_len as u64, &mut header_and_proof);
-    header_and_proof.put_u32_le(share.low_nonce);
-
-    // Put the anns in the header_and_proof
-    for ann in anns {
-        header_and_proof.put(&ann[..]);
-    }
-
-    header_and_proof.put(pb);
+    protocol::put_varint(PC_TYPE_PROOF, &mut header);
+    protocol::put_varint(proof_len as u64, &mut header);
+    header.put_u32_le(share.low_nonce);
+
+    let mut version = bytes::BytesMut::with_capacity(8);
+    protocol::put_varint(PC_TYPE_VER, &mut version);
+    protocol::put_varint(1, &mut version);
+    protocol::put_varint(PC_VERSION, &mut version);
+
+    let mut header_and_proof = Vec::with_capacity(anns.len() + 3);
+    header_and_proof.push(header.freeze());
+    header_and_proof.extend(anns.iter().map(|a| bytes::Bytes::copy_from_slice(&a[..])));
+    header_and_proof.push(pb);
+    header_and_proof.push(version.freeze());
+    let body = ShareBody {
+        coinbase_commit,
+        header_and_proof,
+    };
 
-    protocol::put_varint(PC_TYPE_VER, &mut header_and_proof);
-    protocol::put_varint(1, &mut header_and_proof);
-    protocol::put_varint(PC_VERSION, &mut header_and_proof);
+    let block = match &work {
+        Some(w) if is_block => mk_block(w, &body, share_n),
+        _ => None,
+    };
 
     Ok(Share {
-        json: serde_json::to_string(&protocol::BlkShare {
-            header_and_proof: header_and_proof.freeze(),
-            coinbase_commit,
-        })?,
+        body,
         handler_url,
         num: share_n,
+        diff: packetcrypt_sys::difficulty::tar_to_diff(share_target),
+        is_block,
+        block,
+        value,
+        hash,
+        state,
+        ann_classes,
+        pool,
     })
 }
 
+const UNITS_PER_PKT: f64 = (1_u64 << 30) as f64;
+
+// What a share is expected to earn: the chance that it is a block, times the part of
+// the block reward the pool pays out. Both targets are made effective with the anns we
+// are mining because the effective share target is capped at the minimum difficulty.
+fn share_value(ba: &BlkArgs, work: &protocol::Work, ann_min_work: u32, ann_count: u32) -> f64 {
+    use packetcrypt_sys::difficulty::tar_to_diff;
+    let payout = block_payout(ba, work);
+    if payout <= 0.0 {
+        return 0.0;
+    }
+    let eff = |tar: u32| tar_to_diff(pc_get_effective_target(tar, ann_min_work, ann_count as u64));
+    let block_diff = eff(work.header.work_bits);
+    if block_diff <= 0.0 {
+        return 0.0;
+    }
+    eff(work.share_target) / block_diff * payout
+}
+
+// The part of the block reward which the pool pays out, zero if it is unknown
+fn block_payout(ba: &BlkArgs, work: &protocol::Work) -> f64 {
+    let reward = if ba.block_reward > 0.0 {
+        ba.block_reward
+    } else {
+        match protocol::tx_output_value(&work.coinbase_no_witness) {
+            Ok(v) => v as f64 / UNITS_PER_PKT,
+            Err(e) => {
+                debug!("Unable to read block reward from coinbase: {}", e);
+                return 0.0;
+            }
+        }
+    };
+    reward * (100.0 - ba.pool_fee).max(0.0) / 100.0
+}
+
+fn add_earnings(bm: &BlkMine, share_num: usize, value: f64, pool: usize) {
+    *bm.pool_shares[pool].earnings.lock().unwrap() += value;
+    let mut e = bm.earnings.lock().unwrap();
+    *e += value;
+    bm.telemetry.set_earnings(*e);
+    let hours = (util::now_ms() - bm.time_started_ms) as f64 / 3_600_000.0;
+    info!(
+        "[{}] Estimated earnings {:.4} PKT, {:.4} PKT/h",
+        share_num,
+        *e,
+        *e / hours.max(1.0 / 60.0)
+    );
+}
+
+// Each ann in an accepted share earns its class an equal part of the share's value, so
+// that it's clear which classes are worth downloading and keeping
+fn credit_classes(bm: &BlkMine, share: &Share) {
+    let mut ce = bm.class_earnings.lock().unwrap();
+    let part = share.value / share.ann_classes.len() as f64;
+    for c in &share.ann_classes {
+        let e = ce.entry(*c).or_default();
+        e.anns += 1;
+        e.value += part;
+    }
+    // Older classes can't be mined any more
+    if let Some(newest) = ce.keys().map(|(h, _)| *h).max() {
+        ce.retain(|(h, _), _| *h > newest - BLOCK_HASH_CACHE_DEPTH);
+    }
+    let classes = share
+        .ann_classes
+        .iter()
+        .map(|(h, w)| format!("{} @ {}", h, packetcrypt_sys::difficulty::tar_to_diff(*w)))
+        .collect::<Vec<_>>();
+    debug!(
+        "[{}] Share earned {:.6} PKT for anns of [{}]",
+        share.num,
+        share.value,
+        classes.join(", ")
+    );
+}
+
+// OP_RETURN, push 36 bytes, then the BIP-141 witness commitment header
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// Whether one of the outputs of this coinbase (serialized without a witness) is a
+// segwit commitment
+fn has_witness_commitment(coinbase: &[u8]) -> Result<bool> {
+    use bytes::Buf;
+    let mut b = bytes::Bytes::copy_from_slice(coinbase);
+    let skip = |b: &mut bytes::Bytes, n: usize| {
+        if b.len() < n {
+            bail!("runt coinbase");
+        }
+        b.advance(n);
+        Ok(())
+    };
+    // version
+    skip(&mut b, 4)?;
+    if protocol::get_varint(&mut b)? != 1 {
+        bail!("coinbase does not have exactly one input");
+    }
+    // prevout, then the script and the sequence
+    skip(&mut b, 36)?;
+    let script_len = protocol::get_varint(&mut b)? as usize;
+    skip(&mut b, script_len + 4)?;
+    for _ in 0..protocol::get_varint(&mut b)? {
+        // value
+        skip(&mut b, 8)?;
+        let script_len = protocol::get_varint(&mut b)? as usize;
+        if b.len() < script_len {
+            bail!("runt coinbase");
+        }
+        if b[..script_len].starts_with(&WITNESS_COMMITMENT_HEADER) {
+            return Ok(true);
+        }
+        b.advance(script_len);
+    }
+    Ok(false)
+}
+
+// Serialize the coinbase with its witness, which is the 32 byte witness reserved value.
+// That is all zeros, the same as what pktd uses when it makes the commitment for
+// getblocktemplate.
+fn with_witness_nonce(coinbase: &[u8]) -> bytes::BytesMut {
+    let (version, rest) = coinbase.split_at(4);
+    let (body, locktime) = rest.split_at(rest.len() - 4);
+    let mut out = bytes::BytesMut::with_capacity(coinbase.len() + 36);
+    out.put(version);
+    // segwit marker and flag
+    out.put(&[0x00, 0x01][..]);
+    out.put(body);
+    // one input, with one witness item of 32 bytes
+    protocol::put_varint(1, &mut out);
+    protocol::put_varint(32, &mut out);
+    out.put(&[0_u8; 32][..]);
+    out.put(locktime);
+    out
+}
+
+// Serialize the full block, this is only possible if the coinbase is the only
+// transaction because the pool does not tell us about the others.
+fn mk_block(work: &protocol::Work, body: &ShareBody, share_n: usize) -> Option<bytes::Bytes> {
+    if !work.coinbase_merkle.is_empty() {
+        warn!(
+            "[{}] Share is a block but the work has other transactions, \
+            cannot submit it to pktd",
+            share_n
+        );
+        return None;
+    }
+    let coinbase = coinbase_with_commit(work, &body.coinbase_commit);
+    let coinbase = match has_witness_commitment(&coinbase) {
+        Ok(false) => coinbase,
+        Ok(true) => with_witness_nonce(&coinbase),
+        Err(e) => {
+            warn!(
+                "[{}] Share is a block but the coinbase could not be parsed ({}), \
+                cannot submit it to pktd",
+                share_n, e
+            );
+            return None;
+        }
+    };
+    let hap_len: usize = body.header_and_proof.iter().map(|p| p.len()).sum();
+    let mut block = bytes::BytesMut::with_capacity(hap_len + 3 + coinbase.len());
+    for p in &body.header_and_proof {
+        block.put(&p[..]);
+    }
+    protocol::put_varint(PC_TYPE_END, &mut block);
+    protocol::put_varint(0, &mut block);
+    // Number of transactions
+    protocol::put_varint(1, &mut block);
+    block.put(coinbase);
+    Some(block.freeze())
+}
+
+async fn submit_to_pktd(bm: &BlkMine, block: bytes::Bytes, share_num: usize) {
+    let pktd = if let Some(pktd) = &bm.ba.pktd {
+        pktd
+    } else {
+        return;
+    };
+    info!("[{}] Submitting block to pktd [{}]", share_num, pktd.url);
+    let timeout = Duration::from_secs(bm.ba.upload_timeout as u64);
+    match pktd::submit_block(pktd, &block, timeout).await {
+        Ok(()) => info!("[{}] BLOCK accepted by pktd [{}]", share_num, pktd.url),
+        Err(e) => warn!("[{}] Failed to submit block to pktd: {}", share_num, e),
+    }
+}
+
+fn log_dry_run_share(share: &Share) {
+    info!(
+        "[{}] DRY RUN: would post share worth {} ({} bytes) to [{}]",
+        share.num,
+        share.diff,
+        share.body.json_len(),
+        &share.handler_url
+    );
+}
+
+async fn capture_rejected(bm: &BlkMine, share: &Share, errors: &[String]) {
+    let content = serde_json::json!({
+        "shareNum": share.num,
+        "handlerUrl": share.handler_url,
+        "time": util::now_ms(),
+        "poolTime": clock::pool_now_ms(),
+        "errors": errors,
+        "state": share.state,
+        "share": serde_json::from_str::<serde_json::Value>(&share.body.json()).ok(),
+    });
+    if let Err(e) = capture::write(
+        std::path::Path::new(&bm.ba.capture_dir),
+        bm.ba.capture_max_mb as u64 * 1024 * 1024,
+        &format!("{}_{}", util::now_ms(), share.num),
+        content.to_string().as_bytes(),
+    )
+    .await
+    {
+        warn!("[{}] Unable to save rejected share: {}", share.num, e);
+    }
+}
+
+// Which address to ask for a share to be paid to. With a split, it's whoever is most
+// behind on difficulty for their weight, so everyone gets their part of the work even
+// though shares don't all have the same difficulty.
+fn pick_payee(bm: &BlkMine, diff: f64) -> &str {
+    if bm.ba.payment_split.is_empty() {
+        return &bm.ba.payment_addr;
+    }
+    let mut pd = bm.payee_diff.lock().unwrap();
+    let behind = |i: usize| pd[i] / bm.ba.payment_split[i].1 as f64;
+    let best = (0..pd.len())
+        .min_by(|a, b| {
+            behind(*a)
+                .partial_cmp(&behind(*b))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .unwrap_or(0);
+    pd[best] += diff;
+    &bm.ba.payment_split[best].0
+}
+
+const SHARE_DEDUP_MS: u64 = 5 * 60_000;
+
+// True the first time a share is seen, so that the same share found twice or sent again
+// after a slow post is only submitted once
+fn first_post(bm: &BlkMine, share: &Share) -> bool {
+    let now = util::now_ms();
+    let mut posted = bm.posted_shares.lock().unwrap();
+    posted.retain(|_, t| *t + SHARE_DEDUP_MS > now);
+    if posted.insert(share.hash, now).is_some() {
+        bm.dup_shares.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    true
+}
+
 async fn post_share(bm: &BlkMine, share: Share) -> Result<()> {
-    debug!("[{}] Posting share", share.num);
-    let res = reqwest::ClientBuilder::new()
-        .timeout(Duration::from_secs(bm.ba.upload_timeout as u64))
-        .build()?
-        .post(&share.handler_url)
-        .header("x-pc-payto", &bm.ba.payment_addr)
+    if !first_post(bm, &share) {
+        info!("[{}] Duplicate share, not posting", share.num);
+        return Ok(());
+    }
+    if bm.ba.dry_run {
+        log_dry_run_share(&share);
+        add_earnings(bm, share.num, share.value, share.pool);
+        return Ok(());
+    }
+    if bm.standby.as_ref().map_or(false, |sb| !sb.is_active()) {
+        debug!("[{}] Standby, not posting share", share.num);
+        return Ok(());
+    }
+    if let Some(block) = share.block.clone() {
+        // Don't delay submission to the pool
+        let bm = bm.clone();
+        let num = share.num;
+        tokio::spawn(async move { submit_to_pktd(&bm, block, num).await });
+    }
+    if bm.ba.templates.is_some() {
+        let out = bm.template_out.lock().unwrap().clone();
+        if let Some(out) = out {
+            template::write_share(&out, &share.body.json())?;
+            debug!("[{}] Share written to template adapter", share.num);
+        }
+        return Ok(());
+    }
+    if share.handler_url.is_empty() {
+        bail!("[{}] No block handler to post share to", share.num);
+    }
+    let payto = pick_payee(bm, share.diff);
+    debug!("[{}] Posting share for {}", share.num, payto);
+    *bm.last_share.lock().unwrap() = (share.handler_url.clone(), util::now_ms());
+    let req = util::request(&bm.share_client, reqwest::Method::POST, &share.handler_url).await?;
+    let res = util::with_token(req, &bm.ba.pool_token)
+        .header("x-pc-payto", payto)
         .header("x-pc-sver", 1)
-        .body(share.json)
+        .header(reqwest::header::CONTENT_LENGTH, share.body.json_len())
+        .body(reqwest::Body::wrap_stream(tokio::stream::iter(
+            share
+                .body
+                .clone()
+                .into_json_chunks()
+                .map(|c| Ok(c) as Result<bytes::Bytes>),
+        )))
         .send()
         .await?;
 
@@ -1081,9 +2784,13 @@ async fn post_share(bm: &BlkMine, share: Share) -> Result<()> {
         } else {
             &e
         };
+        // Skew doesn't change the work, but it's the first thing people suspect
+        let skew = clock::describe_skew()
+            .map(|d| format!(" ({})", d))
+            .unwrap_or_default();
         warn!(
-            "[{}] handler [{}] replied with error [{}]",
-            share.num, &share.handler_url, ee
+            "[{}] handler [{}] replied with error [{}]{}",
+            share.num, &share.handler_url, ee, skew
         );
     }
     for w in &reply.warn {
@@ -1092,6 +2799,21 @@ async fn post_share(bm: &BlkMine, share: Share) -> Result<()> {
             share.num, &share.handler_url, w
         );
     }
+    let ps = &bm.pool_shares[share.pool];
+    if reply.error.is_empty() {
+        bm.telemetry
+            .accepted
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        ps.accepted.fetch_add(1, Ordering::Relaxed);
+    } else {
+        bm.telemetry
+            .rejected
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        ps.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+    if !bm.ba.capture_dir.is_empty() && !reply.error.is_empty() {
+        capture_rejected(bm, &share, &reply.error).await;
+    }
     //Validate_checkBlock_INSUF_POW
     let result = match reply.result {
         protocol::MaybeBlkShareEvent::Bse(bse) => bse,
@@ -1109,6 +2831,13 @@ async fn post_share(bm: &BlkMine, share: Share) -> Result<()> {
             );
         }
     };
+    if reply.error.is_empty() {
+        if let Some(ev) = &mut *bm.block_event.lock().unwrap() {
+            ev.shares_accepted += 1;
+        }
+        add_earnings(bm, share.num, share.value, share.pool);
+        credit_classes(bm, &share);
+    }
     if let Some(hash) = result.header_hash {
         info!("[{}] BLOCK [{}]", share.num, hash);
     } else {
@@ -1122,42 +2851,312 @@ async fn post_share(bm: &BlkMine, share: Share) -> Result<()> {
     Ok(())
 }
 
-async fn get_share_loop(bm: &BlkMine) {
+// If no share has been posted for a while, the connection is probably about to be
+// closed as idle and the next share would wait for a new one, so poke the handler.
+const SHARE_WARM_MS: u64 = 20_000;
+async fn share_warm_loop(bm: &BlkMine) {
+    loop {
+        util::sleep_ms(SHARE_WARM_MS).await;
+        let (url, last_ms) = bm.last_share.lock().unwrap().clone();
+        if url.is_empty() || util::now_ms().saturating_sub(last_ms) < SHARE_WARM_MS {
+            continue;
+        }
+        let res = match util::request(&bm.share_client, reqwest::Method::HEAD, &url).await {
+            Ok(req) => util::with_token(req, &bm.ba.pool_token)
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            debug!("Unable to keep connection to [{}] warm: {}", url, e);
+        }
+    }
+}
+
+async fn get_share_loop(bm: &BlkMine, pool: usize) {
+    let recv = match &bm.alt {
+        Some(alt) if pool == 1 => &alt.share_recv,
+        _ => &bm.share_channel_recv,
+    };
     loop {
-        let share = if let Some(s) = bm.share_channel_recv.lock().await.recv().await {
+        let share = if let Some(s) = recv.lock().await.recv().await {
             s
         } else {
             warn!("Got a none from the receiver");
             continue;
         };
         if let Err(e) = post_share(bm, share).await {
+            bm.telemetry
+                .errors
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             warn!("{}", e);
         }
     }
 }
 
+impl Stats for BlkMineS {
+    fn component(&self) -> &'static str {
+        "blkmine"
+    }
+    fn metrics(&self) -> Vec<Metric> {
+        let counts = self.pool.counts();
+        let (anns, height) = match &*self.current_mining.lock().unwrap() {
+            Some(cm) => (cm.count, cm.mining_height),
+            None => (0, 0),
+        };
+        let t = &self.telemetry;
+        let mut out = vec![
+            Metric::gauge("hashrate", "Hashes per second", t.hashrate()),
+            Metric::gauge("earnings", "Estimated PKT earned", t.earnings()),
+            Metric::counter(
+                "share_errors_total",
+                "Shares which could not be sent",
+                t.errors.load(Ordering::Relaxed) as u64,
+            ),
+            Metric::counter(
+                "duplicate_shares_total",
+                "Shares which were found again and not posted",
+                self.dup_shares.load(Ordering::Relaxed) as u64,
+            ),
+            Metric::gauge("anns_mining", "Anns in the block being mined", anns as f64),
+            Metric::gauge(
+                "mining_height",
+                "Height being mined, 0 if none",
+                height as f64,
+            ),
+            Metric::gauge(
+                "ann_slots_ready",
+                "Anns ready to be mined",
+                counts.ready as f64,
+            ),
+            Metric::gauge(
+                "ann_slots_available",
+                "Space for more anns",
+                counts.available() as f64,
+            ),
+        ];
+        let mut pools = vec![&self.pcli.url];
+        if let Some(alt) = &self.alt {
+            pools.push(&alt.pcli.url);
+        }
+        for (ps, url) in self.pool_shares.iter().zip(pools) {
+            let c = |v: &AtomicUsize| v.load(Ordering::Relaxed) as u64;
+            out.extend(
+                vec![
+                    Metric::counter("blocks_total", "Blocks worked on", c(&ps.blocks)),
+                    Metric::counter("shares_accepted_total", "Shares accepted", c(&ps.accepted)),
+                    Metric::counter("shares_rejected_total", "Shares rejected", c(&ps.rejected)),
+                ]
+                .into_iter()
+                .map(|m| m.label("pool", url)),
+            );
+        }
+        out
+    }
+}
+
 impl BlkMine {
     pub async fn start(&self) -> Result<()> {
-        for _ in 0..self.ba.uploaders {
+        stats::register(&self.0);
+        if self.ba.dry_run {
+            warn!("Dry run mode, shares will be logged but NOT submitted to the pool");
+        }
+        if !self.ba.debug_bind.is_empty() {
+            start_debug_server(self)?;
+        }
+        let rt = if self.ba.realtime_priority > 0 {
+            info!(
+                "Uploading shares and updating work at realtime priority {}",
+                self.ba.realtime_priority
+            );
+            Some(realtime_runtime(self.ba.realtime_priority)?)
+        } else {
+            None
+        };
+        let critical = |f: &dyn Fn()| match &rt {
+            Some(h) => h.enter(f),
+            None => f(),
+        };
+        critical(&|| {
+            for i in 0..self.ba.uploaders {
+                let a = self.clone();
+                tasks::spawn(format!("share upload {}", i), Restart::Always, move || {
+                    let a = a.clone();
+                    async move { get_share_loop(&a, 0).await }
+                });
+                if self.alt.is_some() {
+                    let a = self.clone();
+                    tasks::spawn(
+                        format!("alt share upload {}", i),
+                        Restart::Always,
+                        move || {
+                            let a = a.clone();
+                            async move { get_share_loop(&a, 1).await }
+                        },
+                    );
+                }
+            }
+            if !self.ba.dry_run && self.ba.templates.is_none() {
+                let a = self.clone();
+                tasks::spawn("share warm", Restart::Always, move || {
+                    let a = a.clone();
+                    async move { share_warm_loop(&a).await }
+                });
+            }
+        });
+        if self.ba.max_shares_per_sec > 0 {
             let a = self.clone();
-            tokio::spawn(async move { get_share_loop(&a).await });
+            tasks::spawn("share select", Restart::Always, move || {
+                let a = a.clone();
+                async move { select_shares_loop(&a).await }
+            });
         }
         {
             let a = self.clone();
-            tokio::spawn(async move { update_work_loop(&a).await });
+            tasks::spawn("blkmine stats", Restart::Always, move || {
+                let a = a.clone();
+                async move { stats_loop(&a).await }
+            });
+        }
+        if self.standby.is_some() {
+            let a = self.clone();
+            tasks::spawn("standby", Restart::Always, move || {
+                let a = a.clone();
+                async move { standby::health_loop(a.standby.as_ref().unwrap()).await }
+            });
         }
+        if let Some(path) = self.ba.checkpoint.clone() {
+            // Reading all of the anns takes a while so it gets its own thread
+            let a = self.clone();
+            std::thread::spawn(move || checkpoint_loop(&a, &path));
+        }
+        if let Some(path) = &self.ba.replay {
+            // Nothing comes from the pool
+            let a = self.clone();
+            return replay::start(path, Box::new(move |ev| on_replay(&a, ev)));
+        }
+        if let Some(src) = &self.ba.templates {
+            let a = self.clone();
+            template::start(src, Arc::new(move |w, o| on_template(&a, w, o)))?;
+        }
+        critical(&|| {
+            let a = self.clone();
+            tasks::spawn("update work", Restart::Always, move || {
+                let a = a.clone();
+                async move { update_work_loop(&a, 0).await }
+            });
+            if self.alt.is_some() {
+                let a = self.clone();
+                tasks::spawn("alt update work", Restart::Always, move || {
+                    let a = a.clone();
+                    async move { update_work_loop(&a, 1).await }
+                });
+            }
+            if self.ba.templates.is_none() {
+                // With templates, the pool's height has nothing to do with the work
+                let a = self.clone();
+                tasks::spawn("newest height", Restart::Always, move || {
+                    let a = a.clone();
+                    async move { newest_height_loop(&a).await }
+                });
+            }
+        });
         if let Some(spray) = &self.spray {
             spray.set_handler(self.clone());
             spray.start();
         } else {
             let a = self.clone();
-            tokio::spawn(async move { downloader_loop(&a).await });
+            tasks::spawn("downloader", Restart::Always, move || {
+                let a = a.clone();
+                async move { downloader_loop(&a).await }
+            });
         }
-        {
+        if telemetry::enabled() {
             let a = self.clone();
-            tokio::spawn(async move { stats_loop(&a).await });
+            tasks::spawn("telemetry", Restart::Always, move || {
+                let a = a.clone();
+                async move {
+                    telemetry::report_loop(&a.pcli, "blk", &a.ba.payment_addr, &a.telemetry).await
+                }
+            });
+        }
+        if let Some(dir) = &self.ba.state_dir {
+            let (a, dir) = (self.clone(), dir.clone());
+            tasks::spawn("history", Restart::Always, move || {
+                let (a, dir) = (a.clone(), dir.clone());
+                async move { history::record_loop(&dir, "blk", &a.pcli.url, &a.telemetry).await }
+            });
         }
         poolclient::start(&self.pcli).await;
+        if let Some(alt) = &self.alt {
+            poolclient::start(&alt.pcli).await;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{has_witness_commitment, with_witness_nonce, ShareBody};
+    use packetcrypt_util::protocol;
+
+    #[test]
+    fn test_witness_nonce() {
+        let mut cb = vec![1, 0, 0, 0, 1];
+        cb.extend_from_slice(&[0; 36]);
+        // script, sequence
+        cb.extend_from_slice(&[2, 0x51, 0x51, 0xff, 0xff, 0xff, 0xff]);
+        // two outputs, the second is the commitment
+        cb.push(2);
+        cb.extend_from_slice(&[0; 8]);
+        cb.extend_from_slice(&[1, 0x51]);
+        cb.extend_from_slice(&[0; 8]);
+        cb.push(38);
+        cb.extend_from_slice(&super::WITNESS_COMMITMENT_HEADER);
+        cb.extend_from_slice(&[0x11; 32]);
+        let no_commit = cb.len() - 47;
+        // locktime
+        cb.extend_from_slice(&[0; 4]);
+
+        assert!(has_witness_commitment(&cb).unwrap());
+        let mut plain = cb[..no_commit].to_vec();
+        plain[no_commit - 11] = 1;
+        plain.extend_from_slice(&[0; 4]);
+        assert!(!has_witness_commitment(&plain).unwrap());
+        assert!(has_witness_commitment(&cb[..20]).is_err());
+
+        let w = with_witness_nonce(&cb);
+        assert_eq!(w.len(), cb.len() + 36);
+        assert_eq!(&w[4..6], &[0x00, 0x01]);
+        assert_eq!(&w[6..(cb.len() - 2)], &cb[4..(cb.len() - 4)]);
+        assert_eq!(
+            &w[(w.len() - 38)..(w.len() - 4)],
+            &[&[1, 32][..], &[0; 32][..]].concat()[..]
+        );
+    }
+
+    #[test]
+    fn test_share_body_json() {
+        let pieces: Vec<bytes::Bytes> = vec![
+            vec![0xab; 80].into(),
+            vec![0x01; 1024].into(),
+            bytes::Bytes::new(),
+            vec![0xff, 0x00, 0x7f].into(),
+        ];
+        let body = ShareBody {
+            coinbase_commit: vec![0x6a, 0x30, 0x09].into(),
+            header_and_proof: pieces.clone(),
+        };
+        let expected = serde_json::to_string(&protocol::BlkShare {
+            coinbase_commit: body.coinbase_commit.clone(),
+            header_and_proof: pieces.concat().into(),
+        })
+        .unwrap();
+        assert_eq!(body.json(), expected);
+        assert_eq!(body.json_len(), expected.len());
+        let streamed = body.into_json_chunks().collect::<Vec<_>>().concat();
+        assert_eq!(streamed, expected.as_bytes());
+    }
+}
@@ -1,6 +1,8 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
-use anyhow::{bail, Result};
-use packetcrypt_sys::{BlockMine_Res_t, BlockMine_t};
+use crate::shared::Lane;
+use anyhow::{anyhow, Context, Result};
+use packetcrypt_sys::{BlockMine_Create_t, BlockMine_Res_t, BlockMine_t};
+use packetcrypt_util::exit::Fatal;
 use std::ffi::CStr;
 use std::os::raw::{c_char, c_int, c_void};
 use std::pin::Pin;
@@ -41,6 +43,8 @@ pub struct BlkMiner {
     cbc: Pin<Box<CallbackCtx>>,
     miner: *mut BlockMine_t,
     pub max_anns: u32,
+    // If the anns are in shared memory, dropped after the miner is destroyed
+    lane: Option<Lane>,
 }
 unsafe impl Send for BlkMiner {}
 unsafe impl Sync for BlkMiner {}
@@ -62,32 +66,60 @@ unsafe fn mk_str(ptr: *const c_char) -> &'static str {
 
 impl BlkMiner {
     pub fn new(maxmem: u64, threads: u32) -> Result<BlkMiner> {
+        Self::create(None, |ptr| unsafe {
+            packetcrypt_sys::BlockMine_create(maxmem, threads as c_int, Some(on_share_found), ptr)
+        })
+    }
+    /// Mine the anns in a lane of shared ann memory, see shared.rs
+    pub fn new_shared(lane: Lane, threads: u32) -> Result<BlkMiner> {
+        let (anns, max_anns) = (lane.anns(), lane.ann_count());
+        Self::create(Some(lane), |ptr| unsafe {
+            packetcrypt_sys::BlockMine_createShared(
+                anns as *mut packetcrypt_sys::PacketCrypt_Announce_t,
+                max_anns,
+                threads as c_int,
+                Some(on_share_found),
+                ptr,
+            )
+        })
+    }
+    fn create(
+        lane: Option<Lane>,
+        mk: impl FnOnce(*mut c_void) -> BlockMine_Create_t,
+    ) -> Result<BlkMiner> {
         let mut cbc = Box::pin(CallbackCtx {
             handler: RwLock::new(None),
         });
         let ptr = (&mut *cbc as *mut CallbackCtx) as *mut c_void;
         let (max_anns, miner) = unsafe {
-            let res = packetcrypt_sys::BlockMine_create(
-                maxmem,
-                threads as c_int,
-                Some(on_share_found),
-                ptr,
-            );
+            let res = mk(ptr);
             match res.miner.as_mut() {
                 Some(miner) => (miner.maxAnns, miner),
-                None => bail!(
-                    "Failed to create block miner: During [{}] got [{}]",
-                    mk_str(res.stage),
-                    mk_str(res.err),
-                ),
+                None => {
+                    let stage = mk_str(res.stage);
+                    let kind = match stage {
+                        "mmap()" | "malloc()" => Fatal::Memory,
+                        _ => Fatal::CLib,
+                    };
+                    return Err(anyhow!(
+                        "Failed to create block miner: During [{}] got [{}]",
+                        stage,
+                        mk_str(res.err),
+                    ))
+                    .context(kind);
+                }
             }
         };
         Ok(BlkMiner {
             cbc,
             miner,
             max_anns,
+            lane,
         })
     }
+    pub fn lane(&self) -> Option<&Lane> {
+        self.lane.as_ref()
+    }
     pub fn set_handler(&self, handler: impl OnShare) {
         self.cbc.handler.write().unwrap().replace(Box::new(handler));
     }
@@ -100,6 +132,9 @@ impl BlkMiner {
         unsafe {
             packetcrypt_sys::BlockMine_updateAnn(self.miner, index, ann.as_ptr());
         }
+        if let Some(lane) = &self.lane {
+            lane.commit(index, ann);
+        }
     }
     pub fn hashes_per_second(&self) -> i64 {
         unsafe { packetcrypt_sys::BlockMine_getHashesPerSecond(self.miner) }
@@ -0,0 +1,581 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! The pool of ann slots in the block miner's slab. Everything which wants space for
+//! anns gets it from alloc() and hands it back with place(), and only reload() moves
+//! slots in and out of mining. Each slot is always in one of these states:
+//!
+//! * free: never used, or holding nothing worth keeping
+//! * spare: holding anns which are not being mined and may be overwritten
+//! * ready: holding newly loaded anns, waiting for the next reload
+//! * locked: holding anns which are being mined, these must not be touched
+//! * taken: given out by alloc() and not yet placed
+//!
+//! The counts of each are kept as slots move, if they ever stop adding up to the size
+//! of the slab then slots have been lost.
+//!
+//! There is also an index of how many anns there are of each class (parent block height
+//! and min work) which could be mined, so choosing what to mine only needs to look at
+//! the classes rather than every AnnInfo. The index is changed under its lock but read
+//! from a snapshot which is published whenever a class is added or removed and on each
+//! reload(), so readers never wait on the intake threads and always see every class
+//! as it was at one moment. Counts in the snapshot can be behind by the anns which
+//! have come and gone in a class since it was published.
+//!
+//! On each reload() the AnnInfos which go back to the shards are compacted, neighbours
+//! in memory with the same kind of content become one.
+use arc_swap::ArcSwap;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub struct FreeInfo {
+    // Number of anns at this location
+    pub ann_count: u32,
+
+    // Location of the ann in the memory slab
+    pub mloc: u32,
+}
+
+#[derive(Clone, Default)]
+pub struct AnnInfo {
+    // Parent block height for this batch of anns
+    pub parent_block_height: i32,
+
+    // Work for this batch, this is the least work of any ann in it
+    pub ann_min_work: u32,
+
+    // Effective work for this batch, temporary and used when sorting active_infos
+    pub ann_effective_work: u32,
+
+    // Number of anns or ann slots at this memory location
+    pub ann_count: u32,
+
+    // Location of the ann in the memory slab
+    pub mloc: u32,
+
+    // Hashes of anns, empty if this represents a block of free space
+    pub hashes: Vec<[u8; 32]>,
+}
+
+struct Shard {
+    // Free space and discards from last mining lock
+    inactive_infos: Mutex<Vec<AnnInfo>>,
+
+    // Newly added, not yet selected for mining
+    new_infos: Mutex<Vec<AnnInfo>>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Class {
+    pub parent_block_height: i32,
+    pub ann_min_work: u32,
+    // Anns of this class which are spare, ready or locked
+    pub ann_count: u32,
+}
+
+// Newest blocks and then most work first
+type ClassKey = (std::cmp::Reverse<i32>, u32);
+
+fn class_key(ai: &AnnInfo) -> ClassKey {
+    (std::cmp::Reverse(ai.parent_block_height), ai.ann_min_work)
+}
+
+#[derive(Serialize, Clone, Copy, Default, Debug)]
+pub struct Counts {
+    pub capacity: u32,
+    pub free: u32,
+    pub spare: u32,
+    pub ready: u32,
+    pub locked: u32,
+    pub taken: u32,
+}
+impl Counts {
+    /// Slots which can be given out by alloc()
+    pub fn available(&self) -> u32 {
+        self.free + self.spare
+    }
+    /// Slots which are not accounted for, anything but zero is a bug
+    pub fn leaked(&self) -> i64 {
+        self.capacity as i64
+            - (self.free + self.spare + self.ready + self.locked + self.taken) as i64
+    }
+}
+
+pub struct BufPool {
+    // Shard n owns the memory from n * shard_size up to (n + 1) * shard_size
+    shards: Vec<Shard>,
+    shard_size: u32,
+
+    // Currently in use mining (do not touch these anns)
+    active_infos: Mutex<Vec<AnnInfo>>,
+
+    // Maximum number of anns which we allow to mine at a time
+    // This should be less than the size of the slab in order to allow
+    // new anns to be added while mining is ongoing
+    max_locked: u32,
+
+    capacity: u32,
+    free: AtomicU32,
+    spare: AtomicU32,
+    ready: AtomicU32,
+    locked: AtomicU32,
+    taken: AtomicU32,
+
+    // Always locked after the shard locks
+    classes: Mutex<BTreeMap<ClassKey, u32>>,
+
+    // What is in classes, newest and most work first
+    published: ArcSwap<Vec<Class>>,
+}
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+thread_local! {
+    static SHARD_NUM: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A pool of capacity slots starting from mloc 0, split into shard_count shards.
+pub fn new(capacity: u32, shard_count: u32, max_locked: u32) -> BufPool {
+    let shard_size = capacity / shard_count;
+    let shards = (0..shard_count)
+        .map(|i| {
+            let mloc = i * shard_size;
+            // The last shard gets the remainder
+            let ann_count = if i + 1 == shard_count {
+                capacity - mloc
+            } else {
+                shard_size
+            };
+            Shard {
+                inactive_infos: Mutex::new(vec![AnnInfo {
+                    parent_block_height: 0,
+                    ann_min_work: 0,
+                    ann_effective_work: 0,
+                    ann_count,
+                    mloc,
+                    hashes: Vec::new(),
+                }]),
+                new_infos: Mutex::new(Vec::new()),
+            }
+        })
+        .collect::<Vec<_>>();
+    if shard_count > 1 {
+        info!(
+            "Intake split into {} shards of {} anns",
+            shard_count, shard_size
+        );
+    }
+    BufPool {
+        shards,
+        shard_size,
+        active_infos: Mutex::new(Vec::new()),
+        max_locked,
+        capacity,
+        free: AtomicU32::new(capacity),
+        spare: AtomicU32::new(0),
+        ready: AtomicU32::new(0),
+        locked: AtomicU32::new(0),
+        taken: AtomicU32::new(0),
+        classes: Mutex::new(BTreeMap::new()),
+        published: ArcSwap::from_pointee(Vec::new()),
+    }
+}
+
+// Returns true if the class is gone
+fn class_sub(classes: &mut BTreeMap<ClassKey, u32>, key: ClassKey, count: u32) -> bool {
+    if let Some(c) = classes.get_mut(&key) {
+        *c = c.saturating_sub(count);
+        if *c == 0 {
+            classes.remove(&key);
+            return true;
+        }
+    }
+    false
+}
+
+// Partial takes by alloc() leave pieces of AnnInfos behind and intake which lands in
+// scattered slots makes small ones, so join those which are next to each other in
+// memory and hold nothing or the same class of anns. Otherwise the lists only grow
+// and everything which walks them or sizes its output by their length does more.
+fn compact(infos: &mut Vec<AnnInfo>) {
+    infos.sort_by_key(|ai| ai.mloc);
+    let mut out: Vec<AnnInfo> = Vec::with_capacity(infos.len());
+    for ai in infos.drain(..) {
+        if let Some(last) = out.last_mut() {
+            let joinable = if ai.hashes.is_empty() {
+                last.hashes.is_empty()
+            } else {
+                class_key(last) == class_key(&ai)
+                    && last.hashes.len() == last.ann_count as usize
+                    && ai.hashes.len() == ai.ann_count as usize
+            };
+            if joinable && last.mloc + last.ann_count == ai.mloc {
+                last.ann_count += ai.ann_count;
+                last.hashes.extend(ai.hashes);
+                continue;
+            }
+        }
+        out.push(ai);
+    }
+    *infos = out;
+}
+
+fn class_list(classes: &BTreeMap<ClassKey, u32>) -> Vec<Class> {
+    classes
+        .iter()
+        .map(|((h, w), c)| Class {
+            parent_block_height: h.0,
+            ann_min_work: *w,
+            ann_count: *c,
+        })
+        .collect()
+}
+
+impl BufPool {
+    // Each thread sticks to one shard
+    fn my_shard(&self) -> &Shard {
+        &self.shards[SHARD_NUM.with(|n| *n) % self.shards.len()]
+    }
+
+    fn shard_of(&self, mloc: u32) -> usize {
+        std::cmp::min((mloc / self.shard_size) as usize, self.shards.len() - 1)
+    }
+
+    pub fn max_locked(&self) -> u32 {
+        self.max_locked
+    }
+
+    /// Take free space or poor quality anns which are not currently being mined.
+    /// This might not return the number of slots you want, it can even return none
+    /// if there is no space available. Our own shard is used first. The reserve is
+    /// spread evenly over the shards. Everything returned must be given to place().
+    pub fn alloc(&self, mut count: u32, reserve: u32) -> Vec<FreeInfo> {
+        let first = SHARD_NUM.with(|n| *n);
+        let shard_reserve = reserve / self.shards.len() as u32;
+        let mut out = Vec::new();
+        for i in 0..self.shards.len() {
+            let shard = &self.shards[(first + i) % self.shards.len()];
+            let mut inactive_l = shard.inactive_infos.lock().unwrap();
+            if shard_reserve > 0 {
+                let avail: u32 = inactive_l.iter().map(|ai| ai.ann_count).sum();
+                let want = std::cmp::min(count, avail.saturating_sub(shard_reserve));
+                count -= want - self.take_free(&mut inactive_l, want, &mut out);
+            } else {
+                count = self.take_free(&mut inactive_l, count, &mut out);
+            }
+            if count == 0 {
+                break;
+            }
+        }
+        out
+    }
+
+    // Returns the number which are still wanted, the inactive lock must be held so
+    // that reload() sees consistent counts
+    fn take_free(
+        &self,
+        inactive_l: &mut Vec<AnnInfo>,
+        mut count: u32,
+        out: &mut Vec<FreeInfo>,
+    ) -> u32 {
+        loop {
+            if count == 0 {
+                return count;
+            }
+            let fi = if let Some(mut ai) = inactive_l.pop() {
+                let (was, key) = if ai.hashes.is_empty() {
+                    (&self.free, None)
+                } else {
+                    (&self.spare, Some(class_key(&ai)))
+                };
+                if ai.ann_count > count {
+                    // Split the AnnInfo, taking the low mloc's and leaving the high ones
+                    let fi = FreeInfo {
+                        ann_count: count,
+                        mloc: ai.mloc,
+                    };
+                    ai.mloc += count;
+                    ai.ann_count -= count;
+                    if ai.hashes.len() > count as usize {
+                        // remove the first n hashes so that the AnnInfo returned
+                        // is still valid
+                        ai.hashes.drain(0..(count as usize)).count();
+                    }
+                    inactive_l.push(ai);
+                    count = 0;
+                    was.fetch_sub(fi.ann_count, Ordering::Relaxed);
+                    fi
+                } else {
+                    count -= ai.ann_count;
+                    was.fetch_sub(ai.ann_count, Ordering::Relaxed);
+                    FreeInfo {
+                        ann_count: ai.ann_count,
+                        mloc: ai.mloc,
+                    }
+                }
+            } else {
+                return count;
+            };
+            self.taken.fetch_add(fi.ann_count, Ordering::Relaxed);
+            if let Some(key) = key {
+                let mut classes = self.classes.lock().unwrap();
+                if class_sub(&mut classes, key, fi.ann_count) {
+                    self.publish(&classes);
+                }
+            }
+            out.push(fi);
+        }
+    }
+
+    /// Give back the slots from alloc() once the anns have been written into them,
+    /// taken is the total number of slots which alloc() returned and info is the
+    /// anns which are now in them.
+    pub fn place(&self, info: &mut Vec<AnnInfo>, taken: u32) {
+        let landed: u32 = info.iter().map(|ai| ai.ann_count).sum();
+        {
+            let mut new_l = self.my_shard().new_infos.lock().unwrap();
+            let mut classes = self.classes.lock().unwrap();
+            let mut added = false;
+            for ai in info.iter() {
+                let c = classes.entry(class_key(ai)).or_insert(0);
+                added |= *c == 0;
+                *c += ai.ann_count;
+            }
+            if added {
+                self.publish(&classes);
+            }
+            new_l.append(info);
+            self.ready.fetch_add(landed, Ordering::Relaxed);
+            self.taken.fetch_sub(taken, Ordering::Relaxed);
+        }
+        if landed != taken {
+            warn!(
+                "Allocated {} ann slots but placed {}, slots have been lost",
+                taken, landed
+            );
+        }
+    }
+
+    /// Give back slots from alloc() which are not going to be used after all, they
+    /// become free again.
+    pub fn unalloc(&self, free: Vec<FreeInfo>) {
+        for fi in free {
+            let mut inactive_l = self.shards[self.shard_of(fi.mloc)]
+                .inactive_infos
+                .lock()
+                .unwrap();
+            inactive_l.push(AnnInfo {
+                ann_count: fi.ann_count,
+                mloc: fi.mloc,
+                ..Default::default()
+            });
+            self.free.fetch_add(fi.ann_count, Ordering::Relaxed);
+            self.taken.fetch_sub(fi.ann_count, Ordering::Relaxed);
+        }
+    }
+
+    // Must be called with the classes lock held so that snapshots are published in order
+    fn publish(&self, classes: &BTreeMap<ClassKey, u32>) {
+        self.published.store(Arc::new(class_list(classes)));
+    }
+
+    /// The latest snapshot of the classes, newest and most work first
+    pub fn classes(&self) -> Arc<Vec<Class>> {
+        self.published.load_full()
+    }
+
+    /// Lock the anns which are being mined, this must be held across reload()
+    pub fn lock_active(&self) -> MutexGuard<'_, Vec<AnnInfo>> {
+        self.active_infos.lock().unwrap()
+    }
+
+    /// Collect every AnnInfo which is not taken, choose() is given them along with
+    /// the classes, newest and most work first. It orders the AnnInfos and returns how
+    /// many from the front are to be mined, these go into active_l and the rest go
+    /// back to the shard which owns their memory.
+    pub fn reload(
+        &self,
+        active_l: &mut Vec<AnnInfo>,
+        choose: impl FnOnce(&mut Vec<AnnInfo>, &[Class]) -> usize,
+    ) {
+        // Lets avoid unlocking inactive until we've re-added entries to it because
+        // otherwise a call to alloc() will have no free space
+        let mut inactive_ls = self
+            .shards
+            .iter()
+            .map(|s| s.inactive_infos.lock().unwrap())
+            .collect::<Vec<_>>();
+        let mut new_ls = self
+            .shards
+            .iter()
+            .map(|s| s.new_infos.lock().unwrap())
+            .collect::<Vec<_>>();
+
+        let mut v = Vec::with_capacity(
+            inactive_ls
+                .iter()
+                .chain(new_ls.iter())
+                .map(|l| l.len())
+                .sum::<usize>()
+                + active_l.len(),
+        );
+        for l in inactive_ls.iter_mut().chain(new_ls.iter_mut()) {
+            v.append(l);
+        }
+        v.append(active_l);
+
+        // With every shard locked, nothing else can change the classes so they are
+        // exact here
+        let mut classes_l = self.classes.lock().unwrap();
+        self.publish(&classes_l);
+        let best_i = choose(&mut v, &self.classes());
+
+        // Rebuilt from scratch so that any mistake in the index does not last
+        classes_l.clear();
+        let (mut free, mut spare, mut locked) = (0, 0, 0);
+        for (i, elem) in (0..).zip(v.drain(..)) {
+            if !elem.hashes.is_empty() {
+                *classes_l.entry(class_key(&elem)).or_insert(0) += elem.ann_count;
+            }
+            if i >= best_i {
+                // Give it back to the shard which owns the memory
+                if elem.hashes.is_empty() {
+                    free += elem.ann_count;
+                } else {
+                    spare += elem.ann_count;
+                }
+                inactive_ls[self.shard_of(elem.mloc)].push(elem);
+            } else {
+                locked += elem.ann_count;
+                active_l.push(elem);
+            }
+        }
+        self.publish(&classes_l);
+        drop(classes_l);
+        // This is important because if we keep inactive sorted
+        for inactive_l in inactive_ls.iter_mut() {
+            compact(inactive_l);
+            inactive_l.sort_by(|b, a| a.parent_block_height.cmp(&b.parent_block_height));
+        }
+        self.free.store(free, Ordering::Relaxed);
+        self.spare.store(spare, Ordering::Relaxed);
+        self.ready.store(0, Ordering::Relaxed);
+        self.locked.store(locked, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> Counts {
+        Counts {
+            capacity: self.capacity,
+            free: self.free.load(Ordering::Relaxed),
+            spare: self.spare.load(Ordering::Relaxed),
+            ready: self.ready.load(Ordering::Relaxed),
+            locked: self.locked.load(Ordering::Relaxed),
+            taken: self.taken.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Every list of AnnInfos, named "active", "new" or "inactive"
+    pub fn lists(&self) -> Vec<(&'static str, &Mutex<Vec<AnnInfo>>)> {
+        let mut out = vec![("active", &self.active_infos)];
+        out.extend(self.shards.iter().map(|s| ("new", &s.new_infos)));
+        out.extend(self.shards.iter().map(|s| ("inactive", &s.inactive_infos)));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compact, new, AnnInfo, FreeInfo};
+
+    fn info(parent_block_height: i32, mloc: u32, ann_count: u32, has_anns: bool) -> AnnInfo {
+        AnnInfo {
+            parent_block_height,
+            ann_min_work: 0x200fffff,
+            ann_count,
+            mloc,
+            hashes: if has_anns {
+                vec![[0u8; 32]; ann_count as usize]
+            } else {
+                Vec::new()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn total(fis: &[FreeInfo]) -> u32 {
+        fis.iter().map(|fi| fi.ann_count).sum()
+    }
+
+    #[test]
+    fn test_counts() {
+        let bp = new(100, 1, 50);
+        assert_eq!(bp.counts().free, 100);
+
+        let fis = bp.alloc(30, 0);
+        assert_eq!(total(&fis), 30);
+        let c = bp.counts();
+        assert_eq!((c.free, c.taken, c.leaked()), (70, 30, 0));
+
+        let mut placed = fis
+            .iter()
+            .map(|fi| info(5, fi.mloc, fi.ann_count, true))
+            .collect();
+        bp.place(&mut placed, 30);
+        let c = bp.counts();
+        assert_eq!((c.ready, c.taken, c.leaked()), (30, 0, 0));
+        assert_eq!(bp.classes().len(), 1);
+        assert_eq!(bp.classes()[0].ann_count, 30);
+
+        let mut active = bp.lock_active();
+        bp.reload(&mut active, |v, classes| {
+            assert_eq!(classes.len(), 1);
+            v.sort_by_key(|ai| ai.hashes.is_empty());
+            v.iter().take_while(|ai| !ai.hashes.is_empty()).count()
+        });
+        drop(active);
+        let c = bp.counts();
+        assert_eq!((c.free, c.locked, c.ready, c.leaked()), (70, 30, 0, 0));
+
+        // Locked anns are never given out
+        let fis = bp.alloc(100, 0);
+        assert_eq!(total(&fis), 70);
+        assert_eq!(bp.counts().available(), 0);
+
+        // Placing less than was allocated shows up as leaked
+        bp.place(&mut vec![info(6, fis[0].mloc, 60, true)], 70);
+        assert_eq!(bp.counts().leaked(), 10);
+    }
+
+    #[test]
+    fn test_reserve() {
+        // Each shard keeps half of the reserve back
+        let bp = new(100, 2, 50);
+        let fis = bp.alloc(100, 40);
+        assert_eq!(total(&fis), 60);
+        assert_eq!(bp.counts().free, 40);
+        assert_eq!(total(&bp.alloc(100, 40)), 0);
+        assert_eq!(total(&bp.alloc(100, 0)), 40);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut infos = vec![
+            info(0, 10, 5, false),
+            info(5, 17, 3, true),
+            info(0, 0, 10, false),
+            info(5, 15, 2, true),
+            info(6, 20, 1, true),
+            info(5, 21, 1, true),
+            info(0, 30, 1, false),
+        ];
+        compact(&mut infos);
+        let got = infos
+            .iter()
+            .map(|ai| (ai.mloc, ai.ann_count, ai.hashes.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            got,
+            vec![(0, 15, 0), (15, 5, 5), (20, 1, 1), (21, 1, 1), (30, 1, 0)]
+        );
+    }
+}
@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Shares which the pool rejects, saved for looking at later. The directory is kept
+//! under a size limit by deleting the oldest captures, a capture which alone is over
+//! the limit is not saved.
+use anyhow::{bail, Result};
+use log::{debug, info};
+use std::path::Path;
+
+const PREFIX: &str = "reject_";
+
+pub async fn write(dir: &Path, max_bytes: u64, name: &str, content: &[u8]) -> Result<()> {
+    if content.len() as u64 > max_bytes {
+        bail!(
+            "capture of {} bytes is more than the limit of {}",
+            content.len(),
+            max_bytes
+        );
+    }
+    tokio::fs::create_dir_all(dir).await?;
+    let path = dir.join(format!("{}{}.json", PREFIX, name));
+    tokio::fs::write(&path, content).await?;
+    info!("Rejected share saved to {}", path.display());
+
+    let mut files = Vec::new();
+    let mut rd = tokio::fs::read_dir(dir).await?;
+    while let Some(f) = rd.next_entry().await? {
+        if !f.file_name().to_string_lossy().starts_with(PREFIX) {
+            continue;
+        }
+        let md = f.metadata().await?;
+        files.push((md.modified()?, md.len(), f.path()));
+    }
+    files.sort();
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    for (_, len, p) in files {
+        if total <= max_bytes {
+            break;
+        }
+        // Never the one just written, even if its time sorts it among the old ones
+        if p == path {
+            continue;
+        }
+        debug!("Deleting old capture {}", p.display());
+        tokio::fs::remove_file(&p).await?;
+        total -= len;
+    }
+    Ok(())
+}
@@ -0,0 +1,311 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Checkpoint of the anns in memory along with their classes and hashes, so that a
+//! block miner which restarts has something to mine right away instead of waiting to
+//! download and classify gigabytes of anns again. The file is:
+//!
+//! ```text
+//! MAGIC | record | record | ...
+//! record: parent_block_height: i32 | ann_min_work: u32 | ann_count: u32 |
+//!         hashes: [32 bytes] * ann_count | anns: [1024 bytes] * ann_count
+//! ```
+//!
+//! Numbers are little endian, each record is one AnnInfo.
+//!
+//! A checkpoint from another run or another machine can also be loaded as an archive
+//! with --ann-archive, it is mapped read-only and never written. The anns still have to
+//! be copied into the miner's memory because that is the only place the miner can mine
+//! them from, but they are copied straight from the mapping. Since the file may have
+//! come from anywhere nothing in it but the anns is used: their hashes and classes
+//! are worked out again from their bytes and each one is checked with check_ann(), so
+//! anns whose parent block the miner doesn't know yet are left out.
+use crate::blkmine::ann_class_work;
+use crate::blkminer::BlkMiner;
+use crate::bufpool::{AnnInfo, BufPool};
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use packetcrypt_util::{hash, util};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+
+const MAGIC: &[u8; 8] = b"pcblkck1";
+
+// How often the checkpoint is rewritten
+pub const CHECKPOINT_EVERY_SECS: u64 = 300;
+
+// More than this in one record means the file is corrupt
+const MAX_RECORD_ANNS: u32 = 1 << 24;
+
+fn write_file(path: &str, infos: &[AnnInfo], miner: &BlkMiner) -> Result<usize> {
+    let tmp = format!("{}.tmp", path);
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    out.write_all(MAGIC)?;
+    let mut ann = vec![0u8; 1024];
+    let mut anns = Vec::new();
+    let mut count = 0;
+    'infos: for ai in infos {
+        anns.clear();
+        for (i, h) in ai.hashes.iter().enumerate() {
+            miner.get_ann(ai.mloc + i as u32, &mut ann);
+            // The slots might have been reused since the infos were copied
+            if &hash::compress32(&ann) != h {
+                debug!("Anns at {} changed while checkpointing", ai.mloc);
+                continue 'infos;
+            }
+            anns.extend_from_slice(&ann);
+        }
+        out.write_all(&ai.parent_block_height.to_le_bytes())?;
+        out.write_all(&ai.ann_min_work.to_le_bytes())?;
+        out.write_all(&(ai.hashes.len() as u32).to_le_bytes())?;
+        for h in &ai.hashes {
+            out.write_all(h)?;
+        }
+        out.write_all(&anns)?;
+        count += ai.hashes.len();
+    }
+    out.flush()?;
+    drop(out);
+    std::fs::rename(&tmp, path)?;
+    Ok(count)
+}
+
+/// Write the checkpoint, replacing the old one only once the new one is complete.
+pub fn write(path: &str, pool: &BufPool, miner: &BlkMiner) -> Result<usize> {
+    // Copy the infos so that nothing is locked while the anns are read
+    let mut infos = Vec::new();
+    for (_, l) in pool.lists() {
+        let l = l.lock().unwrap();
+        infos.extend(l.iter().filter(|ai| !ai.hashes.is_empty()).cloned());
+    }
+    write_file(path, &infos, miner).with_context(|| format!("Unable to write checkpoint {}", path))
+}
+
+fn read_u32(r: &mut impl Read) -> std::io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+// None at the end of the file
+fn read_record(r: &mut impl Read) -> Result<Option<(AnnInfo, Vec<u8>)>> {
+    let parent_block_height = match read_u32(r) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        x => x? as i32,
+    };
+    let ann_min_work = read_u32(r)?;
+    let ann_count = read_u32(r)?;
+    if ann_count > MAX_RECORD_ANNS {
+        bail!("record of {} anns", ann_count);
+    }
+    let mut hashes = vec![[0u8; 32]; ann_count as usize];
+    for h in &mut hashes {
+        r.read_exact(h)?;
+    }
+    let mut anns = vec![0u8; ann_count as usize * 1024];
+    r.read_exact(&mut anns)?;
+    let ai = AnnInfo {
+        parent_block_height,
+        ann_min_work,
+        ann_effective_work: u32::MAX,
+        ann_count,
+        mloc: 0,
+        hashes,
+    };
+    Ok(Some((ai, anns)))
+}
+
+// Place the anns in free space, as much as there is
+fn place(pool: &BufPool, miner: &BlkMiner, ai: AnnInfo, anns: &[u8]) -> usize {
+    let free = pool.alloc(ai.ann_count, 0);
+    let taken = free.iter().map(|fi| fi.ann_count).sum();
+    let mut infos = Vec::with_capacity(free.len());
+    let mut ann_i = 0;
+    for fi in free {
+        for i in 0..fi.ann_count as usize {
+            let ann = &anns[(ann_i + i) * 1024..(ann_i + i + 1) * 1024];
+            miner.put_ann(fi.mloc + i as u32, ann);
+        }
+        infos.push(AnnInfo {
+            parent_block_height: ai.parent_block_height,
+            ann_min_work: ai.ann_min_work,
+            ann_effective_work: u32::MAX,
+            ann_count: fi.ann_count,
+            mloc: fi.mloc,
+            hashes: ai.hashes[ann_i..ann_i + fi.ann_count as usize].to_vec(),
+        });
+        ann_i += fi.ann_count as usize;
+    }
+    pool.place(&mut infos, taken);
+    ann_i
+}
+
+/// Load the anns from a checkpoint, if there is one.
+pub fn restore(path: &str, pool: &BufPool, miner: &BlkMiner) -> Result<()> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            info!("No checkpoint at {}, starting empty", path);
+            return Ok(());
+        }
+        Err(e) => bail!("Unable to open checkpoint {}: {}", path, e),
+    };
+    let mut r = BufReader::new(file);
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)
+        .with_context(|| format!("Checkpoint {} is empty", path))?;
+    if &magic != MAGIC {
+        bail!("{} is not a checkpoint", path);
+    }
+    let time_started_ms = util::now_ms();
+    let (mut count, mut landed) = (0, 0);
+    loop {
+        let (ai, anns) = match read_record(&mut r) {
+            Ok(Some(x)) => x,
+            Ok(None) => break,
+            Err(e) => {
+                // Keep what was read, the rest will be downloaded again
+                warn!("Checkpoint {} is truncated: {}", path, e);
+                break;
+            }
+        };
+        count += ai.ann_count as usize;
+        landed += place(pool, miner, ai, &anns);
+    }
+    info!(
+        "Restored {} of {} anns from checkpoint {} in {}ms",
+        landed,
+        count,
+        path,
+        util::now_ms() - time_started_ms
+    );
+    Ok(())
+}
+
+fn take<'a>(b: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if b.len() < len {
+        bail!("{} bytes left, expecting {}", b.len(), len);
+    }
+    let (out, rest) = b.split_at(len);
+    *b = rest;
+    Ok(out)
+}
+
+fn take_u32(b: &mut &[u8]) -> Result<u32> {
+    Ok(u32::from_le_bytes(take(b, 4)?.try_into().unwrap()))
+}
+
+// Like read_record() but the anns are left in the mapping, None at the end
+fn map_record<'a>(b: &mut &'a [u8]) -> Result<Option<(AnnInfo, &'a [u8])>> {
+    if b.is_empty() {
+        return Ok(None);
+    }
+    let parent_block_height = take_u32(b)? as i32;
+    let ann_min_work = take_u32(b)?;
+    let ann_count = take_u32(b)?;
+    if ann_count > MAX_RECORD_ANNS {
+        bail!("record of {} anns", ann_count);
+    }
+    let hashes = take(b, ann_count as usize * 32)?
+        .chunks_exact(32)
+        .map(|h| h.try_into().unwrap())
+        .collect::<Vec<[u8; 32]>>();
+    let anns = take(b, ann_count as usize * 1024)?;
+    let ai = AnnInfo {
+        parent_block_height,
+        ann_min_work,
+        ann_effective_work: u32::MAX,
+        ann_count,
+        mloc: 0,
+        hashes,
+    };
+    Ok(Some((ai, anns)))
+}
+
+/// Load the anns from a checkpoint which was made by another run, without changing it.
+/// Only anns which pass check_ann() with a block hash from parent_hashes are loaded.
+pub fn load_archive(
+    path: &str,
+    pool: &BufPool,
+    miner: &BlkMiner,
+    class_bits: u32,
+    parent_hashes: &HashMap<i32, [u8; 32]>,
+) -> Result<()> {
+    let map = util::MappedFile::open(path)
+        .with_context(|| format!("Unable to open ann archive {}", path))?;
+    let mut b = map.bytes();
+    if b.len() < MAGIC.len() || &b[..MAGIC.len()] != MAGIC {
+        bail!("{} is not a checkpoint", path);
+    }
+    b = &b[MAGIC.len()..];
+    let time_started_ms = util::now_ms();
+    let (mut count, mut landed, mut bad) = (0, 0, 0);
+    loop {
+        let anns = match map_record(&mut b) {
+            Ok(Some((_, anns))) => anns,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Ann archive {} is truncated: {}", path, e);
+                break;
+            }
+        };
+        count += anns.len() / 1024;
+        // None if the ann can't be checked or is invalid
+        let checked = anns
+            .par_chunks(1024)
+            .map_init(
+                || None,
+                |vctx, ann| {
+                    let height = packetcrypt_sys::parent_block_height(ann);
+                    let pbh = parent_hashes.get(&height)?;
+                    let pca = packetcrypt_sys::PacketCryptAnn {
+                        bytes: util::aligned_bytes(ann, 4),
+                    };
+                    let vctx = vctx.get_or_insert_with(packetcrypt_sys::ValidateCtx::default);
+                    packetcrypt_sys::check_ann(&pca, pbh, vctx).ok()?;
+                    let work = ann_class_work(packetcrypt_sys::work_bits(ann), class_bits);
+                    Some(((height, work), hash::compress32(ann)))
+                },
+            )
+            .collect::<Vec<_>>();
+        // Each run of good anns of one class is one AnnInfo
+        let mut i = 0;
+        while i < checked.len() {
+            let class = if let Some((class, _)) = checked[i] {
+                class
+            } else {
+                bad += 1;
+                i += 1;
+                continue;
+            };
+            let start = i;
+            while i < checked.len() && checked[i].map(|(c, _)| c) == Some(class) {
+                i += 1;
+            }
+            let ai = AnnInfo {
+                parent_block_height: class.0,
+                ann_min_work: class.1,
+                ann_effective_work: u32::MAX,
+                ann_count: (i - start) as u32,
+                mloc: 0,
+                hashes: checked[start..i].iter().map(|c| c.unwrap().1).collect(),
+            };
+            landed += place(pool, miner, ai, &anns[start * 1024..i * 1024]);
+        }
+    }
+    if bad > 0 {
+        warn!(
+            "{} anns in archive {} are invalid or their parent block is not known",
+            bad, path
+        );
+    }
+    info!(
+        "Loaded {} of {} anns from archive {} in {}ms",
+        landed,
+        count,
+        path,
+        util::now_ms().saturating_sub(time_started_ms)
+    );
+    Ok(())
+}
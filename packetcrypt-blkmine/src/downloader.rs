@@ -1,27 +1,60 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
 use anyhow::{bail, format_err, Result};
 use log::{debug, info};
+use packetcrypt_util::backoff::Backoff;
 use packetcrypt_util::protocol::AnnIndex;
-use packetcrypt_util::util;
-use std::collections::VecDeque;
-use std::sync::Arc;
+use packetcrypt_util::{tasks, util};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 use tokio::sync::{broadcast, Mutex};
 
 // Maximum number of files to queue for download, prevents memory leak if
 // the miner cannot keep up with the ann handlers.
 const MAX_QUEUE_LENGTH: usize = 5_000;
 
+// How long to keep an idle keep-alive connection to a handler open
+const IDLE_CONN_TIMEOUT_SECS: u64 = 90;
+
+// A file which fails to download is tried again this many times, waiting twice as long
+// each time starting from RETRY_MS, so a hiccup in the handler doesn't leave a hole.
+pub const MAX_RETRIES: u32 = 3;
+const RETRY_MS: u64 = 5_000;
+
+// Waits before getting the index again when the handler can't be reached
+const INDEX_RETRY_MIN_MS: u64 = 10_000;
+const INDEX_RETRY_MAX_MS: u64 = 120_000;
+
+// Anns are passed on in chunks of this many as they arrive, rather than when the
+// whole file is in, so that big files on slow links start being used sooner
+const STREAM_ANNS: usize = 256;
+
 #[derive(Clone)]
 pub struct Stats {
     pub downloading: usize,
     pub downloaded: usize,
     pub queued: usize,
+    pub retrying: usize,
+    // Files which failed MAX_RETRIES times
+    pub lost: usize,
+}
+
+struct Retry {
+    file: String,
+    tries: u32,
+    retry_at_ms: u64,
+    // Bytes at the start of the file which were already passed on
+    skip: usize,
 }
 
 struct DownloaderM {
     downloading: usize,
     downloaded: usize,
     to_download: VecDeque<String>,
+    to_retry: VecDeque<Retry>,
+    // Files in the last index, ones which are not there anymore are not retried
+    listed: HashSet<String>,
+    lost: usize,
     stop: bool,
 }
 
@@ -34,7 +67,13 @@ pub struct DownloaderS<T: OnAnns> {
     downloader_count: usize,
     url_base: String,
     handler_pass: Option<String>,
+    // Ask the handler to group its files so that each one has about this many anns
+    file_anns: usize,
     m: Mutex<DownloaderM>,
+
+    // Shared by all workers for this handler so that connections are kept alive
+    // and reused rather than making a new TCP (and TLS) handshake per file.
+    client: reqwest::Client,
 }
 pub type Downloader<T> = Arc<DownloaderS<T>>;
 
@@ -55,16 +94,54 @@ async fn done_downloading<T: OnAnns>(apw: &AhPollWorker<T>, success: bool) {
     }
 }
 
+// Queue a file which failed to be tried again, unless it has had all of its tries
+async fn retry_later<T: OnAnns>(apw: &AhPollWorker<T>, file: String, tries: u32, skip: usize) {
+    let mut ahp_l = apw.ahp.m.lock().await;
+    if tries >= MAX_RETRIES {
+        info!(
+            "Giving up on {}/anns/{} after {} tries",
+            apw.url_base,
+            file,
+            tries + 1
+        );
+        ahp_l.lost += 1;
+        return;
+    }
+    ahp_l.to_retry.push_back(Retry {
+        file,
+        tries: tries + 1,
+        retry_at_ms: util::now_ms() + (RETRY_MS << tries),
+        skip,
+    });
+}
+
+// The next file which is due to be retried, files which the handler has since
+// deleted are dropped
+fn next_retry(m: &mut DownloaderM) -> Option<(String, u32, usize)> {
+    let now = util::now_ms();
+    while let Some(r) = m.to_retry.front() {
+        if r.retry_at_ms > now {
+            return None;
+        }
+        let r = m.to_retry.pop_front()?;
+        if m.listed.contains(&r.file) {
+            return Some((r.file, r.tries, r.skip));
+        }
+        debug!("Not retrying {}, it is no longer in the index", r.file);
+    }
+    None
+}
+
 async fn get_url_bin(
     url: &str,
     ignore_statuses: &[u16],
     client: &reqwest::Client,
-    passwd: &Option<String>,
+    headers: &[(&str, String)],
 ) -> Result<Option<bytes::Bytes>> {
     loop {
-        let mut req = client.get(url);
-        if let Some(p) = passwd {
-            req = req.header("x-pc-passwd", p);
+        let mut req = util::request(client, reqwest::Method::GET, url).await?;
+        for (k, v) in headers {
+            req = req.header(*k, v);
         }
         let res = req.send().await?;
         return match res.status() {
@@ -83,6 +160,59 @@ async fn get_url_bin(
     }
 }
 
+// Download a file of anns and pass them to on_anns in chunks of whole anns as they
+// arrive, false if the status was one of ignore_statuses. The first skip bytes were
+// passed on by an earlier try so they are not again, delivered counts what has been
+// passed on so far.
+async fn stream_anns(
+    url: &str,
+    ignore_statuses: &[u16],
+    client: &reqwest::Client,
+    headers: &[(&str, String)],
+    skip: usize,
+    delivered: &mut usize,
+    on_anns: impl Fn(bytes::Bytes),
+) -> Result<bool> {
+    let mut res = loop {
+        let mut req = util::request(client, reqwest::Method::GET, url).await?;
+        for (k, v) in headers {
+            req = req.header(*k, v);
+        }
+        let res = req.send().await?;
+        match res.status() {
+            reqwest::StatusCode::OK => break res,
+            reqwest::StatusCode::MULTIPLE_CHOICES => continue,
+            st if ignore_statuses.contains(&st.as_u16()) => return Ok(false),
+            st => bail!("Status code was {:?}", st),
+        }
+    };
+    let mut buf = bytes::BytesMut::new();
+    let mut received = 0;
+    while let Some(chunk) = res.chunk().await? {
+        let already = skip.saturating_sub(received).min(chunk.len());
+        received += chunk.len();
+        buf.extend_from_slice(&chunk[already..]);
+        if buf.len() >= STREAM_ANNS * 1024 {
+            let whole = buf.len() - buf.len() % 1024;
+            *delivered += whole;
+            on_anns(buf.split_to(whole).freeze());
+        }
+    }
+    let whole = buf.len() - buf.len() % 1024;
+    if whole > 0 {
+        *delivered += whole;
+        on_anns(buf.split_to(whole).freeze());
+    }
+    if !buf.is_empty() {
+        // Cut off in the middle of an ann, the retry picks up from the last whole one
+        bail!(
+            "{} bytes of a partial ann at the end of the file",
+            buf.len()
+        );
+    }
+    Ok(true)
+}
+
 async fn poll_ann_handler_worker<T: OnAnns>(mut apw: AhPollWorker<T>) {
     let worker_id = format!("Ann dl worker [{} {}]", apw.url_base, apw.worker_num);
     loop {
@@ -92,7 +222,9 @@ async fn poll_ann_handler_worker<T: OnAnns>(mut apw: AhPollWorker<T>) {
                 info!("{} got stop request", worker_id);
                 return;
             }
-            let x = ahp_l.to_download.pop_back();
+            // Retries first, they have been waiting the longest
+            let x =
+                next_retry(&mut ahp_l).or_else(|| ahp_l.to_download.pop_back().map(|f| (f, 0, 0)));
             if x.is_some() {
                 ahp_l.downloading += 1;
             }
@@ -100,45 +232,87 @@ async fn poll_ann_handler_worker<T: OnAnns>(mut apw: AhPollWorker<T>) {
         } {
             to_dl
         } else {
-            let _ = apw.wakeup.recv().await;
+            if let Err(broadcast::RecvError::Closed) = apw.wakeup.recv().await {
+                // The index loader is gone, if it is restarted then it makes new workers
+                info!("{} index loader exited", worker_id);
+                return;
+            }
             continue;
         };
+        let (to_dl, tries, skip) = to_dl;
         let url = format!("{}/anns/{}", apw.url_base, to_dl);
         //debug!("get {} ...", url);
-        let bin = match get_url_bin(&url, &[404, 405], &apw.client, &apw.handler_pass).await {
+        let headers = apw
+            .handler_pass
+            .iter()
+            .map(|p| ("x-pc-passwd", p.clone()))
+            .collect::<Vec<_>>();
+        let mut delivered = skip;
+        let on_anns = |bin| apw.ahp.onanns.on_anns(bin, &url);
+        let found = match stream_anns(
+            &url,
+            &[404, 405],
+            &apw.client,
+            &headers,
+            skip,
+            &mut delivered,
+            on_anns,
+        )
+        .await
+        {
             Ok(x) => x,
             Err(e) => {
-                // We will not try to re-download the file because it might be gone
                 info!("error downloading {}: {}", url, e);
                 done_downloading(&apw, false).await;
+                retry_later(&apw, to_dl, tries, delivered).await;
                 continue;
             }
         };
         done_downloading(&apw, true).await;
-        if let Some(bin) = bin {
-            //debug!("get {} done (ok)", url);
-            apw.ahp.onanns.on_anns(bin, &url);
-        } else {
+        if !found {
             debug!("get {} done (not found)", url);
         }
     }
 }
 
+// The number of the newest file in anns_<n>.bin or anns_<first>-<last>.bin
+fn file_num(name: &str) -> Option<i64> {
+    let nums = name.strip_prefix("anns_")?.strip_suffix(".bin")?;
+    nums.rsplit('-').next()?.parse().ok()
+}
+
 async fn poll_ann_handlers<T: OnAnns + 'static>(downloader: &Downloader<T>) {
-    let (wakeup_tx, _) = broadcast::channel(32);
+    let wakeup_tx = Arc::new(broadcast::channel(32).0);
     for worker_num in 0..downloader.downloader_count {
-        let apw = AhPollWorker {
-            url_base: downloader.url_base.clone(),
-            handler_pass: downloader.handler_pass.clone(),
-            worker_num,
-            ahp: Arc::clone(downloader),
-            wakeup: wakeup_tx.subscribe(),
-            client: reqwest::Client::builder().build().unwrap(),
-        };
-        tokio::spawn(async move { poll_ann_handler_worker(apw).await });
+        let dl = Arc::clone(downloader);
+        // Only the index loader holds the sender, so that when it exits the workers see
+        // the channel close rather than waiting on it forever
+        let wakeup_tx: Weak<broadcast::Sender<()>> = Arc::downgrade(&wakeup_tx);
+        tasks::spawn(
+            format!("ann download {} {}", downloader.url_base, worker_num),
+            tasks::Restart::OnPanic,
+            move || {
+                let apw = wakeup_tx.upgrade().map(|tx| AhPollWorker {
+                    url_base: dl.url_base.clone(),
+                    handler_pass: dl.handler_pass.clone(),
+                    worker_num,
+                    ahp: Arc::clone(&dl),
+                    wakeup: tx.subscribe(),
+                    client: dl.client.clone(),
+                });
+                async move {
+                    if let Some(apw) = apw {
+                        poll_ann_handler_worker(apw).await
+                    }
+                }
+            },
+        );
     }
     let index_url = format!("{}/anns/index.json", downloader.url_base);
-    let mut top_file: Option<String> = None;
+    // The newest file which has been queued, after a short disconnect the handler is
+    // asked for just the files after it
+    let mut have: Option<i64> = None;
+    let mut backoff = Backoff::new(&index_url, INDEX_RETRY_MIN_MS, INDEX_RETRY_MAX_MS);
     loop {
         if downloader.m.lock().await.stop {
             info!(
@@ -147,53 +321,85 @@ async fn poll_ann_handlers<T: OnAnns + 'static>(downloader: &Downloader<T>) {
             );
             return;
         }
+        let mut index_headers = Vec::new();
+        if downloader.file_anns > 0 {
+            index_headers.push(("x-pc-file-anns", downloader.file_anns.to_string()));
+        }
+        if let Some(h) = have {
+            index_headers.push(("x-pc-since", h.to_string()));
+        }
         debug!("Getting index {}", index_url);
-        let text = match util::get_url_text(&index_url).await {
-            Ok(res) => res,
+        let bin = match get_url_bin(&index_url, &[], &downloader.client, &index_headers).await {
+            Ok(Some(res)) => res,
+            Ok(None) => {
+                info!("Ann index [{}] not found", index_url);
+                backoff.wait().await;
+                continue;
+            }
             Err(e) => {
-                info!("Unable to reach ann index [{}] because [{}]", index_url, e);
-                util::sleep_ms(10_000).await;
+                if !backoff.is_open() {
+                    info!("Unable to reach ann index [{}] because [{}]", index_url, e);
+                }
+                backoff.wait().await;
                 continue;
             }
         };
-        let mut ai = match serde_json::from_str::<AnnIndex>(text.as_str()) {
+        let mut ai = match serde_json::from_slice::<AnnIndex>(&bin) {
             Err(e) => {
-                info!("Failed to deserialize ann index {:?} {:?}", text, e);
-                util::sleep_ms(10_000).await;
+                info!(
+                    "Failed to deserialize ann index {:?} {:?}",
+                    String::from_utf8_lossy(&bin),
+                    e
+                );
+                backoff.wait().await;
                 continue;
             }
             Ok(r) => r,
         };
+        backoff.success();
         {
             let mut ahp_l = downloader.m.lock().await;
             let mut new_files = 0;
-            let mut seek_to = if let Some(tf) = &top_file {
-                let mut seek_to = None;
-                //debug!("Top file was {}", tf);
-                for f in ai.files.iter().rev() {
-                    if f == tf {
-                        //debug!("Found seek_to {}", tf);
-                        seek_to = Some(tf.clone());
-                        break;
-                    }
+            match (have, ai.lowest_ann_file) {
+                (Some(h), _) if ai.highest_ann_file >= 0 && ai.highest_ann_file < h => {
+                    info!(
+                        "Ann files from {} start again at {}, getting all of them",
+                        downloader.url_base, ai.highest_ann_file
+                    );
+                    have = None;
+                }
+                (Some(h), Some(lo)) if lo > h + 1 => {
+                    info!(
+                        "Ann files {} to {} are gone from {}, some anns were missed",
+                        h + 1,
+                        lo - 1,
+                        downloader.url_base
+                    );
                 }
-                seek_to
-            } else {
-                None
-            };
-            if let Some(f) = ai.files.last() {
-                top_file = Some(f.clone());
-                //debug!("Top file is {}, Seeking to {:?}", f, seek_to);
+                _ => (),
             }
-            for f in ai.files.drain(..) {
-                if let Some(st) = &seek_to {
-                    if st != &f {
-                        continue;
-                    }
-                    seek_to = None;
+            for f in &ai.files {
+                let new = match (file_num(f), have) {
+                    (Some(n), Some(h)) => n > h,
+                    (Some(_), None) => true,
+                    // Not numbered, only new if it wasn't in the last index
+                    (None, _) => !ahp_l.listed.contains(f),
+                };
+                if new {
+                    ahp_l.to_download.push_back(f.clone());
+                    new_files += 1;
                 }
-                ahp_l.to_download.push_back(f);
-                new_files += 1;
+            }
+            // Handlers which know x-pc-since only list the new files, the older ones
+            // are still there if they're not older than lowest_ann_file
+            let lowest = ai.lowest_ann_file;
+            ahp_l.listed.retain(|f| match (file_num(f), lowest) {
+                (Some(n), Some(lo)) => n >= lo,
+                _ => false,
+            });
+            ahp_l.listed.extend(ai.files.drain(..));
+            if ai.highest_ann_file >= 0 {
+                have = Some(have.map_or(ai.highest_ann_file, |h| h.max(ai.highest_ann_file)));
             }
             loop {
                 // Prevent the queue from growing forever
@@ -202,7 +408,12 @@ async fn poll_ann_handlers<T: OnAnns + 'static>(downloader: &Downloader<T>) {
                 }
                 ahp_l.to_download.pop_front();
             }
-            if new_files > 0 {
+            // Make sure a worker is awake for the retries too
+            let retry_due = ahp_l
+                .to_retry
+                .front()
+                .map_or(false, |r| r.retry_at_ms <= util::now_ms());
+            if new_files > 0 || retry_due {
                 debug!(
                     "Queued {} new files from {}",
                     new_files, downloader.url_base
@@ -222,6 +433,7 @@ pub async fn new<T>(
     url_base: String,
     onanns: &T,
     handler_pass: Option<String>,
+    file_anns: usize,
 ) -> Downloader<T>
 where
     T: OnAnns + 'static + Clone,
@@ -231,10 +443,19 @@ where
         url_base,
         onanns: onanns.clone(),
         handler_pass,
+        file_anns,
+        client: util::client_builder()
+            .pool_max_idle_per_host(downloader_count)
+            .pool_idle_timeout(Duration::from_secs(IDLE_CONN_TIMEOUT_SECS))
+            .build()
+            .unwrap(),
         m: Mutex::new(DownloaderM {
             downloading: 0,
             downloaded: 0,
             to_download: VecDeque::new(),
+            to_retry: VecDeque::new(),
+            listed: HashSet::new(),
+            lost: 0,
             stop: false,
         }),
     })
@@ -248,9 +469,14 @@ pub async fn start<T: OnAnns + 'static>(downloader: &Downloader<T>) -> Result<()
         );
     }
     let dl = Arc::clone(downloader);
-    tokio::spawn(async move {
-        poll_ann_handlers(&dl).await;
-    });
+    tasks::spawn(
+        format!("ann index {}", downloader.url_base),
+        tasks::Restart::OnPanic,
+        move || {
+            let dl = Arc::clone(&dl);
+            async move { poll_ann_handlers(&dl).await }
+        },
+    );
     Ok(())
 }
 
@@ -261,12 +487,16 @@ pub async fn stop<T: OnAnns>(downloader: &Downloader<T>) {
 pub async fn stats<T: OnAnns>(downloader: &Downloader<T>, reset_downloade: bool) -> Stats {
     let mut dl_l = downloader.m.lock().await;
     let downloaded = dl_l.downloaded;
+    let lost = dl_l.lost;
     if reset_downloade {
         dl_l.downloaded = 0;
+        dl_l.lost = 0;
     }
     Stats {
         downloaded,
         downloading: dl_l.downloading,
         queued: dl_l.to_download.len(),
+        retrying: dl_l.to_retry.len(),
+        lost,
     }
 }
@@ -1,5 +1,15 @@
 mod blkminer;
+mod bufpool;
+mod capture;
+mod checkpoint;
 mod downloader;
 mod prooftree;
+mod replay;
+mod shared;
+mod standby;
 
+pub mod annfilter;
+pub mod annproxy;
 pub mod blkmine;
+pub mod pktd;
+pub mod template;
@@ -0,0 +1,73 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Serialize)]
+struct RpcReq<'a> {
+    jsonrpc: &'static str,
+    id: &'static str,
+    method: &'static str,
+    params: [&'a str; 1],
+}
+
+#[derive(Deserialize)]
+struct RpcErr {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct RpcRes {
+    result: Option<String>,
+    error: Option<RpcErr>,
+}
+
+pub struct Pktd {
+    pub url: String,
+    // user:pass for the RPC server
+    pub auth: String,
+}
+
+/// Submit a serialized block to pktd using the submitblock RPC.
+pub async fn submit_block(pktd: &Pktd, block: &[u8], timeout: Duration) -> Result<()> {
+    let block_hex = hex::encode(block);
+    let body = serde_json::to_string(&RpcReq {
+        jsonrpc: "1.0",
+        id: "blkmine",
+        method: "submitblock",
+        params: [&block_hex],
+    })?;
+    let mut req = reqwest::ClientBuilder::new()
+        .timeout(timeout)
+        .build()?
+        .post(&pktd.url)
+        .header("content-type", "application/json")
+        .body(body);
+    if !pktd.auth.is_empty() {
+        let mut ui = pktd.auth.splitn(2, ':');
+        let user = ui.next().unwrap_or("");
+        req = req.basic_auth(user, ui.next());
+    }
+    let res = req.send().await?;
+    let status = res.status();
+    let resbytes = res.bytes().await?;
+    let reply = if let Ok(x) = serde_json::from_slice::<RpcRes>(&resbytes) {
+        x
+    } else {
+        bail!(
+            "pktd [{}] replied [{}]: [{}] which cannot be parsed",
+            &pktd.url,
+            status,
+            String::from_utf8_lossy(&resbytes[..])
+        );
+    };
+    if let Some(e) = reply.error {
+        bail!("pktd [{}] error [{}]: {}", &pktd.url, e.code, e.message);
+    }
+    // submitblock returns null on success, otherwise the reason for rejection
+    if let Some(reason) = reply.result {
+        bail!("pktd [{}] rejected block: {}", &pktd.url, reason);
+    }
+    Ok(())
+}
@@ -5,6 +5,9 @@ use packetcrypt_sys::*;
 use rayon::prelude::*;
 use std::convert::TryInto;
 
+/// Returned by compute() when cancel() said to stop
+pub const CANCELLED: &str = "tree build cancelled";
+
 pub struct AnnData {
     pub hash: [u8; 32],
     pub mloc: u32,
@@ -50,11 +53,32 @@ impl ProofTree {
             root_hash: None,
         }
     }
+    /// Keep the tree in RAM, so building it after a new block never waits for the disk
+    pub fn mlock(&self) -> anyhow::Result<()> {
+        // Same as PacketCryptProof_allocTree()
+        let mut total_anns = self.capacity as u64 + 1;
+        let mut entries = 0;
+        while total_anns > 1 {
+            total_anns += total_anns & 1;
+            entries += total_anns;
+            total_anns >>= 1;
+        }
+        let entry_len = std::mem::size_of::<ProofTree_Entry_t>();
+        let len = (entries as usize + 1) * entry_len + 8 + 32 + entry_len;
+        unsafe { packetcrypt_util::util::mlock(self.raw as *const u8, len) }
+    }
     pub fn reset(&mut self) {
         self.size = 0;
         self.root_hash = None;
     }
-    pub fn compute(&mut self, data: &mut [AnnData]) -> Result<Vec<u32>, &'static str> {
+    /// Build the tree, cancel() is checked between the steps and after each layer of the
+    /// merkle tree and if it returns true, this gives up and returns CANCELLED. The tree
+    /// then needs reset() before it can be used again.
+    pub fn compute(
+        &mut self,
+        data: &mut [AnnData],
+        cancel: impl Fn() -> bool,
+    ) -> Result<Vec<u32>, &'static str> {
         if self.root_hash.is_some() {
             return Err("tree is in computed state, call reset() first");
         }
@@ -67,6 +91,9 @@ impl ProofTree {
 
         // Sort the data items
         data.par_sort_by(|a, b| a.hash_pfx().cmp(&b.hash_pfx()));
+        if cancel() {
+            return Err(CANCELLED);
+        }
 
         // Create the index table
         let mut out = Vec::with_capacity(self.size as usize);
@@ -88,6 +115,9 @@ impl ProofTree {
             }
         }
         debug!("Loaded {} out of {} anns", out.len(), data.len());
+        if cancel() {
+            return Err(CANCELLED);
+        }
 
         // Copy the data to the location
         data.par_iter().for_each(|d| {
@@ -111,6 +141,9 @@ impl ProofTree {
         let mut odx = count_this_layer;
         let mut idx = 0;
         while count_this_layer > 1 {
+            if cancel() {
+                return Err(CANCELLED);
+            }
             if (count_this_layer & 1) != 0 {
                 unsafe { ProofTree_putEntry(self.raw, odx as u32, fff_entry()) };
                 count_this_layer += 1;
@@ -0,0 +1,190 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Recording of everything which comes into the block miner from outside, and replay of
+//! a recording in the same order and with the same timing, so that problems which only
+//! happen with real traffic can be reproduced. A trace is a sequence of records:
+//!
+//! ```text
+//! kind: u8 | time_ms: u64 | len: u32 | payload
+//! ```
+//!
+//! Numbers are little endian and time_ms is the time since the recording began, it is
+//! taken while holding the trace so it never goes backward from one record to the next.
+//!
+//! * kind 1: JSON list of the BlockInfo in a pool update
+//! * kind 2: conf_len: u32 | JSON MasterConf | work, as in work_<height>.bin
+//! * kind 3: url_len: u16 | url | anns, a file from the downloader
+//! * kind 4: anns from the sprayer
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BytesMut};
+use log::{info, warn};
+use packetcrypt_util::protocol::{self, BlockInfo, MasterConf, Work};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const KIND_BLOCKS: u8 = 1;
+const KIND_WORK: u8 = 2;
+const KIND_DOWNLOADED: u8 = 3;
+const KIND_SPRAYED: u8 = 4;
+
+pub struct Recorder {
+    out: Mutex<BufWriter<File>>,
+    time_started: Instant,
+}
+
+pub fn record(path: &str) -> Result<Recorder> {
+    let file = File::create(path).with_context(|| format!("Unable to create trace {}", path))?;
+    info!("Recording everything the miner receives to {}", path);
+    Ok(Recorder {
+        out: Mutex::new(BufWriter::new(file)),
+        time_started: Instant::now(),
+    })
+}
+
+impl Recorder {
+    fn write(&self, kind: u8, parts: &[&[u8]]) {
+        let len: usize = parts.iter().map(|p| p.len()).sum();
+        let mut out = self.out.lock().unwrap();
+        let time_ms = self.time_started.elapsed().as_millis() as u64;
+        let res = (|| {
+            out.write_all(&[kind])?;
+            out.write_all(&time_ms.to_le_bytes())?;
+            out.write_all(&(len as u32).to_le_bytes())?;
+            for p in parts {
+                out.write_all(p)?;
+            }
+            // So the trace is usable if the miner crashes
+            out.flush()
+        })();
+        if let Err(e) = res {
+            warn!("Unable to write to trace: {}", e);
+        }
+    }
+
+    pub fn blocks(&self, blocks: &[BlockInfo]) {
+        match serde_json::to_vec(blocks) {
+            Ok(j) => self.write(KIND_BLOCKS, &[&j[..]]),
+            Err(e) => warn!("Unable to record blocks: {}", e),
+        }
+    }
+
+    pub fn work(&self, conf: &MasterConf, work: &Work) {
+        let conf = match serde_json::to_vec(conf) {
+            Ok(j) => j,
+            Err(e) => {
+                warn!("Unable to record work: {}", e);
+                return;
+            }
+        };
+        let mut w = BytesMut::new();
+        protocol::work_encode(work, &mut w);
+        let conf_len = (conf.len() as u32).to_le_bytes();
+        self.write(KIND_WORK, &[&conf_len[..], &conf[..], &w[..]]);
+    }
+
+    pub fn downloaded(&self, url: &str, anns: &[u8]) {
+        if url.len() > u16::MAX as usize {
+            warn!("Not recording anns from a url of {} bytes", url.len());
+            return;
+        }
+        let url_len = (url.len() as u16).to_le_bytes();
+        self.write(KIND_DOWNLOADED, &[&url_len[..], url.as_bytes(), anns]);
+    }
+
+    pub fn sprayed(&self, anns: &[&[u8]]) {
+        self.write(KIND_SPRAYED, anns);
+    }
+}
+
+pub enum Event {
+    Blocks(Vec<BlockInfo>),
+    Work(MasterConf, Work),
+    Downloaded(String, bytes::Bytes),
+    Sprayed(bytes::Bytes),
+}
+
+fn decode(kind: u8, mut payload: bytes::Bytes) -> Result<Event> {
+    Ok(match kind {
+        KIND_BLOCKS => Event::Blocks(serde_json::from_slice(&payload)?),
+        KIND_WORK => {
+            if payload.remaining() < 4 {
+                bail!("runt work record");
+            }
+            let conf_len = payload.get_u32_le() as usize;
+            if payload.remaining() < conf_len {
+                bail!("runt work record");
+            }
+            let conf = serde_json::from_slice(&payload[..conf_len])?;
+            payload.advance(conf_len);
+            let mut work = Work::default();
+            protocol::work_decode(&mut work, &mut payload)?;
+            Event::Work(conf, work)
+        }
+        KIND_DOWNLOADED => {
+            if payload.remaining() < 2 {
+                bail!("runt downloaded record");
+            }
+            let url_len = payload.get_u16_le() as usize;
+            if payload.remaining() < url_len {
+                bail!("runt downloaded record");
+            }
+            let url = String::from_utf8_lossy(&payload[..url_len]).into_owned();
+            payload.advance(url_len);
+            Event::Downloaded(url, payload)
+        }
+        KIND_SPRAYED => Event::Sprayed(payload),
+        k => bail!("unknown record kind {}", k),
+    })
+}
+
+// None at the end of the trace
+fn read_record(r: &mut impl Read) -> Result<Option<(u64, Event)>> {
+    let mut head = [0u8; 13];
+    match r.read_exact(&mut head) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        x => x?,
+    }
+    let mut time = [0u8; 8];
+    time.copy_from_slice(&head[1..9]);
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&head[9..13]);
+    let mut payload = vec![0u8; u32::from_le_bytes(len) as usize];
+    r.read_exact(&mut payload)
+        .context("trace ends part way through a record")?;
+    let ev = decode(head[0], bytes::Bytes::from(payload))?;
+    Ok(Some((u64::from_le_bytes(time), ev)))
+}
+
+pub type OnEvent = Box<dyn Fn(Event) + Send>;
+
+/// Feed the recorded events to on_event one at a time and in the order they were
+/// recorded, all from one new thread, each one at the same time after the start as when
+/// it was recorded. If handling an event takes longer than the gap to the next, the next
+/// is handed over as soon as the last returns so the order is never lost.
+pub fn start(path: &str, on_event: OnEvent) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Unable to open trace {}", path))?;
+    let path = path.to_owned();
+    info!("Replaying {}, shares will NOT be submitted", path);
+    std::thread::spawn(move || {
+        let mut r = BufReader::new(file);
+        let time_started = Instant::now();
+        let mut n = 0;
+        loop {
+            let (time_ms, ev) = match read_record(&mut r) {
+                Ok(Some(x)) => x,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Error reading record {} of {}: {}", n, path, e);
+                    break;
+                }
+            };
+            let due = time_started + Duration::from_millis(time_ms);
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+            on_event(ev);
+            n += 1;
+        }
+        info!("Replay of {} finished after {} records", path, n);
+    });
+    Ok(())
+}
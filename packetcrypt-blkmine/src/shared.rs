@@ -0,0 +1,352 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Ann memory which is shared by the block miners on one machine (--shared-anns), so
+//! that when one of them crashes or is stopped for an upgrade its anns stay in memory
+//! and the next one to start mines them right away. The file, normally in /dev/shm,
+//! is split into lanes and each miner mines only the anns in its own lane, because
+//! anns which are being mined must not be overwritten by anyone. The file is:
+//!
+//! ```text
+//! header: [4096 bytes]
+//!   magic: u64 | lane_count: u32 | lane_anns: u32 | padding to 64 |
+//!   owners: [u32] * lane_count
+//! hashes: [[32 bytes] * lane_anns] * lane_count
+//! padding to 4096
+//! anns: [[1024 bytes] * lane_anns] * lane_count
+//! ```
+//!
+//! Numbers are in the byte order of the machine. The owner of a lane is the pid of the
+//! miner mining it, or 0 if it is free, with the high bit set once it has been used. A
+//! miner takes a free lane, or one whose owner is not running anymore, by swapping its
+//! own pid in with compare and swap, so two miners which start at once can't both get
+//! it and no lock is needed. Lanes which have been used come first because they have
+//! anns in them.
+//!
+//! The slot metadata of the miner which had the lane is lost with it, so the next miner
+//! rebuilds it from the anns. Each ann slot has a hash which is written after the ann,
+//! when they don't match the ann was being written when the miner died and is left out.
+use crate::blkmine::ann_class_work;
+use crate::bufpool::{AnnInfo, BufPool, FreeInfo};
+use anyhow::{bail, Context, Result};
+use log::info;
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use packetcrypt_util::{hash, util};
+use rayon::prelude::*;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+const MAGIC: &[u8; 8] = b"pcshann1";
+const HEADER_LEN: usize = 4096;
+const OWNERS_OFF: usize = 64;
+const USED: u32 = 1 << 31;
+pub const MAX_LANES: u32 = ((HEADER_LEN - OWNERS_OFF) / 4) as u32;
+
+// How long to wait for the miner which is creating the file to finish
+const CREATE_WAIT_SECS: u64 = 10;
+
+fn magic() -> u64 {
+    u64::from_ne_bytes(*MAGIC)
+}
+
+fn round_up(x: usize, to: usize) -> usize {
+    (x + to - 1) / to * to
+}
+
+/// One lane of the shared ann memory, the lane is given back when this is dropped.
+pub struct Lane {
+    ptr: *mut u8,
+    len: usize,
+    lane: u32,
+    lane_anns: u32,
+    hashes: *mut [u8; 32],
+    anns: *mut u8,
+    pid: u32,
+}
+unsafe impl Send for Lane {}
+unsafe impl Sync for Lane {}
+
+impl Drop for Lane {
+    fn drop(&mut self) {
+        let _ = self.owner(self.lane).compare_exchange(
+            self.pid | USED,
+            USED,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+        let _ = unsafe { munmap(self.ptr as *mut nix::libc::c_void, self.len) };
+    }
+}
+
+fn alive(pid: u32) -> bool {
+    use nix::sys::signal::kill;
+    use nix::unistd::Pid;
+    // EPERM means it is running as someone else
+    !matches!(
+        kill(Pid::from_raw(pid as i32), None),
+        Err(nix::Error::Sys(nix::errno::Errno::ESRCH))
+    )
+}
+
+fn map(fd: i32, len: usize, path: &str) -> Result<*mut u8> {
+    let ptr = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            len,
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            MapFlags::MAP_SHARED,
+            fd,
+            0,
+        )
+    }
+    .with_context(|| format!("Unable to mmap {}", path))?;
+    Ok(ptr as *mut u8)
+}
+
+fn file_len(lane_count: u32, lane_anns: u32) -> (usize, usize) {
+    let slots = lane_count as usize * lane_anns as usize;
+    let anns_off = round_up(HEADER_LEN + slots * 32, 4096);
+    (anns_off, anns_off + slots * 1024)
+}
+
+/// Map the shared ann memory at path and take a lane, the file is made with lane_count
+/// lanes of lane_anns anns if it doesn't exist, otherwise the sizes are those it has.
+pub fn open(path: &str, lane_count: u32, lane_anns: u32) -> Result<Lane> {
+    if lane_count == 0 || lane_count > MAX_LANES || lane_anns == 0 {
+        bail!("Need 1 to {} lanes of at least one ann", MAX_LANES);
+    }
+    let created = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path);
+    let (ptr, len) = match created {
+        Ok(file) => {
+            let (_, len) = file_len(lane_count, lane_anns);
+            file.set_len(len as u64)
+                .with_context(|| format!("Unable to size {}", path))?;
+            let ptr = map(file.as_raw_fd(), len, path)?;
+            unsafe {
+                *(ptr.add(8) as *mut u32) = lane_count;
+                *(ptr.add(12) as *mut u32) = lane_anns;
+                (*(ptr as *const AtomicU64)).store(magic(), Ordering::Release);
+            }
+            info!(
+                "Created shared ann memory {} of {} lanes of {} anns",
+                path, lane_count, lane_anns
+            );
+            (ptr, len)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => open_existing(path)?,
+        Err(e) => bail!("Unable to create {}: {}", path, e),
+    };
+    let mut lane = Lane {
+        ptr,
+        len,
+        lane: 0,
+        lane_anns: 0,
+        hashes: std::ptr::null_mut(),
+        anns: std::ptr::null_mut(),
+        pid: std::process::id(),
+    };
+    let (lane_count, lane_anns) =
+        unsafe { (*(ptr.add(8) as *const u32), *(ptr.add(12) as *const u32)) };
+    let pid = lane.pid;
+    let take = |i: u32, used: u32| {
+        let owner = lane.owner(i);
+        let cur = owner.load(Ordering::Acquire);
+        let was = cur & !USED;
+        cur & USED == used
+            && (was == 0 || (was != pid && !alive(was)))
+            && owner
+                .compare_exchange(cur, pid | USED, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+    };
+    let num = (0..lane_count)
+        .find(|&i| take(i, USED))
+        .or_else(|| (0..lane_count).find(|&i| take(i, 0)));
+    let num = if let Some(num) = num {
+        num
+    } else {
+        // Don't give back a lane we don't have
+        lane.pid = 0;
+        bail!("All {} lanes of {} are taken", lane_count, path);
+    };
+    let (anns_off, _) = file_len(lane_count, lane_anns);
+    let slot = num as usize * lane_anns as usize;
+    lane.lane = num;
+    lane.lane_anns = lane_anns;
+    lane.hashes = unsafe { ptr.add(HEADER_LEN + slot * 32) as *mut [u8; 32] };
+    lane.anns = unsafe { ptr.add(anns_off + slot * 1024) };
+    info!("Mining lane {} of shared ann memory {}", num, path);
+    Ok(lane)
+}
+
+fn open_existing(path: &str) -> Result<(*mut u8, usize)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Unable to open {}", path))?;
+    for _ in 0..(CREATE_WAIT_SECS * 10) {
+        // The file is sized before anything is written in it
+        let len = file.metadata()?.len() as usize;
+        if len >= HEADER_LEN {
+            let ptr = map(file.as_raw_fd(), len, path)?;
+            let (head, lane_count, lane_anns) = unsafe {
+                (
+                    (*(ptr as *const AtomicU64)).load(Ordering::Acquire),
+                    *(ptr.add(8) as *const u32),
+                    *(ptr.add(12) as *const u32),
+                )
+            };
+            if head == magic() {
+                if lane_count == 0
+                    || lane_count > MAX_LANES
+                    || file_len(lane_count, lane_anns).1 != len
+                {
+                    let _ = unsafe { munmap(ptr as *mut nix::libc::c_void, len) };
+                    bail!("{} is corrupt, remove it", path);
+                }
+                return Ok((ptr, len));
+            }
+            let _ = unsafe { munmap(ptr as *mut nix::libc::c_void, len) };
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    bail!(
+        "{} is not shared ann memory, or the miner which made it died, remove it",
+        path
+    );
+}
+
+impl Lane {
+    fn owner(&self, lane: u32) -> &AtomicU32 {
+        unsafe { &*(self.ptr.add(OWNERS_OFF + lane as usize * 4) as *const AtomicU32) }
+    }
+    pub fn ann_count(&self) -> u32 {
+        self.lane_anns
+    }
+    /// The anns of this lane, to be mined by the block miner.
+    pub fn anns(&self) -> *mut u8 {
+        self.anns
+    }
+    fn ann(&self, mloc: u32) -> &[u8] {
+        assert!(mloc < self.lane_anns);
+        unsafe { std::slice::from_raw_parts(self.anns.add(mloc as usize * 1024), 1024) }
+    }
+    /// Record the ann which has just been written at mloc, so that a miner which takes
+    /// the lane after us knows it was written completely.
+    pub fn commit(&self, mloc: u32, ann: &[u8]) {
+        assert!(mloc < self.lane_anns);
+        unsafe { *self.hashes.add(mloc as usize) = hash::compress32(ann) };
+    }
+    // None unless the slot holds an ann which was committed
+    fn committed(&self, mloc: u32) -> Option<[u8; 32]> {
+        let h = unsafe { *self.hashes.add(mloc as usize) };
+        if util::is_zero(&h) || hash::compress32(self.ann(mloc)) != h {
+            return None;
+        }
+        Some(h)
+    }
+}
+
+/// Put the anns which the last miner of this lane left into the pool, this must be
+/// done before anything else uses the pool.
+pub fn recover(lane: &Lane, pool: &BufPool, class_bits: u32) -> usize {
+    let time_started_ms = util::now_ms();
+    let found = (0..lane.ann_count())
+        .into_par_iter()
+        .map(|mloc| {
+            let h = lane.committed(mloc)?;
+            let ann = lane.ann(mloc);
+            let height = packetcrypt_sys::parent_block_height(ann);
+            let work = ann_class_work(packetcrypt_sys::work_bits(ann), class_bits);
+            Some(((height, work), h))
+        })
+        .collect::<Vec<_>>();
+    // Take everything and give back what has no anns, so each AnnInfo stays in a shard
+    let mut infos = Vec::new();
+    let mut empty = Vec::new();
+    for fi in pool.alloc(lane.ann_count(), 0) {
+        let end = fi.mloc + fi.ann_count;
+        let mut i = fi.mloc;
+        while i < end {
+            let start = i;
+            let class = found[i as usize].map(|(c, _)| c);
+            while i < end && found[i as usize].map(|(c, _)| c) == class {
+                i += 1;
+            }
+            if let Some((height, work)) = class {
+                infos.push(AnnInfo {
+                    parent_block_height: height,
+                    ann_min_work: work,
+                    ann_effective_work: u32::MAX,
+                    ann_count: i - start,
+                    mloc: start,
+                    hashes: found[start as usize..i as usize]
+                        .iter()
+                        .map(|f| f.unwrap().1)
+                        .collect(),
+                });
+            } else {
+                empty.push(FreeInfo {
+                    ann_count: i - start,
+                    mloc: start,
+                });
+            }
+        }
+    }
+    let landed = infos.iter().map(|ai| ai.ann_count).sum();
+    pool.place(&mut infos, landed);
+    pool.unalloc(empty);
+    info!(
+        "Recovered {} anns from shared ann memory in {}ms",
+        landed,
+        util::now_ms().saturating_sub(time_started_ms)
+    );
+    landed as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bufpool;
+
+    #[test]
+    fn test_lanes() {
+        let path = std::env::temp_dir().join(format!("pcshann-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let _ = std::fs::remove_file(&path);
+
+        let a = open(&path, 2, 16).unwrap();
+        let b = open(&path, 2, 16).unwrap();
+        assert_ne!(a.lane, b.lane);
+        assert!(open(&path, 2, 16).is_err());
+
+        // One good ann and one which was being written when the miner stopped
+        let mut ann = [0u8; 1024];
+        ann[3] = 7;
+        unsafe { std::ptr::copy_nonoverlapping(ann.as_ptr(), a.anns().add(3 * 1024), 1024) };
+        a.commit(3, &ann);
+        a.commit(5, &ann);
+        ann[3] = 8;
+        unsafe { std::ptr::copy_nonoverlapping(ann.as_ptr(), a.anns().add(5 * 1024), 1024) };
+        let num = a.lane;
+        drop(a);
+
+        // Sizes of the file win over those asked for
+        let c = open(&path, 1, 1).unwrap();
+        assert_eq!(c.lane, num);
+        assert_eq!(c.ann_count(), 16);
+        let pool = bufpool::new(c.ann_count(), 2, c.ann_count());
+        assert_eq!(recover(&c, &pool, 23), 1);
+        let counts = pool.counts();
+        assert_eq!(counts.ready, 1);
+        assert_eq!(counts.free, 15);
+        assert_eq!(counts.leaked(), 0);
+
+        drop((b, c));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
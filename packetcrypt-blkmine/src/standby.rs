@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! A block miner started with --standby-of is a hot standby for another one which
+//! mines for the same pool. It takes in the same anns, either from the same handlers
+//! or by subscribing to the primary's sprayer, which passes on everything it receives,
+//! and it builds trees and mines as usual. Its shares are only submitted while the
+//! primary's debug api is not answering /health, so it can take over at once.
+use log::{info, warn};
+use packetcrypt_util::util;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const CHECK_MS: u64 = 5_000;
+const TIMEOUT_MS: u64 = 2_000;
+
+// Failed health checks in a row before taking over
+const FAILURES: u32 = 3;
+
+pub struct Standby {
+    // Debug api of the primary, e.g. http://10.0.0.2:8099
+    url: String,
+    active: AtomicBool,
+}
+
+impl Standby {
+    pub fn new(primary: &str) -> Standby {
+        Standby {
+            url: primary.trim_end_matches('/').to_owned(),
+            active: false.into(),
+        }
+    }
+
+    /// Whether the primary is down and this miner's shares should be submitted
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+async fn healthy(client: &reqwest::Client, url: &str) -> bool {
+    let req = match util::request(client, reqwest::Method::GET, url).await {
+        Ok(req) => req,
+        Err(_) => return false,
+    };
+    match req.send().await {
+        Ok(res) => res.status().is_success(),
+        Err(_) => false,
+    }
+}
+
+pub async fn health_loop(sb: &Standby) {
+    let url = format!("{}/health", sb.url);
+    let client = match util::client_builder()
+        .timeout(Duration::from_millis(TIMEOUT_MS))
+        .build()
+    {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Unable to check the primary, taking over: {}", e);
+            sb.active.store(true, Ordering::Relaxed);
+            return;
+        }
+    };
+    info!(
+        "Standby for [{}], not submitting shares while it is up",
+        sb.url
+    );
+    let mut failures = 0;
+    loop {
+        if healthy(&client, &url).await {
+            failures = 0;
+            if sb.active.swap(false, Ordering::Relaxed) {
+                info!("Primary [{}] is back, going back to standby", sb.url);
+            }
+        } else {
+            failures += 1;
+            if failures == FAILURES && !sb.active.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Primary [{}] failed {} health checks, taking over share submission",
+                    sb.url, failures
+                );
+            }
+        }
+        util::sleep_ms(CHECK_MS).await;
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Block templates from an external program, for forks and testnets where the work
+//! files from the pool can't be used. Templates are read one JSON object per line,
+//! either from stdin or from connections to a unix socket:
+//!
+//! ```text
+//! {"height":1234,"version":1,"prevBlockHash":"<hex>","time":1600000000,
+//!  "bits":503382015,"shareTarget":503382015,"coinbase":"<hex>","merkleBranch":[]}
+//! ```
+//!
+//! * `prevBlockHash` is in the byte order which it has in the block header
+//! * `coinbase` is the coinbase transaction without witness, it must contain the
+//!   commitment placeholder `6a3009f91102` followed by 44 bytes of `fc`
+//! * `merkleBranch` is the list of hashes which lead from the coinbase txid to the
+//!   merkle root, empty if the coinbase is the only transaction
+//! * `shareTarget` is optional, if it is missing then only blocks are reported
+//!
+//! Each share which is found is written back on one line, in the same JSON which
+//! is posted to the pool, to stdout or to the connection which sent the template.
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use packetcrypt_util::protocol;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+pub enum Source {
+    Stdin,
+    Socket(PathBuf),
+}
+
+/// "-" means stdin, anything else is the path of a unix socket to listen on.
+pub fn parse_source(s: &str) -> Source {
+    if s == "-" {
+        Source::Stdin
+    } else {
+        Source::Socket(PathBuf::from(s))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Template {
+    height: i32,
+    version: u32,
+    prev_block_hash: String,
+    time: i32,
+    bits: u32,
+    share_target: Option<u32>,
+    coinbase: String,
+    #[serde(default)]
+    merkle_branch: Vec<String>,
+}
+
+fn decode_hash(s: &str) -> Result<[u8; 32]> {
+    let v = hex::decode(s)?;
+    if v.len() != 32 {
+        bail!("expected 32 bytes, got {}", v.len());
+    }
+    let mut out = [0_u8; 32];
+    out.copy_from_slice(&v);
+    Ok(out)
+}
+
+pub fn decode(line: &str) -> Result<protocol::Work> {
+    let t: Template = serde_json::from_str(line)?;
+    let coinbase = hex::decode(&t.coinbase).context("coinbase")?;
+    if !coinbase
+        .windows(crate::blkmine::COINBASE_COMMIT_LEN)
+        .any(|w| w == crate::blkmine::COINBASE_COMMIT_PATTERN)
+    {
+        bail!("coinbase does not contain the commitment placeholder");
+    }
+    let mut coinbase_merkle = Vec::with_capacity(t.merkle_branch.len());
+    for h in &t.merkle_branch {
+        let h = decode_hash(h).context("merkleBranch")?;
+        coinbase_merkle.push(bytes::Bytes::copy_from_slice(&h));
+    }
+    Ok(protocol::Work {
+        header: protocol::BlockHeader {
+            version: t.version,
+            hash_prev_block: decode_hash(&t.prev_block_hash).context("prevBlockHash")?,
+            hash_merkle_root: [0_u8; 32],
+            time_seconds: t.time,
+            work_bits: t.bits,
+            nonce: 0,
+        },
+        signing_key: [0_u8; 32],
+        share_target: t.share_target.unwrap_or(t.bits),
+        ann_target: 0,
+        height: t.height,
+        coinbase_no_witness: bytes::Bytes::from(coinbase),
+        coinbase_merkle,
+    })
+}
+
+/// The same JSON as a template, for recording what was being mined
+pub fn encode(work: &protocol::Work) -> serde_json::Value {
+    serde_json::json!(Template {
+        height: work.height,
+        version: work.header.version,
+        prev_block_hash: hex::encode(&work.header.hash_prev_block),
+        time: work.header.time_seconds,
+        bits: work.header.work_bits,
+        share_target: Some(work.share_target),
+        coinbase: hex::encode(&work.coinbase_no_witness),
+        merkle_branch: work.coinbase_merkle.iter().map(hex::encode).collect(),
+    })
+}
+
+/// Where to write the shares found while mining a template
+pub type Output = Arc<Mutex<Box<dyn Write + Send>>>;
+
+pub type OnTemplate = Arc<dyn Fn(protocol::Work, Output) + Send + Sync>;
+
+fn read_templates(r: impl BufRead, out: Output, on_template: &OnTemplate) {
+    for line in r.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("Error reading templates: {}", e);
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match decode(&line) {
+            Ok(work) => on_template(work, Arc::clone(&out)),
+            Err(e) => warn!("Invalid block template: {}", e),
+        }
+    }
+}
+
+pub fn start(src: &Source, on_template: OnTemplate) -> Result<()> {
+    match src {
+        Source::Stdin => {
+            info!("Reading block templates from stdin");
+            std::thread::spawn(move || {
+                let out: Output = Arc::new(Mutex::new(Box::new(std::io::stdout())));
+                read_templates(std::io::stdin().lock(), out, &on_template);
+                warn!("Block templates ended");
+            });
+            Ok(())
+        }
+        Source::Socket(path) => listen(path, on_template),
+    }
+}
+
+#[cfg(unix)]
+fn listen(path: &std::path::Path, on_template: OnTemplate) -> Result<()> {
+    use std::os::unix::net::UnixListener;
+    // Left behind by the last run
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path).with_context(|| format!("bind({})", path.display()))?;
+    info!("Reading block templates from {}", path.display());
+    std::thread::spawn(move || {
+        for conn in listener.incoming() {
+            let conn = match conn {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("Error accepting template connection: {}", e);
+                    continue;
+                }
+            };
+            let write = match conn.try_clone() {
+                Ok(w) => w,
+                Err(e) => {
+                    warn!("Error accepting template connection: {}", e);
+                    continue;
+                }
+            };
+            let on_template = Arc::clone(&on_template);
+            std::thread::spawn(move || {
+                let out: Output = Arc::new(Mutex::new(Box::new(write)));
+                read_templates(std::io::BufReader::new(conn), out, &on_template);
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen(_path: &std::path::Path, _on_template: OnTemplate) -> Result<()> {
+    bail!("Block templates from a socket are only supported on unix");
+}
+
+pub fn write_share(out: &Output, share_json: &str) -> Result<()> {
+    let mut out = out.lock().unwrap();
+    writeln!(out, "{}", share_json)?;
+    out.flush()?;
+    Ok(())
+}
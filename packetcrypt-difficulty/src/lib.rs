@@ -0,0 +1,354 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! PacketCrypt difficulty math, in safe Rust and without the rest of packetcrypt, for
+//! explorers and pool software.
+//!
+//! Targets are in the bitcoin "compact" form, a 1 byte size and a 3 byte mantissa. A
+//! hash meets a target if it is not greater than it and the work of a target is the
+//! number of hashes which are needed on average to find one which meets it, so a
+//! smaller target means more work. Block targets are made easier by the announcements
+//! which are mined in the block, see effective_target().
+//!
+//! It is a crate of its own so that packetcrypt-sys, which gives the C code the same
+//! functions under the names it links to, and packetcrypt-util, which re-exports it as
+//! packetcrypt_util::difficulty, share one implementation without the wasm build of
+//! packetcrypt-sys depending on the rest of packetcrypt-util.
+pub use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use num_traits::{One, Zero};
+use std::cmp::min;
+
+/// The easiest target there is
+pub const MAX_COMPACT: u32 = 0x207fffff;
+
+/// Announcements can't be used until they are this many blocks old and then their
+/// target is halved with every block, see degrade_ann_target()
+pub const ANN_WAIT_PERIOD: u32 = 3;
+
+/// Returned by degrade_ann_target() for announcements which are too young or too old
+/// to be used
+pub const UNUSABLE_TARGET: u32 = 0xffffffff;
+
+// Negative targets can't be met by any hash, the public functions check for them so
+// that this never panics on something which came off the network
+fn is_negative(compact: u32) -> bool {
+    compact & 0x00800000 != 0
+}
+
+fn bn_for_compact(compact: u32) -> BigUint {
+    let size = compact >> 24;
+    if is_negative(compact) {
+        panic!("Negative bignum not supported");
+    }
+    let word = compact & 0x007fffff;
+    if size <= 3 {
+        BigUint::from(word >> (8 * (3 - size)))
+    } else {
+        BigUint::from(word) << (8 * (size - 3))
+    }
+}
+
+fn compact_for_bn(bn: BigUint) -> u32 {
+    let (compact, size) = {
+        let size = {
+            let bits = bn.bits() as u32;
+            bits / 8 + if bits % 8 == 0 { 0 } else { 1 }
+        };
+        let compact = if size <= 3 {
+            bn.to_u32().unwrap() << (8 * (3 - size))
+        } else {
+            (bn >> (8 * (size - 3))).to_u32().unwrap()
+        };
+        if compact & 0x00800000 != 0 {
+            (compact >> 8, size + 1)
+        } else {
+            (compact, size)
+        }
+    };
+    compact | (size << 24)
+}
+
+fn bn256() -> BigUint {
+    BigUint::one() << 256
+}
+
+// work = 2**256 / (target + 1)
+fn work_for_tar(target: BigUint) -> BigUint {
+    bn256() / (target + BigUint::one())
+}
+
+// diffOut = (2**256 - work) / work
+fn tar_for_work(work: BigUint) -> BigUint {
+    if work.is_zero() {
+        bn256()
+    } else if work.bits() > 256 {
+        BigUint::zero()
+    } else {
+        (bn256() - &work) / work
+    }
+}
+
+// effective_work = work**3 / 1024 / ann_work / ann_count**2
+fn get_effective_work(blk_work: BigUint, ann_work: BigUint, ann_count: u64) -> BigUint {
+    if ann_work.is_zero() || ann_count == 0 {
+        // This is work *required* so when there is no work and no announcements
+        // that work is "infinite".
+        return bn256();
+    }
+
+    // workOut = workOut**3
+    let mut out = blk_work.pow(3);
+
+    // difficulty /= 1024
+    out >>= 10;
+
+    // workOut /= annWork
+    out /= ann_work;
+
+    // workOut /= annCount
+    out /= BigUint::from(ann_count).pow(2);
+
+    out
+}
+
+/// True if this is a compact target which can be used, it is non-zero, not negative
+/// and not easier than MAX_COMPACT
+pub fn is_valid_compact(compact: u32) -> bool {
+    compact > 0 && !is_negative(compact) && compact <= MAX_COMPACT
+}
+
+/// The target which a compact target stands for, None if it is negative
+pub fn compact_to_target(compact: u32) -> Option<BigUint> {
+    if is_negative(compact) {
+        None
+    } else {
+        Some(bn_for_compact(compact))
+    }
+}
+
+/// The compact form of a target, which keeps the top 3 bytes of it, so it is exact for
+/// any target that came from a compact
+pub fn target_to_compact(target: &BigUint) -> u32 {
+    compact_for_bn(target.clone())
+}
+
+/// The average number of hashes which it takes to find one that meets the target,
+/// 2**256 / (target + 1)
+pub fn work_for_target(target: &BigUint) -> BigUint {
+    work_for_tar(target.clone())
+}
+
+/// The target which takes this much work, (2**256 - work) / work
+pub fn target_for_work(work: &BigUint) -> BigUint {
+    tar_for_work(work.clone())
+}
+
+/// The work which a block miner must do with ann_count announcements whose least work
+/// is ann_work, for a block whose work is blk_work, work**3 / 1024 / ann_work /
+/// ann_count**2, or 2**256 if there are no anns
+pub fn effective_work(blk_work: &BigUint, ann_work: &BigUint, ann_count: u64) -> BigUint {
+    get_effective_work(blk_work.clone(), ann_work.clone(), ann_count)
+}
+
+/// The target which a block miner must meet with ann_count announcements whose least
+/// work target is ann_tar, for a block whose target is block_tar. More anns, or anns
+/// with more work, make it easier. 0, which nothing meets, if either target is negative.
+pub fn effective_target(block_tar: u32, ann_tar: u32, ann_count: u64) -> u32 {
+    if is_negative(block_tar) || is_negative(ann_tar) {
+        return 0;
+    }
+    let blk_work = work_for_tar(bn_for_compact(block_tar));
+    let ann_work = work_for_tar(bn_for_compact(ann_tar));
+    let effective_work = get_effective_work(blk_work, ann_work, ann_count);
+    let out = compact_for_bn(tar_for_work(effective_work));
+    min(out, MAX_COMPACT)
+}
+
+/// How many times more effective a block miner's hashes are with ann_count anns whose
+/// least work target is ann_tar, saturating at u64::MAX, 0 if ann_tar is negative
+pub fn hashrate_multiplier(ann_tar: u32, ann_count: u64) -> u64 {
+    if is_negative(ann_tar) {
+        return 0;
+    }
+    let bn_ann_tar = bn_for_compact(ann_tar);
+    let bn_ann_work = work_for_tar(bn_ann_tar);
+    let bn_ann_count_2 = BigUint::from(ann_count).pow(2);
+    let out: BigUint = (bn_ann_work * bn_ann_count_2) >> 10;
+    if out.bits() > 64 {
+        u64::MAX
+    } else {
+        out.to_u64().unwrap()
+    }
+}
+
+/// The target which an announcement mined at ann_tar counts for when it is
+/// ann_age_blocks old, UNUSABLE_TARGET if it is too young or too old to be used or
+/// if ann_tar is negative
+#[allow(clippy::if_same_then_else)]
+pub fn degrade_ann_target(ann_tar: u32, ann_age_blocks: u32) -> u32 {
+    if is_negative(ann_tar) {
+    } else if ann_age_blocks < ANN_WAIT_PERIOD {
+    } else if ann_age_blocks > 256 + ANN_WAIT_PERIOD {
+    } else if ann_age_blocks == ANN_WAIT_PERIOD {
+        return ann_tar;
+    } else {
+        let bn_ann_tar = bn_for_compact(ann_tar) << (ann_age_blocks - ANN_WAIT_PERIOD);
+        if bn_ann_tar.bits() < 256 {
+            let out = compact_for_bn(bn_ann_tar);
+            if out <= MAX_COMPACT {
+                return out;
+            }
+        }
+    }
+    UNUSABLE_TARGET
+}
+
+/// True if ann_tar is a target which announcements may be mined at
+pub fn is_min_ann_diff_ok(ann_tar: u32) -> bool {
+    if is_valid_compact(ann_tar) {
+        let tar = bn_for_compact(ann_tar);
+        if !tar.is_zero() {
+            let work = work_for_tar(tar);
+            if !work.is_zero() && work.bits() < 257 {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// The work of a compact target as a float, 0.0 if it is not valid
+pub fn tar_to_diff(ann_tar: u32) -> f64 {
+    if is_valid_compact(ann_tar) {
+        let tar = bn_for_compact(ann_tar);
+        if !tar.is_zero() {
+            let work = work_for_tar(tar);
+            if !work.is_zero() && work.bits() < 257 {
+                return work.to_f64().unwrap_or(0.0);
+            }
+        }
+    }
+    0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+    use rand::Rng;
+
+    // A valid compact whose target is not zero
+    fn rand_compact(rng: &mut impl Rng) -> u32 {
+        let size = rng.gen_range(4, 0x21);
+        let mantissa = rng.gen_range(1, 0x800000);
+        let c = size << 24 | mantissa;
+        if c > super::MAX_COMPACT {
+            super::MAX_COMPACT
+        } else {
+            c
+        }
+    }
+
+    #[test]
+    fn compact_round_trip() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let c = rand_compact(&mut rng);
+            let t = super::compact_to_target(c).unwrap();
+            let t2 = super::compact_to_target(super::target_to_compact(&t)).unwrap();
+            assert_eq!(t, t2, "compact {:08x}", c);
+            assert!(super::is_valid_compact(super::target_to_compact(&t)));
+        }
+        assert_eq!(super::compact_to_target(0x1d800000), None);
+        assert!(!super::is_valid_compact(0x21000001));
+    }
+
+    #[test]
+    fn work_and_target() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let (a, b) = (rand_compact(&mut rng), rand_compact(&mut rng));
+            let ta = super::compact_to_target(a).unwrap();
+            let tb = super::compact_to_target(b).unwrap();
+            let (wa, wb) = (super::work_for_target(&ta), super::work_for_target(&tb));
+            // Less target is more work
+            if ta <= tb {
+                assert!(wa >= wb, "{:08x} {:08x}", a, b);
+                assert!(super::tar_to_diff(a) >= super::tar_to_diff(b));
+            }
+            // Going to work and back is never harder than where we started
+            assert!(super::target_for_work(&wa) >= ta, "{:08x}", a);
+        }
+        assert_eq!(
+            super::target_for_work(&BigUint::from(0u32)),
+            BigUint::from(1u32) << 256
+        );
+    }
+
+    #[test]
+    fn effective_target() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let blk = rand_compact(&mut rng);
+            let ann = rand_compact(&mut rng);
+            let count = rng.gen_range(1, 1u64 << 32);
+            let t = super::effective_target(blk, ann, count);
+            assert!(t <= super::MAX_COMPACT);
+            // More anns never make it harder
+            let more = super::effective_target(blk, ann, count * 2);
+            assert!(
+                super::compact_to_target(more) >= super::compact_to_target(t),
+                "{:08x} {:08x} {}",
+                blk,
+                ann,
+                count
+            );
+            assert!(
+                super::hashrate_multiplier(ann, count * 2)
+                    >= super::hashrate_multiplier(ann, count)
+            );
+        }
+        // No anns means nothing can be mined
+        assert_eq!(super::effective_target(0x1d00ffff, 0x2000ffff, 0), 0);
+    }
+
+    #[test]
+    fn negative_compacts() {
+        for c in &[0x1d800000, 0x1dffffff, 0x00800000, 0x20800001] {
+            assert_eq!(super::compact_to_target(*c), None);
+            assert!(!super::is_valid_compact(*c));
+            assert!(!super::is_min_ann_diff_ok(*c));
+            assert_eq!(super::tar_to_diff(*c), 0.0);
+            assert_eq!(super::effective_target(*c, 0x2000ffff, 1000), 0);
+            assert_eq!(super::effective_target(0x1d00ffff, *c, 1000), 0);
+            assert_eq!(super::hashrate_multiplier(*c, 1000), 0);
+            for age in 0..300 {
+                assert_eq!(super::degrade_ann_target(*c, age), super::UNUSABLE_TARGET);
+            }
+        }
+    }
+
+    #[test]
+    fn degrade_ann_target() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let ann = rand_compact(&mut rng);
+            for age in 0..super::ANN_WAIT_PERIOD {
+                assert_eq!(super::degrade_ann_target(ann, age), super::UNUSABLE_TARGET);
+            }
+            assert_eq!(super::degrade_ann_target(ann, super::ANN_WAIT_PERIOD), ann);
+            let age = rng.gen_range(super::ANN_WAIT_PERIOD + 1, 300);
+            let older = super::degrade_ann_target(ann, age);
+            let younger = super::degrade_ann_target(ann, age - 1);
+            // Once unusable it stays that way, until then it only gets easier
+            if younger == super::UNUSABLE_TARGET {
+                assert_eq!(older, super::UNUSABLE_TARGET, "{:08x} {}", ann, age);
+            } else if older != super::UNUSABLE_TARGET {
+                assert!(
+                    super::compact_to_target(older) > super::compact_to_target(younger),
+                    "{:08x} {}",
+                    ann,
+                    age
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,305 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Append-only log of every anns event which the ann handler credits, written and synced
+//! before the event goes to the paymaker. Unlike the paylogs, these files are never
+//! deleted so payouts can be recomputed or audited after a crash.
+//!
+//! The work credited to each address in the current payout round is also kept in
+//! memory, so that miners can check it before they are paid. After a restart or when a
+//! new round begins, it is read back from the log.
+use anyhow::Result;
+use log::{info, warn};
+use packetcrypt_util::difficulty::tar_to_diff;
+use packetcrypt_util::protocol::AnnsEvent;
+use packetcrypt_util::util;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+pub const DEFAULT_ROTATE_SECONDS: u64 = 60 * 60;
+
+// If the pool doesn't say how long its payout rounds are
+pub const DEFAULT_ROUND_SECONDS: u64 = 60 * 60;
+
+const FILE_REGEX: &str = "^acct_([0-9]+).ndjson$";
+
+struct AccountingMut {
+    file: File,
+    // Seconds since the epoch when the current file was opened, also its name
+    opened_sec: u64,
+    // Work credited to each address since round_start_ms, which is 0 until it's asked for
+    round_start_ms: u64,
+    round: HashMap<String, PayoutLine>,
+    // Number of events written since startup
+    written: u64,
+}
+
+pub struct _Accounting {
+    m: Mutex<AccountingMut>,
+    // Number of the written events which are known to be on disk, held while syncing
+    synced: Mutex<u64>,
+    dir: String,
+    rotate_seconds: u64,
+}
+pub type Accounting = Arc<_Accounting>;
+
+async fn open(dir: &str, sec: u64) -> Result<File> {
+    let name = format!("{}/acct_{}.ndjson", dir, sec);
+    info!("Writing accounting log {}", name);
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&name)
+        .await?)
+}
+
+pub async fn new(dir: &str, rotate_seconds: u64) -> Result<Accounting> {
+    util::ensure_exists_dir(dir).await?;
+    let opened_sec = util::now_ms() / 1000;
+    Ok(Arc::new(_Accounting {
+        m: Mutex::new(AccountingMut {
+            file: open(dir, opened_sec).await?,
+            opened_sec,
+            round_start_ms: 0,
+            round: HashMap::new(),
+            written: 0,
+        }),
+        synced: Mutex::new(0),
+        dir: dir.to_owned(),
+        rotate_seconds,
+    }))
+}
+
+/// Returns once the event is on disk
+pub async fn write(acct: &Accounting, ev: &AnnsEvent) -> Result<()> {
+    let mut line = serde_json::to_string(ev)?;
+    line.push('\n');
+    let mut m = acct.m.lock().await;
+    let now_sec = util::now_ms() / 1000;
+    if now_sec >= m.opened_sec + acct.rotate_seconds {
+        m.file.sync_all().await?;
+        m.file = open(&acct.dir, now_sec).await?;
+        m.opened_sec = now_sec;
+    }
+    m.file.write_all(line.as_bytes()).await?;
+    // The sync is on another handle, which only sees what has been flushed
+    m.file.flush().await?;
+    m.written += 1;
+    let seq = m.written;
+    if m.round_start_ms > 0 && ev.time >= m.round_start_ms {
+        credit(&mut m.round, ev);
+    }
+    drop(m);
+
+    // Events which are written while one sync runs are all covered by the next one, so
+    // under load there are far fewer syncs than events
+    let mut synced = acct.synced.lock().await;
+    if *synced < seq {
+        let (mut file, upto) = {
+            let m = acct.m.lock().await;
+            (m.file.try_clone().await?, m.written)
+        };
+        file.sync_data().await?;
+        *synced = upto;
+    }
+    Ok(())
+}
+
+/// All events in the log with time between from_ms and to_ms (inclusive), oldest first
+pub async fn read(dir: &str, from_ms: u64, to_ms: u64) -> Result<Vec<AnnsEvent>> {
+    let mut files = util::numbered_files(dir, &Regex::new(FILE_REGEX)?).await?;
+    files.sort_by_key(|(_, sec)| *sec);
+    let mut out = Vec::new();
+    for (i, (name, sec)) in files.iter().enumerate() {
+        if (*sec as u64) * 1000 > to_ms {
+            break;
+        }
+        if let Some((_, next_sec)) = files.get(i + 1) {
+            // Clock changes can cause out of order events, so allow some slack
+            if (*next_sec as u64 + 60) * 1000 < from_ms {
+                continue;
+            }
+        }
+        let content = tokio::fs::read_to_string(format!("{}/{}", dir, name)).await?;
+        for (n, line) in content.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<AnnsEvent>(line) {
+                Ok(ev) if ev.time >= from_ms && ev.time <= to_ms => out.push(ev),
+                Ok(_) => (),
+                // Most likely the last line of a file which was being written during a crash
+                Err(e) => warn!("{}:{} unreadable record: {}", name, n + 1, e),
+            }
+        }
+    }
+    out.sort_by_key(|ev| ev.time);
+    Ok(out)
+}
+
+/// Write out the events as ndjson which can be posted to the paymaker /events endpoint,
+/// or as csv for spreadsheets. Returns the number of events.
+pub async fn export(
+    dir: &str,
+    from_ms: u64,
+    to_ms: u64,
+    csv: bool,
+    out: &mut impl Write,
+) -> Result<usize> {
+    let events = read(dir, from_ms, to_ms).await?;
+    if csv {
+        writeln!(out, "time,pay_to,target,accepted,event_id")?;
+    }
+    for ev in &events {
+        if csv {
+            writeln!(
+                out,
+                "{},{},{:08x},{},{}",
+                ev.time, ev.pay_to, ev.target, ev.accepted, ev.event_id
+            )?;
+        } else {
+            writeln!(out, "{}", serde_json::to_string(ev)?)?;
+        }
+    }
+    Ok(events.len())
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PayoutLine {
+    pub pay_to: String,
+    pub events: usize,
+    pub anns: u64,
+    // Sum of the difficulty of every accepted ann
+    pub work: f64,
+    // Fraction of the round's work, between 0 and 1
+    pub share: f64,
+    // PKT
+    pub amount: f64,
+}
+
+fn credit(by_addr: &mut HashMap<String, PayoutLine>, ev: &AnnsEvent) {
+    let l = by_addr
+        .entry(ev.pay_to.clone())
+        .or_insert_with(|| PayoutLine {
+            pay_to: ev.pay_to.clone(),
+            ..Default::default()
+        });
+    l.events += 1;
+    l.anns += ev.accepted as u64;
+    l.work += ev.accepted as f64 * tar_to_diff(ev.target);
+}
+
+// Shares and amounts of the credited work, largest first
+fn split(by_addr: &HashMap<String, PayoutLine>, total_pkt: f64) -> Vec<PayoutLine> {
+    let total_work: f64 = by_addr.values().map(|l| l.work).sum();
+    let mut out = by_addr.values().cloned().collect::<Vec<_>>();
+    for l in out.iter_mut() {
+        if total_work > 0.0 {
+            l.share = l.work / total_work;
+        }
+        l.amount = l.share * total_pkt;
+    }
+    out.sort_by(|a, b| {
+        b.work
+            .partial_cmp(&a.work)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.pay_to.cmp(&b.pay_to))
+    });
+    out
+}
+
+/// Split a payout between the addresses in proportion to the work they were credited,
+/// largest first. Nothing is paid, this is for checking what the paymaker should do.
+pub fn payout(events: &[AnnsEvent], total_pkt: f64) -> Vec<PayoutLine> {
+    let mut by_addr = HashMap::new();
+    for ev in events {
+        credit(&mut by_addr, ev);
+    }
+    split(&by_addr, total_pkt)
+}
+
+/// The work credited to each address in the current round, largest first, and the time
+/// when the round started. Rounds start at multiples of round_seconds since the epoch.
+/// The amounts are zero.
+pub async fn round_work(acct: &Accounting, round_seconds: u64) -> Result<(u64, Vec<PayoutLine>)> {
+    let round_ms = round_seconds.max(1) * 1000;
+    let now = util::now_ms();
+    let start_ms = now - now % round_ms;
+    let mut m = acct.m.lock().await;
+    if m.round_start_ms != start_ms {
+        // Nothing is written while the lock is held, so no event is missed or counted twice
+        let mut round = HashMap::new();
+        for ev in read(&acct.dir, start_ms, u64::MAX).await? {
+            credit(&mut round, &ev);
+        }
+        m.round = round;
+        m.round_start_ms = start_ms;
+    }
+    Ok((start_ms, split(&m.round, 0.0)))
+}
+
+/// Write the payout round for the events between from_ms and to_ms as csv or ndjson,
+/// returns the lines of the report.
+pub async fn payout_report(
+    dir: &str,
+    from_ms: u64,
+    to_ms: u64,
+    total_pkt: f64,
+    csv: bool,
+    out: &mut impl Write,
+) -> Result<Vec<PayoutLine>> {
+    let events = read(dir, from_ms, to_ms).await?;
+    let lines = payout(&events, total_pkt);
+    if csv {
+        writeln!(out, "pay_to,events,anns,work,share,amount")?;
+    }
+    for l in &lines {
+        if csv {
+            writeln!(
+                out,
+                "{},{},{},{},{:.8},{:.9}",
+                l.pay_to, l.events, l.anns, l.work, l.share, l.amount
+            )?;
+        } else {
+            writeln!(out, "{}", serde_json::to_string(l)?)?;
+        }
+    }
+    info!(
+        "Payout round of {} PKT from {} events, {} addresses, NO payments were made",
+        total_pkt,
+        events.len(),
+        lines.len()
+    );
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use packetcrypt_util::protocol::AnnsEvent;
+
+    fn ev(pay_to: &str, accepted: u32) -> AnnsEvent {
+        AnnsEvent {
+            pay_to: pay_to.into(),
+            accepted,
+            target: 0x2000ffff,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn payout() {
+        let evs = [ev("a", 10), ev("b", 30), ev("a", 10)];
+        let lines = super::payout(&evs, 100.0);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].pay_to, "b");
+        assert_eq!(lines[0].anns, 30);
+        assert!((lines[0].amount - 60.0).abs() < 1e-9);
+        assert_eq!(lines[1].events, 2);
+        assert!((lines[1].share - 0.4).abs() < 1e-9);
+    }
+}
@@ -1,3 +1,4 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+pub mod accounting;
 pub mod paymakerclient;
 pub mod poolcfg;
@@ -4,6 +4,7 @@ use core::time::Duration;
 use log::{debug, error, trace, warn};
 use packetcrypt_util::poolclient::{self, PoolClient};
 use packetcrypt_util::protocol::PaymakerReply;
+use packetcrypt_util::tasks::Restart;
 use packetcrypt_util::{hash, util};
 use regex::Regex;
 use serde::Serialize;
@@ -107,13 +108,13 @@ async fn pc_update_loop(pmc: &PaymakerClient) {
 }
 
 pub async fn start(pmc: &PaymakerClient) {
-    packetcrypt_util::async_spawn!(pmc, {
+    packetcrypt_util::async_supervise!("paymaker switch", Restart::Always, pmc, {
         switch_loop(&pmc).await;
     });
-    packetcrypt_util::async_spawn!(pmc, {
+    packetcrypt_util::async_supervise!("paymaker submit", Restart::Always, pmc, {
         submit_loop(&pmc).await;
     });
-    packetcrypt_util::async_spawn!(pmc, {
+    packetcrypt_util::async_supervise!("paymaker update", Restart::Always, pmc, {
         pc_update_loop(&pmc).await;
     });
 }
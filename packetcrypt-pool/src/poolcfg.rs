@@ -7,22 +7,98 @@ pub struct AnnHandlerCfg {
     pub skip_check_chance: f32,
     pub num_workers: usize,
     pub input_queue_len: usize,
+    // Threads which do nothing but verify anns, on top of num_workers, default 0
+    pub verify_workers: Option<usize>,
+    // Batches which can wait for verification, for each of new and old anns, default 64
+    pub verify_queue_len: Option<usize>,
+    // Verify anns for the newest block before any backlog of anns for older ones,
+    // default true
+    pub verify_newest_first: Option<bool>,
     pub public_url: String,
     pub bind_pub: String,
+    // Refuse uploads of more anns than this, miners are told so they can send less,
+    // default is no limit
+    pub max_upload_anns: Option<usize>,
     pub files_to_keep: usize,
+    // Write an ann file when it has this many anns or is this old, default 1024 and 2000
+    pub ann_file_max_anns: Option<usize>,
+    pub ann_file_max_ms: Option<u64>,
+    // Also prune ann files older than this, or the oldest while all of them take more
+    // than this, default is no limit
+    pub ann_file_max_age_seconds: Option<u64>,
+    pub ann_files_max_mb: Option<usize>,
+    // Keep the file and offset of every ann by its hash, for explorers
+    pub ann_hash_index: Option<bool>,
+    // Keep the ann files in memory rather than on disk
+    pub ann_files_in_memory: Option<bool>,
+    // Also copy every ann file into this directory, with indexes, for publishing
+    pub ann_mirror_dir: Option<String>,
+    // Publish the accepted anns on an NNG pub0 socket here, only their headers unless
+    // ann_pub_bodies is true
+    pub ann_pub_bind: Option<String>,
+    pub ann_pub_bodies: Option<bool>,
 
     pub block_miner_passwd: String,
     pub bind_pvt: String,
     pub spray_workers: u32,
     pub subscribe_to: Vec<String>,
+    // Largest sprayer datagram, default is to fit the path MTU
     pub mss: Option<usize>,
     pub spray_at: Option<Vec<String>>,
+    // Also accept sprayer subscriptions over TCP, for block miners which can't get UDP
+    pub spray_tcp: Option<bool>,
+    // Seal sprayer traffic with this pre-shared key, for links which cross the internet
+    pub spray_psk: Option<String>,
+
+    // Serve the stats for Prometheus at /metrics and as JSON at /stats.json here
+    pub stats_bind: Option<String>,
+
+    // Password for the moderation api, if unset then it is disabled
+    pub admin_passwd: Option<String>,
+    // How long to ban sources which send too many invalid anns
+    pub ban_seconds: Option<u64>,
+    // Ask each miner's IP for a proof of work with this many zero bits before taking its
+    // uploads, at most 24, unset is no challenge
+    pub upload_challenge_bits: Option<u32>,
+    // Key of the challenges, the same on every shard, required with upload_challenge_bits
+    pub challenge_secret: Option<String>,
+    // Only take uploads which carry this bearer token, the --pool-token of the miners,
+    // the same on every shard, unset is open to all
+    pub upload_token: Option<String>,
+    // Start a new accounting log file this often, default is 3600
+    pub accounting_rotate_seconds: Option<u64>,
+
+    // Header in which the TLS proxy in front of the handler puts the subject of the
+    // client certificate which it verified, if set then rigs without one are refused
+    pub client_cert_header: Option<String>,
+    // Spread this handler over multiple machines, each one owns a range of ann hashes
+    // and forwards the anns it receives for the others. shard_urls is the submit url
+    // of every shard in order and shard_num is which one this is.
+    pub shard_urls: Option<Vec<String>>,
+    pub shard_num: Option<usize>,
+    // Shards which know this password can pass on the address of the miner
+    pub shard_passwd: Option<String>,
+
+    // Where to also send the anns of some content types
+    pub content_routes: Option<Vec<ContentRoute>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ContentRoute {
+    pub content_type: u32,
+    // Send each ann as a datagram to this unix socket or UDP address, one of them
+    pub unix_socket: Option<String>,
+    pub udp: Option<String>,
+    // Also put them in the ann files and spray them, default true
+    pub keep: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Config {
     pub paymaker_http_password: String,
     pub master_url: String,
+    // Access token if the pool master is private
+    pub pool_token: Option<String>,
     pub root_workdir: String,
     pub ann_handler: HashMap<String, AnnHandlerCfg>,
 }
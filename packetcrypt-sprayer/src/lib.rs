@@ -2,17 +2,28 @@
 use anyhow::{bail, Context, Result};
 use log::{debug, info, warn};
 use packetcrypt_util::protocol::SprayerReq;
-use packetcrypt_util::util;
+use packetcrypt_util::stats::{self, Metric, Stats};
+use packetcrypt_util::{resolver, util};
 use parking_lot::{Mutex, RwLock};
 
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::SocketAddr;
+use std::net::TcpListener;
 use std::net::UdpSocket;
-use std::sync::atomic::{self, AtomicUsize};
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
 use std::sync::Arc;
+use std::time::Instant;
+
+mod mtu;
+mod seal;
+mod tcp;
 
 // 1MB per send/recv chunk
 const ANN_PER_CHUNK: usize = 1024;
@@ -20,12 +31,24 @@ const ANN_PER_CHUNK: usize = 1024;
 // Never store more tha 128MB to be sent to any given address
 const MAX_SEND_QUEUE_CHUNKS_PER_PEER: usize = 128;
 
+// When relaying, up to 1GB more per address can be buffered on disk
+const MAX_SPILL_CHUNKS_PER_PEER: usize = 1024;
+
+// Chunks are sent newest first so sequence numbers can go backward by this much
+// without it meaning that the sender restarted
+const MAX_SEQ_REORDER: u64 =
+    ((MAX_SEND_QUEUE_CHUNKS_PER_PEER + MAX_SPILL_CHUNKS_PER_PEER) * ANN_PER_CHUNK) as u64;
+
 // How long a node doesn't send a subscription update before we stop flooding them
 const SECONDS_UNTIL_SUB_TIMEOUT: usize = 30;
 
 // How often to resend subscriptions
 const SECONDS_UNTIL_RESUB: usize = 5;
 
+// How often to check whether peers given by name have moved, lookups are cached for
+// the TTL of the record so DNS is only asked again once it expires
+const SECONDS_BETWEEN_RESOLVE: u64 = 5;
+
 ///
 
 const STATS_EVERY: usize = 10;
@@ -36,8 +59,27 @@ const RECV_BUF_SZ: usize = 512 * 1024 * 1024;
 const MSG_PREFIX: usize = 8;
 const PKT_LENGTH: usize = 1024 + MSG_PREFIX;
 const CHUNK_LEN: usize = ANN_PER_CHUNK * PKT_LENGTH;
+// A packet on the wire when there is a psk
+const SEALED_LENGTH: usize = PKT_LENGTH + seal::OVERHEAD;
 const LOG_CREDITS: usize = 16;
 
+// Pacing limits in packets per second, sending starts at PACE_INITIAL_PPS once the
+// subscriber first reports how many packets it received and is adjusted from there.
+// Subscribers which never report (older versions) are not paced at all.
+const PACE_MIN_PPS: f64 = 1_000.0;
+const PACE_INITIAL_PPS: f64 = 100_000.0;
+const PACE_MAX_PPS: f64 = 1_500_000.0;
+
+// Never send more than this many packets back-to-back, this is the most which fits
+// in one GSO send so pacing does not cost any extra syscalls.
+const PACE_BURST_PACKETS: f64 = (0xffff / PKT_LENGTH) as f64;
+
+// If more than this fraction of packets are lost, we are sending too fast
+const PACE_MAX_LOSS: f64 = 0.02;
+
+// Don't adjust the rate based on fewer packets than this, the numbers are noise
+const PACE_MIN_SAMPLE: u64 = ANN_PER_CHUNK as u64;
+
 #[derive(Clone)]
 pub struct Chunk {
     bcur: usize,
@@ -117,12 +159,154 @@ impl ChunkPool {
     }
 }
 
+// Chunks which did not fit in a peer's send queue, kept on disk until there is room
+struct Spill {
+    path: PathBuf,
+    file: File,
+    // Slot and length of each chunk in the file, oldest first
+    used: VecDeque<(usize, usize)>,
+    free: Vec<usize>,
+    slots: usize,
+}
+impl Spill {
+    fn open(path: PathBuf) -> Result<Spill> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .with_context(|| format!("open({})", path.display()))?;
+        Ok(Spill {
+            path,
+            file,
+            used: VecDeque::new(),
+            free: Vec::new(),
+            slots: 0,
+        })
+    }
+
+    // Returns the number of anns which were dropped to make room
+    fn put(&mut self, c: &Chunk) -> Result<usize> {
+        let mut dropped = 0;
+        let slot = if let Some(slot) = self.free.pop() {
+            slot
+        } else if self.slots < MAX_SPILL_CHUNKS_PER_PEER {
+            self.slots += 1;
+            self.slots - 1
+        } else {
+            let (slot, len) = self.used.pop_front().unwrap();
+            dropped = len / PKT_LENGTH;
+            slot
+        };
+        let res = self
+            .file
+            .seek(SeekFrom::Start((slot * CHUNK_LEN) as u64))
+            .and_then(|_| self.file.write_all(c.all_anns()));
+        if let Err(e) = res {
+            self.free.push(slot);
+            bail!("write to {}: {}", self.path.display(), e);
+        }
+        self.used.push_back((slot, c.ecur - c.bcur));
+        Ok(dropped)
+    }
+
+    // Newest first, same as the send queue
+    fn take(&mut self, c: &mut Chunk) -> Result<bool> {
+        let (slot, len) = if let Some(x) = self.used.pop_back() {
+            x
+        } else {
+            return Ok(false);
+        };
+        self.free.push(slot);
+        self.file
+            .seek(SeekFrom::Start((slot * CHUNK_LEN) as u64))
+            .and_then(|_| self.file.read_exact(&mut c.bytes[..len]))
+            .with_context(|| format!("read from {}", self.path.display()))?;
+        c.bcur = 0;
+        c.ecur = len;
+        Ok(true)
+    }
+}
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
 struct SendQueue {
     next_num: u64,
     q: VecDeque<Box<Chunk>>,
     chunk_pool: Arc<ChunkPool>,
+
+    // If relaying, where to buffer chunks which overflow the queue
+    spill_path: Option<PathBuf>,
+    spill: Option<Spill>,
 }
 impl SendQueue {
+    fn new(chunk_pool: &Arc<ChunkPool>, relay_dir: &Option<PathBuf>, peer: &SocketAddr) -> Self {
+        SendQueue {
+            next_num: 0,
+            q: VecDeque::new(),
+            chunk_pool: Arc::clone(chunk_pool),
+            spill_path: relay_dir
+                .as_ref()
+                .map(|d| d.join(format!("{}.spill", peer).replace(':', "_"))),
+            spill: None,
+        }
+    }
+
+    // Returns the number of anns which could not be kept
+    fn spill_chunk(&mut self, mut c: Box<Chunk>) -> usize {
+        let path = if let Some(path) = &self.spill_path {
+            path
+        } else {
+            return c.len();
+        };
+        if self.spill.is_none() {
+            match Spill::open(path.clone()) {
+                Ok(s) => self.spill = Some(s),
+                Err(e) => {
+                    warn!("Unable to open relay buffer, disabling it: {}", e);
+                    self.spill_path = None;
+                    return c.len();
+                }
+            }
+        }
+        match self.spill.as_mut().unwrap().put(&c) {
+            Ok(dropped) => {
+                c.reset();
+                self.chunk_pool.give(c);
+                dropped
+            }
+            Err(e) => {
+                warn!("Relay buffer failed, disabling it: {}", e);
+                self.spill_path = None;
+                self.spill = None;
+                c.len()
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Box<Chunk>> {
+        if let Some(c) = self.q.pop_back() {
+            return Some(c);
+        }
+        let spill = self.spill.as_mut()?;
+        let mut c = self.chunk_pool.take();
+        match spill.take(&mut c) {
+            Ok(true) => return Some(c),
+            Ok(false) => (),
+            Err(e) => {
+                warn!("Relay buffer failed, disabling it: {}", e);
+                self.spill_path = None;
+                self.spill = None;
+            }
+        }
+        self.chunk_pool.give(c);
+        None
+    }
+
     fn push_ann(&mut self, ann: &[u8]) -> usize {
         let mut overflow = 0;
         loop {
@@ -140,28 +324,155 @@ impl SendQueue {
             self.q.push_back(self.chunk_pool.take());
             if self.q.len() > MAX_SEND_QUEUE_CHUNKS_PER_PEER {
                 let c = self.q.pop_front().unwrap();
-                overflow += c.len();
+                overflow += self.spill_chunk(c);
             }
         }
         overflow
     }
 }
+
+// Token bucket which spreads sends across time rather than sending in bursts
+struct Pacer {
+    rate_pps: f64,
+    tokens: f64,
+    last_refill: Instant,
+
+    // Total packets actually sent to the peer
+    packets_sent: u64,
+
+    // Counters as of the last feedback from the peer
+    fb_packets_sent: u64,
+    fb_packets_recv: Option<u64>,
+}
+impl Pacer {
+    fn new() -> Self {
+        Pacer {
+            rate_pps: PACE_INITIAL_PPS,
+            tokens: PACE_BURST_PACKETS,
+            last_refill: Instant::now(),
+            packets_sent: 0,
+            fb_packets_sent: 0,
+            fb_packets_recv: None,
+        }
+    }
+
+    // Number of packets which may be sent right now
+    fn allowance(&mut self) -> usize {
+        if self.fb_packets_recv.is_none() {
+            // No feedback yet, send whole chunks
+            return ANN_PER_CHUNK;
+        }
+        let now = Instant::now();
+        let secs = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + secs * self.rate_pps).min(PACE_BURST_PACKETS);
+        self.tokens as usize
+    }
+
+    fn on_sent(&mut self, packets: usize) {
+        self.tokens -= packets as f64;
+        self.packets_sent += packets as u64;
+    }
+
+    // Additive increase, multiplicative decrease
+    fn on_feedback(&mut self, peer: &SocketAddr, packets_recv: u64) {
+        let sent = self.packets_sent - self.fb_packets_sent;
+        let last_recv = self.fb_packets_recv.replace(packets_recv);
+        self.fb_packets_sent = self.packets_sent;
+        if last_recv.is_none() {
+            // Pacing starts now, forget about what was sent before
+            self.tokens = PACE_BURST_PACKETS;
+            self.last_refill = Instant::now();
+        }
+        let recv = match last_recv {
+            Some(lr) if lr <= packets_recv => packets_recv - lr,
+            // first feedback or the peer restarted, start over
+            _ => return,
+        };
+        if sent < PACE_MIN_SAMPLE {
+            return;
+        }
+        let loss = 1.0 - (recv as f64 / sent as f64);
+        let old_rate = self.rate_pps;
+        if loss > PACE_MAX_LOSS {
+            self.rate_pps = (self.rate_pps * 0.8).max(PACE_MIN_PPS);
+        } else {
+            self.rate_pps = (self.rate_pps + PACE_INITIAL_PPS / 10.0).min(PACE_MAX_PPS);
+        }
+        debug!(
+            "pacing {} loss {:.1}% rate {} -> {} pps",
+            peer,
+            loss * 100.0,
+            old_rate as u64,
+            self.rate_pps as u64
+        );
+    }
+}
+
 struct Subscriber {
     peer: SocketAddr,
     last_update_sec: AtomicUsize,
     send_queue: Mutex<SendQueue>,
+    pacer: Mutex<Pacer>,
+    // Subscribed over TCP, sent to by its own thread and not paced
+    tcp: bool,
 }
 
+// Works out how many packets should have arrived from the sequence numbers which the
+// sender puts in front of each ann, so that loss on the hop can be measured.
+#[derive(Default)]
+struct SeqTracker {
+    // Packets expected before the sender last restarted
+    base: u64,
+    // Lowest and highest sequence number since then
+    range: Option<(u64, u64)>,
+}
+impl SeqTracker {
+    fn expected(&self) -> u64 {
+        self.base + self.range.map(|(lo, hi)| hi - lo + 1).unwrap_or(0)
+    }
+
+    fn on_packets(&mut self, pkts: &[u8]) {
+        for p in pkts.chunks_exact(PKT_LENGTH) {
+            let seq = u64::from_le_bytes(p[..8].try_into().unwrap());
+            self.range = match self.range {
+                Some((lo, hi)) if seq.saturating_add(MAX_SEQ_REORDER) >= lo => {
+                    Some((lo.min(seq), hi.max(seq)))
+                }
+                // first packet or the sender restarted
+                _ => {
+                    self.base = self.expected();
+                    Some((seq, seq))
+                }
+            };
+        }
+    }
+}
+
+#[derive(Default)]
 struct Subscription {
     //    peer: SocketAddr,
     last_update_sec: AtomicUsize,
     packets_received: AtomicUsize,
+    seq: Mutex<SeqTracker>,
+    // When we first subscribed without having received anything, 0 if we have
+    first_sub_sec: AtomicUsize,
+    // Given up on UDP, the subscription is made by a thread in tcp.rs
+    tcp: AtomicBool,
 }
 
 struct SprayerMut {
     subscribers: Vec<Subscriber>,
 }
 
+// The peers from the config, swapped for a new set when one of their names resolves
+// to a different address
+struct Peers {
+    subscribe_to: Vec<SocketAddr>,
+    subscribed_to: HashMap<SocketAddr, Arc<Subscription>>,
+    force_subscribe: Vec<Arc<Subscriber>>,
+}
+
 pub trait OnAnns: Send + Sync {
     fn on_anns(&self, anns: &[&[u8]]);
 }
@@ -173,6 +484,7 @@ impl<T: Send + Sync> OnAnns for Option<T> {
 struct PeerCounters {
     last_logged_ms: u64,
     last_packets_recv: usize,
+    last_packets_expected: u64,
     last_packets_sent: u64,
 }
 #[derive(Clone)]
@@ -182,16 +494,21 @@ pub struct PeerStats {
     pub kbps_out: f64,
     pub packets_in: u64,
     pub packets_out: u64,
+    pub pace_kbps: f64,
+    // Fraction of the packets sent to us which did not arrive, inbound links only
+    pub loss: f64,
 }
 
 struct SprayerS {
     m: RwLock<SprayerMut>,
-    subscribe_to: Vec<SocketAddr>,
+    peers: RwLock<Arc<Peers>>,
+    // Only if some peers are given by name
+    resolver: Option<resolver::Resolver>,
+    subscribe_names: Vec<String>,
+    spray_at_names: Vec<String>,
     passwd: String,
     socket: UdpSocket,
     handler: RwLock<Option<Box<dyn OnAnns>>>,
-    subscribed_to: HashMap<SocketAddr, Subscription>,
-    force_subscribe: Vec<Subscriber>,
     workers: usize,
     gso_ok: bool,
     is_mcast: bool,
@@ -202,6 +519,11 @@ struct SprayerS {
     chunk_pool: Arc<ChunkPool>,
     pkt_size: usize,
     self_addr: SocketAddr,
+    relay_dir: Option<PathBuf>,
+    tcp_fallback: bool,
+    tcp_listener: Option<TcpListener>,
+    proxy: String,
+    psk: Option<seal::Psk>,
 }
 pub struct Sprayer(Arc<SprayerS>);
 
@@ -218,9 +540,20 @@ pub struct Config {
     pub workers: usize,
     pub subscribe_to: Vec<String>,
     pub log_peer_stats: bool,
+    // Largest datagram to send, 0 to choose from the path MTU
     pub mss: usize,
     pub spray_at: Vec<String>,
     pub mcast: String,
+    // If non-empty, anns which can't be sent fast enough are buffered on disk here
+    pub relay_dir: String,
+    // Accept subscriptions over TCP on the bind address, and subscribe over TCP to
+    // peers which send nothing over UDP
+    pub tcp_fallback: bool,
+    // If non-empty, TCP subscriptions go through this socks5:// or http:// proxy
+    pub proxy: String,
+    // If non-empty, everything sent is sealed with this and anything which is not is
+    // dropped, see seal.rs
+    pub psk: String,
 }
 
 #[cfg(windows)]
@@ -234,8 +567,87 @@ fn raw_fd(s: &UdpSocket) -> i32 {
     s.as_raw_fd()
 }
 
+// Peers may be given as host:port, in which case the first address is used until the
+// name stops resolving to it, see refresh_peers()
+async fn resolve_peer(r: &Option<resolver::Resolver>, peer: &str) -> Result<SocketAddr> {
+    if let Ok(sa) = peer.parse() {
+        return Ok(sa);
+    }
+    let r = if let Some(r) = r {
+        r
+    } else {
+        bail!("Unable to resolve [{}], no resolver", peer);
+    };
+    let addrs = resolver::lookup_host(r, peer).await?;
+    if addrs.len() > 1 {
+        debug!(
+            "[{}] has multiple addresses {:?}, using the first",
+            peer, addrs
+        );
+    }
+    Ok(addrs[0])
+}
+
+// A peer which is still at one of the addresses its name resolves to is left alone so
+// that round-robin DNS does not make it move, if the lookup fails it stays put as well
+async fn re_resolve(
+    r: &resolver::Resolver,
+    names: &[String],
+    current: &[SocketAddr],
+) -> Vec<SocketAddr> {
+    let mut out = Vec::with_capacity(names.len());
+    for (name, cur) in names.iter().zip(current) {
+        match resolver::lookup_host(r, name).await {
+            Ok(addrs) if addrs.contains(cur) => out.push(*cur),
+            Ok(addrs) => {
+                info!("[{}] moved from {} to {}", name, cur, addrs[0]);
+                out.push(addrs[0]);
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to resolve [{}] again, staying with {}: {}",
+                    name, cur, e
+                );
+                out.push(*cur);
+            }
+        }
+    }
+    out
+}
+
+fn forced_subscriber(
+    peer: SocketAddr,
+    chunk_pool: &Arc<ChunkPool>,
+    relay_dir: &Option<PathBuf>,
+) -> Subscriber {
+    Subscriber {
+        peer,
+        send_queue: Mutex::new(SendQueue::new(chunk_pool, relay_dir, &peer)),
+        last_update_sec: AtomicUsize::new(0),
+        pacer: Mutex::new(Pacer::new()),
+        tcp: false,
+    }
+}
+
+impl SprayerS {
+    fn peers(&self) -> Arc<Peers> {
+        Arc::clone(&self.peers.read())
+    }
+}
+
 impl Sprayer {
-    pub fn new(cfg: &Config) -> Result<Sprayer> {
+    pub async fn new(cfg: &Config) -> Result<Sprayer> {
+        let r = if cfg
+            .subscribe_to
+            .iter()
+            .chain(cfg.spray_at.iter())
+            .any(|p| p.parse::<SocketAddr>().is_err())
+        {
+            Some(resolver::shared().await?)
+        } else {
+            None
+        };
+
         let gso_err = unsafe {
             let err = packetcrypt_sys::UdpGso_supported();
             if !err.is_null() {
@@ -268,18 +680,24 @@ impl Sprayer {
                 bail!("Cannot do multicast with ipv6 bind");
             }
         }
+        let tcp_listener = if cfg.tcp_fallback && mcast.is_none() {
+            Some(TcpListener::bind(addr).with_context(|| format!("TcpListener::bind({})", addr))?)
+        } else {
+            None
+        };
 
-        let fd = raw_fd(&socket);
-        let pkt_size = (cfg.mss / PKT_LENGTH) * PKT_LENGTH;
-        if let Some(ref err) = gso_err {
-            warn!("UDP_GSO not supported {}, expect high CPU usage", err);
+        let psk = if cfg.psk.is_empty() {
+            None
         } else {
-            let res = unsafe { packetcrypt_sys::UdpGro_enable(fd, pkt_size as i32) };
-            if res != 0 {
-                bail!("UdpGro_enable() failed {}", res);
-            }
-        }
+            Some(seal::Psk::new(&cfg.psk))
+        };
+        let wire_len = if psk.is_some() {
+            SEALED_LENGTH
+        } else {
+            PKT_LENGTH
+        };
 
+        let fd = raw_fd(&socket);
         let res = unsafe { packetcrypt_sys::UdpGro_setRecvBuf(fd, RECV_BUF_SZ as i32) };
         if res != 0 {
             warn!(
@@ -292,43 +710,69 @@ impl Sprayer {
             subscribers: Vec::new(),
         });
 
-        let mut subscribed_to: HashMap<SocketAddr, Subscription> = HashMap::new();
+        let mut subscribed_to: HashMap<SocketAddr, Arc<Subscription>> = HashMap::new();
         let mut subscribe_to = Vec::new();
         for s in &cfg.subscribe_to {
-            let peer = s.parse()?;
+            let peer = resolve_peer(&r, s).await?;
             subscribe_to.push(peer);
-            subscribed_to.insert(
-                peer,
-                Subscription {
-                    last_update_sec: AtomicUsize::new(0),
-                    packets_received: AtomicUsize::new(0),
-                },
-            );
+            subscribed_to.insert(peer, Arc::new(Subscription::default()));
         }
 
         let chunk_pool = Arc::new(ChunkPool {
             q: Mutex::new(VecDeque::new()),
         });
 
+        let relay_dir = if cfg.relay_dir.is_empty() {
+            None
+        } else {
+            let dir = PathBuf::from(&cfg.relay_dir);
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("create_dir_all({})", dir.display()))?;
+            info!("Relaying, overflow will be buffered in {}", dir.display());
+            Some(dir)
+        };
+
         let mut force_subscribe = Vec::new();
         for s in &cfg.spray_at {
-            let peer = s.parse()?;
-            force_subscribe.push(Subscriber {
-                peer,
-                send_queue: Mutex::new(SendQueue {
-                    next_num: 0,
-                    q: VecDeque::new(),
-                    chunk_pool: Arc::clone(&chunk_pool),
-                }),
-                last_update_sec: AtomicUsize::new(0),
-            });
+            let peer = resolve_peer(&r, s).await?;
+            force_subscribe.push(Arc::new(forced_subscriber(peer, &chunk_pool, &relay_dir)));
+        }
+
+        let mss = if cfg.mss > 0 {
+            cfg.mss
+        } else {
+            let peers = subscribe_to
+                .iter()
+                .chain(force_subscribe.iter().map(|s| &s.peer));
+            mtu::auto_mss(&addr, peers)
+        };
+        let pkt_size = (mss / wire_len) * wire_len;
+        if pkt_size == 0 {
+            bail!(
+                "mss {} is too small for a packet of {} bytes",
+                mss,
+                wire_len
+            );
+        }
+        if let Some(ref err) = gso_err {
+            warn!("UDP_GSO not supported {}, expect high CPU usage", err);
+        } else {
+            let res = unsafe { packetcrypt_sys::UdpGro_enable(fd, pkt_size as i32) };
+            if res != 0 {
+                bail!("UdpGro_enable() failed {}", res);
+            }
         }
 
         Ok(Sprayer(Arc::new(SprayerS {
             m,
-            subscribe_to,
-            subscribed_to,
-            force_subscribe,
+            peers: RwLock::new(Arc::new(Peers {
+                subscribe_to,
+                subscribed_to,
+                force_subscribe,
+            })),
+            resolver: r,
+            subscribe_names: cfg.subscribe_to.clone(),
+            spray_at_names: cfg.spray_at.clone(),
             passwd: cfg.passwd.clone(),
             socket,
             gso_ok: gso_err.is_none(),
@@ -342,6 +786,11 @@ impl Sprayer {
             chunk_pool,
             pkt_size,
             self_addr: addr,
+            relay_dir,
+            tcp_fallback: cfg.tcp_fallback,
+            tcp_listener,
+            proxy: cfg.proxy.clone(),
+            psk,
         })))
     }
 
@@ -352,7 +801,7 @@ impl Sprayer {
     pub fn push_anns(&self, anns: &[&[u8]]) -> usize {
         let oldest_allowed_time = (util::now_ms() / 1000) as usize - SECONDS_UNTIL_SUB_TIMEOUT;
         let mut overflow = 0;
-        for s in &self.0.force_subscribe {
+        for s in &self.0.peers().force_subscribe {
             let mut sq = s.send_queue.lock();
             for ann in anns {
                 overflow += sq.push_ann(ann);
@@ -373,11 +822,30 @@ impl Sprayer {
     }
 
     pub fn start(&self) {
+        stats::register(&self.0);
+        if let Some(l) = &self.0.tcp_listener {
+            match l.try_clone() {
+                Ok(l) => tcp::listen(self, l),
+                Err(e) => warn!("Unable to listen for TCP subscriptions: {}", e),
+            }
+        }
+        if self.0.resolver.is_some() {
+            let g = Sprayer(Arc::clone(&self.0));
+            tokio::spawn(async move {
+                loop {
+                    util::sleep_ms(SECONDS_BETWEEN_RESOLVE * 1000).await;
+                    g.refresh_peers().await;
+                }
+            });
+        }
         for tid in 0..self.0.workers {
             let g = Sprayer(Arc::clone(&self.0));
             let rchunk = self.0.chunk_pool.take();
             std::thread::spawn(move || {
                 Box::new(SprayWorker {
+                    sealer: g.0.psk.as_ref().map(seal::Sealer::new),
+                    opener: g.0.psk.as_ref().map(seal::Opener::new),
+                    wbuf: vec![0_u8; 0x10000],
                     g,
                     rchunk,
                     time_of_last_log: 0,
@@ -389,67 +857,153 @@ impl Sprayer {
         }
     }
 
-    fn get_to_send(&self, tid: usize) -> Option<(Box<Chunk>, SocketAddr)> {
+    // Look up the names of the peers again and if any have moved then swap in a new set
+    // of peers, everything about the peers which did not move is kept
+    async fn refresh_peers(&self) {
+        let r = if let Some(r) = &self.0.resolver {
+            r
+        } else {
+            return;
+        };
+        let old = self.0.peers();
+        let old_spray_at = old
+            .force_subscribe
+            .iter()
+            .map(|s| s.peer)
+            .collect::<Vec<_>>();
+        let subscribe_to = re_resolve(r, &self.0.subscribe_names, &old.subscribe_to).await;
+        let spray_at = re_resolve(r, &self.0.spray_at_names, &old_spray_at).await;
+        if subscribe_to == old.subscribe_to && spray_at == old_spray_at {
+            return;
+        }
+        let subscribed_to = subscribe_to
+            .iter()
+            .map(|p| (*p, old.subscribed_to.get(p).cloned().unwrap_or_default()))
+            .collect();
+        let force_subscribe = spray_at
+            .iter()
+            .map(
+                |p| match old.force_subscribe.iter().find(|s| s.peer == *p) {
+                    Some(s) => Arc::clone(s),
+                    None => Arc::new(forced_subscriber(*p, &self.0.chunk_pool, &self.0.relay_dir)),
+                },
+            )
+            .collect();
+        *self.0.peers.write() = Arc::new(Peers {
+            subscribe_to,
+            subscribed_to,
+            force_subscribe,
+        });
+    }
+
+    // Returns a chunk along with how many packets the pacer allows us to send
+    fn get_to_send(&self, tid: usize) -> Option<(Box<Chunk>, SocketAddr, usize)> {
+        let try_sub = |sub: &Subscriber| {
+            if sub.tcp {
+                return None;
+            }
+            let mut pacer = sub.pacer.lock();
+            let allowance = pacer.allowance();
+            if allowance == 0 {
+                return None;
+            }
+            let chunk = sub.send_queue.lock().pop()?;
+            Some((chunk, sub.peer, allowance))
+        };
         {
             let m = self.0.m.read();
             if !m.subscribers.is_empty() {
                 let start = tid % m.subscribers.len();
                 for sub in &m.subscribers[start..] {
-                    if let Some(chunk) = sub.send_queue.lock().q.pop_back() {
-                        return Some((chunk, sub.peer));
+                    if let Some(x) = try_sub(sub) {
+                        return Some(x);
                     }
                 }
                 for sub in &m.subscribers[0..start] {
-                    if let Some(chunk) = sub.send_queue.lock().q.pop_back() {
-                        return Some((chunk, sub.peer));
+                    if let Some(x) = try_sub(sub) {
+                        return Some(x);
                     }
                 }
             }
         }
-        for sub in &self.0.force_subscribe {
-            if let Some(chunk) = sub.send_queue.lock().q.pop_back() {
-                return Some((chunk, sub.peer));
+        for sub in &self.0.peers().force_subscribe {
+            if let Some(x) = try_sub(sub) {
+                return Some(x);
             }
         }
         None
     }
 
-    fn return_to_send(&self, chunk: Box<Chunk>, addr: SocketAddr) {
-        for sub in &self.0.force_subscribe {
-            if sub.peer == addr {
+    // Account for sent packets and requeue the chunk if it is not empty
+    fn return_to_send(&self, chunk: Box<Chunk>, addr: SocketAddr, sent: usize) {
+        let ret = |sub: &Subscriber, chunk: Box<Chunk>| {
+            sub.pacer.lock().on_sent(sent);
+            if chunk.is_empty() {
+                self.0.chunk_pool.give(chunk);
+            } else {
                 sub.send_queue.lock().q.push_back(chunk);
-                return;
+            }
+        };
+        for sub in &self.0.peers().force_subscribe {
+            if sub.peer == addr {
+                return ret(sub, chunk);
             }
         }
         let m = self.0.m.read();
         for sub in &m.subscribers {
             if sub.peer == addr {
-                sub.send_queue.lock().q.push_back(chunk);
-                return;
+                return ret(sub, chunk);
             }
         }
-        warn!(
-            "Could not return chunk to peer {}, it seems they disappeared",
-            addr
-        );
+        if chunk.is_empty() {
+            self.0.chunk_pool.give(chunk);
+        } else {
+            warn!(
+                "Could not return chunk to peer {}, it seems they disappeared",
+                addr
+            );
+        }
+    }
+
+    // How long a packet is on the wire
+    fn wire_len(&self) -> usize {
+        if self.0.psk.is_some() {
+            SEALED_LENGTH
+        } else {
+            PKT_LENGTH
+        }
     }
 
-    fn send_subs(&self) -> Option<(std::io::Error, SocketAddr)> {
+    fn send_subs(&self, sealer: &mut Option<seal::Sealer>) -> Option<(std::io::Error, SocketAddr)> {
         let now_sec = (util::now_ms() / 1000) as usize;
         let update_time = now_sec - SECONDS_UNTIL_RESUB;
-        for (peer, sub) in self.0.subscribed_to.iter() {
+        for (peer, sub) in self.0.peers().subscribed_to.iter() {
             let time_sec = sub.last_update_sec.load(atomic::Ordering::Relaxed);
-            if time_sec > update_time {
+            if time_sec > update_time || sub.tcp.load(atomic::Ordering::Relaxed) {
                 continue;
             }
-            let req = serde_json::to_string(&SprayerReq {
-                yes_please_dos_me_passwd: self.0.passwd.clone(),
-                num: Some(0),
-                count: Some(1),
-            })
-            .unwrap();
+            if self.0.tcp_fallback && sub.packets_received.load(atomic::Ordering::Relaxed) == 0 {
+                let first = sub.first_sub_sec.load(atomic::Ordering::Relaxed);
+                if first == 0 {
+                    sub.first_sub_sec.store(now_sec, atomic::Ordering::Relaxed);
+                } else if now_sec - first >= tcp::TCP_FALLBACK_SECONDS {
+                    info!(
+                        "Nothing from {} over UDP in {} seconds, falling back to TCP",
+                        peer,
+                        now_sec - first
+                    );
+                    sub.tcp.store(true, atomic::Ordering::Relaxed);
+                    tcp::subscribe(self, *peer);
+                    continue;
+                }
+            }
+            let req = self.sub_req(peer);
             debug!("subscribing to {}", peer);
-            if let Err(e) = self.0.socket.send_to(&req.as_bytes(), peer) {
+            let req = match sealer {
+                Some(s) => s.seal_to_vec(req.as_bytes()),
+                None => req.into_bytes(),
+            };
+            if let Err(e) = self.0.socket.send_to(&req, peer) {
                 return Some((e, *peer));
             }
             sub.last_update_sec
@@ -458,14 +1012,55 @@ impl Sprayer {
         None
     }
 
-    fn incoming_subscription(&self, from: SocketAddr) {
+    fn sub_req(&self, peer: &SocketAddr) -> String {
+        let packets_received = self
+            .0
+            .peers()
+            .subscribed_to
+            .get(peer)
+            .map(|sub| sub.packets_received.load(atomic::Ordering::Relaxed) as u64);
+        serde_json::to_string(&SprayerReq {
+            yes_please_dos_me_passwd: self.0.passwd.clone(),
+            num: Some(0),
+            count: Some(1),
+            packets_received,
+        })
+        .unwrap()
+    }
+
+    // Pass anns which arrived on to our subscribers and the handler
+    fn received(&self, chunk: &Chunk) -> usize {
+        let bufs = chunk
+            .ann_iter()
+            .map(|v| &v[MSG_PREFIX..])
+            .collect::<Vec<_>>();
+        let overflow = self.push_anns(&bufs);
+        let handler = self.0.handler.read();
+        match &*handler {
+            Some(h) => h.on_anns(&bufs),
+            None => (),
+        }
+        overflow
+    }
+
+    fn incoming_subscription(&self, from: SocketAddr, packets_received: Option<u64>) {
         let now_sec = (util::now_ms() / 1000) as usize;
         let oldest_allowed_time = now_sec - SECONDS_UNTIL_SUB_TIMEOUT;
+        if let Some(pr) = packets_received {
+            for s in &self.0.peers().force_subscribe {
+                if s.peer == from {
+                    s.pacer.lock().on_feedback(&from, pr);
+                }
+            }
+        }
         {
             let m = self.0.m.read();
             for s in &m.subscribers {
                 if s.peer == from {
                     s.last_update_sec.store(now_sec, atomic::Ordering::Relaxed);
+                    if let Some(pr) = packets_received {
+                        s.pacer.lock().on_feedback(&from, pr);
+                    }
                     return;
                 }
             }
@@ -488,12 +1083,14 @@ impl Sprayer {
             }
             m.subscribers.push(Subscriber {
                 peer: from,
-                send_queue: Mutex::new(SendQueue {
-                    next_num: 0,
-                    q: VecDeque::new(),
-                    chunk_pool: Arc::clone(&self.0.chunk_pool),
-                }),
+                send_queue: Mutex::new(SendQueue::new(
+                    &self.0.chunk_pool,
+                    &self.0.relay_dir,
+                    &from,
+                )),
                 last_update_sec: AtomicUsize::new(now_sec),
+                pacer: Mutex::new(Pacer::new()),
+                tcp: false,
             });
         }
     }
@@ -510,13 +1107,19 @@ impl Sprayer {
             .store(now_sec, atomic::Ordering::Relaxed);
 
         let mut ps = self.0.peer_counters.lock();
+        let peers = self.0.peers();
         let m = self.0.m.read();
         let mut peer_stats = Vec::new();
         if self.0.log_peer_stats {
             info!("Sprayer links:");
         }
-        for sub in m.subscribers.iter().chain(self.0.force_subscribe.iter()) {
+        for sub in m
+            .subscribers
+            .iter()
+            .chain(peers.force_subscribe.iter().map(|s| &**s))
+        {
             let packets_sent_ever = { sub.send_queue.lock().next_num };
+            let pace_pps = { sub.pacer.lock().rate_pps };
             match ps.get_mut(&sub.peer) {
                 Some(p) => {
                     let packets = (packets_sent_ever - p.last_packets_sent) as u64;
@@ -527,13 +1130,16 @@ impl Sprayer {
                         kbps_out: compute_kbps(packets, ms),
                         packets_in: 0,
                         kbps_in: 0.0,
+                        pace_kbps: compute_kbps(pace_pps as u64, 1000),
+                        loss: 0.0,
                     };
                     if self.0.log_peer_stats {
                         info!(
-                            "<- {} sent {} anns {}",
+                            "<- {} sent {} anns {} (pace {})",
                             sub.peer,
                             packets,
-                            util::format_kbps(st.kbps_out)
+                            util::format_kbps(st.kbps_out),
+                            util::format_kbps(st.pace_kbps)
                         );
                     }
                     peer_stats.push(st);
@@ -547,21 +1153,24 @@ impl Sprayer {
                             last_logged_ms: now_ms,
                             last_packets_sent: packets_sent_ever,
                             last_packets_recv: 0,
+                            last_packets_expected: 0,
                         },
                     );
                 }
             }
         }
-        for peer in &self.0.subscribe_to {
-            let sub = if let Some(p) = self.0.subscribed_to.get(peer) {
+        for peer in &peers.subscribe_to {
+            let sub = if let Some(p) = peers.subscribed_to.get(peer) {
                 p
             } else {
                 continue;
             };
+            let packets_expected_ever = sub.seq.lock().expected();
             match ps.get_mut(peer) {
                 Some(p) => {
                     let packets_recv_ever = sub.packets_received.load(atomic::Ordering::Relaxed);
                     let packets = (packets_recv_ever - p.last_packets_recv) as u64;
+                    let expected = packets_expected_ever.saturating_sub(p.last_packets_expected);
                     let ms = now_ms - p.last_logged_ms;
                     let st = PeerStats {
                         peer: *peer,
@@ -569,18 +1178,26 @@ impl Sprayer {
                         kbps_out: 0.0,
                         packets_in: packets,
                         packets_out: 0,
+                        pace_kbps: 0.0,
+                        loss: if expected > packets {
+                            1.0 - packets as f64 / expected as f64
+                        } else {
+                            0.0
+                        },
                     };
                     if self.0.log_peer_stats {
                         info!(
-                            "-> {} recv {} anns ({})",
+                            "-> {} recv {} anns ({}) loss {:.1}%",
                             peer,
                             packets,
-                            util::format_kbps(st.kbps_in)
+                            util::format_kbps(st.kbps_in),
+                            st.loss * 100.0
                         );
                     }
                     peer_stats.push(st);
                     p.last_logged_ms = now_ms;
                     p.last_packets_recv = sub.packets_received.load(atomic::Ordering::Relaxed);
+                    p.last_packets_expected = packets_expected_ever;
                 }
                 None => {
                     ps.insert(
@@ -588,6 +1205,7 @@ impl Sprayer {
                         PeerCounters {
                             last_logged_ms: now_ms,
                             last_packets_recv: sub.packets_received.load(atomic::Ordering::Relaxed),
+                            last_packets_expected: packets_expected_ever,
                             last_packets_sent: 0,
                         },
                     );
@@ -599,12 +1217,85 @@ impl Sprayer {
     }
 }
 
+impl Stats for SprayerS {
+    fn component(&self) -> &'static str {
+        "sprayer"
+    }
+    fn metrics(&self) -> Vec<Metric> {
+        let mut out = Vec::new();
+        let peers = self.peers();
+        let m = self.m.read();
+        for sub in m
+            .subscribers
+            .iter()
+            .chain(peers.force_subscribe.iter().map(|s| &**s))
+        {
+            let sent = sub.send_queue.lock().next_num;
+            let pace = compute_kbps(sub.pacer.lock().rate_pps as u64, 1000);
+            out.push(
+                Metric::counter("packets_sent_total", "Anns sent to the peer", sent)
+                    .label("peer", sub.peer),
+            );
+            out.push(
+                Metric::gauge("pace_kbps", "How fast anns may be sent to the peer", pace)
+                    .label("peer", sub.peer),
+            );
+        }
+        for (peer, sub) in &peers.subscribed_to {
+            let recv = sub.packets_received.load(atomic::Ordering::Relaxed) as u64;
+            out.push(
+                Metric::counter(
+                    "packets_received_total",
+                    "Anns received from the peer",
+                    recv,
+                )
+                .label("peer", peer),
+            );
+        }
+        for ps in self.peer_stats.lock().iter() {
+            if ps.packets_in > 0 {
+                out.push(
+                    Metric::gauge(
+                        "loss",
+                        "Fraction of packets from the peer which were lost",
+                        ps.loss,
+                    )
+                    .label("peer", ps.peer),
+                );
+            }
+        }
+        out
+    }
+}
+
 struct SprayWorker {
     g: Sprayer,
     rchunk: Box<Chunk>,
     time_of_last_log: usize,
     log_credits: usize,
     tid: usize,
+    // With a psk, packets are sealed and opened through wbuf
+    sealer: Option<seal::Sealer>,
+    opener: Option<seal::Opener>,
+    wbuf: Vec<u8>,
+}
+
+// The packets as they go on the wire, sealed into buf if there is a psk
+fn wire_packets<'a>(
+    sealer: &mut Option<seal::Sealer>,
+    buf: &'a mut [u8],
+    pkts: &'a [u8],
+) -> &'a [u8] {
+    let s = match sealer {
+        Some(s) => s,
+        None => return pkts,
+    };
+    let mut len = 0;
+    for p in pkts.chunks_exact(PKT_LENGTH) {
+        s.seal(p, &mut buf[len..(len + SEALED_LENGTH)]);
+        len += SEALED_LENGTH;
+    }
+    &buf[..len]
 }
 
 impl SprayWorker {
@@ -623,12 +1314,15 @@ impl SprayWorker {
         }
     }
 
-    fn send_slow(&mut self, chunk: &mut Box<Chunk>, addr: SocketAddr) {
-        while chunk.bcur + PKT_LENGTH <= chunk.ecur {
-            let buf = &chunk.bytes[chunk.bcur..(chunk.bcur + PKT_LENGTH)];
+    fn send_slow(&mut self, chunk: &mut Box<Chunk>, addr: SocketAddr, max_packets: usize) {
+        let end = std::cmp::min(chunk.ecur, chunk.bcur + max_packets * PKT_LENGTH);
+        let wire = self.g.wire_len();
+        while chunk.bcur + PKT_LENGTH <= end {
+            let pkt = &chunk.bytes[chunk.bcur..(chunk.bcur + PKT_LENGTH)];
+            let buf = wire_packets(&mut self.sealer, &mut self.wbuf, pkt);
             match self.g.0.socket.send_to(buf, &addr) {
                 Ok(l) => {
-                    if l == PKT_LENGTH {
+                    if l == wire {
                         chunk.bcur += PKT_LENGTH;
                         continue;
                     }
@@ -646,7 +1340,7 @@ impl SprayWorker {
         }
     }
 
-    fn send_gso(&mut self, chunk: &mut Box<Chunk>, addr: SocketAddr) {
+    fn send_gso(&mut self, chunk: &mut Box<Chunk>, addr: SocketAddr, max_packets: usize) {
         let fd = raw_fd(&self.g.0.socket);
         let mut caddr = packetcrypt_sys::UdpGro_Sockaddr {
             isIpv6: if addr.is_ipv6() { 1 } else { 0 },
@@ -657,7 +1351,8 @@ impl SprayWorker {
             IpAddr::V6(a) => caddr.addr.copy_from_slice(&a.octets()),
             IpAddr::V4(a) => caddr.addr[0..4].copy_from_slice(&a.octets()),
         }
-        let max_len = 0xffff / PKT_LENGTH * PKT_LENGTH;
+        let wire = self.g.wire_len();
+        let max_len = 0xffff / wire * wire;
         let pkt_size = if addr.ip() == self.g.0.self_addr.ip() {
             // If same IP as our socket, this is going to internally use the loopback
             // which does not support GRO/GSO but does have a big MTU so we need to send
@@ -667,18 +1362,25 @@ impl SprayWorker {
         } else {
             self.g.0.pkt_size
         } as i32;
+        let end = std::cmp::min(chunk.ecur, chunk.bcur + max_packets * PKT_LENGTH);
         let ret = loop {
-            let buf = &chunk.bytes[chunk.bcur..chunk.ecur];
-            if buf.is_empty() {
+            let pkts = &chunk.bytes[chunk.bcur..end];
+            if pkts.is_empty() {
                 break 0;
             }
-            let len = std::cmp::min(buf.len(), max_len) as i32;
+            let count = std::cmp::min(pkts.len() / PKT_LENGTH, max_len / wire);
+            let buf = wire_packets(
+                &mut self.sealer,
+                &mut self.wbuf,
+                &pkts[..(count * PKT_LENGTH)],
+            );
+            let len = buf.len() as i32;
             let ret =
                 unsafe { packetcrypt_sys::UdpGro_sendmsg(fd, &caddr, buf.as_ptr(), len, pkt_size) };
             if ret > 0 {
                 let uret = ret as usize;
-                let count = uret / PKT_LENGTH;
-                if (count * PKT_LENGTH) != uret {
+                let count = uret / wire;
+                if (count * wire) != uret {
                     self.log(&|| {
                         warn!(
                             "Partial write to {}, only {} of {}",
@@ -704,7 +1406,7 @@ impl SprayWorker {
         if self.tid == 0 && !self.g.0.is_mcast {
             // Busyloop until we manage to send the subscriptions.
             loop {
-                if let Some((e, to)) = self.g.send_subs() {
+                if let Some((e, to)) = self.g.send_subs(&mut self.sealer) {
                     if e.kind() == std::io::ErrorKind::WouldBlock {
                         continue;
                     }
@@ -714,24 +1416,37 @@ impl SprayWorker {
             }
         }
         let mut did_something = false;
-        while let Some((mut chunk, addr)) = self.g.get_to_send(self.tid) {
+        while let Some((mut chunk, addr, allowance)) = self.g.get_to_send(self.tid) {
             did_something = true;
+            let len = chunk.len();
             if self.g.0.gso_ok {
-                self.send_gso(&mut chunk, addr);
+                self.send_gso(&mut chunk, addr, allowance);
             } else {
-                self.send_slow(&mut chunk, addr);
+                self.send_slow(&mut chunk, addr, allowance);
             }
-            if chunk.len() > 0 {
-                self.g.return_to_send(chunk, addr);
+            let sent = len - chunk.len();
+            let blocked = sent < std::cmp::min(len, allowance);
+            self.g.return_to_send(chunk, addr, sent);
+            if blocked {
                 break;
-            } else {
-                self.g.0.chunk_pool.give(chunk);
             }
         }
         did_something
     }
 
     fn maybe_subscribe(&mut self, msg: &[u8], from: SocketAddr) {
+        let mut sealed = Vec::new();
+        let msg = if let Some(o) = &mut self.opener {
+            sealed.extend_from_slice(msg);
+            if let Some(m) = o.open(&mut sealed) {
+                &*m
+            } else {
+                self.log(&|| debug!("Subscription from {} which could not be opened", from));
+                return;
+            }
+        } else {
+            msg
+        };
         let msg = if let Ok(x) = serde_json::from_slice::<SprayerReq>(msg) {
             x
         } else {
@@ -749,23 +1464,63 @@ impl SprayWorker {
             return;
         }
         self.log(&|| debug!("Got subscription from {}", from));
-        self.g.incoming_subscription(from);
+        self.g.incoming_subscription(from, msg.packets_received);
+    }
+
+    // Open the sealed packets in wbuf[..len] into rchunk after ecur, dropping any which
+    // are not authentic, returns how many packets there are after ecur
+    fn open_packets(&mut self, len: usize, from: SocketAddr) -> usize {
+        let o = match &mut self.opener {
+            Some(o) => o,
+            None => return len / PKT_LENGTH,
+        };
+        let mut c = self.rchunk.ecur;
+        let mut bad = 0;
+        for p in self.wbuf[..len].chunks_exact_mut(SEALED_LENGTH) {
+            if let Some(pkt) = o.open(p) {
+                self.rchunk.bytes[c..(c + PKT_LENGTH)].copy_from_slice(pkt);
+                c += PKT_LENGTH;
+            } else {
+                bad += 1;
+            }
+        }
+        if bad > 0 {
+            self.log(&|| {
+                warn!(
+                    "Dropped {} packets from {} which could not be opened, is the psk the same?",
+                    bad, from
+                )
+            });
+        }
+        (c - self.rchunk.ecur) / PKT_LENGTH
     }
 
     // If there's a stub packet then this is returned
     fn recv_slow(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
         let mut out = None;
+        let wire = self.g.wire_len();
         loop {
-            if self.rchunk.ecur >= self.rchunk.bytes.len() {
+            let ecur = self.rchunk.ecur;
+            if ecur >= self.rchunk.bytes.len() {
                 break;
             }
-            let buf = &mut self.rchunk.bytes[self.rchunk.ecur..(self.rchunk.ecur + PKT_LENGTH)];
+            let buf = if self.opener.is_some() {
+                &mut self.wbuf[..wire]
+            } else {
+                &mut self.rchunk.bytes[ecur..(ecur + PKT_LENGTH)]
+            };
             match self.g.0.socket.recv_from(buf) {
                 Ok((len, fr)) => {
-                    if len == PKT_LENGTH {
-                        if let Some(sub) = self.g.0.subscribed_to.get(&fr) {
-                            sub.packets_received.fetch_add(1, atomic::Ordering::Relaxed);
-                            self.rchunk.ecur += PKT_LENGTH;
+                    if len == wire {
+                        let peers = self.g.0.peers();
+                        if let Some(sub) = peers.subscribed_to.get(&fr) {
+                            if self.open_packets(len, fr) == 1 {
+                                sub.packets_received.fetch_add(1, atomic::Ordering::Relaxed);
+                                sub.seq
+                                    .lock()
+                                    .on_packets(&self.rchunk.bytes[ecur..(ecur + PKT_LENGTH)]);
+                                self.rchunk.ecur += PKT_LENGTH;
+                            }
                             continue;
                         }
                     } else {
@@ -793,8 +1548,17 @@ impl SprayWorker {
             addr: [0_u8; 16usize],
         };
         let mut pkt_sz = 0_i32;
-        let buf = &mut self.rchunk.bytes[self.rchunk.ecur..];
-        if buf.len() < PKT_LENGTH {
+        let wire = self.g.wire_len();
+        let ecur = self.rchunk.ecur;
+        let buf = if self.opener.is_some() {
+            // As many sealed packets as there is room for once they are opened
+            let room = (self.rchunk.bytes.len() - ecur) / PKT_LENGTH * wire;
+            let l = std::cmp::min(room, self.wbuf.len());
+            &mut self.wbuf[..l]
+        } else {
+            &mut self.rchunk.bytes[ecur..]
+        };
+        if buf.len() < wire {
             return;
         }
         let res_len = unsafe {
@@ -833,19 +1597,22 @@ impl SprayWorker {
         };
 
         // Stub message at the end? maybe it's a subscribe...
-        let count = len / PKT_LENGTH;
-        let anns_len = count * PKT_LENGTH;
+        let anns_len = len / wire * wire;
         let stub_len = len - anns_len;
         if stub_len != 0 {
-            let mut x = [0_u8; PKT_LENGTH];
-            x[0..stub_len].copy_from_slice(
-                &self.rchunk.bytes[self.rchunk.ecur + anns_len..self.rchunk.ecur + len],
-            );
-            self.maybe_subscribe(&x[0..stub_len], address);
-        } else if let Some(sub) = self.g.0.subscribed_to.get(&address) {
+            let x = if self.opener.is_some() {
+                Vec::from(&self.wbuf[anns_len..len])
+            } else {
+                Vec::from(&self.rchunk.bytes[ecur + anns_len..ecur + len])
+            };
+            self.maybe_subscribe(&x, address);
+        } else if let Some(sub) = self.g.0.peers().subscribed_to.get(&address) {
+            let count = self.open_packets(len, address);
+            let end = ecur + count * PKT_LENGTH;
             sub.packets_received
                 .fetch_add(count, atomic::Ordering::Relaxed);
-            self.rchunk.ecur += len;
+            sub.seq.lock().on_packets(&self.rchunk.bytes[ecur..end]);
+            self.rchunk.ecur = end;
         } else {
             self.log(&|| {
                 warn!(
@@ -885,18 +1652,91 @@ impl SprayWorker {
                 // keep polling until we have neatly a full buffer
                 continue;
             }
-            let bufs = self
-                .rchunk
-                .ann_iter()
-                .map(|v| &v[MSG_PREFIX..])
-                .collect::<Vec<_>>();
-            overflow += self.g.push_anns(&bufs);
-            let handler = self.g.0.handler.read();
-            match &*handler {
-                Some(h) => h.on_anns(&bufs),
-                None => (),
-            }
+            overflow += self.g.received(&self.rchunk);
             self.rchunk.reset();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkts(seqs: &[u64]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for s in seqs {
+            out.extend_from_slice(&s.to_le_bytes());
+            out.extend_from_slice(&[0_u8; 1024]);
+        }
+        out
+    }
+
+    #[test]
+    fn seq_tracker() {
+        let mut st = SeqTracker::default();
+        assert_eq!(st.expected(), 0);
+        // Newest first, with 2 and 5 lost
+        let b = MAX_SEQ_REORDER * 2;
+        st.on_packets(&pkts(&[b + 6, b + 7, b + 3, b + 4, b, b + 1]));
+        assert_eq!(st.expected(), 8);
+        // Sender restarted
+        st.on_packets(&pkts(&[0, 1]));
+        assert_eq!(st.expected(), 10);
+    }
+
+    #[test]
+    fn pacer_waits_for_feedback() {
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut p = Pacer::new();
+        for _ in 0..100 {
+            assert_eq!(p.allowance(), ANN_PER_CHUNK);
+            p.on_sent(ANN_PER_CHUNK);
+        }
+        p.on_feedback(&peer, 0);
+        assert_eq!(p.allowance(), PACE_BURST_PACKETS as usize);
+    }
+
+    #[test]
+    fn spill() {
+        // Chunks are built on the stack before being boxed
+        std::thread::Builder::new()
+            .stack_size(16 << 20)
+            .spawn(spill_thread)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    fn spill_thread() {
+        let path = std::env::temp_dir().join(format!("sprayer-test-{}.spill", std::process::id()));
+        let pool = ChunkPool {
+            q: Mutex::new(VecDeque::new()),
+        };
+        let mut sp = Spill::open(path.clone()).unwrap();
+        for i in 0..(MAX_SPILL_CHUNKS_PER_PEER as u64 + 2) {
+            let mut c = pool.take();
+            c.push_ann(&[i as u8; 1024], i);
+            let dropped = sp.put(&c).unwrap();
+            assert_eq!(
+                dropped,
+                if i < MAX_SPILL_CHUNKS_PER_PEER as u64 {
+                    0
+                } else {
+                    1
+                }
+            );
+            c.reset();
+            pool.give(c);
+        }
+        let mut c = pool.take();
+        assert!(sp.take(&mut c).unwrap());
+        assert_eq!(c.len(), 1);
+        assert_eq!(
+            c.all_anns()[..8],
+            (MAX_SPILL_CHUNKS_PER_PEER as u64 + 1).to_le_bytes()
+        );
+        assert_eq!(sp.used.len(), MAX_SPILL_CHUNKS_PER_PEER - 1);
+        drop(sp);
+        assert!(!path.exists());
+    }
+}
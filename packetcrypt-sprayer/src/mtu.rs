@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Choosing the size of the sprayer's datagrams from the path MTU, so that links with
+//! a smaller MTU than ethernet, such as PPPoE, don't have every packet fragmented. The
+//! kernel is asked for the MTU of the path to each peer which we know of, or of the
+//! default route if there are none, which includes anything learned from ICMP. This
+//! only ever makes datagrams smaller than DEFAULT_MSS because peers which subscribe
+//! later may be on another path.
+use log::{debug, info};
+use std::net::{SocketAddr, UdpSocket};
+
+/// The largest UDP payload in one ethernet frame, used if the path MTU is not known
+pub const DEFAULT_MSS: usize = 1472;
+
+// Nothing is sent to these, they only find the route which everything else takes
+const PROBE_V4: &str = "192.0.2.1:9";
+const PROBE_V6: &str = "[2001:db8::1]:9";
+
+#[cfg(target_os = "linux")]
+fn path_mtu(peer: &SocketAddr) -> Option<usize> {
+    use std::mem::size_of;
+    use std::os::unix::io::AsRawFd;
+    let (bind, level, discover, mtu_opt) = if peer.is_ipv4() {
+        (
+            "0.0.0.0:0",
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_MTU,
+        )
+    } else {
+        (
+            "[::]:0",
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_MTU,
+        )
+    };
+    let s = UdpSocket::bind(bind).ok()?;
+    let fd = s.as_raw_fd();
+    // Don't fragment, so the MTU is the path's rather than the interface's
+    let on: libc::c_int = libc::IP_PMTUDISC_DO;
+    unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            discover,
+            &on as *const _ as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    s.connect(peer).ok()?;
+    let mut mtu: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            mtu_opt,
+            &mut mtu as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 || mtu <= 0 {
+        None
+    } else {
+        Some(mtu as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn path_mtu(_peer: &SocketAddr) -> Option<usize> {
+    None
+}
+
+// Less the IP and UDP headers
+fn mss_for(peer: &SocketAddr, mtu: usize) -> usize {
+    let ip = if peer.is_ipv4() { 20 } else { 40 };
+    mtu.saturating_sub(ip + 8)
+}
+
+/// The largest UDP payload which gets to every peer without being fragmented
+pub fn auto_mss<'a>(bind: &SocketAddr, peers: impl Iterator<Item = &'a SocketAddr>) -> usize {
+    let mut peers = peers.copied().collect::<Vec<_>>();
+    if peers.is_empty() {
+        let probe = if bind.is_ipv4() { PROBE_V4 } else { PROBE_V6 };
+        peers.push(probe.parse().unwrap());
+    }
+    let mut mss = DEFAULT_MSS;
+    for p in &peers {
+        match path_mtu(p) {
+            Some(mtu) => {
+                debug!("Path MTU to {} is {}", p, mtu);
+                mss = mss.min(mss_for(p, mtu));
+            }
+            None => debug!("Unable to get the path MTU to {}", p),
+        }
+    }
+    if mss < DEFAULT_MSS {
+        info!(
+            "Path MTU is small, sending datagrams of at most {} bytes",
+            mss
+        );
+    }
+    mss
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn mss_for() {
+        let v4 = "10.0.0.1:1".parse().unwrap();
+        let v6 = "[fc00::1]:1".parse().unwrap();
+        assert_eq!(super::mss_for(&v4, 1500), super::DEFAULT_MSS);
+        // PPPoE
+        assert_eq!(super::mss_for(&v4, 1492), 1464);
+        assert_eq!(super::mss_for(&v6, 1500), 1452);
+        assert_eq!(super::mss_for(&v6, 20), 0);
+    }
+}
@@ -0,0 +1,311 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Authenticated encryption of the sprayer's links, for anns which cross networks which
+//! are not trusted. When the sprayers on both ends of a link have the same psk, every
+//! packet, subscription and TCP frame is sealed with ChaCha20-Poly1305 and anything
+//! which does not open is dropped, so without the psk anns can be neither read nor
+//! changed nor injected. Sealed:
+//!
+//! ```text
+//! session: [u8; 16] | counter: u64 | time_sec: u64 | ciphertext | tag: [u8; 16]
+//! ```
+//!
+//! The key is made from the passphrase with PBKDF2, so a weak passphrase is costly to guess
+//! from captured packets. Each sealer picks a random session when it is made and seals
+//! with the key blake2b(key || session), the nonce is the counter which goes up with
+//! every message, so no nonce is used twice with one key however many threads and peers
+//! there are. The session, counter and time are authenticated along with the message.
+//! Messages more than MAX_AGE_SEC away from our clock are dropped, as are counters of a
+//! session which were already seen, so nothing can be replayed. The counters which were
+//! seen are shared by every opener made from one Psk, packets of one session arrive at
+//! any of the workers.
+use packetcrypt_util::{hash, util};
+use parking_lot::Mutex;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+const SESSION_LEN: usize = 16;
+const HEADER_LEN: usize = SESSION_LEN + 8 + 8;
+const TAG_LEN: usize = 16;
+
+/// How much longer a message is once it is sealed
+pub const OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+
+// An opener remembers the keys of this many sessions, then starts over
+const MAX_SESSIONS: usize = 1024;
+
+// Anything sealed longer ago than this, or this far in the future, is dropped, so the
+// clocks of the two ends must be within it
+const MAX_AGE_SEC: u64 = 60;
+
+// How far behind the highest counter of a session a message may be and still open,
+// sealers send in order so this only has to cover reordering on the way
+const WINDOW: u64 = 1024;
+
+// The psk is derived once at startup so this can be slow
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const PBKDF2_SALT: &[u8] = b"packetcrypt sprayer psk";
+
+// Which of the last WINDOW counters of a session were seen
+struct Window {
+    top: u64,
+    seen: [u64; (WINDOW / 64) as usize],
+    last_sec: u64,
+}
+impl Window {
+    fn new(counter: u64) -> Window {
+        Window {
+            top: counter,
+            seen: [0; (WINDOW / 64) as usize],
+            last_sec: 0,
+        }
+    }
+
+    fn slot(&mut self, counter: u64) -> (&mut u64, u64) {
+        let i = (counter % WINDOW) as usize;
+        (&mut self.seen[i / 64], 1 << (i % 64))
+    }
+
+    // False if the counter was seen before or is too old to tell
+    fn check(&mut self, counter: u64) -> bool {
+        if counter > self.top {
+            if counter - self.top >= WINDOW {
+                self.seen = [0; (WINDOW / 64) as usize];
+            } else {
+                for c in (self.top + 1)..=counter {
+                    let (word, bit) = self.slot(c);
+                    *word &= !bit;
+                }
+            }
+            self.top = counter;
+        } else if self.top - counter >= WINDOW {
+            return false;
+        }
+        let (word, bit) = self.slot(counter);
+        if *word & bit != 0 {
+            return false;
+        }
+        *word |= bit;
+        true
+    }
+}
+
+#[derive(Default)]
+struct Replay {
+    sessions: HashMap<[u8; SESSION_LEN], Window>,
+}
+impl Replay {
+    fn check(&mut self, session: &[u8; SESSION_LEN], counter: u64, now_sec: u64) -> bool {
+        if !self.sessions.contains_key(session) && self.sessions.len() >= MAX_SESSIONS {
+            // Sessions which have sent nothing in MAX_AGE_SEC can't send anything which
+            // would open, so they can be forgotten
+            self.sessions
+                .retain(|_, w| w.last_sec + MAX_AGE_SEC >= now_sec);
+            if self.sessions.len() >= MAX_SESSIONS {
+                return false;
+            }
+        }
+        let w = self
+            .sessions
+            .entry(*session)
+            .or_insert_with(|| Window::new(counter));
+        w.last_sec = now_sec;
+        w.check(counter)
+    }
+}
+
+#[derive(Clone)]
+pub struct Psk {
+    key: [u8; 32],
+    replay: Arc<Mutex<Replay>>,
+}
+impl Psk {
+    pub fn new(passphrase: &str) -> Psk {
+        let mut key = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            PBKDF2_SALT,
+            passphrase.as_bytes(),
+            &mut key,
+        );
+        Psk {
+            key,
+            replay: Arc::new(Mutex::new(Replay::default())),
+        }
+    }
+    fn session_key(&self, session: &[u8]) -> LessSafeKey {
+        let mut buf = [0u8; 32 + SESSION_LEN];
+        buf[..32].copy_from_slice(&self.key);
+        buf[32..].copy_from_slice(session);
+        let key = UnboundKey::new(&aead::CHACHA20_POLY1305, &hash::compress32(&buf)).unwrap();
+        LessSafeKey::new(key)
+    }
+}
+
+fn nonce(counter: &[u8]) -> Nonce {
+    let mut n = [0u8; 12];
+    n[..8].copy_from_slice(counter);
+    Nonce::assume_unique_for_key(n)
+}
+
+pub struct Sealer {
+    header: [u8; HEADER_LEN],
+    key: LessSafeKey,
+    counter: u64,
+}
+impl Sealer {
+    pub fn new(psk: &Psk) -> Sealer {
+        let mut header = [0u8; HEADER_LEN];
+        SystemRandom::new()
+            .fill(&mut header[..SESSION_LEN])
+            .expect("no random numbers");
+        Sealer {
+            key: psk.session_key(&header[..SESSION_LEN]),
+            header,
+            counter: 0,
+        }
+    }
+
+    /// Seal msg into out, which must be msg.len() + OVERHEAD long
+    pub fn seal(&mut self, msg: &[u8], out: &mut [u8]) {
+        assert_eq!(out.len(), msg.len() + OVERHEAD);
+        self.header[SESSION_LEN..(SESSION_LEN + 8)].copy_from_slice(&self.counter.to_le_bytes());
+        self.header[(SESSION_LEN + 8)..].copy_from_slice(&(util::now_ms() / 1000).to_le_bytes());
+        self.counter += 1;
+        let (header, rest) = out.split_at_mut(HEADER_LEN);
+        let (body, tag) = rest.split_at_mut(msg.len());
+        header.copy_from_slice(&self.header);
+        body.copy_from_slice(msg);
+        let t = self
+            .key
+            .seal_in_place_separate_tag(
+                nonce(&self.header[SESSION_LEN..(SESSION_LEN + 8)]),
+                Aad::from(&self.header),
+                body,
+            )
+            .unwrap();
+        tag.copy_from_slice(t.as_ref());
+    }
+
+    pub fn seal_to_vec(&mut self, msg: &[u8]) -> Vec<u8> {
+        let mut out = vec![0u8; msg.len() + OVERHEAD];
+        self.seal(msg, &mut out);
+        out
+    }
+}
+
+pub struct Opener {
+    psk: Psk,
+    keys: HashMap<[u8; SESSION_LEN], LessSafeKey>,
+}
+impl Opener {
+    pub fn new(psk: &Psk) -> Opener {
+        Opener {
+            psk: psk.clone(),
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Open a sealed message in place, the message is returned if it is authentic and
+    /// it is not a replay
+    pub fn open<'a>(&mut self, sealed: &'a mut [u8]) -> Option<&'a mut [u8]> {
+        self.open_at(sealed, util::now_ms() / 1000)
+    }
+
+    fn open_at<'a>(&mut self, sealed: &'a mut [u8], now_sec: u64) -> Option<&'a mut [u8]> {
+        if sealed.len() < OVERHEAD {
+            return None;
+        }
+        let (header, body) = sealed.split_at_mut(HEADER_LEN);
+        let session: [u8; SESSION_LEN] = header[..SESSION_LEN].try_into().unwrap();
+        let counter =
+            u64::from_le_bytes(header[SESSION_LEN..(SESSION_LEN + 8)].try_into().unwrap());
+        let time_sec = u64::from_le_bytes(header[(SESSION_LEN + 8)..].try_into().unwrap());
+        let n = nonce(&header[SESSION_LEN..(SESSION_LEN + 8)]);
+        let aad = Aad::from(&header[..]);
+        let msg = if let Some(key) = self.keys.get(&session) {
+            key.open_in_place(n, aad, body).ok()?
+        } else {
+            // Only sessions with a message which opens are kept, so garbage can't fill it
+            let key = self.psk.session_key(&session);
+            let msg = key.open_in_place(n, aad, body).ok()?;
+            if self.keys.len() >= MAX_SESSIONS {
+                self.keys.clear();
+            }
+            self.keys.insert(session, key);
+            msg
+        };
+        // Only now that the header is known to be authentic
+        if time_sec + MAX_AGE_SEC < now_sec || time_sec > now_sec + MAX_AGE_SEC {
+            return None;
+        }
+        if !self.psk.replay.lock().check(&session, counter, now_sec) {
+            return None;
+        }
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_open() {
+        let psk = Psk::new("correct horse battery staple");
+        let mut s = Sealer::new(&psk);
+        let mut o = Opener::new(&psk);
+        let a = s.seal_to_vec(b"hello");
+        let mut b = s.seal_to_vec(b"hello");
+        // Same message, different nonce
+        assert_ne!(a, b);
+        assert_eq!(o.open(&mut b.clone()).unwrap(), b"hello");
+        assert_eq!(o.open(&mut a.clone()).unwrap(), b"hello");
+        // Replayed, also to another opener
+        assert!(o.open(&mut a.clone()).is_none());
+        assert!(Opener::new(&psk).open(&mut b.clone()).is_none());
+
+        // Any change to the session, counter, message or tag
+        for i in 0..b.len() {
+            let mut t = b.clone();
+            t[i] ^= 1;
+            assert!(o.open(&mut t).is_none(), "byte {}", i);
+        }
+        assert!(o.open(&mut b[..4]).is_none());
+
+        // Another psk
+        let mut o2 = Opener::new(&Psk::new("Tr0ub4dor&3"));
+        assert!(o2.open(&mut a.clone()).is_none());
+    }
+
+    #[test]
+    fn stale() {
+        let psk = Psk::new("correct horse battery staple");
+        let mut s = Sealer::new(&psk);
+        let mut o = Opener::new(&psk);
+        let now = util::now_ms() / 1000;
+        let a = s.seal_to_vec(b"hello");
+        assert!(o.open_at(&mut a.clone(), now + MAX_AGE_SEC + 5).is_none());
+        assert!(o.open_at(&mut a.clone(), now - MAX_AGE_SEC - 5).is_none());
+        assert!(o.open_at(&mut a.clone(), now).is_some());
+    }
+
+    #[test]
+    fn window() {
+        let mut w = Window::new(5);
+        assert!(w.check(5));
+        assert!(!w.check(5));
+        assert!(w.check(3));
+        assert!(w.check(5 + WINDOW));
+        assert!(!w.check(5));
+        assert!(w.check(6));
+        assert!(!w.check(6));
+        assert!(w.check(6 + WINDOW));
+        assert!(!w.check(6));
+    }
+}
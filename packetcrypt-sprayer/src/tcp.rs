@@ -0,0 +1,383 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! TCP transport for networks which block or heavily shape UDP. Each message which
+//! would be a datagram is sent as a frame over one connection:
+//!
+//! ```text
+//! len: u32 | message
+//! ```
+//!
+//! The length is little endian. A subscription which gets no packets back over UDP for
+//! TCP_FALLBACK_SECONDS connects to the same address over TCP, through a socks5:// or
+//! http:// proxy if one is configured, and sends its subscriptions as frames. The anns
+//! come back in frames of up to one chunk of packets. TCP does its own congestion
+//! control so these links are not paced. With a psk, each frame is sealed whole.
+use crate::seal::{self, Opener, Sealer};
+use crate::{
+    Chunk, Pacer, SendQueue, Sprayer, Subscriber, PKT_LENGTH, SECONDS_UNTIL_RESUB,
+    SECONDS_UNTIL_SUB_TIMEOUT,
+};
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use packetcrypt_util::backoff::Backoff;
+use packetcrypt_util::protocol::SprayerReq;
+use packetcrypt_util::util;
+use parking_lot::Mutex;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How long a subscription gets nothing over UDP before switching to TCP
+pub const TCP_FALLBACK_SECONDS: usize = 20;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Most TCP connections served at once, each one has its own thread and until it sends
+// a subscription anyone can open one
+const MAX_CONNECTIONS: usize = 256;
+
+// Waits before reconnecting a dropped link, longer each time it fails
+const RECONNECT_MIN_MS: u64 = 5_000;
+const RECONNECT_MAX_MS: u64 = 60_000;
+
+// Longest reply we'll read from an http proxy
+const MAX_HTTP_HEAD: usize = 8192;
+
+fn write_frame(s: &mut TcpStream, msg: &[u8]) -> std::io::Result<()> {
+    s.write_all(&(msg.len() as u32).to_le_bytes())?;
+    s.write_all(msg)
+}
+
+fn read_frame(s: &mut TcpStream, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = [0u8; 4];
+    s.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > buf.len() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes, limit is {}", len, buf.len()),
+        ));
+    }
+    s.read_exact(&mut buf[..len])?;
+    Ok(len)
+}
+
+// Frames are sealed whole when there is a psk
+fn write_msg(s: &mut TcpStream, sealer: &mut Option<Sealer>, msg: &[u8]) -> std::io::Result<()> {
+    match sealer {
+        Some(sl) => write_frame(s, &sl.seal_to_vec(msg)),
+        None => write_frame(s, msg),
+    }
+}
+
+// Read a frame into buf, opening it first if there is a psk
+fn read_msg(s: &mut TcpStream, opener: &mut Option<Opener>, buf: &mut [u8]) -> Result<usize> {
+    let o = match opener {
+        Some(o) => o,
+        None => return Ok(read_frame(s, buf)?),
+    };
+    let mut sealed = vec![0u8; buf.len() + seal::OVERHEAD];
+    let len = read_frame(s, &mut sealed)?;
+    let msg = o
+        .open(&mut sealed[..len])
+        .context("frame which could not be opened, is the psk the same?")?;
+    buf[..msg.len()].copy_from_slice(msg);
+    Ok(msg.len())
+}
+
+fn socks5_connect(s: &mut TcpStream, peer: &SocketAddr) -> Result<()> {
+    // No authentication
+    s.write_all(&[5, 1, 0])?;
+    let mut reply = [0u8; 2];
+    s.read_exact(&mut reply)?;
+    if reply != [5, 0] {
+        bail!("socks5 proxy refused, reply {:?}", reply);
+    }
+    let mut req = vec![5, 1, 0];
+    match peer {
+        SocketAddr::V4(a) => {
+            req.push(1);
+            req.extend_from_slice(&a.ip().octets());
+        }
+        SocketAddr::V6(a) => {
+            req.push(4);
+            req.extend_from_slice(&a.ip().octets());
+        }
+    }
+    req.extend_from_slice(&peer.port().to_be_bytes());
+    s.write_all(&req)?;
+    let mut head = [0u8; 4];
+    s.read_exact(&mut head)?;
+    if head[1] != 0 {
+        bail!(
+            "socks5 proxy could not connect to {}, error {}",
+            peer,
+            head[1]
+        );
+    }
+    // Skip the address which the proxy bound
+    let addr_len = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut l = [0u8; 1];
+            s.read_exact(&mut l)?;
+            l[0] as usize
+        }
+        t => bail!("socks5 proxy replied with address type {}", t),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    s.read_exact(&mut bound)?;
+    Ok(())
+}
+
+fn http_connect(s: &mut TcpStream, peer: &SocketAddr) -> Result<()> {
+    write!(s, "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", peer)?;
+    let mut head = Vec::new();
+    let mut b = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_HEAD {
+            bail!("http proxy reply is too long");
+        }
+        s.read_exact(&mut b)?;
+        head.push(b[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status = head.lines().next().unwrap_or_default();
+    if !status.starts_with("HTTP/1.") || status.split_whitespace().nth(1) != Some("200") {
+        bail!("http proxy could not connect to {}: {}", peer, status);
+    }
+    Ok(())
+}
+
+/// Connect to peer, through proxy unless it is empty
+fn connect(peer: &SocketAddr, proxy: &str) -> Result<TcpStream> {
+    if proxy.is_empty() {
+        return Ok(TcpStream::connect_timeout(peer, CONNECT_TIMEOUT)?);
+    }
+    let (scheme, host) = if let Some(i) = proxy.find("://") {
+        (&proxy[..i], proxy[i + 3..].trim_end_matches('/'))
+    } else {
+        bail!(
+            "Proxy [{}] should be socks5://host:port or http://host:port",
+            proxy
+        );
+    };
+    let paddr = host
+        .to_socket_addrs()
+        .with_context(|| format!("Unable to resolve proxy [{}]", host))?
+        .next()
+        .with_context(|| format!("No address for proxy [{}]", host))?;
+    let mut s = TcpStream::connect_timeout(&paddr, CONNECT_TIMEOUT)?;
+    s.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    match scheme {
+        // The peer is already resolved so these are the same
+        "socks5" | "socks5h" => socks5_connect(&mut s, peer)?,
+        "http" => http_connect(&mut s, peer)?,
+        _ => bail!("Unsupported proxy type [{}]", scheme),
+    }
+    s.set_read_timeout(None)?;
+    Ok(s)
+}
+
+/// Accept TCP subscriptions in a new thread
+pub fn listen(g: &Sprayer, listener: TcpListener) {
+    let g = Sprayer(Arc::clone(&g.0));
+    let conns = Arc::new(AtomicUsize::new(0));
+    std::thread::spawn(move || {
+        for s in listener.incoming() {
+            let s = match s {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Error accepting sprayer TCP connection: {}", e);
+                    continue;
+                }
+            };
+            if conns.fetch_add(1, atomic::Ordering::Relaxed) >= MAX_CONNECTIONS {
+                conns.fetch_sub(1, atomic::Ordering::Relaxed);
+                warn!(
+                    "Refusing sprayer TCP connection from {:?}, already have {}",
+                    s.peer_addr(),
+                    MAX_CONNECTIONS
+                );
+                continue;
+            }
+            let g = Sprayer(Arc::clone(&g.0));
+            let conns = Arc::clone(&conns);
+            std::thread::spawn(move || {
+                if let Ok(from) = s.peer_addr() {
+                    if let Err(e) = serve(&g, s, from) {
+                        debug!("TCP subscriber {} is gone: {}", from, e);
+                    }
+                    remove_subscriber(&g, from);
+                }
+                conns.fetch_sub(1, atomic::Ordering::Relaxed);
+            });
+        }
+    });
+}
+
+fn check_sub(g: &Sprayer, msg: &[u8], from: SocketAddr) -> Result<()> {
+    let req = serde_json::from_slice::<SprayerReq>(msg)
+        .with_context(|| format!("undecodable subscription from {}", from))?;
+    if req.yes_please_dos_me_passwd != g.0.passwd {
+        bail!("subscription from {} with wrong password", from);
+    }
+    Ok(())
+}
+
+fn serve(g: &Sprayer, mut s: TcpStream, from: SocketAddr) -> Result<()> {
+    s.set_read_timeout(Some(Duration::from_secs(SECONDS_UNTIL_SUB_TIMEOUT as u64)))?;
+    let mut buf = [0u8; PKT_LENGTH];
+    let mut opener = g.0.psk.as_ref().map(Opener::new);
+    let len = read_msg(&mut s, &mut opener, &mut buf)?;
+    check_sub(g, &buf[..len], from)?;
+    info!("Got TCP subscription from {}", from);
+    add_subscriber(g, from);
+    let ws = s.try_clone()?;
+    let g1 = Sprayer(Arc::clone(&g.0));
+    std::thread::spawn(move || send_loop(&g1, ws, from));
+    loop {
+        let len = read_msg(&mut s, &mut opener, &mut buf)?;
+        check_sub(g, &buf[..len], from)?;
+        let now_sec = (util::now_ms() / 1000) as usize;
+        for sub in &g.0.m.read().subscribers {
+            if sub.tcp && sub.peer == from {
+                sub.last_update_sec
+                    .store(now_sec, atomic::Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn add_subscriber(g: &Sprayer, from: SocketAddr) {
+    let now_sec = (util::now_ms() / 1000) as usize;
+    g.0.m.write().subscribers.push(Subscriber {
+        peer: from,
+        send_queue: Mutex::new(SendQueue::new(&g.0.chunk_pool, &g.0.relay_dir, &from)),
+        last_update_sec: AtomicUsize::new(now_sec),
+        pacer: Mutex::new(Pacer::new()),
+        tcp: true,
+    });
+}
+
+fn remove_subscriber(g: &Sprayer, from: SocketAddr) {
+    g.0.m
+        .write()
+        .subscribers
+        .retain(|s| !(s.tcp && s.peer == from));
+}
+
+// Outer None if the subscriber is gone
+fn pop(g: &Sprayer, peer: SocketAddr) -> Option<Option<Box<Chunk>>> {
+    let m = g.0.m.read();
+    let sub = m.subscribers.iter().find(|s| s.tcp && s.peer == peer)?;
+    let chunk = sub.send_queue.lock().pop();
+    Some(chunk)
+}
+
+fn send_loop(g: &Sprayer, mut s: TcpStream, peer: SocketAddr) {
+    let mut sealer = g.0.psk.as_ref().map(Sealer::new);
+    while let Some(chunk) = pop(g, peer) {
+        let mut chunk = if let Some(c) = chunk {
+            c
+        } else {
+            std::thread::sleep(Duration::from_millis(5));
+            continue;
+        };
+        let res = write_msg(&mut s, &mut sealer, chunk.all_anns());
+        chunk.reset();
+        g.0.chunk_pool.give(chunk);
+        if let Err(e) = res {
+            debug!("Unable to send to TCP subscriber {}: {}", peer, e);
+            break;
+        }
+    }
+    // Make the reader stop too
+    let _ = s.shutdown(Shutdown::Both);
+}
+
+/// Subscribe to peer over TCP in a new thread, reconnecting whenever the link drops
+pub fn subscribe(g: &Sprayer, peer: SocketAddr) {
+    let g = Sprayer(Arc::clone(&g.0));
+    std::thread::spawn(move || {
+        let mut chunk = g.0.chunk_pool.take();
+        let mut backoff = Backoff::new(
+            format!("TCP link to {}", peer),
+            RECONNECT_MIN_MS,
+            RECONNECT_MAX_MS,
+        );
+        loop {
+            let started_ms = util::now_ms();
+            let res = subscription(&g, peer, &mut chunk);
+            // A link which worked for a while is not part of an outage
+            if util::now_ms() - started_ms > RECONNECT_MAX_MS {
+                backoff.success();
+            }
+            if let Err(e) = res {
+                if !backoff.is_open() {
+                    warn!("TCP link to {} failed: {}", peer, e);
+                }
+            }
+            chunk.reset();
+            backoff.wait_blocking();
+        }
+    });
+}
+
+fn subscription(g: &Sprayer, peer: SocketAddr, chunk: &mut Box<Chunk>) -> Result<()> {
+    let mut s = connect(&peer, &g.0.proxy)?;
+    info!("Subscribed to {} over TCP", peer);
+    let mut ws = s.try_clone()?;
+    let g1 = Sprayer(Arc::clone(&g.0));
+    std::thread::spawn(move || {
+        let mut sealer = g1.0.psk.as_ref().map(Sealer::new);
+        loop {
+            let req = g1.sub_req(&peer);
+            if let Err(e) = write_msg(&mut ws, &mut sealer, req.as_bytes()) {
+                debug!("Unable to send TCP subscription to {}: {}", peer, e);
+                let _ = ws.shutdown(Shutdown::Both);
+                return;
+            }
+            std::thread::sleep(Duration::from_secs(SECONDS_UNTIL_RESUB as u64));
+        }
+    });
+    let peers = g.0.peers();
+    let sub = peers.subscribed_to.get(&peer).context("not subscribed")?;
+    let mut opener = g.0.psk.as_ref().map(Opener::new);
+    loop {
+        chunk.reset();
+        let len = read_msg(&mut s, &mut opener, &mut chunk.bytes[..])?;
+        if len % PKT_LENGTH != 0 {
+            bail!("frame of {} bytes is not a whole number of packets", len);
+        }
+        chunk.ecur = len;
+        sub.packets_received
+            .fetch_add(chunk.len(), atomic::Ordering::Relaxed);
+        sub.seq.lock().on_packets(chunk.all_anns());
+        let overflow = g.received(chunk);
+        if overflow > 0 {
+            debug!("Send overflow of {} anns from TCP link {}", overflow, peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames() {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut a = TcpStream::connect(l.local_addr().unwrap()).unwrap();
+        let (mut b, _) = l.accept().unwrap();
+        write_frame(&mut a, b"hello").unwrap();
+        write_frame(&mut a, &[7u8; 300]).unwrap();
+        let mut buf = [0u8; 256];
+        assert_eq!(read_frame(&mut b, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        // Too big for the buffer
+        assert!(read_frame(&mut b, &mut buf).is_err());
+    }
+}
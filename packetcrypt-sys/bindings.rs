@@ -878,6 +878,15 @@ extern "C" {
         cbc: *mut ::std::os::raw::c_void,
     ) -> BlockMine_Create_t;
 }
+extern "C" {
+    pub fn BlockMine_createShared(
+        anns: *mut PacketCrypt_Announce_t,
+        maxAnns: u32,
+        threads: ::std::os::raw::c_int,
+        cb: BlockMine_Callback_t,
+        cbc: *mut ::std::os::raw::c_void,
+    ) -> BlockMine_Create_t;
+}
 extern "C" {
     pub fn BlockMine_destroy(bm: *mut BlockMine_t);
 }
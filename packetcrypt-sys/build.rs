@@ -2,7 +2,100 @@ extern crate cc;
 use walkdir::WalkDir;
 
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+// Sources of the announcement mining kernel, these are compiled one more time for
+// each instruction set in KERNELS and the best one is selected at runtime.
+const KERNEL_FILES: [&str; 7] = [
+    "AnnMiner.c",
+    "Announce.c",
+    "AnnMerkle.c",
+    "CryptoCycle.c",
+    "Hash.c",
+    "RandGen.c",
+    "RandHash_interpreted.c",
+];
+
+// Every non-static symbol defined in KERNEL_FILES, each kernel gets its own prefix so
+// they can be linked alongside the default build. Anything else they call (PTime,
+// Work, libsodium) comes from the default build.
+const KERNEL_SYMBOLS: [&str; 26] = [
+    "AnnMiner_create",
+    "AnnMiner_start",
+    "AnnMiner_stop",
+    "AnnMiner_free",
+    "Announce_mkitem",
+    "Announce_mkitem2",
+    "Announce_createProg",
+    "AnnMerkle__build",
+    "AnnMerkle__getBranch",
+    "AnnMerkle__isItemValid",
+    "CryptoCycle_makeFuzzable",
+    "CryptoCycle_crypt",
+    "CryptoCycle_init",
+    "CryptoCycle_update",
+    "CryptoCycle_smul",
+    "CryptoCycle_final",
+    "Hash_compress64",
+    "Hash_compress32",
+    "Hash_compressDoubleSha256",
+    "Hash_expand",
+    "Hash_eprintHex",
+    "Hash_printHex",
+    "RandGen_generate",
+    "RandHash_interpret",
+    "ValidateCtx_create",
+    "ValidateCtx_destroy",
+];
+
+const KERNELS: [(&str, &[&str]); 3] = [
+    ("sse4", &["-msse4.2"]),
+    ("avx2", &["-mavx2", "-mbmi2"]),
+    ("avx512", &["-mavx512f", "-mavx512bw", "-mavx512vl"]),
+];
+
+// Only in portable builds because otherwise -march=native is already optimal
+fn build_kernels(sodium_dir: &Path, out_dir: &Path) {
+    for (name, _) in KERNELS.iter() {
+        println!("cargo:rustc-check-cfg=cfg(pc_kernel_{})", name);
+    }
+    if !cfg!(feature = "portable") || env::var("CARGO_CFG_TARGET_ARCH").unwrap() != "x86_64" {
+        return;
+    }
+    for (name, flags) in KERNELS.iter() {
+        let mut kcfg = cc::Build::new();
+        if !flags
+            .iter()
+            .all(|f| kcfg.is_flag_supported(f).unwrap_or(false))
+        {
+            println!(
+                "cargo:warning=compiler does not support {:?}, {} kernel disabled",
+                flags, name
+            );
+            continue;
+        }
+        for f in flags.iter() {
+            kcfg.flag(f);
+        }
+        for sym in KERNEL_SYMBOLS.iter() {
+            kcfg.define(sym, Some(format!("pc_{}_{}", name, sym).as_str()));
+        }
+        if kcfg.is_flag_supported("-fno-plt").unwrap() {
+            kcfg.use_plt(false);
+        }
+        kcfg.include(sodium_dir)
+            .include("packetcrypt/include")
+            .include("packetcrypt/src")
+            .flag("-Wno-implicit-function-declaration");
+        for f in KERNEL_FILES.iter() {
+            kcfg.file(format!("packetcrypt/src/{}", f));
+        }
+        kcfg.out_dir(out_dir.join("lib"))
+            .flag("-O2")
+            .compile(&format!("libpacketcrypt_{}.a", name));
+        println!("cargo:rustc-cfg=pc_kernel_{}", name);
+    }
+}
 
 #[cfg(not(feature = "difficulty-test"))]
 fn find_crypto(_cfg: &mut cc::Build) {}
@@ -52,6 +145,11 @@ fn find_crypto(cfg: &mut cc::Build) {
 }
 
 fn main() {
+    // Pure Rust build, nothing to compile
+    if !cfg!(feature = "c") {
+        return;
+    }
+
     #[cfg(feature = "generate-bindings")]
     {
         bindgen::Builder::default()
@@ -71,7 +169,7 @@ fn main() {
     find_crypto(&mut cfg);
 
     let dst = PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    let mut sodium_found = false;
+    let mut sodium_dir = None;
     let search_path = dst.parent().unwrap().parent().unwrap();
     for _ in 0..600 {
         println!("Looking for libsodium in {}", search_path.to_str().unwrap());
@@ -85,18 +183,20 @@ fn main() {
                 let dir = e.path().parent().unwrap();
                 cfg.include(dir);
                 println!("Found sodium.h in {}", dir.to_str().unwrap());
-                sodium_found = true;
+                sodium_dir = Some(dir.to_path_buf());
                 break;
             }
         }
-        if sodium_found {
+        if sodium_dir.is_some() {
             break;
         }
         std::thread::sleep(std::time::Duration::from_secs(1));
     }
-    if !sodium_found {
+    let sodium_dir = if let Some(d) = sodium_dir {
+        d
+    } else {
         panic!("Could not find libsodium source code");
-    }
+    };
 
     if cfg.is_flag_supported("-fno-plt").unwrap() {
         cfg.use_plt(false);
@@ -131,6 +231,8 @@ fn main() {
         .flag("-O2")
         .compile("libpacketcrypt.a");
 
+    build_kernels(&sodium_dir, &dst);
+
     let src = env::current_dir().unwrap().join("packetcrypt");
     println!("cargo:root={}", dst.display());
     println!("cargo:include={}", dst.join("include").display());
@@ -42,6 +42,15 @@ typedef struct BlockMine_Create_s {
 typedef void (* BlockMine_Callback_t)(BlockMine_Res_t* res, void* ctx);
 BlockMine_Create_t BlockMine_create(uint64_t maxmem, int threads, BlockMine_Callback_t cb, void* cbc);
 
+// Mine maxAnns anns in memory which belongs to the caller, such as memory shared with
+// other processes. Only the index is allocated, anns must outlive the miner.
+BlockMine_Create_t BlockMine_createShared(
+    PacketCrypt_Announce_t* anns,
+    uint32_t maxAnns,
+    int threads,
+    BlockMine_Callback_t cb,
+    void* cbc);
+
 void BlockMine_destroy(BlockMine_t* bm);
 
 void BlockMine_updateAnn(const BlockMine_t* bm, uint32_t mloc, const uint8_t* ann);
@@ -58,6 +58,10 @@ typedef struct BlockMine_pvt_s {
     BlockMine_t pub;
     uint64_t maxmem;
 
+    // False if the anns belong to the caller, then only the index is ours and maxmem
+    // is the size of the index
+    bool ownsAnns;
+
     Worker_t* workers;
     int numWorkers;
 
@@ -218,14 +222,19 @@ static void* thread(void* vWorker)
 }
 
 // Main thread
-BlockMine_Create_t BlockMine_create(uint64_t maxmem, int threads, BlockMine_Callback_t cb, void* cbc) {
+// ptr is the mapping of maxmem bytes which is unmapped by BlockMine_destroy()
+static BlockMine_Create_t create(
+    void* ptr,
+    uint64_t maxmem,
+    PacketCrypt_Announce_t* anns,
+    bool ownsAnns,
+    HeaderAndIndex_t* hai,
+    uint64_t maxAnns,
+    int threads,
+    BlockMine_Callback_t cb,
+    void* cbc)
+{
     BlockMine_Create_t bmc = { .miner = NULL, };
-    void* ptr = mapBuf(maxmem);
-    if (ptr == MAP_FAILED) {
-        bmc.stage = "mmap()";
-        bmc.err = strerror(errno);
-        return bmc;
-    }
     BlockMine_pvt_t* out = calloc(sizeof(BlockMine_pvt_t), 1);
     Worker_t* workers = calloc(sizeof(Worker_t), threads);
     if (!out || !workers) {
@@ -236,19 +245,15 @@ BlockMine_Create_t BlockMine_create(uint64_t maxmem, int threads, BlockMine_Call
         free(workers);
         return bmc;
     }
-    uint64_t maxAnns = (maxmem - 80) / 1024;
-    while (maxAnns * 1024 + maxAnns * 4 + 80 > maxmem) {
-        // make room for the index
-        maxAnns--;
-    } 
 
     out->pub.maxAnns = maxAnns;
     out->maxmem = maxmem;
+    out->ownsAnns = ownsAnns;
     out->workers = workers;
     out->numWorkers = threads;
 
-    out->g.anns = (PacketCrypt_Announce_t*) ptr;
-    out->g.hai = (HeaderAndIndex_t*) (&out->g.anns[maxAnns]);
+    out->g.anns = anns;
+    out->g.hai = hai;
     // Lazy man's assertion
     out->g.hai->index[maxAnns - 1] = 0;
     out->g.annCount = 0; // set when we begin mining
@@ -270,6 +275,44 @@ BlockMine_Create_t BlockMine_create(uint64_t maxmem, int threads, BlockMine_Call
     return bmc;
 }
 
+// Main thread
+BlockMine_Create_t BlockMine_create(uint64_t maxmem, int threads, BlockMine_Callback_t cb, void* cbc) {
+    BlockMine_Create_t bmc = { .miner = NULL, };
+    void* ptr = mapBuf(maxmem);
+    if (ptr == MAP_FAILED) {
+        bmc.stage = "mmap()";
+        bmc.err = strerror(errno);
+        return bmc;
+    }
+    uint64_t maxAnns = (maxmem - 80) / 1024;
+    while (maxAnns * 1024 + maxAnns * 4 + 80 > maxmem) {
+        // make room for the index
+        maxAnns--;
+    } 
+    PacketCrypt_Announce_t* anns = (PacketCrypt_Announce_t*) ptr;
+    HeaderAndIndex_t* hai = (HeaderAndIndex_t*) (&anns[maxAnns]);
+    return create(ptr, maxmem, anns, true, hai, maxAnns, threads, cb, cbc);
+}
+
+// Main thread
+BlockMine_Create_t BlockMine_createShared(
+    PacketCrypt_Announce_t* anns,
+    uint32_t maxAnns,
+    int threads,
+    BlockMine_Callback_t cb,
+    void* cbc)
+{
+    BlockMine_Create_t bmc = { .miner = NULL, };
+    uint64_t maxmem = (uint64_t)maxAnns * 4 + 80;
+    void* ptr = mapBuf(maxmem);
+    if (ptr == MAP_FAILED) {
+        bmc.stage = "mmap()";
+        bmc.err = strerror(errno);
+        return bmc;
+    }
+    return create(ptr, maxmem, anns, false, (HeaderAndIndex_t*) ptr, maxAnns, threads, cb, cbc);
+}
+
 // Main thread
 static void waitState(BlockMine_pvt_t* ctx, enum ThreadState desiredState) {
     for (int i = 0; i < 100000; i++) {
@@ -309,7 +352,11 @@ void BlockMine_destroy(BlockMine_t* bm) {
     assert(!pthread_cond_destroy(&ctx->g.cond));
     assert(!pthread_mutex_destroy(&ctx->g.lock));
     free(ctx->workers);
-    assert(!munmap(ctx->g.anns, ctx->maxmem));
+    if (ctx->ownsAnns) {
+        assert(!munmap(ctx->g.anns, ctx->maxmem));
+    } else {
+        assert(!munmap(ctx->g.hai, ctx->maxmem));
+    }
     free(ctx);
 }
 
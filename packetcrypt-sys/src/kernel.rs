@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use crate::{AnnMiner_Callback, AnnMiner_Request_t, AnnMiner_t};
+use std::os::raw::{c_int, c_void};
+
+// In portable builds, build.rs compiles the announcement mining code once more for
+// each of these instruction sets so that one binary can run well on any machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    // Whatever the rest of the crate was compiled with
+    Default,
+    Sse4,
+    Avx2,
+    Avx512,
+}
+
+pub type AnnMinerCreateFn =
+    unsafe extern "C" fn(u32, c_int, *mut c_void, AnnMiner_Callback) -> *mut AnnMiner_t;
+pub type AnnMinerStartFn = unsafe extern "C" fn(*mut AnnMiner_t, *mut AnnMiner_Request_t, c_int);
+pub type AnnMinerFn = unsafe extern "C" fn(*mut AnnMiner_t);
+
+#[derive(Clone, Copy)]
+pub struct AnnMinerKernel {
+    pub kernel: Kernel,
+    pub create: AnnMinerCreateFn,
+    pub start: AnnMinerStartFn,
+    pub stop: AnnMinerFn,
+    pub free: AnnMinerFn,
+}
+
+#[allow(unused_macros)]
+macro_rules! kernel {
+    ($kernel:expr, $create:ident, $start:ident, $stop:ident, $free:ident) => {{
+        extern "C" {
+            fn $create(
+                minerId: u32,
+                threads: c_int,
+                callback_ctx: *mut c_void,
+                ann_found: AnnMiner_Callback,
+            ) -> *mut AnnMiner_t;
+            fn $start(ctx: *mut AnnMiner_t, req: *mut AnnMiner_Request_t, version: c_int);
+            fn $stop(miner: *mut AnnMiner_t);
+            fn $free(miner: *mut AnnMiner_t);
+        }
+        AnnMinerKernel {
+            kernel: $kernel,
+            create: $create,
+            start: $start,
+            stop: $stop,
+            free: $free,
+        }
+    }};
+}
+
+/// Select the fastest announcement mining kernel which this CPU supports.
+pub fn ann_miner_kernel() -> AnnMinerKernel {
+    #[cfg(all(target_arch = "x86_64", pc_kernel_avx512))]
+    if is_x86_feature_detected!("avx512f")
+        && is_x86_feature_detected!("avx512bw")
+        && is_x86_feature_detected!("avx512vl")
+    {
+        return kernel!(
+            Kernel::Avx512,
+            pc_avx512_AnnMiner_create,
+            pc_avx512_AnnMiner_start,
+            pc_avx512_AnnMiner_stop,
+            pc_avx512_AnnMiner_free
+        );
+    }
+    #[cfg(all(target_arch = "x86_64", pc_kernel_avx2))]
+    if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("bmi2") {
+        return kernel!(
+            Kernel::Avx2,
+            pc_avx2_AnnMiner_create,
+            pc_avx2_AnnMiner_start,
+            pc_avx2_AnnMiner_stop,
+            pc_avx2_AnnMiner_free
+        );
+    }
+    #[cfg(all(target_arch = "x86_64", pc_kernel_sse4))]
+    if is_x86_feature_detected!("sse4.2") {
+        return kernel!(
+            Kernel::Sse4,
+            pc_sse4_AnnMiner_create,
+            pc_sse4_AnnMiner_start,
+            pc_sse4_AnnMiner_stop,
+            pc_sse4_AnnMiner_free
+        );
+    }
+    AnnMinerKernel {
+        kernel: Kernel::Default,
+        create: crate::AnnMiner_create,
+        start: crate::AnnMiner_start,
+        stop: crate::AnnMiner_stop,
+        free: crate::AnnMiner_free,
+    }
+}
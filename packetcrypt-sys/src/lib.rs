@@ -2,22 +2,59 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
+#[cfg(not(any(feature = "c", feature = "no-c")))]
+compile_error!("Either the c or the no-c feature must be enabled");
+
 pub mod difficulty;
+#[cfg(feature = "c")]
+pub mod kernel;
+pub mod pure;
 
+#[cfg(feature = "c")]
 use bytes::{BufMut, BytesMut};
+#[cfg(feature = "c")]
 use packetcrypt_util::util;
 
 use std::convert::TryInto;
 
+#[cfg(feature = "c")]
 include!("../bindings.rs");
 
+#[cfg(not(feature = "c"))]
+pub use pure::{check_block_work, ValidateCtx};
+
+#[cfg(feature = "c")]
 pub fn init() {
     sodiumoxide::init().unwrap();
 }
 
+#[cfg(not(feature = "c"))]
+pub fn init() {}
+
+/// Whether ed25519_verify() can return true, it needs libsodium
+pub const HAS_ED25519: bool = cfg!(feature = "c");
+
+/// Whether sig is the ed25519 signature of msg by key
+#[cfg(feature = "c")]
+pub fn ed25519_verify(sig: &[u8], msg: &[u8], key: &[u8; 32]) -> bool {
+    use sodiumoxide::crypto::sign::ed25519;
+    match ed25519::Signature::from_slice(sig) {
+        Some(sig) => ed25519::verify_detached(&sig, msg, &ed25519::PublicKey(*key)),
+        None => false,
+    }
+}
+
+/// There is no ed25519 without libsodium, so nothing is signed
+#[cfg(not(feature = "c"))]
+pub fn ed25519_verify(_sig: &[u8], _msg: &[u8], _key: &[u8; 32]) -> bool {
+    false
+}
+
+#[cfg(feature = "c")]
 pub struct ValidateCtx {
     raw: *mut PacketCrypt_ValidateCtx_t,
 }
+#[cfg(feature = "c")]
 impl Drop for ValidateCtx {
     fn drop(&mut self) {
         unsafe {
@@ -25,6 +62,7 @@ impl Drop for ValidateCtx {
         }
     }
 }
+#[cfg(feature = "c")]
 impl Default for ValidateCtx {
     fn default() -> ValidateCtx {
         ValidateCtx {
@@ -33,6 +71,9 @@ impl Default for ValidateCtx {
     }
 }
 
+pub fn version(bytes: &[u8]) -> u8 {
+    bytes[0]
+}
 pub fn hard_nonce(bytes: &[u8]) -> u32 {
     u32::from_le_bytes(bytes[4..8].try_into().unwrap())
 }
@@ -42,6 +83,15 @@ pub fn work_bits(bytes: &[u8]) -> u32 {
 pub fn parent_block_height(bytes: &[u8]) -> i32 {
     i32::from_le_bytes(bytes[12..16].try_into().unwrap())
 }
+pub fn content_type(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[16..20].try_into().unwrap())
+}
+pub fn content_length(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[20..24].try_into().unwrap())
+}
+pub fn signing_key(bytes: &[u8]) -> &[u8] {
+    &bytes[56..88]
+}
 
 #[derive(Clone, Debug)]
 pub struct PacketCryptAnn {
@@ -71,6 +121,7 @@ impl PacketCryptAnn {
     }
 }
 
+#[cfg(feature = "c")]
 pub fn check_block_work(
     header: &[u8],
     low_nonce: u32,
@@ -137,6 +188,7 @@ pub fn check_block_work(
     }
 }
 
+#[cfg(feature = "c")]
 pub fn check_ann(
     ann: &PacketCryptAnn,
     parent_block_hash: &[u8; 32],
@@ -162,7 +214,16 @@ pub fn check_ann(
     }
 }
 
-#[cfg(test)]
+#[cfg(not(feature = "c"))]
+pub fn check_ann(
+    ann: &PacketCryptAnn,
+    parent_block_hash: &[u8; 32],
+    vctx: &mut ValidateCtx,
+) -> Result<[u8; 32], &'static str> {
+    pure::check_ann(&ann.bytes, parent_block_hash, vctx)
+}
+
+#[cfg(all(test, feature = "c"))]
 mod tests {
     use super::*;
     use std::ffi::CStr;
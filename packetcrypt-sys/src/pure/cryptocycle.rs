@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use super::curve25519;
+use super::hash::{self, Poly1305};
+use std::convert::TryInto;
+
+pub const STATE_SZ: usize = 2048;
+pub const ITEM_SZ: usize = 1024;
+
+// nonce[12], data u32, key_high_or_auth[16], key_low[16]
+const HDR_SZ: usize = 48;
+
+pub type State = [u8; STATE_SZ];
+
+// Bit fields of the little endian u32 header data
+//     0               1               2               3
+//     0 1 2 3 4 5 6 7 0 1 2 3 4 5 6 7 0 1 2 3 4 5 6 7 0 1 2 3 4 5 6 7
+//    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//  8 |   version   |F|      len    |T| add |D|  tzc  |unused |  azc  |
+//    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+const ADDITIONAL_ZEROS: (u32, u32) = (0, 4);
+const TRAILING_ZEROS: (u32, u32) = (8, 4);
+const DECRYPT: (u32, u32) = (12, 1);
+const ADD_LEN: (u32, u32) = (13, 3);
+const TRUNCATED: (u32, u32) = (16, 1);
+const LENGTH: (u32, u32) = (17, 7);
+const FAILED: (u32, u32) = (24, 1);
+const VERSION: (u32, u32) = (25, 7);
+
+fn data(s: &[u8]) -> u32 {
+    u32::from_le_bytes(s[12..16].try_into().unwrap())
+}
+
+fn get(s: &[u8], (begin, count): (u32, u32)) -> u32 {
+    (data(s) >> begin) & ((1 << count) - 1)
+}
+
+fn set(s: &mut [u8], (begin, count): (u32, u32), val: u32) {
+    let mask = (1 << count) - 1;
+    let d = (data(s) & !(mask << begin)) | ((val & mask) << begin);
+    s[12..16].copy_from_slice(&d.to_le_bytes());
+}
+
+pub fn make_fuzzable(s: &mut [u8]) {
+    let (data, key) = s.split_at_mut(16);
+    data[12..16].copy_from_slice(&key[..4]);
+    set(s, VERSION, 0);
+    set(s, FAILED, 0);
+    // Length must be at least 32 blocks (512 bytes) long
+    let len = get(s, LENGTH);
+    set(s, LENGTH, len | 32);
+}
+
+pub fn crypt(s: &mut [u8]) {
+    if get(s, VERSION) != 0 || get(s, FAILED) != 0 {
+        set(s, FAILED, 1);
+        return;
+    }
+    let nonce: [u8; 12] = s[..12].try_into().unwrap();
+    let key: [u8; 32] = s[16..48].try_into().unwrap();
+
+    let mut block0 = [0_u8; 64];
+    hash::chacha20_ietf_xor_ic(&mut block0, &nonce, 0, &key);
+    let mut poly = Poly1305::new(&block0[..32]);
+
+    let aead_len = get(s, ADD_LEN) as usize * 16;
+    let len = get(s, LENGTH);
+    let max_len = 125 - get(s, ADD_LEN);
+    let final_len = std::cmp::min(len, max_len);
+    set(s, TRUNCATED, (final_len != len) as u32);
+    set(s, LENGTH, final_len);
+    let msg_len = final_len as usize * 16;
+    let tzc = get(s, TRAILING_ZEROS) as usize;
+    let azc = get(s, ADDITIONAL_ZEROS) as usize;
+    let decrypt = get(s, DECRYPT) != 0;
+
+    let (aead, msg) = s[HDR_SZ..].split_at_mut(aead_len);
+    let msg = &mut msg[..msg_len];
+    poly.update(aead);
+    if decrypt {
+        poly.update(msg);
+    }
+    hash::chacha20_ietf_xor_ic(msg, &nonce, 1, &key);
+    if !decrypt {
+        for b in msg[msg_len - tzc..].iter_mut() {
+            *b = 0;
+        }
+        poly.update(msg);
+    }
+    let mut slen = [0_u8; 16];
+    // These wrap in the C code when the zero count is more than the length
+    slen[..8].copy_from_slice(&(aead_len as u64).wrapping_sub(azc as u64).to_le_bytes());
+    slen[8..].copy_from_slice(&(msg_len as u64).wrapping_sub(tzc as u64).to_le_bytes());
+    poly.update(&slen);
+    s[16..32].copy_from_slice(&poly.finish());
+}
+
+pub fn init(state: &mut State, seed: &[u8], nonce: u64) {
+    hash::expand(&mut state[..], seed, 0);
+    state[..8].copy_from_slice(&nonce.to_le_bytes());
+    make_fuzzable(state);
+}
+
+pub fn update(state: &mut State, item: &[u8]) {
+    state[32..32 + ITEM_SZ].copy_from_slice(&item[..ITEM_SZ]);
+    make_fuzzable(state);
+    crypt(state);
+}
+
+pub fn item_no(state: &State) -> u64 {
+    u64::from_le_bytes(state[16..24].try_into().unwrap())
+}
+
+pub fn smul(state: &mut State) {
+    let pubkey = curve25519::scalarmult_base(state[32..64].try_into().unwrap());
+    let out = curve25519::scalarmult(state[..32].try_into().unwrap(), &pubkey);
+    state[64..96].copy_from_slice(&out);
+}
+
+pub fn finalize(state: &mut State) {
+    let h = hash::compress32(&state[..]);
+    state[..32].copy_from_slice(&h);
+}
@@ -0,0 +1,250 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! X25519 (RFC 7748), used by CryptoCycle_smul() when checking block proofs.
+//! Nothing secret goes through here so this is written for clarity, not for
+//! resistance to side channels.
+use std::convert::TryInto;
+
+const MASK51: u64 = (1 << 51) - 1;
+
+// Field element mod 2^255 - 19, 5 limbs of 51 bits
+#[derive(Clone, Copy)]
+struct Fe([u64; 5]);
+
+impl Fe {
+    const ZERO: Fe = Fe([0; 5]);
+    const ONE: Fe = Fe([1, 0, 0, 0, 0]);
+
+    fn from_bytes(b: &[u8; 32]) -> Fe {
+        let le = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        Fe([
+            le(0) & MASK51,
+            (le(6) >> 3) & MASK51,
+            (le(12) >> 6) & MASK51,
+            (le(19) >> 1) & MASK51,
+            (le(24) >> 12) & MASK51,
+        ])
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let mut h = self.carry().0;
+        // Compute h mod p by seeing whether h + 19 overflows 2^255
+        let mut q = (h[0] + 19) >> 51;
+        for x in h.iter().skip(1) {
+            q = (x + q) >> 51;
+        }
+        h[0] += 19 * q;
+        for i in 0..4 {
+            h[i + 1] += h[i] >> 51;
+            h[i] &= MASK51;
+        }
+        h[4] &= MASK51;
+        let mut out = [0_u8; 32];
+        let words = [
+            h[0] | (h[1] << 51),
+            (h[1] >> 13) | (h[2] << 38),
+            (h[2] >> 26) | (h[3] << 25),
+            (h[3] >> 39) | (h[4] << 12),
+        ];
+        for (i, w) in words.iter().enumerate() {
+            out[i * 8..(i + 1) * 8].copy_from_slice(&w.to_le_bytes());
+        }
+        out
+    }
+
+    fn carry(self) -> Fe {
+        let mut h = self.0;
+        for i in 0..4 {
+            h[i + 1] += h[i] >> 51;
+            h[i] &= MASK51;
+        }
+        h[0] += 19 * (h[4] >> 51);
+        h[4] &= MASK51;
+        Fe(h)
+    }
+
+    fn add(self, o: Fe) -> Fe {
+        let mut h = self.0;
+        for (x, y) in h.iter_mut().zip(o.0.iter()) {
+            *x += y;
+        }
+        Fe(h).carry()
+    }
+
+    fn sub(self, o: Fe) -> Fe {
+        // add 2p first so that this can never underflow
+        let two_p = [
+            0x000f_ffff_ffff_ffda,
+            0x000f_ffff_ffff_fffe,
+            0x000f_ffff_ffff_fffe,
+            0x000f_ffff_ffff_fffe,
+            0x000f_ffff_ffff_fffe,
+        ];
+        let mut h = self.0;
+        for i in 0..5 {
+            h[i] = h[i] + two_p[i] - o.0[i];
+        }
+        Fe(h).carry()
+    }
+
+    fn mul(self, o: Fe) -> Fe {
+        let a = self.0;
+        let b = o.0;
+        let m = |x: u64, y: u64| x as u128 * y as u128;
+        let b19 = [b[0], b[1] * 19, b[2] * 19, b[3] * 19, b[4] * 19];
+        let r0 =
+            m(a[0], b[0]) + m(a[1], b19[4]) + m(a[2], b19[3]) + m(a[3], b19[2]) + m(a[4], b19[1]);
+        let r1 =
+            m(a[0], b[1]) + m(a[1], b[0]) + m(a[2], b19[4]) + m(a[3], b19[3]) + m(a[4], b19[2]);
+        let r2 = m(a[0], b[2]) + m(a[1], b[1]) + m(a[2], b[0]) + m(a[3], b19[4]) + m(a[4], b19[3]);
+        let r3 = m(a[0], b[3]) + m(a[1], b[2]) + m(a[2], b[1]) + m(a[3], b[0]) + m(a[4], b19[4]);
+        let r4 = m(a[0], b[4]) + m(a[1], b[3]) + m(a[2], b[2]) + m(a[3], b[1]) + m(a[4], b[0]);
+        let mut r = [r0, r1, r2, r3, r4];
+        for i in 0..4 {
+            r[i + 1] += r[i] >> 51;
+            r[i] &= MASK51 as u128;
+        }
+        let c = r[4] >> 51;
+        r[4] &= MASK51 as u128;
+        r[0] += c * 19;
+        Fe([
+            r[0] as u64,
+            r[1] as u64,
+            r[2] as u64,
+            r[3] as u64,
+            r[4] as u64,
+        ])
+        .carry()
+    }
+
+    fn mul_small(self, n: u64) -> Fe {
+        let mut r = [0_u128; 5];
+        for (x, y) in r.iter_mut().zip(self.0.iter()) {
+            *x = *y as u128 * n as u128;
+        }
+        for i in 0..4 {
+            r[i + 1] += r[i] >> 51;
+            r[i] &= MASK51 as u128;
+        }
+        let c = r[4] >> 51;
+        r[4] &= MASK51 as u128;
+        r[0] += c * 19;
+        Fe([
+            r[0] as u64,
+            r[1] as u64,
+            r[2] as u64,
+            r[3] as u64,
+            r[4] as u64,
+        ])
+        .carry()
+    }
+
+    fn square(self) -> Fe {
+        self.mul(self)
+    }
+
+    // self^(p-2)
+    fn invert(self) -> Fe {
+        let sq_n = |mut x: Fe, n: usize| {
+            for _ in 0..n {
+                x = x.square();
+            }
+            x
+        };
+        let z2 = self.square();
+        let z9 = sq_n(z2, 2).mul(self);
+        let z11 = z9.mul(z2);
+        let z2_5_0 = z11.square().mul(z9);
+        let z2_10_0 = sq_n(z2_5_0, 5).mul(z2_5_0);
+        let z2_20_0 = sq_n(z2_10_0, 10).mul(z2_10_0);
+        let z2_40_0 = sq_n(z2_20_0, 20).mul(z2_20_0);
+        let z2_50_0 = sq_n(z2_40_0, 10).mul(z2_10_0);
+        let z2_100_0 = sq_n(z2_50_0, 50).mul(z2_50_0);
+        let z2_200_0 = sq_n(z2_100_0, 100).mul(z2_100_0);
+        let z2_250_0 = sq_n(z2_200_0, 50).mul(z2_50_0);
+        sq_n(z2_250_0, 5).mul(z11)
+    }
+
+    fn cswap(a: &mut Fe, b: &mut Fe, swap: u64) {
+        let mask = 0_u64.wrapping_sub(swap);
+        for i in 0..5 {
+            let t = mask & (a.0[i] ^ b.0[i]);
+            a.0[i] ^= t;
+            b.0[i] ^= t;
+        }
+    }
+}
+
+/// Same as crypto_scalarmult_curve25519(), libsodium rejects an all-zero result but
+/// that only happens with a low order point which CryptoCycle never uses.
+pub fn scalarmult(scalar: &[u8; 32], point: &[u8; 32]) -> [u8; 32] {
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+
+    let x1 = Fe::from_bytes(point);
+    let mut x2 = Fe::ONE;
+    let mut z2 = Fe::ZERO;
+    let mut x3 = x1;
+    let mut z3 = Fe::ONE;
+    let mut swap = 0;
+    for t in (0..255).rev() {
+        let k_t = ((k[t >> 3] >> (t & 7)) & 1) as u64;
+        swap ^= k_t;
+        Fe::cswap(&mut x2, &mut x3, swap);
+        Fe::cswap(&mut z2, &mut z3, swap);
+        swap = k_t;
+
+        let a = x2.add(z2);
+        let aa = a.square();
+        let b = x2.sub(z2);
+        let bb = b.square();
+        let e = aa.sub(bb);
+        let c = x3.add(z3);
+        let d = x3.sub(z3);
+        let da = d.mul(a);
+        let cb = c.mul(b);
+        x3 = da.add(cb).square();
+        z3 = x1.mul(da.sub(cb).square());
+        x2 = aa.mul(bb);
+        z2 = e.mul(aa.add(e.mul_small(121_665)));
+    }
+    Fe::cswap(&mut x2, &mut x3, swap);
+    Fe::cswap(&mut z2, &mut z3, swap);
+
+    x2.mul(z2.invert()).to_bytes()
+}
+
+/// Same as crypto_scalarmult_curve25519_base()
+pub fn scalarmult_base(scalar: &[u8; 32]) -> [u8; 32] {
+    let mut base = [0_u8; 32];
+    base[0] = 9;
+    scalarmult(scalar, &base)
+}
+
+#[cfg(test)]
+mod tests {
+    fn b32(s: &str) -> [u8; 32] {
+        let mut out = [0_u8; 32];
+        out.copy_from_slice(&hex::decode(s).unwrap());
+        out
+    }
+
+    #[test]
+    fn rfc7748() {
+        let k = b32("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4");
+        let u = b32("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c");
+        let r = b32("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552");
+        assert_eq!(super::scalarmult(&k, &u), r);
+
+        let alice = b32("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let alice_pub = b32("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        let bob = b32("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let bob_pub = b32("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+        let shared = b32("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(super::scalarmult_base(&alice), alice_pub);
+        assert_eq!(super::scalarmult_base(&bob), bob_pub);
+        assert_eq!(super::scalarmult(&alice, &bob_pub), shared);
+        assert_eq!(super::scalarmult(&bob, &alice_pub), shared);
+    }
+}
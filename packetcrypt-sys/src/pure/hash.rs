@@ -0,0 +1,240 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use blake2b_simd::Params;
+use std::convert::TryInto;
+
+fn le32(b: &[u8]) -> u32 {
+    u32::from_le_bytes(b[..4].try_into().unwrap())
+}
+
+pub fn compress64(buf: &[u8]) -> [u8; 64] {
+    Params::new()
+        .hash_length(64)
+        .hash(buf)
+        .as_bytes()
+        .try_into()
+        .unwrap()
+}
+
+pub fn compress32(buf: &[u8]) -> [u8; 32] {
+    Params::new()
+        .hash_length(32)
+        .hash(buf)
+        .as_bytes()
+        .try_into()
+        .unwrap()
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u8; 32], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let mut init = [0_u32; 16];
+    init[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for i in 0..8 {
+        init[4 + i] = le32(&key[i * 4..]);
+    }
+    init[12] = counter;
+    for i in 0..3 {
+        init[13 + i] = le32(&nonce[i * 4..]);
+    }
+    let mut s = init;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    let mut out = [0_u8; 64];
+    for i in 0..16 {
+        out[i * 4..(i + 1) * 4].copy_from_slice(&s[i].wrapping_add(init[i]).to_le_bytes());
+    }
+    out
+}
+
+/// Same as libsodium crypto_stream_chacha20_ietf_xor_ic()
+pub fn chacha20_ietf_xor_ic(buf: &mut [u8], nonce: &[u8; 12], ic: u32, key: &[u8; 32]) {
+    for (i, chunk) in buf.chunks_mut(64).enumerate() {
+        let ks = chacha20_block(key, ic.wrapping_add(i as u32), nonce);
+        for (b, k) in chunk.iter_mut().zip(ks.iter()) {
+            *b ^= k;
+        }
+    }
+}
+
+/// Fill buf with a chacha20 keystream derived from the seed and num, this is Hash_expand()
+pub fn expand(buf: &mut [u8], seed: &[u8], num: u32) {
+    let mut nonce = [0_u8; 12];
+    nonce[..4].copy_from_slice(&num.to_le_bytes());
+    nonce[4..].copy_from_slice(b"PC_EXPND");
+    for b in buf.iter_mut() {
+        *b = 0;
+    }
+    chacha20_ietf_xor_ic(buf, &nonce, 0, seed[..32].try_into().unwrap());
+}
+
+/// Incremental poly1305 one-time authenticator, same as libsodium crypto_onetimeauth_poly1305
+pub struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+    buf: [u8; 16],
+    buf_len: usize,
+}
+
+impl Poly1305 {
+    pub fn new(key: &[u8]) -> Self {
+        Poly1305 {
+            r: [
+                le32(&key[0..]) & 0x03ff_ffff,
+                (le32(&key[3..]) >> 2) & 0x03ff_ff03,
+                (le32(&key[6..]) >> 4) & 0x03ff_c0ff,
+                (le32(&key[9..]) >> 6) & 0x03f0_3fff,
+                (le32(&key[12..]) >> 8) & 0x000f_ffff,
+            ],
+            h: [0; 5],
+            pad: [
+                le32(&key[16..]),
+                le32(&key[20..]),
+                le32(&key[24..]),
+                le32(&key[28..]),
+            ],
+            buf: [0; 16],
+            buf_len: 0,
+        }
+    }
+
+    fn block(&mut self, m: &[u8], hibit: u32) {
+        let [r0, r1, r2, r3, r4] = self.r;
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let [mut h0, mut h1, mut h2, mut h3, mut h4] = self.h;
+
+        h0 += le32(&m[0..]) & 0x03ff_ffff;
+        h1 += (le32(&m[3..]) >> 2) & 0x03ff_ffff;
+        h2 += (le32(&m[6..]) >> 4) & 0x03ff_ffff;
+        h3 += (le32(&m[9..]) >> 6) & 0x03ff_ffff;
+        h4 += (le32(&m[12..]) >> 8) | hibit;
+
+        let m64 = |a: u32, b: u32| a as u64 * b as u64;
+        let d0 = m64(h0, r0) + m64(h1, s4) + m64(h2, s3) + m64(h3, s2) + m64(h4, s1);
+        let mut d1 = m64(h0, r1) + m64(h1, r0) + m64(h2, s4) + m64(h3, s3) + m64(h4, s2);
+        let mut d2 = m64(h0, r2) + m64(h1, r1) + m64(h2, r0) + m64(h3, s4) + m64(h4, s3);
+        let mut d3 = m64(h0, r3) + m64(h1, r2) + m64(h2, r1) + m64(h3, r0) + m64(h4, s4);
+        let mut d4 = m64(h0, r4) + m64(h1, r3) + m64(h2, r2) + m64(h3, r1) + m64(h4, r0);
+
+        let mut c = (d0 >> 26) as u32;
+        h0 = d0 as u32 & 0x03ff_ffff;
+        d1 += c as u64;
+        c = (d1 >> 26) as u32;
+        h1 = d1 as u32 & 0x03ff_ffff;
+        d2 += c as u64;
+        c = (d2 >> 26) as u32;
+        h2 = d2 as u32 & 0x03ff_ffff;
+        d3 += c as u64;
+        c = (d3 >> 26) as u32;
+        h3 = d3 as u32 & 0x03ff_ffff;
+        d4 += c as u64;
+        c = (d4 >> 26) as u32;
+        h4 = d4 as u32 & 0x03ff_ffff;
+        h0 += c * 5;
+        c = h0 >> 26;
+        h0 &= 0x03ff_ffff;
+        h1 += c;
+
+        self.h = [h0, h1, h2, h3, h4];
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        if self.buf_len > 0 {
+            let n = std::cmp::min(16 - self.buf_len, data.len());
+            self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            data = &data[n..];
+            if self.buf_len < 16 {
+                return;
+            }
+            let buf = self.buf;
+            self.block(&buf, 1 << 24);
+            self.buf_len = 0;
+        }
+        while data.len() >= 16 {
+            self.block(&data[..16], 1 << 24);
+            data = &data[16..];
+        }
+        self.buf[..data.len()].copy_from_slice(data);
+        self.buf_len = data.len();
+    }
+
+    pub fn finish(mut self) -> [u8; 16] {
+        if self.buf_len > 0 {
+            let mut buf = [0_u8; 16];
+            buf[..self.buf_len].copy_from_slice(&self.buf[..self.buf_len]);
+            buf[self.buf_len] = 1;
+            self.block(&buf, 0);
+        }
+        let [mut h0, mut h1, mut h2, mut h3, mut h4] = self.h;
+
+        let mut c = h1 >> 26;
+        h1 &= 0x03ff_ffff;
+        h2 += c;
+        c = h2 >> 26;
+        h2 &= 0x03ff_ffff;
+        h3 += c;
+        c = h3 >> 26;
+        h3 &= 0x03ff_ffff;
+        h4 += c;
+        c = h4 >> 26;
+        h4 &= 0x03ff_ffff;
+        h0 += c * 5;
+        c = h0 >> 26;
+        h0 &= 0x03ff_ffff;
+        h1 += c;
+
+        // compute h - p and select it if it did not underflow
+        let mut g0 = h0.wrapping_add(5);
+        c = g0 >> 26;
+        g0 &= 0x03ff_ffff;
+        let mut g1 = h1.wrapping_add(c);
+        c = g1 >> 26;
+        g1 &= 0x03ff_ffff;
+        let mut g2 = h2.wrapping_add(c);
+        c = g2 >> 26;
+        g2 &= 0x03ff_ffff;
+        let mut g3 = h3.wrapping_add(c);
+        c = g3 >> 26;
+        g3 &= 0x03ff_ffff;
+        let g4 = h4.wrapping_add(c).wrapping_sub(1 << 26);
+
+        let mask = (g4 >> 31).wrapping_sub(1);
+        h0 = (h0 & !mask) | (g0 & mask);
+        h1 = (h1 & !mask) | (g1 & mask);
+        h2 = (h2 & !mask) | (g2 & mask);
+        h3 = (h3 & !mask) | (g3 & mask);
+        h4 = (h4 & !mask) | (g4 & mask);
+
+        let h = [
+            h0 | (h1 << 26),
+            (h1 >> 6) | (h2 << 20),
+            (h2 >> 12) | (h3 << 14),
+            (h3 >> 18) | (h4 << 8),
+        ];
+        let mut out = [0_u8; 16];
+        let mut f = 0_u64;
+        for i in 0..4 {
+            f = h[i] as u64 + self.pad[i] as u64 + (f >> 32);
+            out[i * 4..(i + 1) * 4].copy_from_slice(&(f as u32).to_le_bytes());
+        }
+        out
+    }
+}
@@ -0,0 +1,396 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! PacketCryptProof_hashProof() and the PcCompress entry table which it uses.
+use super::hash;
+use std::convert::TryInto;
+
+const NUM_ANNS: usize = 4;
+
+// constants
+const F_COMPUTABLE: u16 = 1;
+const F_PAD_ENTRY: u16 = 1 << 1;
+const F_LEAF: u16 = 1 << 2;
+const F_RIGHT: u16 = 1 << 3;
+const F_PAD_SIBLING: u16 = 1 << 4;
+const F_FIRST_ENTRY: u16 = 1 << 5;
+// manipulated by hash_proof()
+const F_HAS_HASH: u16 = 1 << 8;
+const F_HAS_RANGE: u16 = 1 << 9;
+const F_HAS_START: u16 = 1 << 10;
+
+const NONE: u16 = u16::MAX;
+
+fn has_all(flags: u16, want: u16) -> bool {
+    flags & want == want
+}
+
+#[derive(Clone, Copy, Default)]
+struct Entry {
+    hash: [u8; 32],
+    start: u64,
+    end: u64,
+}
+impl Entry {
+    fn to_bytes(self) -> [u8; 48] {
+        let mut out = [0_u8; 48];
+        out[..32].copy_from_slice(&self.hash);
+        out[32..40].copy_from_slice(&self.start.to_le_bytes());
+        out[40..].copy_from_slice(&self.end.to_le_bytes());
+        out
+    }
+    fn hash_u64(&self) -> u64 {
+        u64::from_le_bytes(self.hash[..8].try_into().unwrap())
+    }
+    fn is_ffff(&self) -> bool {
+        self.to_bytes().iter().all(|b| *b == 0xff)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TblEntry {
+    child_left: u16,
+    child_right: u16,
+    // NONE for the root entry
+    parent: u16,
+    flags: u16,
+    e: Entry,
+}
+
+struct Table {
+    branch_height: u32,
+    capacity: usize,
+    entries: Vec<TblEntry>,
+}
+
+impl Table {
+    // PcCompress_mkEntryTable2()
+    fn new(ann_count: u64, ann_numbers: &[u64; NUM_ANNS]) -> Option<Table> {
+        if ann_numbers.iter().any(|n| *n >= ann_count) {
+            return None;
+        }
+        let branch_height = 64 - (ann_count - 1).leading_zeros();
+        let mut tbl = Table {
+            branch_height,
+            capacity: branch_height as usize * NUM_ANNS * 3,
+            entries: Vec::new(),
+        };
+        tbl.mk_entries(ann_numbers, 0, branch_height, NONE, ann_count)?;
+        Some(tbl)
+    }
+
+    fn mk_entries(
+        &mut self,
+        ann_numbers: &[u64; NUM_ANNS],
+        bits: u64,
+        depth: u32,
+        parent: u16,
+        ann_count: u64,
+    ) -> Option<()> {
+        let num = self.entries.len();
+        if num >= self.capacity {
+            return None;
+        }
+        self.entries.push(TblEntry {
+            child_left: NONE,
+            child_right: NONE,
+            parent,
+            flags: 0,
+            e: Entry::default(),
+        });
+
+        let mask = u64::MAX.checked_shl(depth).unwrap_or(0);
+        let mut flags = 0;
+        if (bits.checked_shr(depth).unwrap_or(0) & 1) != 0 {
+            flags |= F_RIGHT;
+        }
+        if depth == 0 {
+            flags |= F_LEAF;
+        }
+        if bits & mask == 0 {
+            flags |= F_FIRST_ENTRY;
+        }
+
+        if ann_numbers.iter().any(|n| (n ^ bits) & mask == 0) {
+            self.entries[num].flags = flags | F_COMPUTABLE;
+            if flags & F_LEAF != 0 {
+                // this entry IS an announcement
+                return Some(());
+            }
+            let left = self.entries.len() as u16;
+            self.mk_entries(ann_numbers, bits, depth - 1, num as u16, ann_count)?;
+            let right = self.entries.len() as u16;
+            let next_bits = bits | (1 << (depth - 1));
+            self.mk_entries(ann_numbers, next_bits, depth - 1, num as u16, ann_count)?;
+            self.entries[num].child_left = left;
+            self.entries[num].child_right = right;
+            if self.entries[right as usize].flags & F_PAD_ENTRY != 0 {
+                self.entries[left as usize].flags |= F_PAD_SIBLING;
+            }
+            return Some(());
+        }
+
+        if bits >= ann_count {
+            // pad entry
+            self.entries[num].flags = flags | F_PAD_ENTRY | F_HAS_HASH | F_HAS_RANGE | F_HAS_START;
+            self.entries[num].e = Entry {
+                hash: [0xff; 32],
+                start: u64::MAX,
+                end: u64::MAX,
+            };
+            return Some(());
+        }
+
+        // it's a sibling for which data must be provided
+        self.entries[num].flags = flags;
+        Some(())
+    }
+
+    fn get_ann(&self, ann_num: u64) -> usize {
+        let mut e = 0;
+        for i in (0..self.branch_height).rev() {
+            let ent = &self.entries[e];
+            e = if (ann_num >> i) & 1 != 0 {
+                ent.child_right
+            } else {
+                ent.child_left
+            } as usize;
+        }
+        e
+    }
+
+    fn get_parent(&self, e: usize) -> Option<usize> {
+        match self.entries[e].parent {
+            NONE => None,
+            p => Some(p as usize),
+        }
+    }
+
+    fn get_sibling(&self, e: usize) -> Option<usize> {
+        let p = &self.entries[self.get_parent(e)?];
+        Some(if p.child_left as usize == e {
+            p.child_right
+        } else {
+            p.child_left
+        } as usize)
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], &'static str> {
+    if buf.len() < n {
+        return Err("proof too short");
+    }
+    let (out, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(out)
+}
+
+fn has_explicit_range(flags: u16) -> bool {
+    // right leaf needs an explicit range provided at the beginning
+    if flags & (F_LEAF | F_RIGHT | F_PAD_ENTRY) == (F_LEAF | F_RIGHT) {
+        return true;
+    }
+    // anything that is not a LEAF, not COMPUTABLE, not a PAD_ENTRY nor a sibling of one
+    flags & (F_LEAF | F_COMPUTABLE | F_PAD_ENTRY | F_PAD_SIBLING) == 0
+}
+
+/// Compute the root hash of a PacketCryptProof, this must be equal to the merkle
+/// root in the coinbase commitment. All arithmetic wraps the same as the C code.
+pub fn hash_proof(
+    ann_hashes: &[[u8; 32]; NUM_ANNS],
+    total_anns: u64,
+    ann_indexes: &[u64; NUM_ANNS],
+    mut proof: &[u8],
+) -> Result<[u8; 32], &'static str> {
+    if total_anns == 0 {
+        return Err("total_anns == 0");
+    }
+    // We need to bump the numbers to account for the zero entry
+    let mut idxs = [0_u64; NUM_ANNS];
+    for (idx, i) in idxs.iter_mut().zip(ann_indexes.iter()) {
+        *idx = (i % total_anns) + 1;
+    }
+    let mut tbl = Table::new(total_anns.wrapping_add(1), &idxs).ok_or("mkEntryTable() null")?;
+
+    // fill in announcement hashes
+    for (idx, h) in idxs.iter().zip(ann_hashes.iter()) {
+        let e = tbl.get_ann(*idx);
+        tbl.entries[e].e.hash = *h;
+        tbl.entries[e].flags |= F_HAS_HASH;
+    }
+
+    // Fill in the hashes and ranges which are provided
+    for ent in tbl.entries.iter_mut() {
+        if has_explicit_range(ent.flags) {
+            ent.e.end = u64::from_le_bytes(take(&mut proof, 8)?.try_into().unwrap());
+            ent.flags |= F_HAS_RANGE;
+        }
+        if ent.flags & (F_HAS_HASH | F_COMPUTABLE) == 0 {
+            ent.e.hash.copy_from_slice(take(&mut proof, 32)?);
+            ent.flags |= F_HAS_HASH;
+        }
+    }
+    if !proof.is_empty() {
+        return Err("proof too long");
+    }
+
+    // Calculate the start and end for each of the announcements and their siblings
+    // We treat leaf siblings specially because right leafs have no explicit range
+    for idx in idxs.iter() {
+        let e = tbl.get_ann(*idx);
+        // same announcement used in two proofs OR two of the announcements are neighbors
+        if tbl.entries[e].flags & F_HAS_START != 0 {
+            continue;
+        }
+        let s = tbl.get_sibling(e).ok_or("ann has no sibling")?;
+        let mut ent = tbl.entries[e];
+        let mut sib = tbl.entries[s];
+        if has_all(sib.flags, F_PAD_ENTRY | F_HAS_START) {
+            // revert this back to a range to simplify code below
+            sib.e.end = 0;
+            sib.flags &= !F_HAS_START;
+        }
+        if !has_all(sib.flags, F_HAS_HASH | F_LEAF) || sib.flags & F_HAS_START != 0 {
+            return Err("bad leaf sibling");
+        }
+        ent.e.start = ent.e.hash_u64();
+        sib.e.start = sib.e.hash_u64();
+        if ent.flags & F_RIGHT != 0 {
+            ent.e.end = ent.e.end.wrapping_add(ent.e.start);
+            sib.e.end = ent.e.start;
+        } else {
+            ent.e.end = sib.e.start;
+            sib.e.end = sib.e.end.wrapping_add(sib.e.start);
+        }
+        if ent.e.end <= ent.e.start {
+            return Err("e.end <= e.start");
+        }
+        ent.flags |= F_HAS_START | F_HAS_RANGE;
+        sib.flags |= F_HAS_START | F_HAS_RANGE;
+        tbl.entries[e] = ent;
+        tbl.entries[s] = sib;
+    }
+
+    // for each announcement, walk up the tree computing as far back as possible
+    // at the last announcement, we must reach the root.
+    for idx in idxs.iter() {
+        let mut e = tbl.get_ann(*idx);
+        // stop when we hit the root
+        while let Some(p) = tbl.get_parent(e) {
+            // Parent has already been computed, dupe or neighboring anns
+            if tbl.entries[p].flags & F_HAS_HASH != 0 {
+                break;
+            }
+            let s = tbl.get_sibling(e).ok_or("no sibling")?;
+            // We can't compute any further because we need to compute the other
+            // sibling in order to continue. When we get to the last announcement,
+            // that will hash up the whole way.
+            if tbl.entries[s].flags & F_HAS_HASH == 0 {
+                break;
+            }
+            let ent = tbl.entries[e];
+            let mut sib = tbl.entries[s];
+            let e_is_right = ent.flags & F_RIGHT != 0;
+
+            if sib.flags & F_HAS_RANGE == 0 {
+                if sib.flags & F_PAD_SIBLING == 0 || e_is_right {
+                    return Err("sibling has no range");
+                }
+                sib.e.end = u64::MAX.wrapping_sub(ent.e.end);
+                sib.flags |= F_HAS_RANGE;
+            }
+            if sib.flags & F_HAS_START == 0 {
+                if e_is_right {
+                    // left.start = right.start - left.range
+                    sib.e.start = ent.e.start.wrapping_sub(sib.e.end);
+                    // left.end = right.start
+                    sib.e.end = ent.e.start;
+                } else {
+                    // right.start = left.end
+                    sib.e.start = ent.e.end;
+                    // right.end = right.range + right.start
+                    sib.e.end = sib.e.end.wrapping_add(sib.e.start);
+                }
+                sib.flags |= F_HAS_START;
+                // No sum of ranges can be greater than UINT_MAX or less than 1
+                if sib.e.end <= sib.e.start {
+                    return Err("sib.end <= sib.start");
+                }
+            }
+            tbl.entries[s] = sib;
+
+            let (left, right) = if e_is_right {
+                (sib.e, ent.e)
+            } else {
+                (ent.e, sib.e)
+            };
+            // the sum of ranges between two announcement hashes must equal
+            // the difference between the hash values
+            if right.start != left.end {
+                return Err("right.start != left.end");
+            }
+            if (left.end <= left.start && !left.is_ffff())
+                || (right.end <= right.start && !right.is_ffff())
+            {
+                return Err("empty range");
+            }
+            let mut buf = [0_u8; 96];
+            buf[..48].copy_from_slice(&left.to_bytes());
+            buf[48..].copy_from_slice(&right.to_bytes());
+            let parent = &mut tbl.entries[p];
+            parent.e.hash = hash::compress32(&buf);
+            parent.e.start = left.start;
+            parent.e.end = right.end;
+            parent.flags |= F_HAS_HASH | F_HAS_RANGE | F_HAS_START;
+            e = p;
+        }
+    }
+
+    let root = &tbl.entries[0];
+    if root.flags != (F_HAS_START | F_HAS_HASH | F_HAS_RANGE | F_COMPUTABLE | F_FIRST_ENTRY)
+        || root.e.start != 0
+        || root.e.end != u64::MAX
+    {
+        return Err("root not computed");
+    }
+    Ok(hash::compress32(&root.e.to_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, hash_proof, Entry};
+    use std::convert::TryInto;
+
+    #[test]
+    fn single_ann() {
+        // With one ann, the tree is just the zero entry and the ann
+        let ann_hash = hash::compress32(b"ann");
+        let ann = Entry {
+            hash: ann_hash,
+            start: u64::from_le_bytes(ann_hash[..8].try_into().unwrap()),
+            end: u64::MAX,
+        };
+        let zero = Entry {
+            hash: [0; 32],
+            start: 0,
+            end: ann.start,
+        };
+        let mut buf = [0_u8; 96];
+        buf[..48].copy_from_slice(&zero.to_bytes());
+        buf[48..].copy_from_slice(&ann.to_bytes());
+        let root = Entry {
+            hash: hash::compress32(&buf),
+            start: 0,
+            end: u64::MAX,
+        };
+        let expected = hash::compress32(&root.to_bytes());
+
+        // The proof is the hash of the zero entry followed by the range of the ann
+        let mut proof = vec![0_u8; 32];
+        proof.extend_from_slice(&(u64::MAX - ann.start).to_le_bytes());
+        let hashes = [ann_hash; 4];
+        assert_eq!(hash_proof(&hashes, 1, &[0, 1, 2, 3], &proof), Ok(expected));
+        assert!(hash_proof(&hashes, 1, &[0; 4], &proof[1..]).is_err());
+        assert!(hash_proof(&hashes, 2, &[0; 4], &proof).is_err());
+        proof[0] = 1;
+        assert_ne!(hash_proof(&hashes, 1, &[0; 4], &proof), Ok(expected));
+    }
+}
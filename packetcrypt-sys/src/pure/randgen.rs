@@ -0,0 +1,423 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use super::hash;
+use super::randhash::op;
+use std::convert::TryInto;
+
+pub const MAX_INSNS: usize = 2048;
+
+const INITIAL_BUDGET: u32 = 20000;
+const MEMORY_COST: u32 = 20;
+const INPUT_COST: u32 = 2;
+const BRANCH_COST: u32 = 50;
+const LOOP_MIN_CYCLES: i64 = 2;
+const RANDOM_BRANCH_LIKELYHOOD: u32 = 2;
+const HIGHER_SCOPE_LIKELYHOOD: u32 = 4;
+const VAR_REUSE_LIKELYHOOD: u32 = 8;
+const IMMEDIATE_LIKELYHOOD: u32 = 4;
+
+fn loop_max_cycles(scope: u32) -> i64 {
+    7 + scope as i64 * 29
+}
+fn should_loop(rand: u32) -> bool {
+    (rand % 32) < 23
+}
+fn should_branch(rand: u32, insn_count: usize) -> bool {
+    (rand % 64) as usize + (insn_count * 25 / MAX_INSNS) < 50
+}
+fn if_body_budget(budget: u32) -> u32 {
+    (budget * 7) / 32
+}
+
+#[derive(Clone, Copy)]
+enum OpType {
+    T1_1,
+    T2_1,
+    T2_2,
+    T4_2,
+    T4_4,
+}
+
+const CODES_1_1: [u32; 11] = [
+    op::POPCNT8,
+    op::POPCNT16,
+    op::POPCNT32,
+    op::CLZ8,
+    op::CLZ16,
+    op::CLZ32,
+    op::CTZ8,
+    op::CTZ16,
+    op::CTZ32,
+    op::BSWAP16,
+    op::BSWAP32,
+];
+const CODES_2_1: [u32; 24] = [
+    op::ADD8,
+    op::ADD16,
+    op::ADD32,
+    op::SUB8,
+    op::SUB16,
+    op::SUB32,
+    op::SHLL8,
+    op::SHLL16,
+    op::SHLL32,
+    op::SHRL8,
+    op::SHRL16,
+    op::SHRL32,
+    op::SHRA8,
+    op::SHRA16,
+    op::SHRA32,
+    op::ROTL8,
+    op::ROTL16,
+    op::ROTL32,
+    op::MUL8,
+    op::MUL16,
+    op::MUL32,
+    op::AND,
+    op::OR,
+    op::XOR,
+];
+const CODES_2_2: [u32; 15] = [
+    op::ADD8C,
+    op::ADD16C,
+    op::ADD32C,
+    op::SUB8C,
+    op::SUB16C,
+    op::SUB32C,
+    op::MUL8C,
+    op::MUL16C,
+    op::MUL32C,
+    op::MULSU8C,
+    op::MULSU16C,
+    op::MULSU32C,
+    op::MULU8C,
+    op::MULU16C,
+    op::MULU32C,
+];
+const CODES_4_2: [u32; 8] = [
+    op::ADD64,
+    op::SUB64,
+    op::SHLL64,
+    op::SHRL64,
+    op::SHRA64,
+    op::ROTL64,
+    op::ROTR64,
+    op::MUL64,
+];
+const CODES_4_4: [u32; 5] = [
+    op::ADD64C,
+    op::SUB64C,
+    op::MUL64C,
+    op::MULSU64C,
+    op::MULU64C,
+];
+
+fn get_op(list: &[u32], idx: u32) -> u32 {
+    list[idx as usize % list.len()]
+}
+
+fn spend(budget: &mut u32, amount: u32) -> bool {
+    if *budget >= amount {
+        *budget -= amount;
+        true
+    } else {
+        false
+    }
+}
+
+fn memory_with_carry(insn: u32, carry: u32) -> u32 {
+    (insn & !(15 << 9)) | ((carry & 15) << 9)
+}
+
+// This must generate exactly the same programs as RandGen.c, including the order
+// in which random numbers are consumed.
+struct Context<'a> {
+    randseed: [u8; 32],
+    randbuf: [u32; 16],
+    next_int: usize,
+    ctr: u32,
+
+    insns: &'a mut [u32; MAX_INSNS],
+    insn_count: usize,
+
+    vars: &'a mut Vec<u32>,
+    scope: u32,
+
+    too_big: bool,
+}
+
+impl<'a> Context<'a> {
+    fn randu32(&mut self) -> u32 {
+        if self.next_int >= self.randbuf.len() {
+            let mut buf = [0_u8; 64];
+            hash::expand(&mut buf, &self.randseed, self.ctr);
+            self.ctr += 1;
+            for (i, x) in self.randbuf.iter_mut().enumerate() {
+                *x = u32::from_le_bytes(buf[i * 4..(i + 1) * 4].try_into().unwrap());
+            }
+            self.next_int = 0;
+        }
+        self.next_int += 1;
+        self.randbuf[self.next_int - 1]
+    }
+
+    fn cointoss(&mut self, one_in: u32) -> bool {
+        self.randu32().is_multiple_of(one_in)
+    }
+
+    fn rand_range(&mut self, start: i64, end: i64) -> i64 {
+        (self.randu32() % ((end - start) as u32)) as i64 + start
+    }
+
+    fn emit(&mut self, insn: u32) {
+        debug_assert!((insn & 0xff) > op::INVALID_ZERO);
+        debug_assert!((insn & 0xff) < op::INVALID_BIG);
+        if self.insn_count >= MAX_INSNS {
+            self.too_big = true;
+            return;
+        }
+        self.insns[self.insn_count] = insn;
+        self.insn_count += 1;
+    }
+
+    fn scope(&mut self) {
+        self.scope += 1;
+        self.vars.push(!0);
+    }
+
+    fn end(&mut self) {
+        self.emit(op::END);
+        self.scope -= 1;
+        while self.vars.pop().unwrap() != !0 {}
+    }
+
+    fn mk_var(&mut self) {
+        self.vars.push(0);
+    }
+
+    fn get_var0(&mut self, dbl: bool) -> usize {
+        let mut eof = self.vars.len() as i64;
+        let mut bof = eof - 1;
+        while bof >= 0 {
+            if self.vars[bof as usize] != !0 {
+                bof -= 1;
+                continue;
+            }
+            // only 1 var in this frame and we're looking for dword, or no vars at all
+            let next_frame = if dbl { bof >= eof - 2 } else { bof >= eof - 1 };
+            if !next_frame {
+                // end of the line, first frame should always have 4 vars
+                if bof == 0 {
+                    break;
+                }
+                // walk up to a higher scope
+                if !self.cointoss(HIGHER_SCOPE_LIKELYHOOD) {
+                    break;
+                }
+            }
+            eof = bof;
+            bof -= 1;
+        }
+        assert!(bof >= 0);
+        let start = self.rand_range(bof + 1, eof);
+        let mut j = start + 1;
+        loop {
+            if j >= eof {
+                j = bof + 1;
+            }
+            // reuse a var, or take one which has not been used yet
+            if ((!dbl || (j > bof + 1)) && self.cointoss(VAR_REUSE_LIKELYHOOD))
+                || (self.vars[j as usize] & 1 == 0 && (!dbl || self.vars[j as usize - 1] & 1 == 0))
+            {
+                return j as usize;
+            }
+            j += 1;
+        }
+    }
+
+    fn get_var(&mut self, dbl: bool) -> u32 {
+        let out = self.get_var0(dbl);
+        assert!(self.vars[out] != !0);
+        self.vars[out] |= 1;
+        if dbl {
+            assert!(self.vars[out - 1] != !0);
+            self.vars[out - 1] |= 1;
+        }
+        out as u32
+    }
+
+    fn get_a(&mut self, dbl: bool) -> u32 {
+        self.get_var(dbl) << 9
+    }
+
+    fn get_b(&mut self, dbl: bool) -> u32 {
+        if self.cointoss(IMMEDIATE_LIKELYHOOD) {
+            (self.randu32() << 20) | (1 << 18)
+        } else {
+            self.get_var(dbl) << 20
+        }
+    }
+
+    fn op(&mut self, t: OpType, budget: &mut u32) -> bool {
+        let rand = self.randu32();
+        let (cost, list, dbl, outputs): (u32, &[u32], bool, usize) = match t {
+            OpType::T1_1 => (1, &CODES_1_1, false, 1),
+            OpType::T2_1 => (2, &CODES_2_1, false, 1),
+            OpType::T2_2 => (4, &CODES_2_2, false, 2),
+            OpType::T4_2 => (8, &CODES_4_2, true, 2),
+            OpType::T4_4 => (16, &CODES_4_4, true, 4),
+        };
+        if !spend(budget, cost) {
+            return false;
+        }
+        let a = self.get_a(dbl);
+        let insn = if let OpType::T1_1 = t {
+            get_op(list, rand) | a
+        } else {
+            get_op(list, rand) | a | self.get_b(dbl)
+        };
+        self.emit(insn);
+        for _ in 0..outputs {
+            self.mk_var();
+        }
+        true
+    }
+
+    fn input(&mut self, budget: &mut u32) -> bool {
+        if !spend(budget, INPUT_COST) {
+            return false;
+        }
+        self.mk_var();
+        let r = self.randu32();
+        self.emit((r << 8) | op::IN);
+        true
+    }
+
+    fn branch(&mut self, budget: &mut u32) -> bool {
+        if !spend(budget, BRANCH_COST) {
+            return false;
+        }
+        let op = if self.cointoss(RANDOM_BRANCH_LIKELYHOOD) {
+            op::IF_RANDOM
+        } else {
+            op::IF_LIKELY
+        };
+        let a = self.get_a(false);
+        self.emit(a | op | (2 << 20));
+        let j1 = self.insn_count;
+        self.emit(op::JMP);
+
+        let mut b1 = if_body_budget(*budget);
+        self.body(&mut b1, true);
+
+        let j2 = self.insn_count;
+        self.emit(op::JMP);
+
+        let mut b2 = if_body_budget(*budget);
+        self.body(&mut b2, true);
+
+        // Now we fill in the first jmp and then the else jmp, if the program is
+        // too big then they might not have been emitted but it will be rejected.
+        if j1 < MAX_INSNS {
+            self.insns[j1] = (((j2 - j1) as u32) << 8) | op::JMP;
+        }
+        if j2 < MAX_INSNS {
+            self.insns[j2] = ((self.insn_count.wrapping_sub(j2 + 1) as u32) << 8) | op::JMP;
+        }
+        true
+    }
+
+    fn loop_(&mut self, budget: &mut u32) -> bool {
+        let loop_len = self.rand_range(LOOP_MIN_CYCLES, loop_max_cycles(self.scope)) as u32;
+        // this must be at least 2
+        let num_mem_acc = self.rand_range(2, 4);
+
+        if *budget < (MEMORY_COST * loop_len) {
+            return false;
+        }
+        *budget /= loop_len;
+        self.emit((loop_len << 20) | op::LOOP);
+        self.scope();
+
+        let mem_template = (self.randu32() << 8) | op::MEMORY;
+        for _ in 0..num_mem_acc {
+            if !spend(budget, MEMORY_COST) {
+                break;
+            }
+            self.mk_var();
+            let carry = self.randu32();
+            self.emit(memory_with_carry(mem_template, carry));
+        }
+        let ret = self.body(budget, false);
+        self.end();
+        ret
+    }
+
+    fn body(&mut self, budget: &mut u32, create_scope: bool) -> bool {
+        if create_scope {
+            self.scope();
+        }
+        'out: loop {
+            if self.insn_count > MAX_INSNS {
+                break;
+            }
+            let max = self.rand_range(2, 12) as u32;
+            for i in 1..=max {
+                if self.cointoss(4 * max / i) && self.op(OpType::T4_4, budget) {
+                    continue;
+                }
+                if self.cointoss(3 * max / i) && self.op(OpType::T4_2, budget) {
+                    continue;
+                }
+                if self.cointoss(3 * max / i) && self.op(OpType::T2_2, budget) {
+                    continue;
+                }
+                if self.cointoss(2 * max / i) && self.op(OpType::T2_1, budget) {
+                    continue;
+                }
+                if self.cointoss(i) && self.input(budget) {
+                    continue;
+                }
+                if self.op(OpType::T1_1, budget) {
+                    continue;
+                }
+                break 'out;
+            }
+            let r = self.randu32();
+            if should_branch(r, self.insn_count) && !self.branch(budget) {
+                break;
+            }
+            let r = self.randu32();
+            if should_loop(r) && !self.loop_(budget) {
+                break;
+            }
+        }
+        if create_scope {
+            self.end();
+        }
+        false
+    }
+}
+
+/// Generate a RandHash program into buf, returns the length of the program or None
+/// if it is too big.
+pub fn generate(buf: &mut [u32; MAX_INSNS], seed: &[u8], vars: &mut Vec<u32>) -> Option<usize> {
+    let mut budget = INITIAL_BUDGET;
+    vars.clear();
+    let mut ctx = Context {
+        randseed: seed[..32].try_into().unwrap(),
+        randbuf: [0; 16],
+        next_int: usize::MAX,
+        ctr: 0,
+        insns: buf,
+        insn_count: 0,
+        vars,
+        scope: 0,
+        too_big: false,
+    };
+    ctx.loop_(&mut budget);
+    if ctx.too_big {
+        None
+    } else {
+        Some(ctx.insn_count)
+    }
+}
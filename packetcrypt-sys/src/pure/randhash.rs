@@ -0,0 +1,437 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use super::cryptocycle::State;
+use super::randgen::MAX_INSNS;
+use std::convert::TryInto;
+
+const MAX_OPS: u32 = 20000;
+const MEMORY_SZ: usize = 256;
+const INOUT_SZ: usize = 256;
+
+// Same numbering as OpCodes.h
+pub mod op {
+    pub const INVALID_ZERO: u32 = 0;
+
+    pub const POPCNT8: u32 = 1;
+    pub const POPCNT16: u32 = 2;
+    pub const POPCNT32: u32 = 3;
+    pub const CLZ8: u32 = 4;
+    pub const CLZ16: u32 = 5;
+    pub const CLZ32: u32 = 6;
+    pub const CTZ8: u32 = 7;
+    pub const CTZ16: u32 = 8;
+    pub const CTZ32: u32 = 9;
+    pub const BSWAP16: u32 = 10;
+    pub const BSWAP32: u32 = 11;
+
+    pub const ADD8: u32 = 12;
+    pub const ADD16: u32 = 13;
+    pub const ADD32: u32 = 14;
+    pub const SUB8: u32 = 15;
+    pub const SUB16: u32 = 16;
+    pub const SUB32: u32 = 17;
+    pub const SHLL8: u32 = 18;
+    pub const SHLL16: u32 = 19;
+    pub const SHLL32: u32 = 20;
+    pub const SHRL8: u32 = 21;
+    pub const SHRL16: u32 = 22;
+    pub const SHRL32: u32 = 23;
+    pub const SHRA8: u32 = 24;
+    pub const SHRA16: u32 = 25;
+    pub const SHRA32: u32 = 26;
+    pub const ROTL8: u32 = 27;
+    pub const ROTL16: u32 = 28;
+    pub const ROTL32: u32 = 29;
+    pub const MUL8: u32 = 30;
+    pub const MUL16: u32 = 31;
+    pub const MUL32: u32 = 32;
+    pub const AND: u32 = 33;
+    pub const OR: u32 = 34;
+    pub const XOR: u32 = 35;
+
+    pub const ADD8C: u32 = 36;
+    pub const ADD16C: u32 = 37;
+    pub const ADD32C: u32 = 38;
+    pub const SUB8C: u32 = 39;
+    pub const SUB16C: u32 = 40;
+    pub const SUB32C: u32 = 41;
+    pub const MUL8C: u32 = 42;
+    pub const MUL16C: u32 = 43;
+    pub const MUL32C: u32 = 44;
+    pub const MULSU8C: u32 = 45;
+    pub const MULSU16C: u32 = 46;
+    pub const MULSU32C: u32 = 47;
+    pub const MULU8C: u32 = 48;
+    pub const MULU16C: u32 = 49;
+    pub const MULU32C: u32 = 50;
+
+    pub const ADD64: u32 = 51;
+    pub const SUB64: u32 = 52;
+    pub const SHLL64: u32 = 53;
+    pub const SHRL64: u32 = 54;
+    pub const SHRA64: u32 = 55;
+    pub const ROTL64: u32 = 56;
+    pub const ROTR64: u32 = 57;
+    pub const MUL64: u32 = 58;
+
+    pub const ADD64C: u32 = 59;
+    pub const SUB64C: u32 = 60;
+    pub const MUL64C: u32 = 61;
+    pub const MULSU64C: u32 = 62;
+    pub const MULU64C: u32 = 63;
+
+    pub const IN: u32 = 64;
+    pub const MEMORY: u32 = 65;
+    pub const LOOP: u32 = 66;
+    pub const IF_LIKELY: u32 = 67;
+    pub const IF_RANDOM: u32 = 68;
+    pub const JMP: u32 = 69;
+    pub const END: u32 = 70;
+    pub const INVALID_BIG: u32 = 71;
+}
+
+fn reg_a(insn: u32) -> usize {
+    ((insn >> 9) & 0x1ff) as usize
+}
+fn reg_b(insn: u32) -> usize {
+    ((insn >> 20) & 0x1ff) as usize
+}
+fn has_imm(insn: u32) -> bool {
+    (insn >> 18) & 1 != 0
+}
+
+fn imm(insn: u32) -> i64 {
+    if insn & (1 << 19) != 0 {
+        // it's a pattern: |S|I|    B    |    A    |
+        let imm = insn >> 20;
+        let a = imm & 31;
+        let b = (imm >> 5) & 31;
+        let i = ((imm >> 10) & 1) as u64;
+        let s = (imm >> 11) as u64;
+        let out = ((i << 63).wrapping_sub(1)) ^ (1 << b) ^ (1 << a);
+        (out | (s << 63)) as i64
+    } else {
+        ((insn as i32) >> 20) as i64
+    }
+}
+
+// Apply a u8 -> u8 or (u8, u8) -> u8 function to each byte lane of a u32
+fn lanes8(a: u32, b: u32, f: impl Fn(u8, u8) -> u8) -> u32 {
+    let (a, b) = (a.to_le_bytes(), b.to_le_bytes());
+    u32::from_le_bytes([f(a[0], b[0]), f(a[1], b[1]), f(a[2], b[2]), f(a[3], b[3])])
+}
+fn lanes16(a: u32, b: u32, f: impl Fn(u16, u16) -> u16) -> u32 {
+    ((f((a >> 16) as u16, (b >> 16) as u16) as u32) << 16) | f(a as u16, b as u16) as u32
+}
+fn lanes8c(a: u32, b: u32, f: impl Fn(u8, u8) -> u16) -> u64 {
+    let (a, b) = (a.to_le_bytes(), b.to_le_bytes());
+    (0..4).fold(0, |acc, i| acc | ((f(a[i], b[i]) as u64) << (16 * i)))
+}
+fn lanes16c(a: u32, b: u32, f: impl Fn(u16, u16) -> u32) -> u64 {
+    ((f((a >> 16) as u16, (b >> 16) as u16) as u64) << 32) | f(a as u16, b as u16) as u64
+}
+
+fn op11(op: u32, a: u32) -> u32 {
+    match op {
+        op::POPCNT8 => lanes8(a, 0, |a, _| a.count_ones() as u8),
+        op::POPCNT16 => lanes16(a, 0, |a, _| a.count_ones() as u16),
+        op::POPCNT32 => a.count_ones(),
+        op::CLZ8 => lanes8(a, 0, |a, _| a.leading_zeros() as u8),
+        op::CLZ16 => lanes16(a, 0, |a, _| a.leading_zeros() as u16),
+        op::CLZ32 => a.leading_zeros(),
+        op::CTZ8 => lanes8(a, 0, |a, _| a.trailing_zeros() as u8),
+        op::CTZ16 => lanes16(a, 0, |a, _| a.trailing_zeros() as u16),
+        op::CTZ32 => a.trailing_zeros(),
+        op::BSWAP16 => lanes16(a, 0, |a, _| a.swap_bytes()),
+        op::BSWAP32 => a.swap_bytes(),
+        _ => unreachable!(),
+    }
+}
+
+fn op21(op: u32, a: u32, b: u32) -> u32 {
+    match op {
+        op::ADD8 => lanes8(a, b, u8::wrapping_add),
+        op::ADD16 => lanes16(a, b, u16::wrapping_add),
+        op::ADD32 => a.wrapping_add(b),
+        op::SUB8 => lanes8(a, b, u8::wrapping_sub),
+        op::SUB16 => lanes16(a, b, u16::wrapping_sub),
+        op::SUB32 => a.wrapping_sub(b),
+        op::SHLL8 => lanes8(a, b, |a, b| a << (b & 7)),
+        op::SHLL16 => lanes16(a, b, |a, b| a << (b & 15)),
+        op::SHLL32 => a << (b & 31),
+        op::SHRL8 => lanes8(a, b, |a, b| a >> (b & 7)),
+        op::SHRL16 => lanes16(a, b, |a, b| a >> (b & 15)),
+        op::SHRL32 => a >> (b & 31),
+        op::SHRA8 => lanes8(a, b, |a, b| ((a as i8) >> (b & 7)) as u8),
+        op::SHRA16 => lanes16(a, b, |a, b| ((a as i16) >> (b & 15)) as u16),
+        op::SHRA32 => ((a as i32) >> (b & 31)) as u32,
+        op::ROTL8 => lanes8(a, b, |a, b| {
+            (a << (b & 7)) | (a >> (8_u8.wrapping_sub(b) & 7))
+        }),
+        op::ROTL16 => lanes16(a, b, |a, b| {
+            (a << (b & 15)) | (a >> (16_u16.wrapping_sub(b) & 15))
+        }),
+        op::ROTL32 => (a << (b & 31)) | (a >> (32_u32.wrapping_sub(b) & 31)),
+        op::MUL8 => lanes8(a, b, u8::wrapping_mul),
+        op::MUL16 => lanes16(a, b, u16::wrapping_mul),
+        op::MUL32 => a.wrapping_mul(b),
+        op::AND => a & b,
+        op::OR => a | b,
+        op::XOR => a ^ b,
+        _ => unreachable!(),
+    }
+}
+
+fn op22(op: u32, a: u32, b: u32) -> u64 {
+    match op {
+        op::ADD8C => lanes8c(a, b, |a, b| a as u16 + b as u16),
+        op::ADD16C => lanes16c(a, b, |a, b| a as u32 + b as u32),
+        op::ADD32C => a as u64 + b as u64,
+        op::SUB8C => lanes8c(a, b, |a, b| (a as u16).wrapping_sub(b as u16)),
+        op::SUB16C => lanes16c(a, b, |a, b| (a as u32).wrapping_sub(b as u32)),
+        op::SUB32C => (a as u64).wrapping_sub(b as u64),
+        op::MUL8C => lanes8c(a, b, |a, b| (a as i8 as i16 * b as i8 as i16) as u16),
+        op::MUL16C => lanes16c(a, b, |a, b| (a as i16 as i32 * b as i16 as i32) as u32),
+        op::MUL32C => (a as i32 as i64 * b as i32 as i64) as u64,
+        op::MULSU8C => lanes8c(a, b, |a, b| (a as i8 as i16 * b as i16) as u16),
+        op::MULSU16C => lanes16c(a, b, |a, b| (a as i16 as i32 * b as i32) as u32),
+        op::MULSU32C => (a as i32 as i64 * b as i64) as u64,
+        op::MULU8C => lanes8c(a, b, |a, b| a as u16 * b as u16),
+        op::MULU16C => lanes16c(a, b, |a, b| a as u32 * b as u32),
+        op::MULU32C => a as u64 * b as u64,
+        _ => unreachable!(),
+    }
+}
+
+fn op42(op: u32, a: u64, b: u64) -> u64 {
+    match op {
+        op::ADD64 => a.wrapping_add(b),
+        op::SUB64 => a.wrapping_sub(b),
+        op::SHLL64 => a << (b & 63),
+        op::SHRL64 => a >> (b & 63),
+        op::SHRA64 => ((a as i64) >> (b & 63)) as u64,
+        op::ROTL64 => (a << (b & 63)) | (a >> (64_u64.wrapping_sub(b) & 63)),
+        op::ROTR64 => (a << (64_u64.wrapping_sub(b) & 63)) | (a >> (b & 63)),
+        op::MUL64 => a.wrapping_mul(b),
+        _ => unreachable!(),
+    }
+}
+
+fn op44(op: u32, a: u64, b: u64) -> u128 {
+    let mk = |lo: u64, hi: u64| ((hi as u128) << 64) | lo as u128;
+    match op {
+        op::ADD64C => {
+            let res = a.wrapping_add(b);
+            mk(res, (res < b) as u64)
+        }
+        op::SUB64C => mk(a.wrapping_sub(b), 0_u64.wrapping_sub((a < b) as u64)),
+        op::MUL64C => (a as i64 as i128 * b as i64 as i128) as u128,
+        op::MULSU64C => (a as i64 as i128 * b as i128) as u128,
+        op::MULU64C => a as u128 * b as u128,
+        _ => unreachable!(),
+    }
+}
+
+// Counterpart of PacketCrypt_ValidateCtx_t, plus the per-interpretation state
+struct Interp<'a> {
+    prog: &'a [u32],
+    memory: &'a [u32],
+    vars: &'a mut Vec<u32>,
+    scopes: &'a mut Vec<u32>,
+    hash_in: [u32; INOUT_SZ],
+    hash_out: [u32; INOUT_SZ],
+    hashctr: usize,
+    loop_cycle: u32,
+    var_count: u32,
+    op_ctr: u32,
+}
+
+impl<'a> Interp<'a> {
+    fn reg(&self, idx: usize) -> u32 {
+        self.vars[idx]
+    }
+    fn get_a(&self, insn: u32) -> u32 {
+        self.reg(reg_a(insn))
+    }
+    fn get_b(&self, insn: u32) -> u32 {
+        if has_imm(insn) {
+            imm(insn) as u32
+        } else {
+            self.reg(reg_b(insn))
+        }
+    }
+    fn get_a2(&self, insn: u32) -> u64 {
+        let r = reg_a(insn);
+        ((self.reg(r) as u64) << 32) | self.reg(r - 1) as u64
+    }
+    fn get_b2(&self, insn: u32) -> u64 {
+        if has_imm(insn) {
+            imm(insn) as u64
+        } else {
+            let r = reg_b(insn);
+            ((self.reg(r) as u64) << 32) | self.reg(r - 1) as u64
+        }
+    }
+
+    fn out1(&mut self, val: u32) {
+        self.vars.push(val);
+        self.var_count += 1;
+    }
+    fn out2(&mut self, val: u64) {
+        self.out1(val as u32);
+        self.out1((val >> 32) as u32);
+    }
+    fn out4(&mut self, val: u128) {
+        self.out2(val as u64);
+        self.out2((val >> 64) as u64);
+    }
+
+    fn branch(&mut self, a: u32, insn: u32, pc: usize) -> Option<usize> {
+        debug_assert_eq!(imm(insn), 2);
+        if a != 0 {
+            self.run(pc + 2)
+        } else {
+            self.run(pc + 1)
+        }
+    }
+
+    // Returns the pc of the END which terminated the scope, None if there were too many ops
+    fn run(&mut self, mut pc: usize) -> Option<usize> {
+        if pc != 0 {
+            self.vars.push(!0);
+            self.scopes.push(self.var_count);
+            self.var_count = 0;
+        }
+        loop {
+            if self.op_ctr > MAX_OPS {
+                return None;
+            }
+            self.op_ctr += 1;
+            let insn = self.prog[pc];
+            let op = insn & 0xff;
+            match op {
+                op::MEMORY => {
+                    let base = insn >> 17;
+                    let step = (insn >> 13) & 15;
+                    let carry = (insn >> 9) & 15;
+                    let idx = base.wrapping_add((self.loop_cycle + carry).wrapping_mul(step));
+                    self.out1(self.memory[idx as usize & (MEMORY_SZ - 1)]);
+                }
+                op::IN => {
+                    let idx = imm(insn) as u32 as usize % INOUT_SZ;
+                    self.out1(self.hash_in[idx]);
+                }
+                op::LOOP => {
+                    let count = imm(insn);
+                    let mut ret = pc;
+                    for i in 0..count {
+                        self.loop_cycle = i as u32;
+                        ret = self.run(pc + 1)?;
+                    }
+                    pc = ret;
+                    if pc == self.prog.len() - 1 {
+                        return Some(pc);
+                    }
+                }
+                op::IF_LIKELY => {
+                    let a = self.get_a(insn) & 7;
+                    pc = self.branch(a, insn, pc)?;
+                }
+                op::IF_RANDOM => {
+                    let a = self.get_a(insn) & 1;
+                    pc = self.branch(a, insn, pc)?;
+                }
+                op::JMP => {
+                    pc += (insn >> 8) as usize;
+                }
+                op::END => {
+                    // output everything first
+                    let start = self.vars.len() - self.var_count as usize;
+                    for i in start..self.vars.len() {
+                        self.hash_out[self.hashctr] =
+                            self.hash_out[self.hashctr].wrapping_add(self.vars[i]);
+                        self.hashctr = (self.hashctr + 1) % INOUT_SZ;
+                    }
+                    self.vars.truncate(start);
+                    let marker = self.vars.pop();
+                    debug_assert_eq!(marker, Some(!0));
+                    self.var_count = self.scopes.pop().unwrap();
+                    return Some(pc);
+                }
+                op::POPCNT8..=op::BSWAP32 => {
+                    let out = op11(op, self.get_a(insn));
+                    self.out1(out);
+                }
+                op::ADD8..=op::XOR => {
+                    let out = op21(op, self.get_a(insn), self.get_b(insn));
+                    self.out1(out);
+                }
+                op::ADD8C..=op::MULU32C => {
+                    let out = op22(op, self.get_a(insn), self.get_b(insn));
+                    self.out2(out);
+                }
+                op::ADD64..=op::MUL64 => {
+                    let out = op42(op, self.get_a2(insn), self.get_b2(insn));
+                    self.out2(out);
+                }
+                op::ADD64C..=op::MULU64C => {
+                    let out = op44(op, self.get_a2(insn), self.get_b2(insn));
+                    self.out4(out);
+                }
+                _ => panic!("invalid opcode {}", op),
+            }
+            pc += 1;
+        }
+    }
+}
+
+/// Run a RandHash program over the cryptocycle state as RandHash_interpret() does,
+/// returns false if the program ran for too many ops.
+pub fn interpret(
+    prog: &[u32; MAX_INSNS],
+    prog_len: usize,
+    vars: &mut Vec<u32>,
+    scopes: &mut Vec<u32>,
+    item_num: u64,
+    state: &mut State,
+    cycles: usize,
+) -> bool {
+    let mem_start = (item_num % (MAX_INSNS - MEMORY_SZ) as u64) as usize;
+    let mut ints = [0_u32; 512];
+    for (i, x) in ints.iter_mut().enumerate() {
+        *x = u32::from_le_bytes(state[i * 4..(i + 1) * 4].try_into().unwrap());
+    }
+    vars.clear();
+    scopes.clear();
+    let mut ctx = Interp {
+        prog: &prog[..prog_len],
+        memory: &prog[mem_start..mem_start + MEMORY_SZ],
+        vars,
+        scopes,
+        hash_in: ints[..INOUT_SZ].try_into().unwrap(),
+        hash_out: ints[INOUT_SZ..].try_into().unwrap(),
+        hashctr: 0,
+        loop_cycle: 0,
+        var_count: 0,
+        op_ctr: 0,
+    };
+    let mut swapped = false;
+    for _ in 0..cycles {
+        ctx.op_ctr = 0;
+        if ctx.run(0).is_none() || ctx.op_ctr > MAX_OPS {
+            return false;
+        }
+        ctx.hashctr = 0;
+        std::mem::swap(&mut ctx.hash_in, &mut ctx.hash_out);
+        swapped = !swapped;
+    }
+    // Swapping the buffers back puts them at their original location in the state
+    let (lo, hi) = if swapped {
+        (&ctx.hash_out, &ctx.hash_in)
+    } else {
+        (&ctx.hash_in, &ctx.hash_out)
+    };
+    for (i, x) in lo.iter().chain(hi.iter()).enumerate() {
+        state[i * 4..(i + 1) * 4].copy_from_slice(&x.to_le_bytes());
+    }
+    true
+}
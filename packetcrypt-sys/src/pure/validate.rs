@@ -0,0 +1,307 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use super::cryptocycle::{self, State, ITEM_SZ, STATE_SZ};
+use super::hash;
+use super::pcp;
+use super::randgen::{self, MAX_INSNS};
+use super::randhash;
+use crate::difficulty;
+use std::convert::TryInto;
+
+const HDR_SZ: usize = 88;
+const MERKLE_DEPTH: usize = 13;
+const TABLE_SZ: u64 = 1 << MERKLE_DEPTH;
+const MERKLE_PROOF_SZ: usize = (MERKLE_DEPTH + 1) * 64;
+const LAST_ANN_PFX: usize = HDR_SZ + MERKLE_PROOF_SZ;
+const RANDHASH_CYCLES: usize = 4;
+
+const NUM_ANNS: usize = 4;
+const BLOCK_HDR_SZ: usize = 80;
+// block header, padding, nonce2, announcements, then the PacketCryptProof
+const HAP_SZ: usize = BLOCK_HDR_SZ + 8 + NUM_ANNS * 1024;
+// magic, annLeastWorkTarget, merkleRoot, numAnns
+const COINBASE_SZ: usize = 48;
+const COINBASE_MAGIC: u32 = 0x0211_f909;
+
+pub struct ValidateCtx {
+    progbuf: Box<[u32; MAX_INSNS]>,
+    prog_len: usize,
+    vars: Vec<u32>,
+    scopes: Vec<u32>,
+}
+impl Default for ValidateCtx {
+    fn default() -> ValidateCtx {
+        ValidateCtx {
+            progbuf: Box::new([0; MAX_INSNS]),
+            prog_len: 0,
+            vars: Vec::new(),
+            scopes: Vec::new(),
+        }
+    }
+}
+
+fn create_prog(vctx: &mut ValidateCtx, seed: &[u8]) -> Result<(), &'static str> {
+    let mut buf = [0_u8; MAX_INSNS * 4];
+    hash::expand(&mut buf, seed, 0);
+    for (i, x) in vctx.progbuf.iter_mut().enumerate() {
+        *x = u32::from_le_bytes(buf[i * 4..(i + 1) * 4].try_into().unwrap());
+    }
+    // The C code ignores this error and validates with whatever program was there
+    // before, there is no valid ann which that would accept so just reject it.
+    vctx.prog_len = randgen::generate(&mut vctx.progbuf, seed, &mut vctx.vars).ok_or("INVAL")?;
+    Ok(())
+}
+
+fn mk_item2(
+    num: u64,
+    item: &mut [u8; ITEM_SZ],
+    seed: &[u8],
+    vctx: &mut ValidateCtx,
+) -> Result<(), &'static str> {
+    let mut state = [0_u8; STATE_SZ];
+    cryptocycle::init(&mut state, seed, num);
+    if !randhash::interpret(
+        &vctx.progbuf,
+        vctx.prog_len,
+        &mut vctx.vars,
+        &mut vctx.scopes,
+        num,
+        &mut state,
+        2,
+    ) {
+        return Err("INVAL");
+    }
+    cryptocycle::make_fuzzable(&mut state);
+    cryptocycle::crypt(&mut state);
+    item.copy_from_slice(&state[..ITEM_SZ]);
+    Ok(())
+}
+
+fn is_item_valid(merkle_proof: &[u8], item_hash: &[u8; 64], mut item_no: u64) -> bool {
+    let mut b = [0_u8; 128];
+    let slot = |n: u64| (n & 1) as usize * 64;
+    b[slot(item_no)..slot(item_no) + 64].copy_from_slice(item_hash);
+    for i in 0..MERKLE_DEPTH {
+        let other = slot(!item_no);
+        b[other..other + 64].copy_from_slice(&merkle_proof[i * 64..(i + 1) * 64]);
+        item_no >>= 1;
+        let h = hash::compress64(&b);
+        b[slot(item_no)..slot(item_no) + 64].copy_from_slice(&h);
+    }
+    b[slot(item_no)..slot(item_no) + 64] == merkle_proof[MERKLE_DEPTH * 64..]
+}
+
+fn soft_nonce_max(target: u32) -> u32 {
+    let mantissa = target & 0x007f_ffff;
+    if mantissa == 0 {
+        return 0;
+    }
+    let log2floor = 31 - mantissa.leading_zeros() as i32;
+    let bits = (22 - log2floor) + ((0x20 - (target >> 24) as i32) * 8) + 10;
+    if bits >= 24 {
+        0x00ff_ffff
+    } else if bits <= 0 {
+        0
+    } else {
+        0x00ff_ffff >> (24 - bits)
+    }
+}
+
+/// Same as Work_check(), the hash is treated as a little endian number
+pub fn work_check(hash: &[u8], target: u32) -> bool {
+    let zero_bytes = (target >> 24) as usize;
+    if target > 0x207f_ffff || zero_bytes < 3 {
+        return false;
+    }
+    let mantissa = target & 0x00ff_ffff;
+    if mantissa > 0x7f_ffff {
+        return false;
+    }
+    if hash[zero_bytes..32].iter().any(|b| *b != 0) {
+        return false;
+    }
+    let significant = ((hash[zero_bytes - 1] as u32) << 16)
+        | ((hash[zero_bytes - 2] as u32) << 8)
+        | hash[zero_bytes - 3] as u32;
+    significant < mantissa
+}
+
+/// Validate an announcement, this is a port of Validate_checkAnn()
+pub fn check_ann(
+    ann: &[u8],
+    parent_block_hash: &[u8; 32],
+    vctx: &mut ValidateCtx,
+) -> Result<[u8; 32], &'static str> {
+    if ann.len() != 1024 {
+        return Err("INVAL");
+    }
+    let work_bits = u32::from_le_bytes(ann[8..12].try_into().unwrap());
+    let merkle_proof = &ann[HDR_SZ..LAST_ANN_PFX];
+    let merkle_root = &merkle_proof[MERKLE_DEPTH * 64..];
+
+    let mut buf = [0_u8; HDR_SZ + 64];
+    buf[..HDR_SZ].copy_from_slice(&ann[..HDR_SZ]);
+    buf[HDR_SZ..HDR_SZ + 32].copy_from_slice(parent_block_hash);
+    // soft nonce
+    buf[1..4].copy_from_slice(&[0, 0, 0]);
+    let ann_hash0 = hash::compress64(&buf);
+    buf[HDR_SZ..].copy_from_slice(merkle_root);
+    let ann_hash1 = hash::compress64(&buf);
+
+    let soft_nonce = u32::from_le_bytes(ann[..4].try_into().unwrap()) >> 8;
+    if soft_nonce > soft_nonce_max(work_bits) {
+        return Err("SOFT_NONCE_HIGH");
+    }
+
+    let mut v1_seed = [0_u8; 128];
+    v1_seed[..64].copy_from_slice(merkle_root);
+    v1_seed[64..].copy_from_slice(&ann_hash0);
+    let v1_seed = hash::compress64(&v1_seed);
+    create_prog(vctx, &v1_seed[..32])?;
+
+    let mut state: State = [0; STATE_SZ];
+    let mut item = [0_u8; ITEM_SZ];
+    cryptocycle::init(&mut state, &ann_hash1[..32], soft_nonce as u64);
+    let mut item_no = 0;
+    for _ in 0..RANDHASH_CYCLES {
+        item_no = cryptocycle::item_no(&state) % TABLE_SZ;
+        mk_item2(item_no, &mut item, &v1_seed[32..], vctx)?;
+        cryptocycle::update(&mut state, &item);
+    }
+    cryptocycle::finalize(&mut state);
+
+    let mut decrypted = [0_u8; 1024];
+    let ann = if ann[0] > 0 {
+        decrypted.copy_from_slice(ann);
+        let proof_len = MERKLE_PROOF_SZ - 64;
+        for (i, b) in decrypted[HDR_SZ..HDR_SZ + proof_len].iter_mut().enumerate() {
+            *b ^= state[i];
+        }
+        for (i, b) in decrypted[LAST_ANN_PFX..].iter_mut().enumerate() {
+            *b ^= state[proof_len + i];
+        }
+        if decrypted[LAST_ANN_PFX..].iter().any(|b| *b != 0) {
+            return Err("INVAL_ITEM4");
+        }
+        // Need to re-compute the item because we are proving the original value
+        create_prog(vctx, &ann_hash0[..32])?;
+        mk_item2(item_no, &mut item, &ann_hash0[32..], vctx)?;
+        &decrypted[..]
+    } else {
+        if item[..1024 - LAST_ANN_PFX] != ann[LAST_ANN_PFX..] {
+            return Err("INVAL_ITEM4");
+        }
+        ann
+    };
+
+    let item_hash = hash::compress64(&item);
+    if !is_item_valid(&ann[HDR_SZ..LAST_ANN_PFX], &item_hash, item_no) {
+        return Err("INVAL");
+    }
+    if !work_check(&state[..32], work_bits) {
+        return Err("INSUF_POW");
+    }
+    Ok(state[..32].try_into().unwrap())
+}
+
+fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+/// Validate a block or share, this is a port of Validate_checkBlock(). The
+/// announcements are only validated if their parent block hashes are provided.
+/// Errors are the same strings as returned by the C version of check_block_work().
+pub fn check_block(
+    hap: &[u8],
+    block_height: u32,
+    share_target: u32,
+    coinbase: &[u8],
+    mut block_hashes: Option<(&[[u8; 32]; NUM_ANNS], &mut ValidateCtx)>,
+) -> Result<[u8; 32], String> {
+    if hap.len() < HAP_SZ {
+        return Err("PCP_INVAL".to_owned());
+    }
+    if coinbase.len() < COINBASE_SZ || le32(coinbase, 0) != COINBASE_MAGIC {
+        return Err("BAD_COINBASE".to_owned());
+    }
+    let ann_least_work_target = le32(coinbase, 4);
+    if !difficulty::pc_is_min_ann_diff_ok(ann_least_work_target) {
+        return Err("BAD_COINBASE".to_owned());
+    }
+    let merkle_root = &coinbase[8..40];
+    let num_anns = u64::from_le_bytes(coinbase[40..48].try_into().unwrap());
+    let anns = &hap[BLOCK_HDR_SZ + 8..HAP_SZ];
+
+    // Check that final work result meets difficulty requirement
+    let mut ann_indexes = [0_u64; NUM_ANNS];
+    let mut state: State = [0; STATE_SZ];
+    let hdr_hash = hash::compress32(&hap[..BLOCK_HDR_SZ]);
+    cryptocycle::init(&mut state, &hdr_hash, le32(hap, BLOCK_HDR_SZ + 4) as u64);
+    for (idx, ann) in ann_indexes.iter_mut().zip(anns.chunks(ITEM_SZ)) {
+        // This gets modded over the total anns in hash_proof()
+        *idx = cryptocycle::item_no(&state);
+        cryptocycle::update(&mut state, ann);
+    }
+    cryptocycle::smul(&mut state);
+    cryptocycle::finalize(&mut state);
+    let work_hash: [u8; 32] = state[..32].try_into().unwrap();
+    let is_work_ok = |target| {
+        let eff = difficulty::pc_get_effective_target(target, ann_least_work_target, num_anns);
+        work_check(&work_hash, eff)
+    };
+    let work_ok = is_work_ok(le32(hap, 72)) || (share_target != 0 && is_work_ok(share_target));
+
+    // Validate announcements
+    let mut ann_hashes = [[0_u8; 32]; NUM_ANNS];
+    for (i, ann) in anns.chunks(ITEM_SZ).enumerate() {
+        if let Some((hashes, vctx)) = block_hashes.as_mut() {
+            if check_ann(ann, &hashes[i], vctx).is_err() {
+                return Err(format!("ANN_INVALID {}", i));
+            }
+        }
+        let ann_work_bits = le32(ann, 8);
+        let effective_ann_target = if block_height < 3 {
+            ann_work_bits
+        } else {
+            difficulty::pc_degrade_announcement_target(
+                ann_work_bits,
+                block_height.wrapping_sub(le32(ann, 12)),
+            )
+        };
+        if effective_ann_target > ann_least_work_target {
+            return Err(format!("ANN_INSUF_POW {}", i));
+        }
+        ann_hashes[i] = hash::compress32(ann);
+    }
+
+    let pcp_hash = pcp::hash_proof(&ann_hashes, num_anns, &ann_indexes, &hap[HAP_SZ..])
+        .map_err(|_| "PCP_INVAL".to_owned())?;
+    if pcp_hash[..] != *merkle_root {
+        return Err("PCP_MISMATCH".to_owned());
+    }
+    if !work_ok {
+        return Err(format!("INSUF_POW {}", hex::encode(work_hash)));
+    }
+    Ok(work_hash)
+}
+
+/// Same as the C check_block_work(), announcements are not validated.
+pub fn check_block_work(
+    header: &[u8],
+    low_nonce: u32,
+    share_target: u32,
+    anns: &[[u8; 1024]],
+    coinbase: &[u8],
+    mining_height: i32,
+    proof: &[u8],
+) -> Result<[u8; 32], String> {
+    let mut hap = Vec::with_capacity(HAP_SZ + proof.len());
+    hap.extend_from_slice(header);
+    hap.extend_from_slice(&0_u32.to_le_bytes());
+    hap.extend_from_slice(&low_nonce.to_le_bytes());
+    for ann in anns.iter() {
+        hap.extend_from_slice(&ann[..]);
+    }
+    assert!(hap.len() == HAP_SZ);
+    hap.extend_from_slice(proof);
+    check_block(&hap, mining_height as u32, share_target, coinbase, None)
+}
@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Counting of the allocations which are made in the block miner's hot path, so that
+//! work on making it allocation free can be measured and regressions caught.
+//!
+//! Code in the hot path runs inside scope() and when built with the alloc_audit
+//! feature, the allocator counts what each thread allocates while it is in a scope.
+//! Without the feature, scope() just runs the code and take() has nothing.
+//!
+//! Allocations are counted on the thread which makes them, so work which is handed to
+//! other threads (e.g. with rayon) needs to be wrapped where it runs.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    // Receiving, checking and copying in anns
+    Intake,
+    // Hashing anns and sorting them into classes
+    Classify,
+    // Building the proof tree for new work
+    Tree,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Intake => "intake",
+            Stage::Classify => "classify",
+            Stage::Tree => "tree",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Counts {
+    pub allocs: u64,
+    pub bytes: u64,
+}
+
+#[cfg(feature = "alloc_audit")]
+mod imp {
+    use super::{Counts, Stage};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const STAGES: [Stage; 3] = [Stage::Intake, Stage::Classify, Stage::Tree];
+    const NONE: usize = usize::MAX;
+
+    thread_local! {
+        static STAGE: Cell<usize> = Cell::new(NONE);
+    }
+
+    static ALLOCS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+    static BYTES: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+    fn count(size: usize) {
+        // try_with because this can be called while the thread is exiting
+        let s = STAGE.try_with(|s| s.get()).unwrap_or(NONE);
+        if s < STAGES.len() {
+            ALLOCS[s].fetch_add(1, Ordering::Relaxed);
+            BYTES[s].fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// The system allocator, counting allocations made in a scope()
+    pub struct CountingAlloc;
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count(layout.size());
+            System.alloc(layout)
+        }
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            count(layout.size());
+            System.alloc_zeroed(layout)
+        }
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    /// Run f, counting what this thread allocates as being for stage
+    pub fn scope<T>(stage: Stage, f: impl FnOnce() -> T) -> T {
+        let outer = STAGE.with(|s| s.replace(stage as usize));
+        let out = f();
+        STAGE.with(|s| s.set(outer));
+        out
+    }
+
+    /// The counts for each stage since the last call
+    pub fn take() -> Vec<(Stage, Counts)> {
+        STAGES
+            .iter()
+            .map(|st| {
+                let i = *st as usize;
+                let c = Counts {
+                    allocs: ALLOCS[i].swap(0, Ordering::Relaxed),
+                    bytes: BYTES[i].swap(0, Ordering::Relaxed),
+                };
+                (*st, c)
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "alloc_audit")]
+pub use imp::{scope, take, CountingAlloc};
+
+#[cfg(not(feature = "alloc_audit"))]
+pub fn scope<T>(_stage: Stage, f: impl FnOnce() -> T) -> T {
+    f()
+}
+
+/// The counts for each stage since the last call, empty without the alloc_audit feature
+#[cfg(not(feature = "alloc_audit"))]
+pub fn take() -> Vec<(Stage, Counts)> {
+    Vec::new()
+}
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Waiting between attempts to reach something which is down. The wait doubles after
+//! each failure, up to max_ms, and is randomized so that all of the miners which lost
+//! the pool at the same moment don't come back at the same moment.
+//!
+//! After BREAKER_FAILURES failures in a row the breaker is open: this is logged once,
+//! every wait is about max_ms and the caller can use is_open() to stop logging each
+//! failure. The first success closes it again.
+use crate::util;
+use log::{info, warn};
+use std::time::Duration;
+
+pub const BREAKER_FAILURES: u32 = 5;
+
+pub struct Backoff {
+    name: String,
+    min_ms: u64,
+    max_ms: u64,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(name: impl Into<String>, min_ms: u64, max_ms: u64) -> Self {
+        Backoff {
+            name: name.into(),
+            min_ms,
+            max_ms: max_ms.max(min_ms),
+            failures: 0,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.failures >= BREAKER_FAILURES
+    }
+
+    pub fn success(&mut self) {
+        if self.is_open() {
+            info!("[{}] is back after {} failures", self.name, self.failures);
+        }
+        self.failures = 0;
+    }
+
+    /// How long to wait before the next attempt
+    pub fn failure(&mut self) -> Duration {
+        self.failures += 1;
+        if self.failures == BREAKER_FAILURES {
+            warn!(
+                "[{}] failed {} times in a row, trying about every {}s",
+                self.name,
+                self.failures,
+                self.max_ms / 1000
+            );
+        }
+        let base = self
+            .min_ms
+            .saturating_mul(1 << (self.failures - 1).min(20))
+            .min(self.max_ms);
+        // Between half and all of it
+        Duration::from_millis(base / 2 + util::rand_u32() as u64 % (base / 2 + 1))
+    }
+
+    pub async fn wait(&mut self) {
+        util::sleep_ms(self.failure().as_millis() as u64).await;
+    }
+
+    /// For threads which are not in the runtime
+    pub fn wait_blocking(&mut self) {
+        std::thread::sleep(self.failure());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Backoff;
+    use std::time::Duration;
+
+    #[test]
+    fn doubles_to_max() {
+        let mut b = Backoff::new("test", 1000, 8000);
+        let waits = (0..6).map(|_| b.failure()).collect::<Vec<_>>();
+        for (w, base) in waits.iter().zip(&[1000, 2000, 4000, 8000, 8000, 8000]) {
+            assert!(*w >= Duration::from_millis(base / 2));
+            assert!(*w <= Duration::from_millis(*base));
+        }
+        assert!(b.is_open());
+        b.success();
+        assert!(!b.is_open());
+        assert!(b.failure() <= Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! A small proof of work which a handler can ask for before it takes uploads, so that
+//! a flooder posting from thousands of addresses pays for each one. The handler answers
+//! an upload without a good answer with 428, an x-pc-challenge token and the number of
+//! x-pc-challenge-bits, and the miner sends "<token>:<nonce>" in x-pc-challenge from then
+//! on, where blake2b(token || nonce) begins with that many zero bits. A token is a
+//! window of time, a random session nonce and a MAC of them and the miner's IP, so the
+//! handler remembers nothing and the answer is only good from that IP, for this window
+//! and the next. The session nonce makes every challenge different, so answers can't be
+//! worked out ahead of time from the window and IP. The handler checks the answer
+//! from the headers, before it reads the upload. The MAC key is challenge_secret from the
+//! config so that every shard, and the handler after a restart, takes the answers which
+//! the others gave out.
+use crate::{hash, util};
+use anyhow::{bail, Result};
+use rand::Rng;
+use std::convert::TryInto;
+use std::net::IpAddr;
+
+/// Most bits a miner will work on, more would hold up its uploads for too long
+pub const MAX_BITS: u32 = 24;
+
+const WINDOW_SEC: u64 = 600;
+const MAC_LEN: usize = 16;
+// window, session nonce, MAC
+const TOKEN_LEN: usize = 8 + 8 + MAC_LEN;
+
+fn zero_bits(h: &[u8; 32]) -> u32 {
+    let mut n = 0;
+    for b in h {
+        if *b != 0 {
+            return n + b.leading_zeros();
+        }
+        n += 8;
+    }
+    n
+}
+
+fn work(token: &[u8], nonce: u64) -> [u8; 32] {
+    let mut buf = token.to_vec();
+    buf.extend_from_slice(&nonce.to_le_bytes());
+    hash::compress32(&buf)
+}
+
+/// The answer to a challenge, to be sent in x-pc-challenge
+pub fn solve(token: &str, bits: u32) -> Result<String> {
+    if bits > MAX_BITS {
+        bail!("challenge of [{}] bits is more than [{}]", bits, MAX_BITS);
+    }
+    let t = hex::decode(token)?;
+    if t.len() != TOKEN_LEN {
+        bail!("challenge token [{}] is not {} bytes", token, TOKEN_LEN);
+    }
+    let nonce = (0..).find(|n| zero_bits(&work(&t, *n)) >= bits).unwrap();
+    Ok(format!("{}:{}", token, nonce))
+}
+
+pub struct Issuer {
+    secret: [u8; 32],
+    pub bits: u32,
+}
+impl Issuer {
+    pub fn new(bits: u32, secret: &str) -> Issuer {
+        Issuer {
+            secret: hash::compress32(secret.as_bytes()),
+            bits,
+        }
+    }
+
+    fn token(&self, ip: &IpAddr, window: u64, session: u64) -> [u8; TOKEN_LEN] {
+        let mut buf = self.secret.to_vec();
+        buf.extend_from_slice(&window.to_le_bytes());
+        buf.extend_from_slice(&session.to_le_bytes());
+        buf.extend_from_slice(ip.to_string().as_bytes());
+        let mut t = [0u8; TOKEN_LEN];
+        t[..8].copy_from_slice(&window.to_le_bytes());
+        t[8..16].copy_from_slice(&session.to_le_bytes());
+        t[16..].copy_from_slice(&hash::compress32(&buf)[..MAC_LEN]);
+        t
+    }
+
+    /// A new challenge for uploads from ip
+    pub fn issue(&self, ip: &IpAddr, now_sec: u64) -> String {
+        let session = rand::thread_rng().gen::<u64>();
+        hex::encode(self.token(ip, now_sec / WINDOW_SEC, session))
+    }
+
+    /// True if answer solves a challenge which was issued to ip in this window or the last
+    pub fn check(&self, ip: &IpAddr, answer: &str, now_sec: u64) -> bool {
+        let mut parts = answer.splitn(2, ':');
+        let (token, nonce) = match (parts.next(), parts.next()) {
+            (Some(t), Some(n)) => (t, n),
+            _ => return false,
+        };
+        let (t, nonce) = match (hex::decode(token), nonce.parse::<u64>()) {
+            (Ok(t), Ok(n)) if t.len() == TOKEN_LEN => (t, n),
+            _ => return false,
+        };
+        let window = u64::from_le_bytes(t[..8].try_into().unwrap());
+        let session = u64::from_le_bytes(t[8..16].try_into().unwrap());
+        let now = now_sec / WINDOW_SEC;
+        if window > now || window + 1 < now {
+            return false;
+        }
+        if !util::secret_eq(&hex::encode(self.token(ip, window, session)), token) {
+            return false;
+        }
+        zero_bits(&work(&t, nonce)) >= self.bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solve_check() {
+        let iss = Issuer::new(8, "secret");
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let now = 1_000_000;
+        let answer = solve(&iss.issue(&ip, now), iss.bits).unwrap();
+        assert!(iss.check(&ip, &answer, now));
+        assert!(iss.check(&ip, &answer, now + WINDOW_SEC));
+        assert!(!iss.check(&ip, &answer, now + 2 * WINDOW_SEC));
+        assert!(!iss.check(&"192.0.2.8".parse().unwrap(), &answer, now));
+        assert!(!iss.check(&ip, &answer.replace(':', ""), now));
+        // Another pool's token, and this pool after a restart
+        assert!(!Issuer::new(8, "other").check(&ip, &answer, now));
+        assert!(Issuer::new(8, "secret").check(&ip, &answer, now));
+        assert!(solve(&iss.issue(&ip, now), MAX_BITS + 1).is_err());
+        // Each challenge has its own session nonce
+        assert_ne!(iss.issue(&ip, now), iss.issue(&ip, now));
+    }
+}
@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! How far the local clock is from the pool's, taken from the Date header of the
+//! replies to the requests which poll the pool. A clock which is far off is warned about
+//! once each time it goes out of bounds, because it makes the pool's logs and ours hard
+//! to compare and gets blamed for rejected shares.
+use crate::util;
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::UNIX_EPOCH;
+
+// More than this either way is warned about
+pub const SKEW_WARN_MS: i64 = 10_000;
+
+// Replies which took longer than this say too little about when they were sent
+const MAX_ROUND_TRIP_MS: u64 = 5_000;
+
+static SKEW_MS: AtomicI64 = AtomicI64::new(0);
+static SKEWED: AtomicBool = AtomicBool::new(false);
+
+/// Pool's clock minus ours, in milliseconds, 0 until there has been a reply
+pub fn skew_ms() -> i64 {
+    SKEW_MS.load(Ordering::Relaxed)
+}
+
+/// The time according to the pool
+pub fn pool_now_ms() -> u64 {
+    (util::now_ms() as i64 + skew_ms()) as u64
+}
+
+/// If the clock is off by more than SKEW_WARN_MS, something to say about it
+pub fn describe_skew() -> Option<String> {
+    let skew = skew_ms();
+    if skew.abs() <= SKEW_WARN_MS {
+        return None;
+    }
+    Some(format!(
+        "local clock is {:.1}s {} the pool",
+        skew.abs() as f64 / 1000.0,
+        if skew > 0 { "behind" } else { "ahead of" }
+    ))
+}
+
+// The Date header only has seconds, so the pool's time is taken to be half way through
+// the second and ours to be half way through the request
+fn estimate(pool_sec: u64, sent_ms: u64, received_ms: u64) -> i64 {
+    (pool_sec * 1000 + 500) as i64 - ((sent_ms + received_ms) / 2) as i64
+}
+
+/// Take the skew from a reply to a request which was sent at sent_ms
+pub fn observe(res: &reqwest::Response, sent_ms: u64) {
+    let received_ms = util::now_ms();
+    if received_ms < sent_ms || received_ms - sent_ms > MAX_ROUND_TRIP_MS {
+        return;
+    }
+    let pool_sec = match res
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|d| d.to_str().ok())
+        .and_then(|d| httpdate::parse_http_date(d).ok())
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+    {
+        Some(d) => d.as_secs(),
+        None => return,
+    };
+    let skew = estimate(pool_sec, sent_ms, received_ms);
+    SKEW_MS.store(skew, Ordering::Relaxed);
+    let skewed = skew.abs() > SKEW_WARN_MS;
+    if SKEWED.swap(skewed, Ordering::Relaxed) == skewed {
+        return;
+    }
+    match describe_skew() {
+        Some(d) => warn!("The {}, please check that NTP is running", d),
+        None => info!("Local clock is within {}ms of the pool again", skew.abs()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn estimate() {
+        // Pool says 100s, we sent at 99.9s and got the reply at 100.1s
+        assert_eq!(super::estimate(100, 99_900, 100_100), 500);
+        // Our clock is a minute ahead
+        assert_eq!(super::estimate(100, 159_900, 160_100), -59_500);
+    }
+}
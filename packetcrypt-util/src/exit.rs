@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! The kinds of error which stop packetcrypt and the exit code for each, so that
+//! scripts and orchestrators can tell a bad config, which will never work, from a pool
+//! which is down or a machine which is short of memory.
+//!
+//! A kind is attached to an error with `.context(Fatal::Config)` where it is known
+//! what went wrong, and `code()` finds it again at the top.
+//!
+//! | code | meaning                                              |
+//! |------|------------------------------------------------------|
+//! | 0    | exited normally                                      |
+//! | 1    | any other error, including bad command line usage    |
+//! | 69   | the pool could not be reached                        |
+//! | 70   | the C library failed                                 |
+//! | 71   | memory could not be allocated or locked              |
+//! | 78   | the configuration file or arguments are not valid    |
+//! | 252  | exit requested with SIGUSR2                          |
+//!
+//! The numbers are from sysexits.h.
+use std::fmt;
+
+pub const OTHER: i32 = 1;
+pub const SIGUSR2: i32 = 252;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fatal {
+    Config,
+    PoolUnreachable,
+    CLib,
+    Memory,
+}
+
+impl Fatal {
+    pub fn code(self) -> i32 {
+        match self {
+            Fatal::PoolUnreachable => 69,
+            Fatal::CLib => 70,
+            Fatal::Memory => 71,
+            Fatal::Config => 78,
+        }
+    }
+}
+
+impl fmt::Display for Fatal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Fatal::Config => "Invalid configuration",
+            Fatal::PoolUnreachable => "Pool unreachable",
+            Fatal::CLib => "C library failure",
+            Fatal::Memory => "Out of memory",
+        })
+    }
+}
+
+impl std::error::Error for Fatal {}
+
+/// The exit code for an error, OTHER if no kind was attached to it
+pub fn code(e: &anyhow::Error) -> i32 {
+    e.downcast_ref::<Fatal>().map_or(OTHER, |f| f.code())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fatal;
+    use anyhow::{anyhow, Context, Result};
+
+    #[test]
+    fn code_through_context() {
+        let r: Result<()> = Err(anyhow!("no such file"))
+            .context(Fatal::Config)
+            .context("Failed to read config file [pool.toml]");
+        assert_eq!(super::code(&r.unwrap_err()), 78);
+        assert_eq!(super::code(&anyhow!("something else")), super::OTHER);
+    }
+}
@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! What a miner has done, kept on the machine so that it can be looked back on with
+//! `packetcrypt stats` without asking the pool. A miner started with --state-dir gets
+//! an identity the first time it runs, which stays the same across restarts, and
+//! appends one line per hour to history.ndjson in that directory.
+use crate::telemetry::Counters;
+use crate::util;
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use tokio::io::AsyncWriteExt;
+
+const RECORD_EVERY_MS: u64 = 60 * 60 * 1000;
+
+// Hashrate is averaged over samples taken this often
+const SAMPLE_EVERY_MS: u64 = 60 * 1000;
+
+const HISTORY_FILE: &str = "history.ndjson";
+const IDENTITY_FILE: &str = "identity";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    // Seconds since the epoch at the end of the hour
+    pub time: u64,
+    pub identity: String,
+    // "ann" or "blk"
+    pub kind: String,
+    pub pool: String,
+    // Average encryptions per second
+    pub hashrate: f64,
+    // Anns for the ann miner and shares for the block miner, during the hour
+    pub accepted: usize,
+    pub rejected: usize,
+    pub errors: usize,
+    // Estimated PKT earned during the hour, only known by the block miner
+    pub earnings: f64,
+}
+
+/// The identity of the miner which keeps its state in dir, made on first use
+pub async fn identity(dir: &str) -> Result<String> {
+    util::ensure_exists_dir(dir).await?;
+    let path = format!("{}/{}", dir, IDENTITY_FILE);
+    if let Ok(id) = tokio::fs::read_to_string(&path).await {
+        let id = id.trim();
+        if !id.is_empty() {
+            return Ok(id.to_owned());
+        }
+    }
+    let id = (0..4)
+        .map(|_| format!("{:08x}", util::rand_u32()))
+        .collect::<String>();
+    tokio::fs::write(&path, format!("{}\n", id))
+        .await
+        .with_context(|| format!("Unable to write miner identity [{}]", path))?;
+    info!("New miner identity {} in [{}]", id, path);
+    Ok(id)
+}
+
+async fn append(dir: &str, rec: &Record) -> Result<()> {
+    let mut line = serde_json::to_string(rec)?;
+    line.push('\n');
+    let mut f = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(format!("{}/{}", dir, HISTORY_FILE))
+        .await?;
+    f.write_all(line.as_bytes()).await?;
+    f.sync_all().await?;
+    Ok(())
+}
+
+/// Write a Record every hour from the counters, which are totals since the miner started
+pub async fn record_loop(dir: &str, kind: &str, pool: &str, c: &Counters) {
+    let identity = match identity(dir).await {
+        Ok(id) => id,
+        Err(e) => {
+            warn!("Not keeping history: {:?}", e);
+            return;
+        }
+    };
+    let (mut accepted, mut rejected, mut errors, mut earnings) = (0, 0, 0, 0.0);
+    loop {
+        let mut hashrate_sum = 0.0;
+        let samples = RECORD_EVERY_MS / SAMPLE_EVERY_MS;
+        for _ in 0..samples {
+            util::sleep_ms(SAMPLE_EVERY_MS).await;
+            hashrate_sum += c.hashrate();
+        }
+        let rec = Record {
+            time: util::now_ms() / 1000,
+            identity: identity.clone(),
+            kind: kind.to_owned(),
+            pool: pool.to_owned(),
+            hashrate: hashrate_sum / samples as f64,
+            accepted: c.accepted.load(Ordering::Relaxed) - accepted,
+            rejected: c.rejected.load(Ordering::Relaxed) - rejected,
+            errors: c.errors.load(Ordering::Relaxed) - errors,
+            earnings: c.earnings() - earnings,
+        };
+        accepted += rec.accepted;
+        rejected += rec.rejected;
+        errors += rec.errors;
+        earnings += rec.earnings;
+        if let Err(e) = append(dir, &rec).await {
+            warn!("Unable to write history to [{}]: {}", dir, e);
+        }
+    }
+}
+
+/// Every record since since_sec
+pub async fn read(dir: &str, since_sec: u64) -> Result<Vec<Record>> {
+    let path = format!("{}/{}", dir, HISTORY_FILE);
+    let text = tokio::fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("Unable to read history [{}]", path))?;
+    let mut out = Vec::new();
+    for (line, n) in text.lines().zip(1..) {
+        match serde_json::from_str::<Record>(line) {
+            Ok(r) if r.time >= since_sec => out.push(r),
+            Ok(_) => (),
+            // The last line may be cut off by a crash
+            Err(e) => warn!("Skipping line {} of [{}]: {}", n, path, e),
+        }
+    }
+    Ok(out)
+}
+
+/// e.g. Tue, 13 Oct 2026 14:00:00 GMT
+pub fn format_time(sec: u64) -> String {
+    httpdate::fmt_http_date(std::time::UNIX_EPOCH + std::time::Duration::from_secs(sec))
+}
+
+/// 7d, 12h, 30m or a number of seconds
+pub fn parse_duration_sec(s: &str) -> Result<u64> {
+    let (num, mul) = match s.chars().last() {
+        Some('d') => (&s[..s.len() - 1], 24 * 60 * 60),
+        Some('h') => (&s[..s.len() - 1], 60 * 60),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('s') => (&s[..s.len() - 1], 1),
+        _ => (s, 1),
+    };
+    match num.parse::<u64>() {
+        Ok(n) => Ok(n * mul),
+        Err(_) => bail!("Invalid duration [{}], expecting e.g. 7d, 12h or 30m", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_duration_sec;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration_sec("7d").unwrap(), 7 * 86400);
+        assert_eq!(parse_duration_sec("12h").unwrap(), 12 * 3600);
+        assert_eq!(parse_duration_sec("90").unwrap(), 90);
+        assert!(parse_duration_sec("d").is_err());
+        assert!(parse_duration_sec("1w").is_err());
+    }
+}
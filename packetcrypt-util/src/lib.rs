@@ -7,7 +7,32 @@ macro_rules! async_spawn {
     }};
 }
 
+// Like async_spawn but the task is named and supervised, see tasks::spawn()
+#[macro_export]
+macro_rules! async_supervise {
+    ($name:expr, $restart:expr, $arc:ident, $blk:block) => {{
+        let $arc = Arc::clone($arc);
+        $crate::tasks::spawn($name, $restart, move || {
+            let $arc = Arc::clone(&$arc);
+            async move { $blk }
+        });
+    }};
+}
+
+pub mod alloc_audit;
+pub mod backoff;
+pub mod challenge;
+pub mod clock;
+pub mod exit;
 pub mod hash;
+pub mod history;
 pub mod poolclient;
 pub mod protocol;
+pub mod resolver;
+pub mod stats;
+pub mod tasks;
+pub mod telemetry;
 pub mod util;
+
+// Kept in a crate of its own so that the wasm build of packetcrypt-sys can use it too
+pub use packetcrypt_difficulty as difficulty;
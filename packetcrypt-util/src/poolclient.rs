@@ -1,6 +1,9 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
-use crate::protocol::{BlockInfo, MasterConf};
-use crate::util;
+use crate::backoff::Backoff;
+use crate::exit::Fatal;
+use crate::protocol::{BlockInfo, MasterConf, PoolFees};
+use crate::{difficulty, resolver, tasks, util};
+use anyhow::{bail, Context, Result};
 use log::{debug, error, info, warn};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -8,6 +11,22 @@ use tokio::sync::broadcast;
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::RwLock;
 
+// Waits between attempts to reach the pool when it is down
+const RETRY_MIN_MS: u64 = 5_000;
+const RETRY_MAX_MS: u64 = 120_000;
+// The pool can only be polled, so we poll more often when a block is due.
+// PKT's target time between blocks, used until some blocks have been seen
+const BLOCK_MS: u64 = 60_000;
+// How often to poll while the next block is due
+const FAST_POLL_MS: u64 = 500;
+// Blocks which are further than this in the future are refused, as in bitcoin
+const MAX_FUTURE_SECS: u64 = 2 * 60 * 60;
+// The pool's height can go back with a rollback, but not further than this
+const MAX_ROLLBACK: i32 = 100;
+// Unless it stays back for this many polls, then the pool was reset or the height we
+// had was wrong, and there is no other work to mine
+const ROLLBACK_ACCEPT_POLLS: u32 = 10;
+
 #[derive(Debug)]
 pub struct PoolClientM {
     mc: Option<MasterConf>,
@@ -21,10 +40,12 @@ pub struct PoolClientS {
     poll_seconds: u64,
     notify: broadcast::Sender<PoolUpdate>,
     history_depth: i32,
+    // Access token for private pools
+    pub token: Option<String>,
 }
 pub type PoolClient = Arc<PoolClientS>;
 
-pub fn new(url: &str, history_depth: i32, poll_seconds: u64) -> PoolClient {
+pub fn new(url: &str, history_depth: i32, poll_seconds: u64, token: Option<String>) -> PoolClient {
     let (tx, _) = broadcast::channel::<PoolUpdate>(32);
     Arc::new(PoolClientS {
         m: RwLock::new(PoolClientM {
@@ -35,6 +56,7 @@ pub fn new(url: &str, history_depth: i32, poll_seconds: u64) -> PoolClient {
         url: String::from(url),
         notify: tx,
         history_depth,
+        token,
     })
 }
 
@@ -44,6 +66,11 @@ pub struct PoolUpdate {
     pub update_blocks: Vec<BlockInfo>,
 }
 
+/// The most recent config from the pool, if any
+pub async fn conf(pcli: &PoolClient) -> Option<MasterConf> {
+    pcli.m.read().await.mc.clone()
+}
+
 pub async fn update_chan(pcli: &PoolClient) -> Receiver<PoolUpdate> {
     pcli.notify.subscribe()
 }
@@ -52,11 +79,44 @@ fn fmt_blk(hash: &[u8; 32], height: i32) -> String {
     format!("{} @ {}", hex::encode(&hash[..]), height)
 }
 
-async fn discover_block(pcli: &PoolClient, height: i32, hash: &[u8; 32]) -> Option<BlockInfo> {
+// Obviously bad block infos, so a broken pool master can't send us off mining garbage
+fn check_block(bi: &BlockInfo, height: i32, hash: &[u8; 32], now_sec: u64) -> Result<()> {
+    let h = &bi.header;
+    if &h.hash != hash {
+        bail!("it is block [{}]", fmt_blk(&h.hash, h.height));
+    }
+    if h.height != height {
+        bail!("its height is {}", h.height);
+    }
+    let bits = u32::from_be_bytes(h.bits);
+    if !difficulty::is_valid_compact(bits) {
+        bail!("its target {:08x} is not valid", bits);
+    }
+    if h.time == 0 || h.time as u64 > now_sec + MAX_FUTURE_SECS {
+        bail!(
+            "its time {} is not near the current time {}",
+            h.time,
+            now_sec
+        );
+    }
+    if height > 0 && (h.previousblockhash == [0; 32] || &h.previousblockhash == hash) {
+        bail!(
+            "its previous block [{}] is not valid",
+            hex::encode(h.previousblockhash)
+        );
+    }
+    Ok(())
+}
+
+async fn discover_block(
+    pcli: &PoolClient,
+    height: i32,
+    hash: &[u8; 32],
+) -> Result<Option<BlockInfo>> {
     if let Some(bi) = pcli.m.read().await.chain.get(&height) {
         if &bi.header.hash == hash {
             debug!("We already know about block [{}]", fmt_blk(hash, height));
-            return None;
+            return Ok(None);
         } else {
             // we have an entry for this block, but it is incorrect (rollback)
             info!(
@@ -69,14 +129,14 @@ async fn discover_block(pcli: &PoolClient, height: i32, hash: &[u8; 32]) -> Opti
         //debug!("New block [{}]", fmt_blk(&hash, height));
     }
     let url = format!("{}/blkinfo_{}.json", pcli.url, hex::encode(&hash[..]));
+    let mut backoff = Backoff::new(&url, RETRY_MIN_MS, RETRY_MAX_MS);
     loop {
-        let text = match util::get_url_text(&url).await {
+        let text = match util::get_url_text(&url, &pcli.token).await {
             Err(e) => {
-                warn!(
-                    "Failed to make request to {} because {:?} retry in 5 seconds",
-                    &url, e
-                );
-                util::sleep_ms(5000).await;
+                if !backoff.is_open() {
+                    warn!("Failed to make request to {} because {:?}", &url, e);
+                }
+                backoff.wait().await;
                 continue;
             }
             Ok(r) => r,
@@ -84,60 +144,314 @@ async fn discover_block(pcli: &PoolClient, height: i32, hash: &[u8; 32]) -> Opti
         let bi = match serde_json::from_str::<BlockInfo>(text.as_str()) {
             Err(e) => {
                 info!("Failed to deserialize block info {:?} {:?}", text, e);
-                util::sleep_ms(5000).await;
+                backoff.wait().await;
                 continue;
             }
             Ok(r) => r,
         };
+        check_block(&bi, height, hash, util::now_ms() / 1000).with_context(|| {
+            format!(
+                "Refusing work from pool, block info for [{}]",
+                fmt_blk(hash, height)
+            )
+        })?;
         info!(
             "Discovered block [{}]",
             fmt_blk(&bi.header.hash, bi.header.height)
         );
-        pcli.m.write().await.chain.insert(bi.header.height, bi);
-        return Some(bi);
+        return Ok(Some(bi));
     }
 }
 
 // This takes a newly discovered block and returns a vector of blocks which have
 // been changed. It calls the pool master iteratively in order to back-fill any
 // blocks which are incorrect and it updates the local state appropriately.
-async fn discover_blocks(pcli: &PoolClient, height: i32, hash: &[u8; 32]) -> Vec<BlockInfo> {
+// Nothing is stored unless every block of the backfill is good, so that a bad block
+// info part of the way back can't leave the chain half rolled back.
+async fn discover_blocks(
+    pcli: &PoolClient,
+    height: i32,
+    hash: &[u8; 32],
+) -> Result<Vec<BlockInfo>> {
     let mut out: Vec<BlockInfo> = Vec::new();
     let mut xhash = *hash;
     let mut xheight = height;
-    loop {
-        if let Some(bi) = discover_block(pcli, xheight, &xhash).await {
-            if bi.header.height <= height - pcli.history_depth {
-                // We've backfilled enough history
-                return out;
+    let deepest = loop {
+        match discover_block(pcli, xheight, &xhash).await? {
+            // We've backfilled enough history
+            Some(bi) if bi.header.height <= height - pcli.history_depth => break Some(bi),
+            Some(bi) => {
+                xhash = bi.header.previousblockhash;
+                xheight -= 1;
+                out.push(bi);
             }
-            xhash = bi.header.previousblockhash;
-            xheight -= 1;
-            out.push(bi);
+            None => break None,
+        }
+    };
+    let mut m = pcli.m.write().await;
+    for bi in out.iter().chain(deepest.iter()) {
+        m.chain.insert(bi.header.height, *bi);
+    }
+    Ok(out)
+}
+
+// Pools may publish their handlers in DNS as srv+http://... URLs, these are expanded
+// here so that everything downstream only ever sees a plain list of handler URLs.
+// If the pool gives no submitAnnWeights then the weights of the SRV records are used.
+async fn expand_handler_urls(conf: &mut MasterConf) -> Result<()> {
+    if !conf
+        .submit_ann_urls
+        .iter()
+        .chain(conf.download_ann_urls.iter())
+        .chain(conf.submit_block_urls.iter())
+        .any(|u| resolver::is_srv_url(u))
+    {
+        return Ok(());
+    }
+    let r = resolver::shared().await?;
+    let submit = resolver::expand_weighted_urls(&r, &conf.submit_ann_urls).await?;
+    if conf.submit_ann_weights.is_none()
+        && conf.submit_ann_urls.iter().all(|u| resolver::is_srv_url(u))
+        && submit.iter().any(|(_, w)| *w > 0)
+    {
+        conf.submit_ann_weights = Some(submit.iter().map(|(_, w)| *w).collect());
+    }
+    conf.submit_ann_urls = submit.into_iter().map(|(u, _)| u).collect();
+    conf.download_ann_urls = resolver::expand_urls(&r, &conf.download_ann_urls).await?;
+    conf.submit_block_urls = resolver::expand_urls(&r, &conf.submit_block_urls).await?;
+    Ok(())
+}
+
+fn is_valid_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://") || resolver::is_srv_url(url)
+}
+
+/// Problems with the conf, errors make it unusable while warnings are things which some
+/// parts of the miner will not work without. Everything is listed so that the pool
+/// operator can fix it all at once.
+pub fn check_conf(conf: &MasterConf) -> (Vec<String>, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    if conf.tip_hash.is_none() {
+        errors.push("tipHash is missing, this pool is too old to mine with".to_owned());
+    }
+    if conf.current_height <= 0 {
+        errors.push(format!(
+            "currentHeight {} is not valid",
+            conf.current_height
+        ));
+    }
+    for (name, urls) in &[
+        ("submitAnnUrls", &conf.submit_ann_urls),
+        ("downloadAnnUrls", &conf.download_ann_urls),
+        ("submitBlockUrls", &conf.submit_block_urls),
+    ] {
+        if urls.is_empty() {
+            warnings.push(format!("{} is empty", name));
+        }
+        for u in urls.iter().filter(|u| !is_valid_url(u)) {
+            errors.push(format!(
+                "{} contains [{}] which is not an http(s) url",
+                name, u
+            ));
+        }
+    }
+    match conf.ann_target {
+        None => warnings.push("annTarget is missing".to_owned()),
+        Some(t) if !difficulty::is_min_ann_diff_ok(t) => {
+            errors.push(format!("annTarget {:08x} is not a valid target", t))
+        }
+        _ => (),
+    }
+    if conf.ann_versions.is_empty() {
+        warnings.push("annVersions is empty".to_owned());
+    }
+    if let Some(w) = &conf.submit_ann_weights {
+        if w.len() != conf.submit_ann_urls.len() {
+            warnings.push(format!(
+                "submitAnnWeights has {} entries but there are {} submitAnnUrls, ignoring it",
+                w.len(),
+                conf.submit_ann_urls.len()
+            ));
+        } else if w.iter().all(|x| *x == 0) {
+            warnings.push("submitAnnWeights are all zero, ignoring it".to_owned());
+        }
+    }
+    if let Some(fee) = conf.pool_fee {
+        if !(0.0..=100.0).contains(&fee) {
+            warnings.push(format!("poolFee {} is not a percentage", fee));
+        }
+    }
+    if let Some(reward) = conf.block_reward {
+        if reward <= 0.0 {
+            warnings.push(format!("blockReward {} is not a number of PKT", reward));
+        }
+    }
+    if conf.payout_interval_seconds == Some(0) {
+        warnings.push("payoutIntervalSeconds is zero".to_owned());
+    }
+    (errors, warnings)
+}
+
+/// Parse and check a config.json
+pub fn parse_conf(text: &str) -> Result<MasterConf> {
+    let conf = serde_json::from_str::<MasterConf>(text).context("Invalid pool config")?;
+    let (errors, _) = check_conf(&conf);
+    if !errors.is_empty() {
+        bail!("Invalid pool config: {}", errors.join(", "));
+    }
+    Ok(conf)
+}
+
+/// The conf as it will be understood by the miners, for people to read
+pub fn describe_conf(conf: &MasterConf) -> String {
+    let mut out = Vec::new();
+    let opt = |x: Option<String>| x.unwrap_or_else(|| "(not given)".to_owned());
+    out.push(format!("Master URL:        {}", conf.master_url));
+    out.push(format!(
+        "Version:           {} (soft version {})",
+        conf.version, conf.soft_version
+    ));
+    out.push(format!(
+        "Mining height:     {} on {}",
+        conf.current_height,
+        opt(conf.tip_hash.map(hex::encode))
+    ));
+    out.push(format!(
+        "Ann target:        {}",
+        opt(conf.ann_target.map(|t| format!("{:08x}", t)))
+    ));
+    out.push(format!("Ann versions:      {:?}", conf.ann_versions));
+    out.push(format!("Mine old anns:     {} blocks", conf.mine_old_anns));
+    out.push(format!(
+        "Pool fee:          {}",
+        opt(conf.pool_fee.map(|f| format!("{}%", f)))
+    ));
+    out.push(format!(
+        "Block reward:      {}",
+        opt(conf.block_reward.map(|r| format!("{} PKT", r)))
+    ));
+    out.push(format!(
+        "Payout interval:   {}",
+        opt(conf
+            .payout_interval_seconds
+            .map(|s| format!("{} seconds", s)))
+    ));
+    out.push(format!(
+        "Protocols:         {}",
+        opt(conf.protocols.as_ref().map(|p| p.join(", ")))
+    ));
+    out.push(format!("Paymaker:          {}", conf.paymaker_url));
+    out.push(format!(
+        "Telemetry:         {}",
+        opt(conf.telemetry_url.clone())
+    ));
+    out.push(format!(
+        "Work signed by:    {}",
+        opt(conf.work_signing_key.map(hex::encode))
+    ));
+    let weights = conf.ann_handler_weights();
+    let total: u64 = weights.map_or(0, |w| w.iter().map(|x| *x as u64).sum());
+    out.push(format!("Ann handlers:      {}", conf.submit_ann_urls.len()));
+    for (i, u) in conf.submit_ann_urls.iter().enumerate() {
+        let share = if let Some(w) = weights {
+            w[i] as f64 * 100.0 / total as f64
         } else {
-            return out;
+            100.0 / conf.submit_ann_urls.len() as f64
         };
+        out.push(format!("  {:>6.2}%  {}", share, u));
+    }
+    out.push(format!(
+        "Ann downloads:     {}",
+        conf.download_ann_urls.len()
+    ));
+    for u in &conf.download_ann_urls {
+        out.push(format!("  {}", u));
+    }
+    out.push(format!(
+        "Block handlers:    {}",
+        conf.submit_block_urls.len()
+    ));
+    for u in &conf.submit_block_urls {
+        out.push(format!("  {}", u));
+    }
+    let (errors, warnings) = check_conf(conf);
+    for e in errors {
+        out.push(format!("ERROR: {}", e));
+    }
+    for w in warnings {
+        out.push(format!("WARNING: {}", w));
+    }
+    out.join("\n")
+}
+
+/// The fees on one line, for logging
+pub fn describe_fees(f: &PoolFees) -> String {
+    let opt = |x: Option<String>| x.unwrap_or_else(|| "(not given)".to_owned());
+    format!(
+        "fee {}, block reward {}, payout interval {}",
+        opt(f.pool_fee.map(|x| format!("{}%", x))),
+        opt(f.block_reward.map(|x| format!("{} PKT", x))),
+        opt(f.payout_interval_seconds.map(|x| format!("{}s", x)))
+    )
+}
+
+// Logged when first seen and warned about when they change, so that a pool which
+// quietly raises its fee does not go unnoticed
+fn log_fees(old: Option<PoolFees>, new: &PoolFees) {
+    match old {
+        None => info!("Pool {}", describe_fees(new)),
+        Some(old) if old != *new => warn!(
+            "Pool changed its {} to {}",
+            describe_fees(&old),
+            describe_fees(new)
+        ),
+        _ => (),
+    }
+}
+
+/// Get the config of the pool at this url, with srv+ handler urls expanded
+pub async fn fetch_conf(pool_url: &str, token: &Option<String>) -> Result<MasterConf> {
+    let url = format!("{}/config.json", pool_url);
+    let text = util::get_url_text(&url, token)
+        .await
+        .with_context(|| format!("Failed to make request to {}", url))
+        .context(Fatal::PoolUnreachable)?;
+    let mut conf = parse_conf(&text).with_context(|| format!("From {}", url))?;
+    expand_handler_urls(&mut conf)
+        .await
+        .context("Failed to resolve handler URLs")?;
+    Ok(conf)
+}
+
+// Slowly right after a block because another is unlikely so soon, quickly from a bit
+// before the next one is due, and back to normal if it is very late.
+fn poll_ms(base_ms: u64, since_block_ms: u64, block_ms: u64) -> u64 {
+    if since_block_ms < block_ms / 2 {
+        base_ms * 2
+    } else if since_block_ms < block_ms * 3 / 4 || since_block_ms > block_ms * 2 {
+        base_ms
+    } else {
+        base_ms.min(FAST_POLL_MS)
     }
 }
 
 async fn cfg_loop(pcli: &PoolClient) {
+    let mut backoff = Backoff::new(&pcli.url, RETRY_MIN_MS, RETRY_MAX_MS);
+    let mut height = 0;
+    // When the current block was seen, unknown for the block which is current at startup
+    let mut block_time_ms = None;
+    // Moving average of the time between blocks
+    let mut block_ms = BLOCK_MS;
+    // Polls in a row which the pool's height was too far back
+    let mut rolled_back_polls = 0;
     loop {
-        let url = format!("{}/config.json", pcli.url);
-        let text = match util::get_url_text(&url).await {
+        let conf = match fetch_conf(&pcli.url, &pcli.token).await {
             Err(e) => {
-                warn!(
-                    "Failed to make request to {} because {:?} retry in 5 seconds",
-                    &url, e
-                );
-                util::sleep_ms(5000).await;
-                continue;
-            }
-            Ok(r) => r,
-        };
-        let conf = match serde_json::from_str::<MasterConf>(text.as_str()) {
-            Err(e) => {
-                info!("Failed to deserialize master conf {:?} {:?}", text, e);
-                util::sleep_ms(5000).await;
+                if !backoff.is_open() {
+                    warn!("{:#}, retrying", e);
+                }
+                backoff.wait().await;
                 continue;
             }
             Ok(r) => r,
@@ -146,9 +460,43 @@ async fn cfg_loop(pcli: &PoolClient) {
             tip_hash
         } else {
             error!("Pool missing tipHash, this pool is too old to mine with");
-            util::sleep_ms(5000).await;
+            backoff.wait().await;
             continue;
         };
+        if conf.current_height < height - MAX_ROLLBACK {
+            rolled_back_polls += 1;
+            if rolled_back_polls < ROLLBACK_ACCEPT_POLLS {
+                if !backoff.is_open() {
+                    error!(
+                        "Refusing work from pool, its height went back from {} to {}",
+                        height, conf.current_height
+                    );
+                }
+                backoff.wait().await;
+                continue;
+            }
+            warn!(
+                "Pool height stayed back at {} from {} for {} polls, accepting it",
+                conf.current_height, height, rolled_back_polls
+            );
+            height = conf.current_height;
+            block_time_ms = None;
+        }
+        rolled_back_polls = 0;
+        backoff.success();
+        if conf.current_height > height {
+            let now = util::now_ms();
+            if let (Some(t), true) = (block_time_ms, conf.current_height == height + 1) {
+                let took = now.saturating_sub(t);
+                block_ms = ((block_ms * 7 + took) / 8)
+                    .max(BLOCK_MS / 6)
+                    .min(BLOCK_MS * 10);
+            }
+            if height > 0 {
+                block_time_ms = Some(now);
+            }
+            height = conf.current_height;
+        }
         if {
             let pcr = pcli.m.read().await;
             if let Some(mcx) = &pcr.mc {
@@ -159,10 +507,24 @@ async fn cfg_loop(pcli: &PoolClient) {
                 } else {
                     info!("Change of master config");
                 }
+                for w in check_conf(&conf).1 {
+                    warn!("Pool config: {}", w);
+                }
                 true
             }
         } {
-            let update_blocks = discover_blocks(pcli, conf.current_height - 1, &tip_hash).await;
+            let old_fees = pcli.m.read().await.mc.as_ref().map(|mc| mc.fees());
+            log_fees(old_fees, &conf.fees());
+            let update_blocks =
+                match discover_blocks(pcli, conf.current_height - 1, &tip_hash).await {
+                    Ok(b) => b,
+                    Err(e) => {
+                        // Keep mining the last work until the pool sends good work
+                        error!("{:#}", e);
+                        backoff.wait().await;
+                        continue;
+                    }
+                };
             let mut pc = pcli.m.write().await;
             pc.mc = Some(conf.clone());
             if let Err(_) = pcli.notify.send(PoolUpdate {
@@ -172,12 +534,66 @@ async fn cfg_loop(pcli: &PoolClient) {
                 info!("Failed to send conf update to channel");
             }
         }
-        util::sleep_ms(1_000 * pcli.poll_seconds).await;
+        let base_ms = 1_000 * pcli.poll_seconds;
+        let wait = match block_time_ms {
+            Some(t) => poll_ms(base_ms, util::now_ms().saturating_sub(t), block_ms),
+            None => base_ms,
+        };
+        util::sleep_ms(wait).await;
     }
 }
 
 pub async fn start(pcli: &PoolClient) {
-    async_spawn!(pcli, {
-        cfg_loop(&pcli).await;
-    });
+    async_supervise!(
+        format!("poolclient {}", pcli.url),
+        tasks::Restart::Always,
+        pcli,
+        {
+            cfg_loop(&pcli).await;
+        }
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_block, MAX_FUTURE_SECS};
+    use crate::protocol::BlockInfo;
+
+    const NOW: u64 = 1_600_000_000;
+
+    fn good() -> BlockInfo {
+        let mut bi = BlockInfo::default();
+        bi.header.hash = [1; 32];
+        bi.header.height = 10;
+        bi.header.bits = 0x1d00ffff_u32.to_be_bytes();
+        bi.header.time = NOW as u32;
+        bi.header.previousblockhash = [2; 32];
+        bi
+    }
+
+    #[test]
+    fn test_check_block() {
+        assert!(check_block(&good(), 10, &[1; 32], NOW).is_ok());
+        assert!(check_block(&good(), 10, &[3; 32], NOW).is_err());
+        assert!(check_block(&good(), 11, &[1; 32], NOW).is_err());
+
+        let mut bi = good();
+        bi.header.bits = 0x1d80ffff_u32.to_be_bytes();
+        assert!(check_block(&bi, 10, &[1; 32], NOW).is_err());
+
+        let mut bi = good();
+        bi.header.time = (NOW + MAX_FUTURE_SECS + 1) as u32;
+        assert!(check_block(&bi, 10, &[1; 32], NOW).is_err());
+        bi.header.time = 0;
+        assert!(check_block(&bi, 10, &[1; 32], NOW).is_err());
+
+        let mut bi = good();
+        bi.header.previousblockhash = [1; 32];
+        assert!(check_block(&bi, 10, &[1; 32], NOW).is_err());
+        bi.header.previousblockhash = [0; 32];
+        assert!(check_block(&bi, 10, &[1; 32], NOW).is_err());
+        // Only the genesis block has no previous block
+        bi.header.height = 0;
+        assert!(check_block(&bi, 0, &[1; 32], NOW).is_ok());
+    }
 }
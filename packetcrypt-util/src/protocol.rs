@@ -73,17 +73,30 @@ pub struct PaymakerReply {
     pub result: Option<PaymakerResult>,
 }
 
+// What became of each ann of an upload, see AnnPostReply::ann_results
+pub const ANN_OK: u8 = 0;
+pub const ANN_DUP: u8 = 1;
+pub const ANN_BAD_POW: u8 = 2;
+pub const ANN_STALE_PARENT: u8 = 3;
+pub const ANN_UNFIT_WORK: u8 = 4;
+// Rejected along with the rest of the upload, the reason is in the error
+pub const ANN_REJECTED: u8 = 5;
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct AnnPostReply {
     pub warn: Vec<String>,
     pub error: Vec<String>,
     pub result: Option<AnnsEvent>,
+    // One ANN_ code for each ann, in the order they were uploaded,
+    // empty if the upload could not be parsed or the handler is older
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ann_results: Vec<u8>,
 }
 
-#[derive(Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct MasterConf {
-    #[serde(with = "SerHexOpt::<Strict>")]
+    #[serde(with = "SerHexOpt::<Strict>", default)]
     pub tip_hash: Option<[u8; 32]>,
 
     pub current_height: i32,
@@ -97,6 +110,66 @@ pub struct MasterConf {
     pub ann_versions: Vec<u8>,
     pub mine_old_anns: u32,
     pub ann_target: Option<u32>,
+    // Relative capacity of each of the submit_ann_urls, if missing they get equal shares
+    pub submit_ann_weights: Option<Vec<u32>>,
+    // Ways of getting anns from the pool other than http, e.g. "sprayer"
+    pub protocols: Option<Vec<String>>,
+    // Percent of the block reward which the pool keeps
+    pub pool_fee: Option<f64>,
+    // Block reward in PKT which the pool pays out from, before the fee
+    pub block_reward: Option<f64>,
+    // How often the pool pays its miners
+    pub payout_interval_seconds: Option<u64>,
+    // Where miners which are run with --telemetry post their stats
+    pub telemetry_url: Option<String>,
+    // ed25519 key of the pool, if set then work_<height>.bin is signed by a detached
+    // signature in work_<height>.sig
+    #[serde(with = "SerHexOpt::<Strict>", default)]
+    pub work_signing_key: Option<[u8; 32]>,
+}
+
+/// What the pool says that it charges and pays, so pools can be compared
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolFees {
+    pub pool_fee: Option<f64>,
+    pub block_reward: Option<f64>,
+    pub payout_interval_seconds: Option<u64>,
+}
+
+impl MasterConf {
+    pub fn fees(&self) -> PoolFees {
+        PoolFees {
+            pool_fee: self.pool_fee,
+            block_reward: self.block_reward,
+            payout_interval_seconds: self.payout_interval_seconds,
+        }
+    }
+
+    /// The weights of the ann handlers, if the pool gave usable ones
+    pub fn ann_handler_weights(&self) -> Option<&[u32]> {
+        match &self.submit_ann_weights {
+            Some(w) if w.len() == self.submit_ann_urls.len() && w.iter().any(|x| *x > 0) => {
+                Some(&w[..])
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Which ann handler an ann belongs to when the handlers are weighted, num is the same
+/// number which would otherwise be taken modulo the number of handlers. Every handler
+/// gets a range of (num % total weight) in the order that they are listed.
+pub fn weighted_ann_handler(num: u64, weights: &[u32]) -> usize {
+    let total: u64 = weights.iter().map(|w| *w as u64).sum();
+    let mut slot = num % total;
+    for (i, w) in weights.iter().enumerate() {
+        if slot < *w as u64 {
+            return i;
+        }
+        slot -= *w as u64;
+    }
+    unreachable!()
 }
 
 #[derive(Debug, Clone, Default)]
@@ -144,6 +217,10 @@ pub struct BlockInfoHeader {
 #[serde(rename_all = "camelCase")]
 pub struct AnnIndex {
     pub highest_ann_file: i64,
+    // Oldest file which the handler still has, so a miner asking for the files since
+    // one it had can tell if some are gone
+    #[serde(default)]
+    pub lowest_ann_file: Option<i64>,
     pub files: Vec<String>,
 }
 
@@ -199,6 +276,29 @@ pub fn work_decode(out: &mut Work, b: &mut Bytes) -> Result<()> {
     Ok(())
 }
 
+pub fn blockheader_encode(h: &BlockHeader, b: &mut BytesMut) {
+    b.put_u32_le(h.version);
+    b.put(&h.hash_prev_block[..]);
+    b.put(&h.hash_merkle_root[..]);
+    b.put_i32_le(h.time_seconds);
+    b.put_u32_le(h.work_bits);
+    b.put_u32_le(h.nonce);
+}
+
+/// The reverse of work_decode()
+pub fn work_encode(w: &Work, b: &mut BytesMut) {
+    blockheader_encode(&w.header, b);
+    b.put(&w.signing_key[..]);
+    b.put_u32_le(w.share_target);
+    b.put_u32_le(w.ann_target);
+    b.put_i32_le(w.height);
+    b.put_u32_le(w.coinbase_no_witness.len() as u32);
+    b.put(&w.coinbase_no_witness[..]);
+    for h in &w.coinbase_merkle {
+        b.put(&h[..]);
+    }
+}
+
 pub fn put_varint(num: u64, b: &mut BytesMut) {
     if num <= 0xfc {
         b.put_u8(num as u8);
@@ -214,6 +314,56 @@ pub fn put_varint(num: u64, b: &mut BytesMut) {
     }
 }
 
+pub fn get_varint(b: &mut Bytes) -> Result<u64> {
+    if b.remaining() < 1 {
+        bail!("runt varint");
+    }
+    let len = match b.get_u8() {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        x => return Ok(x as u64),
+    };
+    if b.remaining() < len {
+        bail!("runt varint");
+    }
+    Ok(b.get_uint_le(len))
+}
+
+/// Total value of all outputs of a serialized transaction without witness data,
+/// for the coinbase this is the block reward plus fees.
+pub fn tx_output_value(tx: &Bytes) -> Result<u64> {
+    let mut b = tx.clone();
+    if b.remaining() < 4 {
+        bail!("runt tx");
+    }
+    b.advance(4); // version
+    for _ in 0..get_varint(&mut b)? {
+        if b.remaining() < 36 {
+            bail!("runt tx input");
+        }
+        b.advance(36); // previous output
+        let script_len = get_varint(&mut b)? as usize;
+        if b.remaining() < script_len + 4 {
+            bail!("runt tx input");
+        }
+        b.advance(script_len + 4); // script + sequence
+    }
+    let mut value = 0_u64;
+    for _ in 0..get_varint(&mut b)? {
+        if b.remaining() < 8 {
+            bail!("runt tx output");
+        }
+        value = value.saturating_add(b.get_u64_le());
+        let script_len = get_varint(&mut b)? as usize;
+        if b.remaining() < script_len {
+            bail!("runt tx output");
+        }
+        b.advance(script_len);
+    }
+    Ok(value)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct BlkShare {
     #[serde(with = "SerHexSeq::<Strict>")]
@@ -230,4 +380,49 @@ pub struct SprayerReq {
     // deprecated, nolonger used
     pub num: Option<u32>,
     pub count: Option<u32>,
+
+    // Total packets which the subscriber has received from us, used for pacing
+    pub packets_received: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+
+    #[test]
+    fn work_encode() {
+        let w = super::Work {
+            header: super::BlockHeader {
+                version: 1,
+                time_seconds: 1600000000,
+                ..Default::default()
+            },
+            signing_key: [7u8; 32],
+            share_target: 0x1e00ffff,
+            height: 1234,
+            coinbase_no_witness: Bytes::from_static(b"coinbase"),
+            coinbase_merkle: vec![Bytes::from(vec![3u8; 32])],
+            ..Default::default()
+        };
+        let mut b = BytesMut::new();
+        super::work_encode(&w, &mut b);
+        let mut out = super::Work::default();
+        super::work_decode(&mut out, &mut b.freeze()).unwrap();
+        assert_eq!(out.header.time_seconds, w.header.time_seconds);
+        assert_eq!(out.signing_key, w.signing_key);
+        assert_eq!(out.share_target, w.share_target);
+        assert_eq!(out.height, w.height);
+        assert_eq!(out.coinbase_no_witness, w.coinbase_no_witness);
+        assert_eq!(out.coinbase_merkle, w.coinbase_merkle);
+    }
+
+    #[test]
+    fn weighted_ann_handler() {
+        let w = [7, 0, 3];
+        let counts = (0..100).fold([0; 3], |mut c, n| {
+            c[super::weighted_ann_handler(n, &w)] += 1;
+            c
+        });
+        assert_eq!(counts, [70, 0, 30]);
+    }
 }
@@ -0,0 +1,375 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use crate::exit::Fatal;
+use anyhow::{bail, format_err, Context, Result};
+use log::debug;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
+
+// Never cache anything for less than this, even if the record says so, otherwise
+// a TTL of zero would cause us to hit the DNS server on every lookup.
+const MIN_TTL: Duration = Duration::from_secs(5);
+
+// A URL of the form srv+http://_pktann._tcp.pool.example/submit is expanded into one
+// URL per SRV record, e.g. http://ah0.pool.example:8080/submit
+const SRV_PREFIX: &str = "srv+";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvTarget {
+    pub host: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+struct CacheEnt<T> {
+    valid_until: Instant,
+    val: T,
+}
+
+struct ResolverM {
+    hosts: HashMap<String, CacheEnt<Vec<SocketAddr>>>,
+    srv: HashMap<String, CacheEnt<Vec<SrvTarget>>>,
+}
+
+pub struct ResolverS {
+    dns: TokioAsyncResolver,
+    m: Mutex<ResolverM>,
+}
+pub type Resolver = Arc<ResolverS>;
+
+static SHARED: Mutex<Option<Resolver>> = Mutex::new(None);
+
+// Set by set_doh(), otherwise the system's DNS is used
+static DOH: OnceCell<ResolverConfig> = OnceCell::new();
+
+// Pinned so that finding the resolver doesn't need DNS
+const DOH_PRESETS: &[(&str, &str)] = &[
+    (
+        "cloudflare",
+        "1.1.1.1#cloudflare-dns.com,1.0.0.1#cloudflare-dns.com",
+    ),
+    ("google", "8.8.8.8#dns.google,8.8.4.4#dns.google"),
+    (
+        "quad9",
+        "9.9.9.9#dns.quad9.net,149.112.112.112#dns.quad9.net",
+    ),
+];
+
+// 1.1.1.1#cloudflare-dns.com or [2606:4700::1111]:443#cloudflare-dns.com
+fn parse_doh(spec: &str) -> Result<(IpAddr, u16, &str)> {
+    let i = if let Some(i) = spec.find('#') {
+        i
+    } else {
+        bail!(
+            "DoH resolver [{}] needs the name on its certificate, e.g. 1.1.1.1#cloudflare-dns.com",
+            spec
+        );
+    };
+    let (addr, name) = (&spec[..i], &spec[(i + 1)..]);
+    if name.is_empty() {
+        bail!("DoH resolver [{}] has an empty certificate name", spec);
+    }
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        return Ok((ip, 443, name));
+    }
+    let (host, port) = split_host_port(addr)?;
+    let ip = host
+        .parse::<IpAddr>()
+        .map_err(|_| format_err!("DoH resolver [{}] must be an IP address", spec))?;
+    Ok((ip, port, name))
+}
+
+/// Resolve names with DNS-over-HTTPS instead of the system's DNS, for networks where DNS
+/// is hijacked. Each resolver is an IP, optional port and the name on its certificate,
+/// e.g. 1.1.1.1#cloudflare-dns.com, or one of cloudflare, google or quad9. This must be
+/// called before the first lookup.
+pub fn set_doh(resolvers: &[&str]) -> Result<()> {
+    let mut group = NameServerConfigGroup::new();
+    for r in resolvers {
+        let spec = DOH_PRESETS
+            .iter()
+            .find(|(n, _)| n == r)
+            .map(|(_, s)| *s)
+            .unwrap_or(*r);
+        for one in spec.split(',') {
+            let (ip, port, name) = parse_doh(one).context(Fatal::Config)?;
+            group.merge(NameServerConfigGroup::from_ips_https(
+                &[ip],
+                port,
+                name.to_owned(),
+            ));
+        }
+    }
+    DOH.set(ResolverConfig::from_parts(None, Vec::new(), group))
+        .map_err(|_| format_err!("DoH is already set"))
+}
+
+pub fn doh_enabled() -> bool {
+    DOH.get().is_some()
+}
+
+fn get_cached<T: Clone>(cache: &HashMap<String, CacheEnt<T>>, name: &str) -> Option<T> {
+    match cache.get(name) {
+        Some(ent) if ent.valid_until > Instant::now() => Some(ent.val.clone()),
+        _ => None,
+    }
+}
+
+fn put_cached<T>(
+    cache: &mut HashMap<String, CacheEnt<T>>,
+    name: &str,
+    valid_until: Instant,
+    val: T,
+) {
+    let min_valid = Instant::now() + MIN_TTL;
+    let valid_until = if valid_until < min_valid {
+        min_valid
+    } else {
+        valid_until
+    };
+    cache.insert(name.to_owned(), CacheEnt { valid_until, val });
+}
+
+pub async fn new() -> Result<Resolver> {
+    let dns = if let Some(conf) = DOH.get() {
+        TokioAsyncResolver::tokio(conf.clone(), ResolverOpts::default()).await
+    } else {
+        TokioAsyncResolver::tokio_from_system_conf().await
+    }
+    .map_err(|e| format_err!("Unable to setup DNS resolver: {}", e))?;
+    Ok(Arc::new(ResolverS {
+        dns,
+        m: Mutex::new(ResolverM {
+            hosts: HashMap::new(),
+            srv: HashMap::new(),
+        }),
+    }))
+}
+
+/// Get the process-wide resolver, creating it on first use.
+pub async fn shared() -> Result<Resolver> {
+    if let Some(r) = &*SHARED.lock().unwrap() {
+        return Ok(Arc::clone(r));
+    }
+    let r = new().await?;
+    let mut shared_l = SHARED.lock().unwrap();
+    if let Some(r) = &*shared_l {
+        // Someone else created it while we were waiting
+        return Ok(Arc::clone(r));
+    }
+    shared_l.replace(Arc::clone(&r));
+    Ok(r)
+}
+
+fn split_host_port(host_port: &str) -> Result<(&str, u16)> {
+    let i = if let Some(i) = host_port.rfind(':') {
+        i
+    } else {
+        bail!("Address [{}] is missing a port number", host_port);
+    };
+    let port = host_port[(i + 1)..]
+        .parse::<u16>()
+        .map_err(|_| format_err!("Address [{}] has an invalid port number", host_port))?;
+    let host = host_port[..i].trim_start_matches('[').trim_end_matches(']');
+    Ok((host, port))
+}
+
+/// Resolve a host:port string, literal IP addresses are returned without a lookup.
+pub async fn lookup_host(r: &Resolver, host_port: &str) -> Result<Vec<SocketAddr>> {
+    if let Ok(sa) = host_port.parse::<SocketAddr>() {
+        return Ok(vec![sa]);
+    }
+    if let Some(addrs) = get_cached(&r.m.lock().unwrap().hosts, host_port) {
+        return Ok(addrs);
+    }
+    let (host, port) = split_host_port(host_port)?;
+    let res = r
+        .dns
+        .lookup_ip(host)
+        .await
+        .map_err(|e| format_err!("Unable to resolve [{}]: {}", host, e))?;
+    let addrs = res
+        .iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        bail!("No addresses found for [{}]", host);
+    }
+    debug!("Resolved [{}] -> {:?}", host_port, addrs);
+    put_cached(
+        &mut r.m.lock().unwrap().hosts,
+        host_port,
+        res.valid_until(),
+        addrs.clone(),
+    );
+    Ok(addrs)
+}
+
+/// Lookup SRV records for a name such as _pktann._tcp.pool.example
+/// The result is sorted by priority and then by host and port so that everyone
+/// who resolves the same name sees the targets in the same order.
+pub async fn lookup_srv(r: &Resolver, name: &str) -> Result<Vec<SrvTarget>> {
+    if let Some(targets) = get_cached(&r.m.lock().unwrap().srv, name) {
+        return Ok(targets);
+    }
+    let res = r
+        .dns
+        .srv_lookup(name)
+        .await
+        .map_err(|e| format_err!("Unable to lookup SRV [{}]: {}", name, e))?;
+    let mut targets = res
+        .iter()
+        .map(|srv| SrvTarget {
+            host: srv.target().to_utf8().trim_end_matches('.').to_owned(),
+            port: srv.port(),
+            priority: srv.priority(),
+            weight: srv.weight(),
+        })
+        .collect::<Vec<_>>();
+    if targets.is_empty() {
+        bail!("No SRV records found for [{}]", name);
+    }
+    targets.sort_by(|a, b| {
+        a.priority
+            .cmp(&b.priority)
+            .then_with(|| a.host.cmp(&b.host))
+            .then_with(|| a.port.cmp(&b.port))
+    });
+    debug!("Resolved SRV [{}] -> {:?}", name, targets);
+    put_cached(
+        &mut r.m.lock().unwrap().srv,
+        name,
+        res.valid_until(),
+        targets.clone(),
+    );
+    Ok(targets)
+}
+
+// srv+http://_pktann._tcp.pool.example/submit -> ("http", "_pktann._tcp.pool.example", "/submit")
+fn split_srv_url(url: &str) -> Option<(&str, &str, &str)> {
+    let rest = url.strip_prefix(SRV_PREFIX)?;
+    let i = rest.find("://")?;
+    let (scheme, after) = (&rest[..i], &rest[(i + 3)..]);
+    let (name, path) = match after.find('/') {
+        Some(j) => (&after[..j], &after[j..]),
+        None => (after, ""),
+    };
+    if scheme.is_empty() || name.is_empty() {
+        None
+    } else {
+        Some((scheme, name, path))
+    }
+}
+
+pub fn is_srv_url(url: &str) -> bool {
+    url.starts_with(SRV_PREFIX)
+}
+
+// Only the targets with the lowest priority are used, the others are backups which
+// RFC 2782 says to use only when none of those can be reached.
+fn primary_targets(targets: &[SrvTarget]) -> Vec<&SrvTarget> {
+    let best = targets.iter().map(|t| t.priority).min();
+    targets
+        .iter()
+        .filter(|t| Some(t.priority) == best)
+        .collect()
+}
+
+/// Expand any srv+ URLs in the list into one URL per SRV target of the lowest priority,
+/// along with the weight of that target. Other URLs are passed through unchanged with
+/// a weight of 1. Lookups are cached for the TTL of the record so calling this on every
+/// conf update re-resolves the names only when they expire.
+pub async fn expand_weighted_urls(r: &Resolver, urls: &[String]) -> Result<Vec<(String, u32)>> {
+    let mut out = Vec::with_capacity(urls.len());
+    for url in urls {
+        if !is_srv_url(url) {
+            out.push((url.clone(), 1));
+            continue;
+        }
+        let (scheme, name, path) = if let Some(x) = split_srv_url(url) {
+            x
+        } else {
+            bail!("Malformed SRV URL [{}]", url);
+        };
+        for t in primary_targets(&lookup_srv(r, name).await?) {
+            out.push((
+                format!("{}://{}:{}{}", scheme, t.host, t.port, path),
+                t.weight as u32,
+            ));
+        }
+    }
+    Ok(out)
+}
+
+/// Same as expand_weighted_urls() but without the weights.
+pub async fn expand_urls(r: &Resolver, urls: &[String]) -> Result<Vec<String>> {
+    Ok(expand_weighted_urls(r, urls)
+        .await?
+        .into_iter()
+        .map(|(u, _)| u)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_doh, primary_targets, split_host_port, split_srv_url, SrvTarget};
+
+    #[test]
+    fn test_primary_targets() {
+        let t = |host: &str, priority: u16| SrvTarget {
+            host: host.to_owned(),
+            port: 80,
+            priority,
+            weight: 1,
+        };
+        let targets = [t("a", 10), t("b", 10), t("c", 20)];
+        let hosts = primary_targets(&targets)
+            .iter()
+            .map(|t| &t.host[..])
+            .collect::<Vec<_>>();
+        assert_eq!(hosts, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_split_srv_url() {
+        assert_eq!(
+            split_srv_url("srv+http://_pktann._tcp.pool.example/submit"),
+            Some(("http", "_pktann._tcp.pool.example", "/submit"))
+        );
+        assert_eq!(
+            split_srv_url("srv+https://_pktann._tcp.pool.example"),
+            Some(("https", "_pktann._tcp.pool.example", ""))
+        );
+        assert_eq!(split_srv_url("http://pool.example/submit"), None);
+        assert_eq!(split_srv_url("srv+pool.example"), None);
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(
+            split_host_port("pool.example:6666").unwrap(),
+            ("pool.example", 6666)
+        );
+        assert_eq!(split_host_port("[::1]:80").unwrap(), ("::1", 80));
+        assert!(split_host_port("pool.example").is_err());
+        assert!(split_host_port("pool.example:99999").is_err());
+    }
+
+    #[test]
+    fn test_parse_doh() {
+        let (ip, port, name) = parse_doh("1.1.1.1#cloudflare-dns.com").unwrap();
+        assert_eq!(
+            (ip.to_string().as_str(), port, name),
+            ("1.1.1.1", 443, "cloudflare-dns.com")
+        );
+        let (ip, port, _) = parse_doh("[2606:4700::1111]:8443#cloudflare-dns.com").unwrap();
+        assert_eq!((ip.to_string().as_str(), port), ("2606:4700::1111", 8443));
+        assert!(parse_doh("1.1.1.1").is_err());
+        assert!(parse_doh("dns.google#dns.google").is_err());
+    }
+}
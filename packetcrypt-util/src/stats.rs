@@ -0,0 +1,279 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! The stats of every part of packetcrypt in one form, so that one dashboard can show
+//! ann miners, block miners, sprayers and handlers alike. Each part implements Stats
+//! and registers itself, then start() serves all of the metrics as JSON at /stats.json
+//! and for Prometheus at /metrics, and logs them on one line every minute at debug
+//! level. Counters are totals since the process started, metric names are
+//! packetcrypt_<component>_<name> wherever they are shown.
+use crate::util;
+use anyhow::Result;
+use log::{debug, info};
+use serde_json::{json, Map, Value as Json};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use warp::Filter;
+
+const LOG_EVERY_MS: u64 = 60_000;
+
+/// A total which only goes up, which can also be taken from for log lines which show
+/// what changed since the last one
+#[derive(Default)]
+pub struct Counter {
+    total: AtomicU64,
+    taken: AtomicU64,
+}
+impl Counter {
+    pub fn add(&self, n: usize) {
+        self.total.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+    /// How much was added since the last take
+    pub fn take(&self) -> usize {
+        let total = self.get();
+        total.saturating_sub(self.taken.fetch_max(total, Ordering::Relaxed)) as usize
+    }
+}
+
+pub enum Value {
+    Counter(u64),
+    Gauge(f64),
+}
+
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: Value,
+}
+impl Metric {
+    pub fn counter(name: &'static str, help: &'static str, v: u64) -> Metric {
+        Metric {
+            name,
+            help,
+            labels: Vec::new(),
+            value: Value::Counter(v),
+        }
+    }
+    pub fn gauge(name: &'static str, help: &'static str, v: f64) -> Metric {
+        Metric {
+            name,
+            help,
+            labels: Vec::new(),
+            value: Value::Gauge(v),
+        }
+    }
+    pub fn label(mut self, name: &'static str, value: impl ToString) -> Metric {
+        self.labels.push((name, value.to_string()));
+        self
+    }
+    fn num(&self) -> f64 {
+        match self.value {
+            Value::Counter(v) => v as f64,
+            Value::Gauge(v) => v,
+        }
+    }
+}
+
+pub trait Stats: Send + Sync {
+    /// e.g. "annmine", the same for every instance of the part
+    fn component(&self) -> &'static str;
+    /// The metrics as they are now
+    fn metrics(&self) -> Vec<Metric>;
+}
+
+// Weak so that a part which is dropped stops being reported
+static SOURCES: Mutex<Vec<Weak<dyn Stats>>> = Mutex::new(Vec::new());
+
+pub fn register<T: Stats + 'static>(s: &Arc<T>) {
+    let w: Weak<dyn Stats> = Arc::downgrade(s);
+    SOURCES.lock().unwrap().push(w);
+}
+
+// Sorted by name within each component so each metric's lines are together
+fn collect() -> Vec<(&'static str, Vec<Metric>)> {
+    let sources = {
+        let mut s = SOURCES.lock().unwrap();
+        s.retain(|w| w.strong_count() > 0);
+        s.iter().filter_map(|w| w.upgrade()).collect::<Vec<_>>()
+    };
+    sources
+        .iter()
+        .map(|s| {
+            let mut m = s.metrics();
+            m.sort_by_key(|m| m.name);
+            (s.component(), m)
+        })
+        .collect()
+}
+
+fn full_name(component: &str, m: &Metric) -> String {
+    format!("packetcrypt_{}_{}", component, m.name)
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn with_labels(name: String, m: &Metric) -> String {
+    if m.labels.is_empty() {
+        return name;
+    }
+    let labels = m
+        .labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect::<Vec<_>>();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+fn prometheus_of(parts: &[(&'static str, Vec<Metric>)]) -> String {
+    let mut out = String::new();
+    let mut described = HashSet::new();
+    for (component, metrics) in parts {
+        for m in metrics {
+            let name = full_name(component, m);
+            if described.insert(name.clone()) {
+                let kind = match m.value {
+                    Value::Counter(_) => "counter",
+                    Value::Gauge(_) => "gauge",
+                };
+                out += &format!("# HELP {} {}\n# TYPE {} {}\n", name, m.help, name, kind);
+            }
+            out += &format!("{} {}\n", with_labels(name, m), m.num());
+        }
+    }
+    out
+}
+
+fn json_of(parts: &[(&'static str, Vec<Metric>)]) -> Json {
+    let mut out = Map::new();
+    for (component, metrics) in parts {
+        let list = out
+            .entry(component.to_string())
+            .or_insert_with(|| json!([]))
+            .as_array_mut()
+            .unwrap();
+        for m in metrics {
+            let labels = m
+                .labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), json!(v)))
+                .collect::<Map<_, _>>();
+            list.push(json!({
+                "name": full_name(component, m),
+                "labels": labels,
+                "value": m.num(),
+            }));
+        }
+    }
+    Json::Object(out)
+}
+
+fn log_line_of(parts: &[(&'static str, Vec<Metric>)]) -> String {
+    parts
+        .iter()
+        .flat_map(|(component, metrics)| {
+            metrics
+                .iter()
+                .map(move |m| format!("{}={}", with_labels(full_name(component, m), m), m.num()))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Everything which is registered, in the Prometheus text format
+pub fn prometheus() -> String {
+    prometheus_of(&collect())
+}
+
+/// Everything which is registered, as {"<component>": [{"name", "labels", "value"}]}
+pub fn to_json() -> Json {
+    json_of(&collect())
+}
+
+/// Everything which is registered, on one line
+pub fn log_line() -> String {
+    log_line_of(&collect())
+}
+
+async fn log_loop() {
+    loop {
+        util::sleep_ms(LOG_EVERY_MS).await;
+        debug!("stats: {}", log_line());
+    }
+}
+
+/// Log the stats every minute, and serve them on bind unless it is empty
+pub fn start(bind: &str) -> Result<()> {
+    tokio::spawn(log_loop());
+    if bind.is_empty() {
+        return Ok(());
+    }
+    let addr: SocketAddr = bind.parse()?;
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .map(|| {
+            warp::reply::with_header(prometheus(), "content-type", "text/plain; version=0.0.4")
+        });
+    let json = warp::get()
+        .and(warp::path("stats.json"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&to_json()));
+    info!("Serving stats on http://{}/metrics and /stats.json", addr);
+    tokio::spawn(async move { warp::serve(metrics.or(json)).run(addr).await });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts() -> Vec<(&'static str, Vec<Metric>)> {
+        vec![(
+            "annmine",
+            vec![
+                Metric::counter("anns_total", "Anns", 5).label("pool", "http://a"),
+                Metric::counter("anns_total", "Anns", 7).label("pool", "http://\"b\""),
+                Metric::gauge("hashrate", "Encryptions per second", 1.5),
+            ],
+        )]
+    }
+
+    #[test]
+    fn formats() {
+        let p = prometheus_of(&parts());
+        assert_eq!(
+            p.matches("# TYPE packetcrypt_annmine_anns_total counter")
+                .count(),
+            1
+        );
+        assert!(p.contains("packetcrypt_annmine_anns_total{pool=\"http://\\\"b\\\"\"} 7\n"));
+        assert!(p.contains(
+            "# TYPE packetcrypt_annmine_hashrate gauge\npacketcrypt_annmine_hashrate 1.5\n"
+        ));
+        let j = json_of(&parts());
+        assert_eq!(j["annmine"][1]["labels"]["pool"], "http://\"b\"");
+        assert_eq!(j["annmine"][2]["value"], 1.5);
+        assert!(log_line_of(&parts())
+            .starts_with("packetcrypt_annmine_anns_total{pool=\"http://a\"}=5 "));
+    }
+
+    #[test]
+    fn counter() {
+        let c = Counter::default();
+        c.add(3);
+        c.add(4);
+        assert_eq!(c.take(), 7);
+        c.add(1);
+        assert_eq!(c.take(), 1);
+        assert_eq!(c.take(), 0);
+        assert_eq!(c.get(), 8);
+    }
+}
@@ -0,0 +1,163 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use crate::util;
+use log::{error, info, warn};
+use std::future::Future;
+use std::sync::Mutex;
+
+// Wait this long before restarting a task, doubling each time it fails again
+// quickly, up to RESTART_MAX_DELAY_MS.
+const RESTART_DELAY_MS: u64 = 1_000;
+const RESTART_MAX_DELAY_MS: u64 = 60_000;
+
+// If a task ran for this long before failing, it is not considered a fast failure
+// and the restart delay goes back to RESTART_DELAY_MS.
+const STABLE_MS: u64 = 5 * 60_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Restart {
+    // Run once, whatever happens
+    Never,
+    // Restart if it panics, but let it exit normally
+    OnPanic,
+    // This should run forever, restart it if it panics or exits
+    Always,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Restarting,
+    Panicked,
+}
+
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: String,
+    pub restart: Restart,
+    pub state: TaskState,
+    pub restarts: u64,
+    pub started_ms: u64,
+    pub last_error: Option<String>,
+}
+
+struct Tasks {
+    next_id: u64,
+    tasks: Vec<TaskInfo>,
+}
+
+static TASKS: Mutex<Tasks> = Mutex::new(Tasks {
+    next_id: 0,
+    tasks: Vec::new(),
+});
+
+fn update(id: u64, f: impl FnOnce(&mut TaskInfo)) {
+    if let Some(t) = TASKS.lock().unwrap().tasks.iter_mut().find(|t| t.id == id) {
+        f(t);
+    }
+}
+
+// Tasks which exited normally and will not be restarted are forgotten, tasks which
+// panicked are kept so that they show up in the dump.
+fn remove(id: u64) {
+    TASKS.lock().unwrap().tasks.retain(|t| t.id != id);
+}
+
+/// Spawn a named task which shows up in the task table, mk_task is called to create
+/// the future each time the task is (re)started.
+pub fn spawn<F, Fut>(name: impl Into<String>, restart: Restart, mk_task: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let name = name.into();
+    let id = {
+        let mut t = TASKS.lock().unwrap();
+        let id = t.next_id;
+        t.next_id += 1;
+        t.tasks.push(TaskInfo {
+            id,
+            name: name.clone(),
+            restart,
+            state: TaskState::Running,
+            restarts: 0,
+            started_ms: util::now_ms(),
+            last_error: None,
+        });
+        id
+    };
+    tokio::spawn(async move {
+        let mut delay_ms = RESTART_DELAY_MS;
+        loop {
+            let started_ms = util::now_ms();
+            update(id, |t| {
+                t.state = TaskState::Running;
+                t.started_ms = started_ms;
+            });
+            let err = match tokio::spawn(mk_task()).await {
+                Ok(()) if restart == Restart::Always => {
+                    warn!("Task [{}] exited, restarting", name);
+                    "exited".to_owned()
+                }
+                Ok(()) => {
+                    remove(id);
+                    return;
+                }
+                Err(e) if e.is_panic() => {
+                    error!("Task [{}] panicked: {}", name, e);
+                    if restart == Restart::Never {
+                        update(id, |t| {
+                            t.state = TaskState::Panicked;
+                            t.last_error = Some(e.to_string());
+                        });
+                        return;
+                    }
+                    e.to_string()
+                }
+                Err(e) => {
+                    // Cancelled, the runtime is shutting down
+                    info!("Task [{}] cancelled: {}", name, e);
+                    remove(id);
+                    return;
+                }
+            };
+            if util::now_ms() - started_ms > STABLE_MS {
+                delay_ms = RESTART_DELAY_MS;
+            }
+            update(id, |t| {
+                t.state = TaskState::Restarting;
+                t.restarts += 1;
+                t.last_error = Some(err);
+            });
+            util::sleep_ms(delay_ms).await;
+            delay_ms = std::cmp::min(delay_ms * 2, RESTART_MAX_DELAY_MS);
+        }
+    });
+}
+
+pub fn list() -> Vec<TaskInfo> {
+    TASKS.lock().unwrap().tasks.clone()
+}
+
+/// Log the task table, this is triggered by SIGUSR1
+pub fn dump() {
+    let now = util::now_ms();
+    let tasks = list();
+    info!("{} supervised tasks:", tasks.len());
+    for t in tasks {
+        info!(
+            "  {} {} {:?} ({:?}) up {}s restarts {}{}",
+            t.id,
+            t.name,
+            t.state,
+            t.restart,
+            (now - t.started_ms) / 1000,
+            t.restarts,
+            if let Some(e) = &t.last_error {
+                format!(" last error: {}", e)
+            } else {
+                String::new()
+            }
+        );
+    }
+}
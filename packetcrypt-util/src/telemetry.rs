@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Opt-in reports from miners to the pool, so that pool operators can help people whose
+//! miners are underperforming. Nothing is sent unless the miner is started with
+//! --telemetry, and then only to pools which publish a telemetryUrl in their config.
+use crate::poolclient::{self, PoolClient};
+use crate::util;
+use anyhow::{bail, format_err, Result};
+use log::{debug, info};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+const REPORT_EVERY_MS: u64 = 5 * 60 * 1000;
+
+static VERSION: OnceCell<String> = OnceCell::new();
+
+/// Turn on telemetry, version is the packetcrypt version which is reported
+pub fn enable(version: &str) -> Result<()> {
+    VERSION
+        .set(version.to_owned())
+        .map_err(|_| format_err!("Telemetry is already enabled"))
+}
+
+pub fn enabled() -> bool {
+    VERSION.get().is_some()
+}
+
+/// Totals since the miner started, accepted and rejected are anns for the ann miner
+/// and shares for the block miner.
+#[derive(Default)]
+pub struct Counters {
+    pub accepted: AtomicUsize,
+    pub rejected: AtomicUsize,
+    pub errors: AtomicUsize,
+    // Encryptions per second, as the bits of an f64
+    hashrate: AtomicU64,
+    // Estimated PKT earned, as the bits of an f64, not reported to the pool
+    earnings: AtomicU64,
+}
+
+impl Counters {
+    pub fn set_hashrate(&self, eps: f64) {
+        self.hashrate.store(eps.to_bits(), Ordering::Relaxed);
+    }
+    pub fn hashrate(&self) -> f64 {
+        f64::from_bits(self.hashrate.load(Ordering::Relaxed))
+    }
+    pub fn set_earnings(&self, pkt: f64) {
+        self.earnings.store(pkt.to_bits(), Ordering::Relaxed);
+    }
+    pub fn earnings(&self) -> f64 {
+        f64::from_bits(self.earnings.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Report<'a> {
+    // "ann" or "blk"
+    pub kind: &'a str,
+    pub version: &'a str,
+    pub pay_to: &'a str,
+    pub hashrate: f64,
+    pub accepted: usize,
+    pub rejected: usize,
+    pub errors: usize,
+    pub uptime_sec: u64,
+    pub time: u64,
+}
+
+async fn send(pcli: &PoolClient, report: &Report<'_>) -> Result<()> {
+    let url = if let Some(url) = poolclient::conf(pcli).await.and_then(|c| c.telemetry_url) {
+        url
+    } else {
+        debug!("Pool [{}] does not take telemetry", pcli.url);
+        return Ok(());
+    };
+    let client = util::client_builder()
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let req = util::request(&client, reqwest::Method::POST, &url).await?;
+    let res = util::with_token(req, &pcli.token)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(report)?)
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        bail!("[{}] replied [{}]", url, res.status());
+    }
+    Ok(())
+}
+
+/// Report to the pool every few minutes, only start this if telemetry is enabled
+pub async fn report_loop(pcli: &PoolClient, kind: &str, pay_to: &str, c: &Counters) {
+    let version = VERSION.get().map(|v| &v[..]).unwrap_or("");
+    info!("Telemetry enabled for [{}]", pcli.url);
+    let time_started_ms = util::now_ms();
+    loop {
+        util::sleep_ms(REPORT_EVERY_MS).await;
+        let now = util::now_ms();
+        let report = Report {
+            kind,
+            version,
+            pay_to,
+            hashrate: c.hashrate(),
+            accepted: c.accepted.load(Ordering::Relaxed),
+            rejected: c.rejected.load(Ordering::Relaxed),
+            errors: c.errors.load(Ordering::Relaxed),
+            uptime_sec: (now - time_started_ms) / 1000,
+            time: now,
+        };
+        if let Err(e) = send(pcli, &report).await {
+            debug!("Unable to send telemetry to [{}]: {}", pcli.url, e);
+        }
+    }
+}
@@ -1,8 +1,12 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
-use anyhow::{format_err, Result};
+use crate::clock;
+use crate::exit::Fatal;
+use crate::resolver;
+use anyhow::{format_err, Context, Result};
 use bytes::buf::BufMut;
 use crossbeam_channel::Sender as SenderCB;
 use log::{error, info, trace, warn, LevelFilter};
+use once_cell::sync::OnceCell;
 use regex::Regex;
 use std::env;
 use std::io::Write;
@@ -36,13 +40,98 @@ pub fn now_ms() -> u64 {
         .as_millis() as u64
 }
 
+static PROXY: OnceCell<reqwest::Proxy> = OnceCell::new();
+static CLIENT_CERT: OnceCell<Vec<u8>> = OnceCell::new();
+
+/// Send all requests to the pool through a proxy, e.g. socks5h://127.0.0.1:9050 for Tor,
+/// this must be called before any clients are made.
+pub fn set_proxy(url: &str) -> Result<()> {
+    let proxy = reqwest::Proxy::all(url)
+        .with_context(|| format!("Invalid proxy [{}]", url))
+        .context(Fatal::Config)?;
+    PROXY
+        .set(proxy)
+        .map_err(|_| format_err!("Proxy is already set"))
+}
+
+/// Present this client certificate to the pool, for pools which only accept known rigs.
+/// The file is PEM with the certificate and the private key, this must be called before
+/// any clients are made.
+pub fn set_client_cert(path: &str) -> Result<()> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("Unable to read client certificate [{}]", path))
+        .context(Fatal::Config)?;
+    reqwest::Identity::from_pem(&pem)
+        .with_context(|| format!("Invalid client certificate [{}]", path))
+        .context(Fatal::Config)?;
+    CLIENT_CERT
+        .set(pem)
+        .map_err(|_| format_err!("Client certificate is already set"))
+}
+
+/// Clients which talk to the pool should be made with this so that they use the proxy
+/// and client certificate.
+pub fn client_builder() -> reqwest::ClientBuilder {
+    let mut b = reqwest::Client::builder();
+    if let Some(p) = PROXY.get() {
+        b = b.proxy(p.clone());
+    }
+    // It was checked by set_client_cert()
+    if let Some(id) = CLIENT_CERT
+        .get()
+        .and_then(|pem| reqwest::Identity::from_pem(pem).ok())
+    {
+        b = b.identity(id);
+    }
+    b
+}
+
+/// A request to url which, if DoH is set, goes to the address that DoH gives for the host.
+/// Only http urls are changed, https needs the name to check the certificate so those
+/// are still resolved by the system. With a proxy the url is never changed, so that a
+/// socks5h proxy resolves the name itself.
+pub async fn request(
+    client: &reqwest::Client,
+    method: reqwest::Method,
+    url: &str,
+) -> Result<reqwest::RequestBuilder> {
+    if !resolver::doh_enabled() || PROXY.get().is_some() {
+        return Ok(client.request(method, url));
+    }
+    let mut u = reqwest::Url::parse(url).with_context(|| format!("Invalid url [{}]", url))?;
+    let host = match u.host_str() {
+        Some(h) if u.scheme() == "http" && h.parse::<std::net::IpAddr>().is_err() => h.to_owned(),
+        _ => {
+            if u.scheme() == "https" {
+                trace!("Not using DoH for [{}], it is https", url);
+            }
+            return Ok(client.request(method, url));
+        }
+    };
+    let port = u.port_or_known_default().unwrap_or(80);
+    let addrs =
+        resolver::lookup_host(&resolver::shared().await?, &format!("{}:{}", host, port)).await?;
+    let host_hdr = match u.port() {
+        Some(p) => format!("{}:{}", host, p),
+        None => host,
+    };
+    u.set_ip_host(addrs[0].ip())
+        .map_err(|_| format_err!("Unable to set address of [{}]", url))?;
+    Ok(client
+        .request(method, u)
+        .header(reqwest::header::HOST, host_hdr))
+}
+
 pub async fn get_url_bin2(
     url: &str,
     ignore_statuses: &[u16],
     client: &reqwest::Client,
 ) -> Result<Option<bytes::Bytes>> {
     loop {
-        let res = client.get(url).send().await?;
+        let res = request(client, reqwest::Method::GET, url)
+            .await?
+            .send()
+            .await?;
         return match res.status() {
             reqwest::StatusCode::OK => Ok(Some(res.bytes().await?)),
             reqwest::StatusCode::MULTIPLE_CHOICES => {
@@ -60,12 +149,23 @@ pub async fn get_url_bin2(
 }
 
 pub async fn get_url_bin1(url: &str, ignore_statuses: &[u16]) -> Result<Option<bytes::Bytes>> {
-    get_url_bin2(url, ignore_statuses, &reqwest::Client::builder().build()?).await
+    get_url_bin2(url, ignore_statuses, &client_builder().build()?).await
+}
+
+/// Private pools may require a token to access their endpoints, it is sent as a bearer token.
+pub fn with_token(req: reqwest::RequestBuilder, token: &Option<String>) -> reqwest::RequestBuilder {
+    if let Some(t) = token {
+        req.bearer_auth(t)
+    } else {
+        req
+    }
 }
 
-pub async fn get_url_bin(url: &str) -> Result<bytes::Bytes> {
+pub async fn get_url_bin(url: &str, token: &Option<String>) -> Result<bytes::Bytes> {
+    let client = client_builder().build()?;
     loop {
-        let res = reqwest::get(url).await?;
+        let req = request(&client, reqwest::Method::GET, url).await?;
+        let res = with_token(req, token).send().await?;
         return match res.status() {
             reqwest::StatusCode::OK => Ok(res.bytes().await?),
             reqwest::StatusCode::MULTIPLE_CHOICES => {
@@ -76,8 +176,11 @@ pub async fn get_url_bin(url: &str) -> Result<bytes::Bytes> {
     }
 }
 
-pub async fn get_url_text(url: &str) -> Result<String> {
-    let res = reqwest::get(url).await?;
+pub async fn get_url_text(url: &str, token: &Option<String>) -> Result<String> {
+    let sent_ms = now_ms();
+    let req = request(&client_builder().build()?, reqwest::Method::GET, url).await?;
+    let res = with_token(req, token).send().await?;
+    clock::observe(&res, sent_ms);
     match res.status() {
         reqwest::StatusCode::OK => Ok(res.text().await?),
         st => Err(format_err!("Status code was {:?}", st)),
@@ -237,6 +340,61 @@ pub async fn setup_env(verbosity: u64) -> Result<()> {
         process::exit(1);
     }));
 
+    let logger = LOGGER.get_or_init(|| ReloadableLogger {
+        verbosity,
+        filter: std::sync::Mutex::new(String::new()),
+        inner: std::sync::RwLock::new(mk_logger(verbosity, "")),
+    });
+    log::set_max_level(logger.inner.read().unwrap().filter());
+    log::set_logger(logger)?;
+
+    Ok(())
+}
+
+// The logger is swapped out whole when the filter changes, because an env_logger
+// filter cannot be changed once it is built.
+struct ReloadableLogger {
+    verbosity: u64,
+    filter: std::sync::Mutex<String>,
+    inner: std::sync::RwLock<env_logger::Logger>,
+}
+impl log::Log for ReloadableLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+    fn log(&self, record: &log::Record) {
+        self.inner.read().unwrap().log(record)
+    }
+    fn flush(&self) {
+        self.inner.read().unwrap().flush()
+    }
+}
+static LOGGER: OnceCell<ReloadableLogger> = OnceCell::new();
+
+/// Change log filtering while running, the filter has the same syntax as RUST_LOG
+/// (e.g. packetcrypt_blkmine::downloader=trace) and is applied on top of RUST_LOG.
+/// An empty filter goes back to what was set at startup.
+pub fn set_log_filter(filter: &str) -> Result<()> {
+    let logger = LOGGER
+        .get()
+        .ok_or_else(|| format_err!("Logging is not set up"))?;
+    let l = mk_logger(logger.verbosity, filter);
+    log::set_max_level(l.filter());
+    *logger.inner.write().unwrap() = l;
+    *logger.filter.lock().unwrap() = filter.to_owned();
+    info!("Log filter is now [{}]", filter);
+    Ok(())
+}
+
+/// The filter which was last passed to set_log_filter()
+pub fn log_filter() -> String {
+    LOGGER
+        .get()
+        .map(|l| l.filter.lock().unwrap().clone())
+        .unwrap_or_default()
+}
+
+fn mk_logger(verbosity: u64, filter: &str) -> env_logger::Logger {
     let rl = if let Ok(rl) = env::var("RUST_LOG") {
         rl
     } else {
@@ -271,15 +429,140 @@ pub async fn setup_env(verbosity: u64) -> Result<()> {
             },
         );
     }
-    log.init();
+    if !filter.is_empty() {
+        log.parse_filters(filter);
+    }
+    log.build()
+}
 
+/// Give the calling thread realtime (SCHED_FIFO) priority, from 1 to 99. This needs
+/// root or CAP_SYS_NICE and a thread spinning at this priority can lock up a core.
+pub fn set_realtime_priority(priority: i32) -> Result<()> {
+    use nix::libc;
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    let ret =
+        unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    if ret != 0 {
+        return Err(format_err!(
+            "Unable to set realtime priority {}: {}",
+            priority,
+            std::io::Error::from_raw_os_error(ret)
+        ));
+    }
     Ok(())
 }
 
+/// Keep the calling thread on these cores, numbered as in /proc/cpuinfo
+pub fn set_affinity(cores: &[usize]) -> Result<()> {
+    use nix::sched::{sched_setaffinity, CpuSet};
+    let mut set = CpuSet::new();
+    for c in cores {
+        set.set(*c)
+            .map_err(|e| format_err!("Invalid core {}: {}", c, e))?;
+    }
+    sched_setaffinity(nix::unistd::Pid::from_raw(0), &set)
+        .map_err(|e| format_err!("Unable to pin thread to cores {:?}: {}", cores, e))
+}
+
+/// A list of cores such as 0-3,8,10-11
+pub fn parse_cores(s: &str) -> Result<Vec<usize>> {
+    let mut out = Vec::new();
+    for part in s.split(',').filter(|p| !p.is_empty()) {
+        let mut ends = part.splitn(2, '-').map(|n| n.trim().parse::<usize>());
+        let res = match (ends.next(), ends.next()) {
+            (Some(Ok(a)), None) => Ok(a..=a),
+            (Some(Ok(a)), Some(Ok(b))) if a <= b => Ok(a..=b),
+            _ => Err(format_err!("Invalid list of cores [{}] at [{}]", s, part)),
+        };
+        out.extend(res?);
+    }
+    Ok(out)
+}
+
+/// 32 bytes written as 64 hex digits, e.g. a key
+pub fn parse_hex32(s: &str) -> Result<[u8; 32]> {
+    let mut out = [0u8; 32];
+    hex::decode_to_slice(s, &mut out)
+        .map_err(|e| format_err!("Invalid [{}], expecting 64 hex digits: {}", s, e))?;
+    Ok(out)
+}
+
+/// Keep memory in RAM so that touching it never waits for the disk. Limited by
+/// RLIMIT_MEMLOCK (ulimit -l) unless running as root.
+///
+/// # Safety
+/// ptr must point to at least len bytes.
+pub unsafe fn mlock(ptr: *const u8, len: usize) -> Result<()> {
+    nix::sys::mman::mlock(ptr as *const nix::libc::c_void, len)
+        .map_err(|e| format_err!("Unable to mlock {} bytes: {}", len, e))
+        .context(Fatal::Memory)
+}
+
+/// A file mapped read-only into memory, so that a big file can be read without copying
+/// it into a buffer first, pages are read from the disk as they are touched.
+pub struct MappedFile {
+    ptr: *mut nix::libc::c_void,
+    len: usize,
+}
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    pub fn open(path: &str) -> Result<MappedFile> {
+        use nix::sys::mman::{mmap, MapFlags, ProtFlags};
+        use std::os::unix::io::AsRawFd;
+        let file = std::fs::File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            // mmap() of nothing is EINVAL
+            return Ok(MappedFile {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        }
+        .map_err(|e| format_err!("Unable to mmap {}: {}", path, e))?;
+        Ok(MappedFile { ptr, len })
+    }
+    pub fn bytes(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            let _ = unsafe { nix::sys::mman::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
 pub fn is_zero(s: &[u8]) -> bool {
     s.iter().all(|x| *x == 0)
 }
 
+/// Compare a secret in a time which depends only on the lengths, so that how long a
+/// wrong guess takes to be refused says nothing about how much of it was right
+pub fn secret_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let diff = a.iter().zip(b.iter()).fold(0u8, |d, (x, y)| d | (x ^ y));
+    diff == 0 && a.len() == b.len()
+}
+
 pub async fn sleep_forever() -> ! {
     loop {
         sleep_ms(100_000_000).await;
@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Verification of PacketCrypt announcements and block proofs. This uses the pure
+//! Rust port in packetcrypt-sys so there is no C code and no async runtime, it can
+//! be built for wasm32-unknown-unknown and used by block explorers and web wallets.
+use std::convert::TryInto;
+
+pub use packetcrypt_sys::difficulty;
+pub use packetcrypt_sys::pure::{check_ann, check_block, work_check, ValidateCtx};
+pub use packetcrypt_sys::{hard_nonce, parent_block_height, work_bits};
+
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+
+const PC_TYPE_END: u64 = 0;
+const PC_TYPE_PROOF: u64 = 1;
+const PC_TYPE_VER: u64 = 4;
+
+const BLOCK_HDR_SZ: usize = 80;
+// low_nonce and 4 anns come before the PacketCryptProof
+const PROOF_PFX_SZ: usize = 4 + 1024 * 4;
+
+/// A block header and PacketCrypt proof, as they appear in a block or in a share
+/// submitted by the block miner.
+pub struct HeaderAndProof<'a> {
+    pub header: &'a [u8],
+    pub low_nonce: u32,
+    pub anns: Vec<&'a [u8]>,
+    pub proof: &'a [u8],
+    // Zero if there is no version entry
+    pub version: u64,
+}
+
+fn read_varint(b: &mut &[u8]) -> Option<u64> {
+    let (first, rest) = b.split_first()?;
+    let len = match first {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        x => {
+            *b = rest;
+            return Some(*x as u64);
+        }
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let mut num = [0_u8; 8];
+    num[..len].copy_from_slice(&rest[..len]);
+    *b = &rest[len..];
+    Some(u64::from_le_bytes(num))
+}
+
+/// Parse the block header and the PacketCrypt type-length-value entries which
+/// follow it, unknown entries are skipped.
+pub fn parse_header_and_proof(hap: &[u8]) -> Result<HeaderAndProof<'_>, String> {
+    if hap.len() < BLOCK_HDR_SZ {
+        return Err("header_and_proof too short".to_owned());
+    }
+    let (header, mut b) = hap.split_at(BLOCK_HDR_SZ);
+    let mut proof = None;
+    let mut version = 0;
+    while !b.is_empty() {
+        let t = read_varint(&mut b).ok_or("truncated entry type")?;
+        let len = read_varint(&mut b).ok_or("truncated entry length")? as usize;
+        if t == PC_TYPE_END {
+            break;
+        }
+        if b.len() < len {
+            return Err(format!("entry type {} length {} runs off the end", t, len));
+        }
+        let (val, rest) = b.split_at(len);
+        b = rest;
+        match t {
+            PC_TYPE_PROOF if proof.is_some() => return Err("duplicate proof entry".to_owned()),
+            PC_TYPE_PROOF => proof = Some(val),
+            PC_TYPE_VER => {
+                version = read_varint(&mut &val[..]).ok_or("invalid version entry")?;
+            }
+            _ => (),
+        }
+    }
+    let proof = proof.ok_or("missing proof entry")?;
+    if proof.len() < PROOF_PFX_SZ {
+        return Err("proof entry too short".to_owned());
+    }
+    Ok(HeaderAndProof {
+        header,
+        low_nonce: u32::from_le_bytes(proof[..4].try_into().unwrap()),
+        anns: proof[4..PROOF_PFX_SZ].chunks(1024).collect(),
+        proof: &proof[PROOF_PFX_SZ..],
+        version,
+    })
+}
+
+/// Check a block or share, returns the work hash. `header_and_proof` and
+/// `coinbase_commit` are the block header with its PacketCrypt entries and the
+/// commitment from the coinbase, the same as what the block miner submits as a
+/// share. `block_height` is the height of the block being mined and if
+/// `parent_block_hashes` is provided, each announcement is checked against the
+/// hash of the block at its parent_block_height.
+pub fn check_share(
+    header_and_proof: &[u8],
+    coinbase_commit: &[u8],
+    block_height: u32,
+    share_target: u32,
+    parent_block_hashes: Option<&[[u8; 32]; 4]>,
+) -> Result<[u8; 32], String> {
+    let hap = parse_header_and_proof(header_and_proof)?;
+    // Validate_checkBlock() wants the proof in memory layout, with padding
+    let mut buf = Vec::with_capacity(BLOCK_HDR_SZ + 8 + 1024 * 4 + hap.proof.len());
+    buf.extend_from_slice(hap.header);
+    buf.extend_from_slice(&0_u32.to_le_bytes());
+    buf.extend_from_slice(&hap.low_nonce.to_le_bytes());
+    for ann in hap.anns.iter() {
+        buf.extend_from_slice(ann);
+    }
+    buf.extend_from_slice(hap.proof);
+    let mut vctx = parent_block_hashes.map(|_| ValidateCtx::default());
+    let block_hashes = parent_block_hashes.zip(vctx.as_mut());
+    check_block(
+        &buf,
+        block_height,
+        share_target,
+        coinbase_commit,
+        block_hashes,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    fn compress32(b: &[u8]) -> [u8; 32] {
+        let h = blake2b_simd::Params::new().hash_length(32).hash(b);
+        h.as_bytes().try_into().unwrap()
+    }
+
+    fn entry(hash: &[u8], start: u64, end: u64) -> Vec<u8> {
+        let mut out = hash.to_vec();
+        out.extend_from_slice(&start.to_le_bytes());
+        out.extend_from_slice(&end.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn varint() {
+        for (bytes, num) in [
+            (&[0xfc][..], 0xfc),
+            (&[0xfd, 0x34, 0x12][..], 0x1234),
+            (&[0xfe, 0x78, 0x56, 0x34, 0x12][..], 0x1234_5678),
+            (&[0xff, 8, 7, 6, 5, 4, 3, 2, 1][..], 0x0102_0304_0506_0708),
+        ]
+        .iter()
+        {
+            let mut b = *bytes;
+            assert_eq!(super::read_varint(&mut b), Some(*num));
+            assert!(b.is_empty());
+            assert_eq!(super::read_varint(&mut &bytes[..bytes.len() - 1]), None);
+        }
+    }
+
+    #[test]
+    fn check_share() {
+        // One ann in the tree, used 4 times
+        let mut ann = [0x55_u8; 1024];
+        ann[8..12].copy_from_slice(&0x207fffff_u32.to_le_bytes());
+        let ann_hash = compress32(&ann);
+        let start = u64::from_le_bytes(ann_hash[..8].try_into().unwrap());
+        let mut pair = entry(&[0; 32], 0, start);
+        pair.extend_from_slice(&entry(&ann_hash, start, u64::MAX));
+        let root = compress32(&entry(&compress32(&pair), 0, u64::MAX));
+        let mut pcp = vec![0_u8; 32];
+        pcp.extend_from_slice(&(u64::MAX - start).to_le_bytes());
+
+        let mut coinbase = vec![0x09, 0xf9, 0x11, 0x02];
+        coinbase.extend_from_slice(&0x207fffff_u32.to_le_bytes());
+        coinbase.extend_from_slice(&root);
+        coinbase.extend_from_slice(&1_u64.to_le_bytes());
+
+        let mk_share = |low_nonce: u32| {
+            let mut hap = vec![0_u8; 80];
+            hap[72..76].copy_from_slice(&0x207fffff_u32.to_le_bytes());
+            hap.extend_from_slice(&[1, 0xfd]);
+            hap.extend_from_slice(&((4 + 4096 + pcp.len()) as u16).to_le_bytes());
+            hap.extend_from_slice(&low_nonce.to_le_bytes());
+            for _ in 0..4 {
+                hap.extend_from_slice(&ann);
+            }
+            hap.extend_from_slice(&pcp);
+            hap.extend_from_slice(&[4, 1, 2]);
+            hap
+        };
+
+        let hap = mk_share(0);
+        let parsed = super::parse_header_and_proof(&hap).unwrap();
+        assert_eq!(parsed.version, 2);
+        assert_eq!(parsed.anns.len(), 4);
+        assert_eq!(parsed.proof, &pcp[..]);
+
+        // The easiest target still needs about one in two hashes to be good
+        let hashes = (0..8)
+            .map(|n| super::check_share(&mk_share(n), &coinbase, 1, 0, None))
+            .collect::<Vec<_>>();
+        assert!(hashes.iter().any(|h| h.is_ok()));
+        for h in hashes {
+            if let Err(e) = h {
+                assert!(e.starts_with("INSUF_POW "));
+            }
+        }
+
+        let mut bad_coinbase = coinbase.clone();
+        bad_coinbase[8] ^= 1;
+        let res = super::check_share(&hap, &bad_coinbase, 1, 0, None);
+        assert_eq!(res, Err("PCP_MISMATCH".to_owned()));
+        let res = super::check_share(&hap[..hap.len() - 10], &coinbase, 1, 0, None);
+        assert!(res.is_err());
+        let res = super::check_share(&hap, &coinbase, 1, 0, Some(&[[0; 32]; 4]));
+        assert_eq!(res, Err("ANN_INVALID 0".to_owned()));
+    }
+}
@@ -0,0 +1,88 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! Plain C ABI for calling from JavaScript, build with
+//! `cargo build --release --target wasm32-unknown-unknown`.
+//!
+//! Buffers are allocated in wasm memory with pc_alloc() and released with pc_free().
+//! Each check writes the 32 byte hash to hash_out and returns 0, or it writes the
+//! error (utf-8, at most ERR_MAX bytes) to err_out and returns its length.
+use std::convert::TryInto;
+use std::slice;
+
+pub const ERR_MAX: usize = 64;
+
+unsafe fn result(res: Result<[u8; 32], String>, hash_out: *mut u8, err_out: *mut u8) -> usize {
+    match res {
+        Ok(h) => {
+            slice::from_raw_parts_mut(hash_out, 32).copy_from_slice(&h);
+            0
+        }
+        Err(e) => {
+            let len = std::cmp::min(e.len(), ERR_MAX);
+            slice::from_raw_parts_mut(err_out, len).copy_from_slice(&e.as_bytes()[..len]);
+            len
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pc_alloc(len: usize) -> *mut u8 {
+    let mut v = Vec::<u8>::with_capacity(len);
+    let ptr = v.as_mut_ptr();
+    std::mem::forget(v);
+    ptr
+}
+
+/// # Safety
+/// ptr must come from pc_alloc() with the same len
+#[no_mangle]
+pub unsafe extern "C" fn pc_free(ptr: *mut u8, len: usize) {
+    drop(Vec::from_raw_parts(ptr, 0, len));
+}
+
+/// # Safety
+/// ann is 1024 bytes, parent_block_hash and hash_out are 32, err_out is ERR_MAX
+#[no_mangle]
+pub unsafe extern "C" fn pc_check_ann(
+    ann: *const u8,
+    parent_block_hash: *const u8,
+    hash_out: *mut u8,
+    err_out: *mut u8,
+) -> usize {
+    let ann = slice::from_raw_parts(ann, 1024);
+    let pbh = slice::from_raw_parts(parent_block_hash, 32)
+        .try_into()
+        .unwrap();
+    let res = crate::check_ann(ann, pbh, &mut crate::ValidateCtx::default());
+    result(res.map_err(|e| e.to_owned()), hash_out, err_out)
+}
+
+/// # Safety
+/// parent_block_hashes is either null or 4 * 32 bytes, hash_out is 32 bytes,
+/// err_out is ERR_MAX
+#[no_mangle]
+pub unsafe extern "C" fn pc_check_share(
+    header_and_proof: *const u8,
+    header_and_proof_len: usize,
+    coinbase_commit: *const u8,
+    coinbase_commit_len: usize,
+    block_height: u32,
+    share_target: u32,
+    parent_block_hashes: *const u8,
+    hash_out: *mut u8,
+    err_out: *mut u8,
+) -> usize {
+    let hap = slice::from_raw_parts(header_and_proof, header_and_proof_len);
+    let cb = slice::from_raw_parts(coinbase_commit, coinbase_commit_len);
+    let mut hashes = [[0_u8; 32]; 4];
+    let hashes = if parent_block_hashes.is_null() {
+        None
+    } else {
+        let pbh = slice::from_raw_parts(parent_block_hashes, 32 * 4);
+        for (h, x) in hashes.iter_mut().zip(pbh.chunks(32)) {
+            h.copy_from_slice(x);
+        }
+        Some(&hashes)
+    };
+    let res = crate::check_share(hap, cb, block_height, share_target, hashes);
+    result(res, hash_out, err_out)
+}
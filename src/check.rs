@@ -0,0 +1,261 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! `--check` on ann, blk, ah and sprayer checks what the command was given and what it
+//! will need when it runs: the pool, memory, directories it writes and addresses it
+//! binds. It prints a report and exits without mining, non-zero if anything failed,
+//! so that provisioning scripts can check a machine before enabling the service.
+use anyhow::{bail, Result};
+use packetcrypt_blkmine::blkmine::BlkArgs;
+use packetcrypt_pool::poolcfg;
+use packetcrypt_util::{poolclient, util};
+
+struct Report {
+    lines: Vec<String>,
+    failed: usize,
+    warned: usize,
+}
+
+impl Report {
+    fn new() -> Report {
+        Report {
+            lines: Vec::new(),
+            failed: 0,
+            warned: 0,
+        }
+    }
+    fn ok(&mut self, what: &str, detail: impl AsRef<str>) {
+        self.lines
+            .push(format!("ok    {}: {}", what, detail.as_ref()));
+    }
+    fn warn(&mut self, what: &str, detail: impl AsRef<str>) {
+        self.warned += 1;
+        self.lines
+            .push(format!("WARN  {}: {}", what, detail.as_ref()));
+    }
+    fn fail(&mut self, what: &str, detail: impl AsRef<str>) {
+        self.failed += 1;
+        self.lines
+            .push(format!("FAIL  {}: {}", what, detail.as_ref()));
+    }
+    fn finish(self) -> Result<()> {
+        for l in &self.lines {
+            println!("{}", l);
+        }
+        if self.failed > 0 {
+            bail!("{} of {} checks failed", self.failed, self.lines.len());
+        }
+        println!(
+            "{} checks passed, {} with warnings",
+            self.lines.len(),
+            self.warned
+        );
+        Ok(())
+    }
+}
+
+async fn pool(r: &mut Report, url: &str, token: &Option<String>) {
+    let what = format!("pool {}", url);
+    match poolclient::fetch_conf(url, token).await {
+        Ok(conf) => {
+            for w in poolclient::check_conf(&conf).1 {
+                r.warn(&what, w);
+            }
+            r.ok(&what, format!("reachable, height {}", conf.current_height));
+        }
+        Err(e) => r.fail(&what, format!("{:#}", e)),
+    }
+}
+
+// Made if it doesn't exist, like the command would do
+async fn dir(r: &mut Report, what: &str, path: &str) {
+    let probe = format!("{}/.packetcrypt-check-{}", path, std::process::id());
+    let res = async {
+        util::ensure_exists_dir(path).await?;
+        tokio::fs::write(&probe, b"").await?;
+        tokio::fs::remove_file(&probe).await?;
+        Ok::<(), anyhow::Error>(())
+    }
+    .await;
+    match res {
+        Ok(()) => r.ok(what, format!("[{}] is writable", path)),
+        Err(e) => r.fail(what, format!("[{}] is not writable: {}", path, e)),
+    }
+}
+
+// The directory of a file which will be written
+async fn parent_dir(r: &mut Report, what: &str, file: &str) {
+    match std::path::Path::new(file).parent().and_then(|p| p.to_str()) {
+        Some(p) if !p.is_empty() => dir(r, what, p).await,
+        _ => dir(r, what, ".").await,
+    }
+}
+
+fn mem_available() -> Option<u64> {
+    let text = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = text.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+fn memory(r: &mut Report, what: &str, want: u64) {
+    match mem_available() {
+        Some(avail) if avail < want => r.fail(
+            what,
+            format!("needs {}MB but {}MB is available", want >> 20, avail >> 20),
+        ),
+        Some(avail) => r.ok(
+            what,
+            format!("needs {}MB, {}MB is available", want >> 20, avail >> 20),
+        ),
+        None => r.warn(what, "unable to tell how much memory is available"),
+    }
+}
+
+fn threads(r: &mut Report, count: usize) {
+    let cpus = num_cpus::get();
+    if count > cpus {
+        r.warn(
+            "threads",
+            format!("{} threads but there are {} cpus", count, cpus),
+        );
+    } else {
+        r.ok("threads", format!("{} threads on {} cpus", count, cpus));
+    }
+}
+
+fn bind_udp(r: &mut Report, what: &str, addr: &str) {
+    match std::net::UdpSocket::bind(addr) {
+        Ok(_) => r.ok(what, format!("able to bind UDP [{}]", addr)),
+        Err(e) => r.fail(what, format!("unable to bind UDP [{}]: {}", addr, e)),
+    }
+}
+
+fn bind_tcp(r: &mut Report, what: &str, addr: &str) {
+    match std::net::TcpListener::bind(addr) {
+        Ok(_) => r.ok(what, format!("able to bind TCP [{}]", addr)),
+        Err(e) => r.fail(what, format!("unable to bind TCP [{}]: {}", addr, e)),
+    }
+}
+
+pub async fn ann(
+    pools: &[String],
+    token: &Option<String>,
+    thread_count: usize,
+    state_dir: Option<&str>,
+) -> Result<()> {
+    let mut r = Report::new();
+    r.ok("flags", "parsed");
+    threads(&mut r, thread_count);
+    for p in pools {
+        pool(&mut r, p, token).await;
+    }
+    if let Some(d) = state_dir {
+        dir(&mut r, "--state-dir", d).await;
+    }
+    r.finish()
+}
+
+pub async fn blk(ba: &BlkArgs) -> Result<()> {
+    let mut r = Report::new();
+    r.ok("flags", "parsed");
+    threads(&mut r, ba.threads);
+    memory(&mut r, "--memorysizemb", ba.max_mem as u64);
+    if let Some(replay) = &ba.replay {
+        match std::fs::metadata(replay) {
+            Ok(_) => r.ok("--replay", format!("[{}] exists", replay)),
+            Err(e) => r.fail("--replay", format!("[{}]: {}", replay, e)),
+        }
+    } else if ba.templates.is_none() {
+        pool(&mut r, &ba.pool_master, &ba.pool_token).await;
+        if let Some(alt) = &ba.alt_pool {
+            pool(&mut r, alt, &ba.pool_token).await;
+        }
+    }
+    if let Some(d) = &ba.state_dir {
+        dir(&mut r, "--state-dir", d).await;
+    }
+    if !ba.capture_dir.is_empty() {
+        dir(&mut r, "--capture-dir", &ba.capture_dir).await;
+    }
+    for f in &ba.ann_archives {
+        match std::fs::metadata(f) {
+            Ok(m) => r.ok("--ann-archive", format!("[{}] {}MB", f, m.len() >> 20)),
+            Err(e) => r.fail("--ann-archive", format!("[{}]: {}", f, e)),
+        }
+    }
+    if let Some(f) = &ba.checkpoint {
+        parent_dir(&mut r, "--checkpoint", f).await;
+    }
+    if let Some(f) = &ba.shared_anns {
+        parent_dir(&mut r, "--shared-anns", f).await;
+    }
+    if let Some(f) = &ba.record {
+        parent_dir(&mut r, "--record", f).await;
+    }
+    if !ba.debug_bind.is_empty() {
+        bind_tcp(&mut r, "--debugbind", &ba.debug_bind);
+    }
+    if let Some(sc) = &ba.spray_cfg {
+        bind_udp(&mut r, "--bind", &sc.bind);
+    }
+    r.finish()
+}
+
+pub async fn ah(
+    cfg: &poolcfg::Config,
+    hconf: &poolcfg::AnnHandlerCfg,
+    handler: &str,
+) -> Result<()> {
+    let mut r = Report::new();
+    r.ok("config", format!("handler {} is defined", handler));
+    pool(&mut r, &cfg.master_url, &cfg.pool_token).await;
+    let base = format!("{}/ah/{}", cfg.root_workdir, handler);
+    dir(&mut r, "accounting", &format!("{}/accounting", base)).await;
+    dir(
+        &mut r,
+        "paylogdir",
+        &format!("{}/ah/paylogdir", cfg.root_workdir),
+    )
+    .await;
+    if hconf.files_to_keep > 0 {
+        if hconf.ann_files_in_memory.unwrap_or(false) {
+            let max_anns = hconf.ann_file_max_anns.unwrap_or(1024) as u64;
+            memory(
+                &mut r,
+                "ann_files_in_memory",
+                hconf.files_to_keep as u64 * max_anns * 1024,
+            );
+        } else {
+            dir(&mut r, "ann files", &format!("{}/anns", base)).await;
+        }
+    }
+    if let Some(d) = &hconf.ann_mirror_dir {
+        dir(&mut r, "ann_mirror_dir", d).await;
+    }
+    bind_tcp(&mut r, "bind_pub", &hconf.bind_pub);
+    if let Some(b) = &hconf.ann_pub_bind {
+        bind_tcp(&mut r, "ann_pub_bind", b);
+    }
+    if let Some(b) = &hconf.stats_bind {
+        bind_tcp(&mut r, "stats_bind", b);
+    }
+    if !hconf.bind_pvt.is_empty() {
+        bind_udp(&mut r, "bind_pvt", &hconf.bind_pvt);
+    }
+    r.finish()
+}
+
+pub async fn sprayer(cfg: &packetcrypt_sprayer::Config) -> Result<()> {
+    let mut r = Report::new();
+    r.ok("flags", "parsed");
+    bind_udp(&mut r, "--bind", &cfg.bind);
+    for s in &cfg.subscribe_to {
+        match s.parse::<std::net::SocketAddr>() {
+            Ok(_) => r.ok("--subscribe", s),
+            Err(e) => r.fail("--subscribe", format!("[{}]: {}", s, e)),
+        }
+    }
+    if !cfg.relay_dir.is_empty() {
+        dir(&mut r, "--relaydir", &cfg.relay_dir).await;
+    }
+    r.finish()
+}
@@ -1,12 +1,13 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 use clap::{App, Arg, SubCommand};
-use log::warn;
+use log::{info, warn};
 use packetcrypt_annhandler::annhandler;
 use packetcrypt_annmine::annmine;
-use packetcrypt_blkmine::blkmine;
-use packetcrypt_pool::{paymakerclient, poolcfg};
-use packetcrypt_util::{poolclient, util};
+use packetcrypt_blkmine::{annfilter, annproxy, blkmine};
+use packetcrypt_pool::{accounting, paymakerclient, poolcfg};
+use packetcrypt_util::exit::{self, Fatal};
+use packetcrypt_util::{history, poolclient, resolver, stats, tasks, telemetry, util};
 #[cfg(not(target_os = "windows"))]
 use tokio::signal::unix::{signal, SignalKind};
 
@@ -14,9 +15,17 @@ use tokio::signal::unix::{signal, SignalKind};
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
+#[cfg(feature = "alloc_audit")]
+#[global_allocator]
+static AUDIT: packetcrypt_util::alloc_audit::CountingAlloc =
+    packetcrypt_util::alloc_audit::CountingAlloc;
+
 #[cfg(feature = "leak_detect")]
 mod alloc;
 
+mod check;
+mod selftest;
+
 #[cfg(feature = "leak_detect")]
 async fn leak_detect() -> Result<()> {
     let al = alloc::alloc_init().await?;
@@ -45,7 +54,7 @@ async fn exiter() -> Result<()> {
     tokio::spawn(async move {
         s.recv().await;
         println!("Got SIGUSR2, calling process::exit()");
-        std::process::exit(252);
+        std::process::exit(exit::SIGUSR2);
     });
     Ok(())
 }
@@ -55,20 +64,76 @@ async fn exiter() -> Result<()> {
     Ok(())
 }
 
-async fn ah_main(config: &str, handler: &str) -> Result<()> {
+#[cfg(not(target_os = "windows"))]
+async fn task_dumper() -> Result<()> {
+    let mut s = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        loop {
+            s.recv().await;
+            tasks::dump();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+async fn task_dumper() -> Result<()> {
+    Ok(())
+}
+
+const LOG_FILTER_FILE: &str = "packetcrypt_log_filter";
+
+// On SIGHUP, apply the log filter in LOG_FILTER_FILE, or go back to the startup
+// filter if the file doesn't exist
+#[cfg(not(target_os = "windows"))]
+async fn log_reloader() -> Result<()> {
+    let mut s = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        loop {
+            s.recv().await;
+            let filter = tokio::fs::read_to_string(LOG_FILTER_FILE)
+                .await
+                .unwrap_or_default();
+            if let Err(e) = util::set_log_filter(filter.trim()) {
+                warn!("Unable to change log filter [{}]", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+async fn log_reloader() -> Result<()> {
+    Ok(())
+}
+
+// Like bail!() but with the exit code for a bad config or arguments
+macro_rules! bail_config {
+    ($($arg:tt)*) => {
+        return Err(anyhow::anyhow!($($arg)*).context(Fatal::Config))
+    };
+}
+
+async fn ah_main(config: &str, handler: &str, check: bool) -> Result<()> {
     let confb = tokio::fs::read(config)
         .await
-        .with_context(|| format!("Failed to read config file [{}]", config))?;
+        .with_context(|| format!("Failed to read config file [{}]", config))
+        .context(Fatal::Config)?;
     let mut cfg: poolcfg::Config = toml::de::from_slice(&confb[..])
-        .with_context(|| format!("Failed to parse config file [{}]", config))?;
+        .with_context(|| format!("Failed to parse config file [{}]", config))
+        .context(Fatal::Config)?;
 
     let hconf = if let Some(x) = cfg.ann_handler.remove(handler) {
         x
     } else {
-        bail!("{} is not defined in the config file [{}]", handler, config);
+        bail_config!("{} is not defined in the config file [{}]", handler, config);
     };
+    if check {
+        return check::ah(&cfg, &hconf, handler).await;
+    }
+    stats::start(hconf.stats_bind.as_deref().unwrap_or_default())?;
 
-    let pc = poolclient::new(&cfg.master_url, 6, 5);
+    let pc = poolclient::new(&cfg.master_url, 6, 5, cfg.pool_token.take());
 
     let pmc = paymakerclient::new(
         &pc,
@@ -81,7 +146,22 @@ async fn ah_main(config: &str, handler: &str) -> Result<()> {
     .await?;
     paymakerclient::start(&pmc).await;
 
-    let ah = annhandler::new(&pc, &pmc, hconf).await?;
+    let acct = accounting::new(
+        &format!("{}/ah/{}/accounting", &cfg.root_workdir, handler),
+        hconf
+            .accounting_rotate_seconds
+            .unwrap_or(accounting::DEFAULT_ROTATE_SECONDS),
+    )
+    .await?;
+
+    let ah = annhandler::new(
+        &pc,
+        &pmc,
+        &acct,
+        &format!("{}/ah/{}/anns", &cfg.root_workdir, handler),
+        hconf,
+    )
+    .await?;
     annhandler::start(&ah).await;
 
     poolclient::start(&pc).await;
@@ -101,13 +181,19 @@ fn warn_if_addr_default(payment_addr: &str) {
     }
 }
 
-async fn blk_main(ba: blkmine::BlkArgs) -> Result<()> {
-    warn_if_addr_default(&ba.payment_addr);
+async fn blk_main(ba: blkmine::BlkArgs, check: bool) -> Result<()> {
+    if ba.payment_split.is_empty() {
+        warn_if_addr_default(&ba.payment_addr);
+    }
+    if check {
+        return check::blk(&ba).await;
+    }
     let bm = blkmine::new(ba).await?;
     bm.start().await?;
     util::sleep_forever().await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn ann_main(
     pools: Vec<String>,
     threads: usize,
@@ -115,8 +201,32 @@ async fn ann_main(
     uploaders: usize,
     upload_timeout: usize,
     mine_old_anns: i32,
+    pool_token: Option<String>,
+    cpu_duty: u32,
+    failing_duty: u32,
+    prev_percent: u32,
+    state_dir: Option<String>,
+    check: bool,
 ) -> Result<()> {
     warn_if_addr_default(payment_addr);
+    if cpu_duty == 0 || cpu_duty > 100 {
+        bail_config!("--cpu-duty must be between 1 and 100, got {}", cpu_duty);
+    }
+    if failing_duty > 100 {
+        bail_config!(
+            "--failing-duty must be between 0 and 100, got {}",
+            failing_duty
+        );
+    }
+    if prev_percent > 50 {
+        bail_config!(
+            "--prev-percent must be between 0 and 50, got {}",
+            prev_percent
+        );
+    }
+    if check {
+        return check::ann(&pools, &pool_token, threads, state_dir.as_deref()).await;
+    }
     let am = annmine::new(annmine::AnnMineCfg {
         pools,
         miner_id: util::rand_u32(),
@@ -125,6 +235,11 @@ async fn ann_main(
         pay_to: String::from(payment_addr),
         upload_timeout,
         mine_old_anns,
+        pool_token,
+        cpu_duty,
+        failing_duty,
+        prev_percent,
+        state_dir,
     })
     .await?;
     annmine::start(&am).await?;
@@ -132,8 +247,91 @@ async fn ann_main(
     util::sleep_forever().await
 }
 
-async fn sprayer_main(cfg: packetcrypt_sprayer::Config) -> Result<()> {
-    packetcrypt_sprayer::Sprayer::new(&cfg)?.start();
+async fn accounting_main(
+    dir: &str,
+    from_sec: u64,
+    to_sec: u64,
+    csv: bool,
+    payout: Option<f64>,
+) -> Result<()> {
+    let to_ms = if to_sec == 0 { u64::MAX } else { to_sec * 1000 };
+    let stdout = std::io::stdout();
+    if let Some(total_pkt) = payout {
+        accounting::payout_report(dir, from_sec * 1000, to_ms, total_pkt, csv, &mut stdout.lock())
+            .await?;
+        return Ok(());
+    }
+    let count = accounting::export(dir, from_sec * 1000, to_ms, csv, &mut stdout.lock()).await?;
+    info!("Exported {} events from {}", count, dir);
+    Ok(())
+}
+
+// Made before mining starts so that every pool's history gets the same one, but not
+// by --check which only checks that the directory can be written
+async fn load_identity(state_dir: Option<&str>, check: bool) -> Result<Option<String>> {
+    let dir = match state_dir {
+        Some(dir) if check => return Ok(Some(dir.to_owned())),
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+    let id = history::identity(dir).await.context(Fatal::Config)?;
+    info!("Miner identity {}", id);
+    Ok(Some(dir.to_owned()))
+}
+
+async fn stats_main(dir: &str, since: &str) -> Result<()> {
+    let since_sec = match history::parse_duration_sec(since) {
+        Ok(s) => (util::now_ms() / 1000).saturating_sub(s),
+        Err(e) => bail_config!("{}", e),
+    };
+    let recs = history::read(dir, since_sec).await?;
+    println!("time                          kind pool                             hashrate   accepted rejected errors earnings");
+    let (mut accepted, mut rejected, mut errors, mut earnings) = (0, 0, 0, 0.0);
+    for r in &recs {
+        println!(
+            "{} {:<4} {:<32} {:>8}e/s {:>8} {:>8} {:>6} {:.4}",
+            history::format_time(r.time),
+            r.kind,
+            r.pool,
+            util::big_number(r.hashrate),
+            r.accepted,
+            r.rejected,
+            r.errors,
+            r.earnings
+        );
+        accepted += r.accepted;
+        rejected += r.rejected;
+        errors += r.errors;
+        earnings += r.earnings;
+    }
+    println!(
+        "{} hours: {} accepted, {} rejected, {} errors, {:.4} PKT estimated",
+        recs.len(),
+        accepted,
+        rejected,
+        errors,
+        earnings
+    );
+    Ok(())
+}
+
+async fn pool_info_main(url: &str, token: Option<String>) -> Result<()> {
+    let conf = poolclient::fetch_conf(url, &token).await?;
+    println!("{}", poolclient::describe_conf(&conf));
+    Ok(())
+}
+
+async fn ann_proxy_main(cfg: annproxy::Config) -> Result<()> {
+    let ap = annproxy::new(cfg)?;
+    annproxy::start(&ap).await?;
+    util::sleep_forever().await
+}
+
+async fn sprayer_main(cfg: packetcrypt_sprayer::Config, check: bool) -> Result<()> {
+    if check {
+        return check::sprayer(&cfg).await;
+    }
+    packetcrypt_sprayer::Sprayer::new(&cfg).await?.start();
     util::sleep_forever().await
 }
 
@@ -160,30 +358,68 @@ macro_rules! get_usize {
         get_num!($m, $s, usize)
     };
 }
+// 0 for auto
+macro_rules! get_mss {
+    ($m:ident) => {
+        if get_str!($m, "mss") == "auto" {
+            0
+        } else {
+            get_usize!($m, "mss")
+        }
+    };
+}
 macro_rules! get_num {
     ($m:ident, $s:expr, $n:ident) => {{
         let s = get_str!($m, $s);
         if let Ok(u) = s.parse::<$n>() {
             u
         } else {
-            println!("Unable to parse argument {} as number [{}]", $s, s);
-            return Ok(());
+            bail_config!("Unable to parse argument {} as number [{}]", $s, s);
         }
     }};
 }
 
+// Unless this is only a --check
+fn start_stats(m: &clap::ArgMatches<'_>) -> Result<()> {
+    if m.is_present("check") {
+        return Ok(());
+    }
+    stats::start(m.value_of("statsbind").unwrap_or_default())
+}
+
 async fn async_main(matches: clap::ArgMatches<'_>) -> Result<()> {
     leak_detect().await?;
     exiter().await?;
+    task_dumper().await?;
     util::setup_env(matches.occurrences_of("v")).await?;
+    log_reloader().await?;
     if let Some(ann) = matches.subcommand_matches("ann") {
         // ann miner
+        if let Some(proxy) = ann.value_of("proxy") {
+            util::set_proxy(proxy)?;
+        }
+        if let Some(cert) = ann.value_of("clientcert") {
+            util::set_client_cert(cert)?;
+        }
+        if let Some(doh) = ann.values_of("doh") {
+            resolver::set_doh(&doh.collect::<Vec<_>>())?;
+        }
+        if ann.is_present("telemetry") {
+            telemetry::enable(version())?;
+        }
         let pools = get_strs!(ann, "pools");
         let payment_addr = get_str!(ann, "paymentaddr");
         let threads = get_usize!(ann, "threads");
         let uploaders = get_usize!(ann, "uploaders");
         let upload_timeout = get_usize!(ann, "uploadtimeout");
         let mine_old_anns = get_num!(ann, "mineold", i32);
+        let pool_token = ann.value_of("pooltoken").map(String::from);
+        let cpu_duty = get_num!(ann, "cpuduty", u32);
+        let failing_duty = get_num!(ann, "failingduty", u32);
+        let prev_percent = get_num!(ann, "prevpercent", u32);
+        let check = ann.is_present("check");
+        let state_dir = load_identity(ann.value_of("statedir"), check).await?;
+        start_stats(ann)?;
         ann_main(
             pools,
             threads,
@@ -191,26 +427,59 @@ async fn async_main(matches: clap::ArgMatches<'_>) -> Result<()> {
             uploaders,
             upload_timeout,
             mine_old_anns,
+            pool_token,
+            cpu_duty,
+            failing_duty,
+            prev_percent,
+            state_dir,
+            check,
         )
         .await?;
     } else if let Some(ah) = matches.subcommand_matches("ah") {
         // ann handler
         let config = get_str!(ah, "config");
         let handler = get_str!(ah, "handler");
-        ah_main(config, handler).await?;
+        ah_main(config, handler, ah.is_present("check")).await?;
     } else if let Some(blk) = matches.subcommand_matches("blk") {
+        if let Some(proxy) = blk.value_of("proxy") {
+            util::set_proxy(proxy)?;
+        }
+        if let Some(cert) = blk.value_of("clientcert") {
+            util::set_client_cert(cert)?;
+        }
+        if let Some(doh) = blk.values_of("doh") {
+            resolver::set_doh(&doh.collect::<Vec<_>>())?;
+        }
+        if blk.is_present("telemetry") {
+            telemetry::enable(version())?;
+        }
+        let mut payment_split = Vec::new();
+        if blk.is_present("payto") {
+            for p in get_strs!(blk, "payto") {
+                let (addr, weight) = match p.find('=') {
+                    Some(i) => (&p[..i], p[i + 1..].parse::<u32>().ok()),
+                    None => (&p[..], Some(1)),
+                };
+                match weight {
+                    Some(w) if w > 0 && !addr.is_empty() => {
+                        payment_split.push((addr.to_owned(), w))
+                    }
+                    _ => bail_config!("--payto [{}] should be <address>=<weight>", p),
+                }
+            }
+        }
         let spray_cfg = if blk.is_present("subscribe") {
             let passwd: String = get_str!(blk, "handlerpass").into();
             if passwd.is_empty() {
-                bail!("When sprayer is enabled, --handlerpass is required");
+                bail_config!("When sprayer is enabled, --handlerpass is required");
             }
             let bind: String = get_str!(blk, "bind").into();
             if bind.is_empty() {
-                bail!("When sprayer is enabled, --bind is required");
+                bail_config!("When sprayer is enabled, --bind is required");
             }
             let subscribe_to = get_strs!(blk, "subscribe");
             let workers = get_usize!(blk, "sprayerthreads");
-            let mss = get_usize!(blk, "mss");
+            let mss = get_mss!(blk);
             let mcast = if blk.is_present("mcast") {
                 get_str!(blk, "mcast")
             } else {
@@ -226,17 +495,22 @@ async fn async_main(matches: clap::ArgMatches<'_>) -> Result<()> {
                 mss,
                 spray_at: Vec::new(),
                 mcast,
+                relay_dir: String::new(),
+                tcp_fallback: blk.is_present("tcpfallback"),
+                proxy: blk.value_of("proxy").unwrap_or_default().to_owned(),
+                psk: blk.value_of("spraypsk").unwrap_or_default().to_owned(),
             })
         } else {
             if blk.is_present("bind") {
-                bail!("--bind (bind UDP sprayer socket) is nonsensical without --subscribe");
+                bail_config!("--bind (bind UDP sprayer socket) is nonsensical without --subscribe");
             }
             None
         };
-        blk_main(blkmine::BlkArgs {
+        let ba = blkmine::BlkArgs {
             max_mem: get_usize!(blk, "memorysizemb") * 1024 * 1024,
             min_free_space: get_num!(blk, "minfree", f64),
             payment_addr: get_str!(blk, "paymentaddr").into(),
+            payment_split,
             threads: get_usize!(blk, "threads"),
             downloader_count: get_usize!(blk, "downloaders"),
             pool_master: get_str!(blk, "pool").into(),
@@ -244,24 +518,123 @@ async fn async_main(matches: clap::ArgMatches<'_>) -> Result<()> {
             uploaders: get_usize!(blk, "uploaders"),
             handler_pass: get_str!(blk, "handlerpass").into(),
             spray_cfg,
-        })
-        .await?;
+            dry_run: blk.is_present("dryrun") || blk.is_present("replay"),
+            debug_bind: get_str!(blk, "debugbind").into(),
+            pktd: if blk.is_present("pktd") {
+                Some(packetcrypt_blkmine::pktd::Pktd {
+                    url: get_str!(blk, "pktd").into(),
+                    auth: get_str!(blk, "pktdauth").into(),
+                })
+            } else {
+                None
+            },
+            pool_token: blk.value_of("pooltoken").map(String::from),
+            ann_class_bits: get_num!(blk, "annclassbits", u32),
+            block_reward: get_num!(blk, "blockreward", f64),
+            pool_fee: get_num!(blk, "poolfee", f64),
+            max_shares_per_sec: get_usize!(blk, "maxsharespersec"),
+            sharded: get_str!(blk, "execmodel") == "sharded",
+            shard_mb: get_usize!(blk, "shardmb"),
+            templates: blk
+                .value_of("templates")
+                .map(packetcrypt_blkmine::template::parse_source),
+            capture_dir: get_str!(blk, "capturedir").into(),
+            capture_max_mb: get_usize!(blk, "capturemaxmb"),
+            fresh_reserve: get_num!(blk, "freshreserve", u32),
+            record: blk.value_of("record").map(String::from),
+            replay: blk.value_of("replay").map(String::from),
+            ann_file_anns: get_usize!(blk, "annfileanns"),
+            checkpoint: blk.value_of("checkpoint").map(String::from),
+            ann_archives: blk
+                .values_of("annarchive")
+                .map(|v| v.map(String::from).collect())
+                .unwrap_or_default(),
+            shared_anns: blk.value_of("sharedanns").map(String::from),
+            shared_lanes: get_num!(blk, "sharedlanes", u32),
+            state_dir: load_identity(blk.value_of("statedir"), blk.is_present("check")).await?,
+            ann_filter: blk
+                .value_of("annfilter")
+                .map(annfilter::parse)
+                .transpose()
+                .context(Fatal::Config)?,
+            realtime_priority: get_num!(blk, "realtimepriority", i32),
+            tree_budget_ms: get_num!(blk, "treebudgetms", u64),
+            mlock_trees: blk.is_present("mlocktrees"),
+            tree_cores: util::parse_cores(blk.value_of("treecores").unwrap_or(""))
+                .context(Fatal::Config)?,
+            intake_cores: util::parse_cores(blk.value_of("intakecores").unwrap_or(""))
+                .context(Fatal::Config)?,
+            standby_of: blk.value_of("standbyof").map(String::from),
+            share_http2: blk.is_present("sharehttp2"),
+            work_key: blk
+                .value_of("workkey")
+                .map(util::parse_hex32)
+                .transpose()
+                .context(Fatal::Config)?,
+            ann_proxy: blk.value_of("annproxy").map(String::from),
+            alt_pool: blk.value_of("altpool").map(String::from),
+        };
+        start_stats(blk)?;
+        blk_main(ba, blk.is_present("check")).await?;
     } else if let Some(spray) = matches.subcommand_matches("sprayer") {
         let spray_at = if spray.is_present("sprayat") {
             get_strs!(spray, "sprayat")
         } else {
             Vec::new()
         };
-        sprayer_main(packetcrypt_sprayer::Config {
+        let cfg = packetcrypt_sprayer::Config {
             passwd: get_str!(spray, "passwd").into(),
             bind: get_str!(spray, "bind").into(),
             workers: get_usize!(spray, "threads"),
             subscribe_to: get_strs!(spray, "subscribe"),
             log_peer_stats: true,
-            mss: get_usize!(spray, "mss"),
+            mss: get_mss!(spray),
             spray_at,
             mcast: "".to_owned(),
-        })
+            relay_dir: get_str!(spray, "relaydir").into(),
+            tcp_fallback: spray.is_present("tcpfallback"),
+            proxy: spray.value_of("proxy").unwrap_or_default().to_owned(),
+            psk: spray.value_of("psk").unwrap_or_default().to_owned(),
+        };
+        start_stats(spray)?;
+        sprayer_main(cfg, spray.is_present("check")).await?;
+    } else if let Some(ap) = matches.subcommand_matches("ann-proxy") {
+        let cfg = annproxy::Config {
+            bind: get_str!(ap, "bind").into(),
+            pool_master: get_str!(ap, "pool").into(),
+            pool_token: ap.value_of("pooltoken").map(String::from),
+            handler_pass: ap.value_of("handlerpass").map(String::from),
+            cache_bytes: get_usize!(ap, "cachemb") * 1024 * 1024,
+        };
+        ann_proxy_main(cfg).await?;
+    } else if let Some(pi) = matches.subcommand_matches("pool-info") {
+        if let Some(proxy) = pi.value_of("proxy") {
+            util::set_proxy(proxy)?;
+        }
+        if let Some(doh) = pi.values_of("doh") {
+            resolver::set_doh(&doh.collect::<Vec<_>>())?;
+        }
+        pool_info_main(
+            get_str!(pi, "pool"),
+            pi.value_of("pooltoken").map(String::from),
+        )
+        .await?;
+    } else if matches.subcommand_matches("self-test").is_some() {
+        selftest::main()?;
+    } else if let Some(st) = matches.subcommand_matches("stats") {
+        stats_main(get_str!(st, "statedir"), get_str!(st, "since")).await?;
+    } else if let Some(acct) = matches.subcommand_matches("accounting") {
+        accounting_main(
+            get_str!(acct, "dir"),
+            get_num!(acct, "from", u64),
+            get_num!(acct, "to", u64),
+            get_str!(acct, "format") == "csv",
+            if acct.is_present("payout") {
+                Some(get_num!(acct, "payout", f64))
+            } else {
+                None
+            },
+        )
         .await?;
     }
     Ok(())
@@ -276,10 +649,8 @@ fn version() -> &'static str {
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cpus_str = format!("{}", num_cpus::get());
-    let matches = App::new("packetcrypt")
+fn app(cpus_str: &str) -> App<'_, '_> {
+    App::new("packetcrypt")
         .version(version())
         .author("Caleb James DeLisle <cjd@cjdns.fr>")
         .about("Bandwidth hard proof of work algorithm")
@@ -291,9 +662,19 @@ async fn main() -> Result<()> {
                 .multiple(true)
                 .help("Verbose logging"),
         )
+        .arg(
+            Arg::with_name("helpjson")
+                .long("help-json")
+                .help("Print every subcommand and flag, with their defaults, as JSON for tooling"),
+        )
         .subcommand(
             SubCommand::with_name("ah")
                 .about("Run announcement handler")
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Check the config file, the pool, the directories which will be written and the addresses which will be bound, print a report and exit, non-zero if something is wrong"),
+                )
                 .arg(
                     Arg::with_name("config")
                         .short("C")
@@ -312,6 +693,17 @@ async fn main() -> Result<()> {
         .subcommand(
             SubCommand::with_name("ann")
                 .about("Run announcement miner")
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Check the flags, the pools and the state directory, print a report and exit, non-zero if something is wrong"),
+                )
+                .arg(
+                    Arg::with_name("statsbind")
+                        .long("statsbind")
+                        .help("Serve the stats for Prometheus at /metrics and as JSON at /stats.json on this address, e.g. 127.0.0.1:9099")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("threads")
                         .short("t")
@@ -350,6 +742,64 @@ async fn main() -> Result<()> {
                         .help("how many blocks old to mine annoucements, -1 to let the pool decide")
                         .default_value("-1"),
                 )
+                .arg(
+                    Arg::with_name("pooltoken")
+                        .long("pool-token")
+                        .help("Access token for private pools, sent with every request to the pool")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("proxy")
+                        .long("proxy")
+                        .help("Connect to the pool through this proxy, e.g. socks5://host:port, socks5h://127.0.0.1:9050 for Tor or http://host:port")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("clientcert")
+                        .long("client-cert")
+                        .help("PEM file with a client certificate and key, for pools which only accept known rigs")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("statedir")
+                        .long("state-dir")
+                        .help("Keep a persistent identity for this miner and an hourly history of what it did in this directory, see packetcrypt stats")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("doh")
+                        .long("doh")
+                        .help("Resolve the pool's names with DNS-over-HTTPS, e.g. cloudflare, google, quad9 or 1.1.1.1#cloudflare-dns.com, can be given more than once")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("telemetry")
+                        .long("telemetry")
+                        .help("Send your hashrate, version, accepted/rejected counts and errors to the pool every 5 minutes, if it asks for them, to help the operator diagnose problems")
+                )
+                .arg(
+                    Arg::with_name("cpuduty")
+                        .long("cpu-duty")
+                        .help("Percent of the time to mine, e.g. 60 to leave the CPU idle 40% of the time so mining stays in the background")
+                        .default_value("100")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("prevpercent")
+                        .long("prev-percent")
+                        .help("Percent of the mining time to spend on anns for the block before the one being mined, e.g. 10, so that block miners have fresh anns of both ages after a block change")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("failingduty")
+                        .long("failing-duty")
+                        .help("Percent of the time to mine once no handler has accepted anns for a minute, 0 to pause with a short burst every 2 minutes to see if they accept again, 100 to keep mining")
+                        .default_value("0")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("pools")
                         .help("The pools to mine in")
@@ -360,6 +810,17 @@ async fn main() -> Result<()> {
         .subcommand(
             SubCommand::with_name("blk")
                 .about("Run block miner")
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Check the flags, the pool, memory, the directories which will be written and the addresses which will be bound, print a report and exit, non-zero if something is wrong"),
+                )
+                .arg(
+                    Arg::with_name("statsbind")
+                        .long("statsbind")
+                        .help("Serve the stats for Prometheus at /metrics and as JSON at /stats.json on this address, e.g. 127.0.0.1:9099")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("paymentaddr")
                         .short("p")
@@ -367,6 +828,13 @@ async fn main() -> Result<()> {
                         .help("Address to request payment for mining")
                         .default_value(DEFAULT_ADDR),
                 )
+                .arg(
+                    Arg::with_name("payto")
+                        .long("payto")
+                        .help("Split the shares between these addresses, each given as <address>=<weight>, e.g. --payto pkt1aaa=3 pkt1bbb=1 for 75% and 25%")
+                        .takes_value(true)
+                        .min_values(1),
+                )
                 .arg(
                     Arg::with_name("threads")
                         .short("t")
@@ -378,8 +846,9 @@ async fn main() -> Result<()> {
                 .arg(
                     Arg::with_name("downloaders")
                         .short("d")
-                        .long("downloaders")
-                        .help("Max concurrent downloads (per handler)")
+                        .long("downloads-per-handler")
+                        .alias("downloaders")
+                        .help("Parallel downloads (and keep-alive connections) per handler")
                         .default_value("30")
                         .takes_value(true),
                 )
@@ -421,6 +890,255 @@ async fn main() -> Result<()> {
                         .default_value("")
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("dryrun")
+                        .long("dry-run")
+                        .help("Mine normally but only log shares instead of submitting them"),
+                )
+                .arg(
+                    Arg::with_name("debugbind")
+                        .long("debugbind")
+                        .help("Address to serve ann class snapshots and the log filter api on, e.g. 127.0.0.1:8099")
+                        .default_value("")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pktd")
+                        .long("pktd")
+                        .help("Also submit blocks to this pktd RPC server, e.g. http://127.0.0.1:64765")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pktdauth")
+                        .long("pktdauth")
+                        .help("Username and password for the pktd RPC server, user:pass")
+                        .default_value("")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pooltoken")
+                        .long("pool-token")
+                        .help("Access token for private pools, sent with every request to the pool")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("proxy")
+                        .long("proxy")
+                        .help("Connect to the pool through this proxy, e.g. socks5://host:port, socks5h://127.0.0.1:9050 for Tor or http://host:port")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("clientcert")
+                        .long("client-cert")
+                        .help("PEM file with a client certificate and key, for pools which only accept known rigs")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("statedir")
+                        .long("state-dir")
+                        .help("Keep a persistent identity for this miner and an hourly history of what it did in this directory, see packetcrypt stats")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("doh")
+                        .long("doh")
+                        .help("Resolve the pool's names with DNS-over-HTTPS, e.g. cloudflare, google, quad9 or 1.1.1.1#cloudflare-dns.com, can be given more than once")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("telemetry")
+                        .long("telemetry")
+                        .help("Send your hashrate, version, accepted/rejected counts and errors to the pool every 5 minutes, if it asks for them, to help the operator diagnose problems")
+                )
+                .arg(
+                    Arg::with_name("annclassbits")
+                        .long("ann-class-bits")
+                        .help("Group anns with similar work into the same class by keeping only this many significant bits of their work target, 23 means exact")
+                        .default_value("23")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("blockreward")
+                        .long("block-reward")
+                        .help("Block reward in PKT used to estimate share value, 0 means read it from the coinbase")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("poolfee")
+                        .long("pool-fee")
+                        .help("Percent of the block reward kept by the pool, used to estimate share value")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("maxsharespersec")
+                        .long("max-shares-per-sec")
+                        .help("Submit at most this many shares per second, keeping the best ones, blocks are always submitted, 0 means no limit")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("execmodel")
+                        .long("exec-model")
+                        .help("shared: all threads load anns into the same lists, sharded: each core gets its own part of the memory, for machines with many cores")
+                        .possible_values(&["shared", "sharded"])
+                        .default_value("shared")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("shardmb")
+                        .long("shard-mb")
+                        .help("With --exec-model sharded, split the memory into shards of this many MB rather than one per core, 0 means one per core")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("annfilter")
+                        .long("ann-filter")
+                        .help("Only keep anns which match this, e.g. \"work<=0x2000ffff && age<3\", see packetcrypt-blkmine/src/annfilter.rs")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("freshreserve")
+                        .long("fresh-reserve")
+                        .help("Keep this many ann slots free for anns on the newest block, so a flood of old anns can't crowd them out after a block change")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("capturedir")
+                        .long("capture-dir")
+                        .help("Save shares which the pool rejects in this directory, with the work which was being mined, for analysis")
+                        .default_value("")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("annfileanns")
+                        .long("ann-file-anns")
+                        .help("Ask ann handlers to group their files so each download has about this many anns, bigger is better for slow or far away connections, 0 to take the files as they are")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("record")
+                        .long("record")
+                        .help("Write all work and announcements which are received to this file, for replaying later")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("replay")
+                        .long("replay")
+                        .help("Mine the work and announcements from a file made with --record instead of from the pool, shares are not submitted")
+                        .conflicts_with("record")
+                        .conflicts_with("checkpoint")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("checkpoint")
+                        .long("checkpoint")
+                        .help("Save the announcements in memory to this file every 5 minutes and load them from it on startup, so a restart does not mean waiting for them to download again")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("annarchive")
+                        .long("ann-archive")
+                        .help("Load the announcements in this --checkpoint file of another run or another machine once the pool has sent its blocks, without changing it, only valid announcements with a known parent block are loaded, can be given more than once")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("sharedanns")
+                        .long("shared-anns")
+                        .help("Keep the announcements in a lane of this shared memory file, e.g. in /dev/shm, so that when this miner crashes or is stopped the next one started on the machine gets them, it is made with --shared-lanes lanes of --memorysizemb each if it does not exist")
+                        .conflicts_with("replay")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("sharedlanes")
+                        .long("shared-lanes")
+                        .help("Number of lanes when making the --shared-anns file, one for each miner which runs at a time on the machine")
+                        .default_value("1")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("realtimepriority")
+                        .long("realtime-priority")
+                        .help("Upload shares and update work on a thread with this realtime priority (1-99) so that announcement intake can't delay the reaction to a new block, needs root or CAP_SYS_NICE, 0 to disable")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("treebudgetms")
+                        .long("tree-budget-ms")
+                        .help("Most milliseconds to spend choosing announcements and building the proof tree when a block comes in, if it takes longer then fewer announcements are used next time, 0 for no limit")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("mlocktrees")
+                        .long("mlock-trees")
+                        .help("Lock the proof trees in RAM so they are never swapped out, check ulimit -l"),
+                )
+                .arg(
+                    Arg::with_name("treecores")
+                        .long("tree-cores")
+                        .help("Build the proof tree with one thread on each of these cores, e.g. 0-3, so that it can't starve announcement intake")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("intakecores")
+                        .long("intake-cores")
+                        .help("Copy in announcements with one thread on each of these cores, e.g. 4,5")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("standbyof")
+                        .long("standby-of")
+                        .help("Be a hot standby for the block miner with this debug api, e.g. http://10.0.0.2:8099, mining but only submitting shares while its /health fails")
+                        .conflicts_with("dryrun")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("workkey")
+                        .long("work-key")
+                        .help("Only mine work signed by this ed25519 key of the pool, in hex, otherwise the first key in the pool's config is pinned and all work after it must be signed with it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("annproxy")
+                        .long("ann-proxy")
+                        .help("Download anns through the packetcrypt ann-proxy at this url, e.g. http://10.0.0.1:8097")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("altpool")
+                        .long("alt-pool")
+                        .help("Also mine for this pool, each block's shares go to whichever of the two pays more for them with the anns in use, or whose effective share target is easier if they pay the same")
+                        .conflicts_with_all(&["templates", "replay"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("sharehttp2")
+                        .long("share-http2")
+                        .help("Submit shares over one HTTP/2 connection to the block handler, without negotiating it, the handler must support HTTP/2 in cleartext if its url is http"),
+                )
+                .arg(
+                    Arg::with_name("capturemaxmb")
+                        .long("capture-max-mb")
+                        .help("Keep at most this many megabytes of rejected shares, oldest are deleted first")
+                        .default_value("100")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("templates")
+                        .long("templates")
+                        .help("Mine JSON block templates from an adapter, - for stdin or the path of a unix socket to listen on, shares are written back to it")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("subscribe")
                         .short("s")
@@ -456,8 +1174,8 @@ async fn main() -> Result<()> {
                     Arg::with_name("mss")
                         .short("M")
                         .long("maxsegmentsize")
-                        .help("Maximum packet size to send when using UDP sprayer, remember IP and UDP overhead")
-                        .default_value("1472")
+                        .help("Maximum packet size to send when using UDP sprayer, remember IP and UDP overhead, auto to fit the path MTU")
+                        .default_value("auto")
                         .takes_value(true),
                 )
                 .arg(
@@ -465,11 +1183,33 @@ async fn main() -> Result<()> {
                     .long("mcast")
                     .help("Connect to this multicast group")
                     .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tcpfallback")
+                        .long("tcpfallback")
+                        .help("If nothing arrives from a sprayer over UDP, subscribe to it over TCP instead, through --proxy if it is set"),
+                )
+                .arg(
+                    Arg::with_name("spraypsk")
+                        .long("spraypsk")
+                        .help("Seal everything to and from the sprayers with this pre-shared key, they must all have it too")
+                        .takes_value(true),
                 ),
         )
         .subcommand(
             SubCommand::with_name("sprayer")
                 .about("Launch ann sprayer daemon")
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Check the flags, the addresses which will be bound and the relay directory, print a report and exit, non-zero if something is wrong"),
+                )
+                .arg(
+                    Arg::with_name("statsbind")
+                        .long("statsbind")
+                        .help("Serve the stats for Prometheus at /metrics and as JSON at /stats.json on this address, e.g. 127.0.0.1:9099")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("threads")
                         .short("t")
@@ -513,12 +1253,253 @@ async fn main() -> Result<()> {
                     Arg::with_name("mss")
                         .short("M")
                         .long("maxsegmentsize")
-                        .help("Maximum packet size to send, remember IP and UDP overhead")
-                        .default_value("1472")
+                        .help("Maximum packet size to send, remember IP and UDP overhead, auto to fit the path MTU")
+                        .default_value("auto")
                         .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("relaydir")
+                        .long("relaydir")
+                        .help("Relay mode, buffer anns which can't be sent downstream fast enough in this directory")
+                        .default_value("")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tcpfallback")
+                        .long("tcpfallback")
+                        .help("Accept subscriptions over TCP on the bind address, and subscribe over TCP to sprayers which send nothing over UDP"),
+                )
+                .arg(
+                    Arg::with_name("proxy")
+                        .long("proxy")
+                        .help("Make TCP subscriptions through this proxy, e.g. socks5://host:port or http://host:port")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("psk")
+                        .long("psk")
+                        .help("Seal everything sent with this pre-shared key and drop anything which is not, so anns can't be read, changed or injected, every peer must have the same key")
+                        .takes_value(true),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("ann-proxy")
+                .about("Download each ann file once and serve it to all of the block miners on this network, see blk --ann-proxy")
+                .arg(
+                    Arg::with_name("bind")
+                        .short("b")
+                        .long("bind")
+                        .help("Address to serve the block miners on")
+                        .default_value("127.0.0.1:8097")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("cachemb")
+                        .long("cache-mb")
+                        .help("Megabytes of ann files to keep in memory, oldest are dropped first")
+                        .default_value("1024")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("handlerpass")
+                        .short("P")
+                        .long("handlerpass")
+                        .help("Password to use for pulling anns from the handlers")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pooltoken")
+                        .long("pool-token")
+                        .help("Access token for private pools")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pool")
+                        .help("The pool url")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pool-info")
+                .about("Print the configuration of a pool, as the miners understand it")
+                .arg(
+                    Arg::with_name("pooltoken")
+                        .long("pool-token")
+                        .help("Access token for private pools")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("proxy")
+                        .long("proxy")
+                        .help("Connect to the pool through this proxy, e.g. socks5://host:port")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("doh")
+                        .long("doh")
+                        .help("Resolve the pool's names with DNS-over-HTTPS, e.g. cloudflare, google, quad9 or 1.1.1.1#cloudflare-dns.com, can be given more than once")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("pool")
+                        .help("The pool url")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("self-test")
+                .about("Check the hashing, validation and difficulty code against known answers"),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Print the hourly history which a miner kept with --state-dir")
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .help("How far back to go, e.g. 7d, 12h or 30m")
+                        .default_value("7d")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("statedir")
+                        .long("state-dir")
+                        .help("The miner's --state-dir")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("accounting")
+                .about("Export the accounting log written by an announcement handler")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .help("Only events from this time onward, seconds since the epoch")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .help("Only events up to this time, seconds since the epoch, 0 means no limit")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("ndjson can be posted to the paymaker to re-run payouts, csv is for reading")
+                        .possible_values(&["ndjson", "csv"])
+                        .default_value("ndjson")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("payout")
+                        .long("payout")
+                        .help("Dry run of a payout round: split this many PKT between the addresses by work done and report what each would get, no payments are made")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .help("The accounting directory, e.g. datastore/pool/ah/ah0/accounting")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Print shell completions, e.g. packetcrypt completions bash > /etc/bash_completion.d/packetcrypt")
+                .arg(
+                    Arg::with_name("shell")
+                        .help("The shell to complete for")
+                        .possible_values(&clap::Shell::variants())
+                        .required(true)
+                        .index(1),
+                ),
+        )
+}
 
-    async_main(matches).await
+// clap 2 has no public way to walk the args, so this uses the same fields as its own
+// completions generator
+fn help_json(app: &App<'_, '_>) -> serde_json::Value {
+    macro_rules! arg {
+        ($b:expr, $short:expr, $long:expr, $default:expr, $possible:expr, $takes:expr) => {
+            serde_json::json!({
+                "name": $b.name,
+                "short": $short.map(|c: char| c.to_string()),
+                "long": $long,
+                "help": $b.help,
+                "takesValue": $takes,
+                "required": $b.is_set(clap::ArgSettings::Required),
+                "multiple": $b.is_set(clap::ArgSettings::Multiple),
+                "default": $default.map(|d: &std::ffi::OsStr| d.to_string_lossy()),
+                "possibleValues": $possible,
+            })
+        };
+    }
+    let p = &app.p;
+    let mut args = Vec::new();
+    for f in &p.flags {
+        args.push(arg!(
+            f.b,
+            f.s.short,
+            f.s.long,
+            None,
+            None::<Vec<&str>>,
+            false
+        ));
+    }
+    for o in &p.opts {
+        let v = &o.v;
+        args.push(arg!(
+            o.b,
+            o.s.short,
+            o.s.long,
+            v.default_val,
+            v.possible_vals.clone(),
+            true
+        ));
+    }
+    for a in p.positionals.values() {
+        let v = &a.v;
+        args.push(arg!(
+            a.b,
+            None,
+            None::<&str>,
+            v.default_val,
+            v.possible_vals.clone(),
+            true
+        ));
+    }
+    serde_json::json!({
+        "name": p.meta.name,
+        "about": p.meta.about,
+        "version": p.meta.version,
+        "args": args,
+        "subcommands": p.subcommands.iter().map(help_json).collect::<Vec<_>>(),
+    })
+}
+
+#[tokio::main]
+async fn main() {
+    let cpus_str = format!("{}", num_cpus::get());
+    let matches = app(&cpus_str).get_matches();
+    if matches.is_present("helpjson") {
+        println!("{:#}", help_json(&app(&cpus_str)));
+        return;
+    }
+    if let Some(c) = matches.subcommand_matches("completions") {
+        let shell = clap::value_t!(c, "shell", clap::Shell).unwrap_or_else(|e| e.exit());
+        app(&cpus_str).gen_completions_to("packetcrypt", shell, &mut std::io::stdout());
+        return;
+    }
+
+    if let Err(e) = async_main(matches).await {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit::code(&e));
+    }
 }
@@ -0,0 +1,207 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//! `packetcrypt self-test` checks the hashing, validation and difficulty code against
+//! answers which are known to be right, so a miscompile on an unusual toolchain or
+//! cpu shows up before it costs any mining time. Both the C code and the pure Rust
+//! port are checked where there are both.
+use anyhow::{bail, Result};
+use packetcrypt_sys::difficulty;
+use packetcrypt_sys::{PacketCryptAnn, ValidateCtx};
+use packetcrypt_util::{hash, util};
+use std::convert::TryInto;
+
+// Same announcement as the packetcrypt-annhandler validate test
+const ANN: &str = concat!(
+    "01cd06000baf7821000002204398070000000000000000000000000000000000",
+    "0000000000000000000000000000000000000000000000008a48028ff7d392a5",
+    "dad2ecf812793d3d1f052053fe6947da093ab56ab2c7731fc7a0d07887b5e81c",
+    "099d7a8976e3927bb0161a6fdad11b3906d0022eb173e8f1fd979ddc2e7a8415",
+    "a5576a5427e9cae65a6a7f06450b4cf0b6cf7dc4a6096b4d64b1e9246aa4d5ac",
+    "662502abbdb245e9600584c12469178ca8dea73edb0fcfa18f0eb776a40fe761",
+    "041711b1c22454d225e51e27c267ead7b89a35e77c8c1806eb3cf5c846a86c3f",
+    "c79908119cd2cd23a23812c38cf30cc4aec2fa24565db6c99302534cc0475b09",
+    "c4544ba0cb1e68fc61cc7ff76da5b381becf4da233dbfe93f3b8736ca83d1474",
+    "1692a466a5d0a4d085f4324c9d7ba40d5052319086338d85eb98d2065297be9e",
+    "c4e9dedc4b92417deb1fdcb7103b2d4c65b779d30f02a6657887c623e2641ead",
+    "0a8a8cb60cadb56e23984a32ce5d5581c6f0bceee3b6d70d8678a99d96a68fb4",
+    "48e04c542823469c431c1fb8ca17f50d52560f0eb2f83964f7c5e64313e63c17",
+    "9cac2d3381e39f272aecbc5e9859d75fe9734544c9df32203ade078a17f9bf2e",
+    "2cb8f1c8b1ca631f502fac1985bcd92e3e58dfec535992182fce953df7c6fd6b",
+    "8f31d78c4b7ec53e55135bf7a264d2217d1984a444bb421d42680a6ea9721b23",
+    "d6dd937f6a0e1e102bfb50e6175425a80729643e49e1fa28882e5b790e14e1a9",
+    "368a28e052ea0d46e29adb311b8291499ee0da03acd654677454b0f3410d1900",
+    "da31fe77b9b382ec6a3d25ad959b502d89855c908e59d7000c7104f175bb1005",
+    "5d3ad4a9557473d63878f9d8494bda01a3688f1f1bfdf26d73acbe93cd8bc890",
+    "bbd9b81cf915ad8fd52e7b5ee3f35cabe6da2b74345d541da0b38a940321ac67",
+    "d95e0cd0749644227da765a6d6319195f831cdda571e188fe01ac60e6218359d",
+    "18cfed8aae4449011ee2d7bd0c637328fdfb589434d564a53b26009c8c0b4d8a",
+    "9bc99c3992378f7dd251d248217bbd0b1e5b9905cfdfabb0bec0bee677a5a65e",
+    "bb5cda542ae4076f6e0e4dba248639ce5861a7bca1748c4386941003d6375f0a",
+    "fa79b921982d4ce6857df031b66865db723bf6068424e414da2714c9c31a0a5f",
+    "8e2f16d673a5b11621158123414cf698ba85603c4be66b98c52df8ed0c58d7a0",
+    "3db7362c9589a258cb3217d7df2cd3ef8c12dd879af19cd55b2e392900969948",
+    "217c404b1dec0abad3dab0e5195825d0d3a5842ce5d181ce850a21b31041bd30",
+    "bc4cac295da680057d83bdd67bf7c0ecc405c406c5a903cf67e0fd7ae128cca6",
+    "1a7dc56366de128f1a662779699490f926acbf5e79aad7e3f6f1a1c8a4f1ff46",
+    "83c622154dac17ad9141c4b4b2a733934af0b24ff24f81ec9a16058f2fee88d4",
+);
+
+// Reversed, as block hashes are printed
+const PARENT_BLOCK_HASH: &str = "255094b788fe98be51bafb4d941d507d4d5a949c751d1f68dfad0715215e1e48";
+const ANN_HASH: &str = "2684bd8f044073677ffd921023dd1cdd28c0605e6ba889d05b0f63663f7d50d4";
+
+// (ann target, age in blocks, degraded target)
+const DEGRADE: [(u32, u32, u32); 7] = [
+    (0x2000ffff, 0, 0xffffffff),
+    (0x2000ffff, 3, 0x2000ffff),
+    (0x2000ffff, 4, 0x2001fffe),
+    (0x2000ffff, 10, 0x207fff80),
+    (0x2000ffff, 20, 0xffffffff),
+    (0x1e0fffff, 4, 0x1e1ffffe),
+    (0x1e0fffff, 10, 0x1f07ffff),
+];
+
+// (block target, ann target, ann count, effective target)
+const EFFECTIVE: [(u32, u32, u64, u32); 3] = [
+    (0x1c0fffff, 0x2000ffff, 1 << 20, 0x1a3ffff4),
+    (0x1b2acb36, 0x1f0fffff, 4096, 0x164c8829),
+    (0x1c0fffff, 0x2000ffff, 0, 0),
+];
+
+// (ann target, ann count, multiplier)
+const MULTIPLIER: [(u32, u64, u64); 2] = [
+    (0x2000ffff, 1024, 262144),
+    (0x1f0fffff, 1 << 20, 4398046511104),
+];
+
+fn to_hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn from_hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+struct Check {
+    failed: Vec<String>,
+    passed: usize,
+}
+
+impl Check {
+    fn eq<T: PartialEq + std::fmt::Debug>(&mut self, what: &str, got: T, want: T) {
+        if got == want {
+            self.passed += 1;
+            println!("ok   {}", what);
+        } else {
+            println!("FAIL {}: got {:?} want {:?}", what, got, want);
+            self.failed.push(what.to_owned());
+        }
+    }
+}
+
+fn check_anns(c: &mut Check) {
+    let ann = from_hex(ANN);
+    let mut pbh: [u8; 32] = from_hex(PARENT_BLOCK_HASH).as_slice().try_into().unwrap();
+    pbh.reverse();
+    c.eq(
+        "ann hash",
+        to_hex(&hash::compress32(&ann)),
+        ANN_HASH.to_owned(),
+    );
+
+    let pc_ann = PacketCryptAnn {
+        bytes: util::aligned_bytes(&ann, 4),
+    };
+    let c_res = packetcrypt_sys::check_ann(&pc_ann, &pbh, &mut ValidateCtx::default());
+    let mut vctx = packetcrypt_sys::pure::ValidateCtx::default();
+    let rs_res = packetcrypt_sys::pure::check_ann(&ann, &pbh, &mut vctx);
+    c.eq("ann is valid (C)", c_res.is_ok(), true);
+    c.eq("ann is valid (Rust)", rs_res.is_ok(), true);
+    c.eq("ann work hash, C and Rust agree", c_res, rs_res);
+    if let Ok(h) = rs_res {
+        let work_bits = packetcrypt_sys::work_bits(&ann);
+        c.eq(
+            "ann work hash meets its target",
+            packetcrypt_sys::pure::work_check(&h, work_bits),
+            true,
+        );
+    }
+    // One flipped bit must be caught
+    let mut bad = ann.clone();
+    bad[100] ^= 1;
+    let bad_ann = PacketCryptAnn {
+        bytes: util::aligned_bytes(&bad, 4),
+    };
+    let c_bad = packetcrypt_sys::check_ann(&bad_ann, &pbh, &mut ValidateCtx::default());
+    let rs_bad = packetcrypt_sys::pure::check_ann(&bad, &pbh, &mut vctx);
+    c.eq("damaged ann is invalid (C)", c_bad.is_err(), true);
+    c.eq("damaged ann is invalid (Rust)", rs_bad.is_err(), true);
+}
+
+fn check_difficulty(c: &mut Check) {
+    for (tar, age, want) in DEGRADE.iter() {
+        c.eq(
+            &format!("pc_degrade_announcement_target({:08x}, {})", tar, age),
+            difficulty::pc_degrade_announcement_target(*tar, *age),
+            *want,
+        );
+    }
+    for (blk, ann, count, want) in EFFECTIVE.iter() {
+        c.eq(
+            &format!(
+                "pc_get_effective_target({:08x}, {:08x}, {})",
+                blk, ann, count
+            ),
+            difficulty::pc_get_effective_target(*blk, *ann, *count),
+            *want,
+        );
+    }
+    for (tar, count, want) in MULTIPLIER.iter() {
+        c.eq(
+            &format!("pc_get_hashrate_multiplier({:08x}, {})", tar, count),
+            difficulty::pc_get_hashrate_multiplier(*tar, *count),
+            *want,
+        );
+    }
+    c.eq(
+        "pc_is_min_ann_diff_ok(2000ffff)",
+        difficulty::pc_is_min_ann_diff_ok(0x2000ffff),
+        true,
+    );
+    c.eq(
+        "pc_is_min_ann_diff_ok(21000001)",
+        difficulty::pc_is_min_ann_diff_ok(0x21000001),
+        false,
+    );
+    c.eq(
+        "tar_to_diff(2000ffff)",
+        difficulty::tar_to_diff(0x2000ffff),
+        256.0,
+    );
+    c.eq(
+        "tar_to_diff(1e0fffff)",
+        difficulty::tar_to_diff(0x1e0fffff),
+        1048577.0,
+    );
+}
+
+pub fn main() -> Result<()> {
+    let mut c = Check {
+        failed: Vec::new(),
+        passed: 0,
+    };
+    check_anns(&mut c);
+    check_difficulty(&mut c);
+    if !c.failed.is_empty() {
+        bail!(
+            "Self test failed {} of {} checks: {}",
+            c.failed.len(),
+            c.failed.len() + c.passed,
+            c.failed.join(", ")
+        );
+    }
+    println!("All {} checks passed", c.passed);
+    Ok(())
+}